@@ -0,0 +1,1547 @@
+//! Pieces of FedoraForge that don't need a Fedora system: the config types and profile merging,
+//! package list and discovery output parsing, drift and user/group diffing, hashing, and the
+//! `CommandRunner` seam every spawned command goes through.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+
+// ========================= COMMAND RUNNER =========================
+
+/// Spawns external commands and reads the system databases (/etc/passwd, /etc/shells) their
+/// results are compared with. The binary uses `SystemRunner`; `MockRunner` stands in for it
+/// so sync logic can run without dnf, podman or systemd.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `argv` to completion with stdout and stderr captured.
+    fn output(&self, argv: &[String]) -> io::Result<Output>;
+
+    /// Runs `argv` with stdout and stderr going straight to the terminal.
+    fn status(&self, argv: &[String]) -> io::Result<ExitStatus>;
+
+    /// Like `output`, with `stdin` written to the command's standard input.
+    fn input(&self, argv: &[String], stdin: &[u8]) -> io::Result<Output>;
+
+    /// Reads a system file such as /etc/passwd.
+    fn read_file(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, argv: &[String]) -> io::Result<Output> {
+        Command::new(&argv[0]).args(&argv[1..]).output()
+    }
+
+    fn status(&self, argv: &[String]) -> io::Result<ExitStatus> {
+        Command::new(&argv[0]).args(&argv[1..]).status()
+    }
+
+    fn input(&self, argv: &[String], stdin: &[u8]) -> io::Result<Output> {
+        let mut child = Command::new(&argv[0])
+            .args(&argv[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut pipe) = child.stdin.take() {
+            pipe.write_all(stdin)?;
+        }
+        child.wait_with_output()
+    }
+}
+
+/// Records every command and answers with canned output. Commands without a canned
+/// response succeed with empty output; files without canned content don't exist.
+#[derive(Default)]
+pub struct MockRunner {
+    responses: Mutex<HashMap<Vec<String>, (i32, String)>>,
+    calls: Mutex<Vec<Vec<String>>>,
+    files: Mutex<HashMap<String, String>>,
+}
+
+impl MockRunner {
+    /// Answers `argv` with the given exit code and stdout from now on.
+    pub fn respond(&self, argv: &[&str], code: i32, stdout: &str) {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.insert(argv.iter().map(|s| s.to_string()).collect(), (code, stdout.to_string()));
+        }
+    }
+
+    /// Serves `content` for reads of `path` from now on.
+    pub fn file(&self, path: &str, content: &str) {
+        if let Ok(mut files) = self.files.lock() {
+            files.insert(path.to_string(), content.to_string());
+        }
+    }
+
+    /// Every command run so far, in order.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn output(&self, argv: &[String]) -> io::Result<Output> {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(argv.to_vec());
+        }
+        let (code, stdout) = self.responses.lock()
+            .ok()
+            .and_then(|r| r.get(argv).cloned())
+            .unwrap_or((0, String::new()));
+        Ok(Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn status(&self, argv: &[String]) -> io::Result<ExitStatus> {
+        self.output(argv).map(|output| output.status)
+    }
+
+    fn input(&self, argv: &[String], _stdin: &[u8]) -> io::Result<Output> {
+        self.output(argv)
+    }
+
+    fn read_file(&self, path: &str) -> io::Result<String> {
+        self.files.lock()
+            .ok()
+            .and_then(|files| files.get(path).cloned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no canned content", path)))
+    }
+}
+
+// ========================= ERRORS =========================
+
+/// Categorised failures. Raised as anyhow errors so callers keep using `?`, and recovered with
+/// `error_kind` where the category matters (run logs, retries).
+#[derive(thiserror::Error, Debug)]
+pub enum FedoraForgeError {
+    #[error("`{cmd}` failed ({}): {}", .code.map_or_else(|| "terminated by signal".to_string(), |c| format!("exit code {}", c)), .stderr.trim())]
+    CommandFailed { cmd: String, code: Option<i32>, stderr: String },
+
+    #[error("failed to parse {path}")]
+    ConfigParse {
+        path: String,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("{0}")]
+    Validation(String),
+
+    #[error("{tool} is not installed or not in PATH{}", .package.map(|p| format!(" (install it with: sudo dnf install {})", p)).unwrap_or_default())]
+    MissingTool { tool: String, package: Option<&'static str> },
+}
+
+impl FedoraForgeError {
+    pub fn command_failed(cmd: &[&str], output: &Output) -> Self {
+        FedoraForgeError::CommandFailed {
+            cmd: cmd.join(" "),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+
+    pub fn missing_tool(tool: &str) -> Self {
+        FedoraForgeError::MissingTool { tool: tool.to_string(), package: providing_package(tool) }
+    }
+
+    pub fn validation(message: impl Into<String>) -> anyhow::Error {
+        FedoraForgeError::Validation(message.into()).into()
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FedoraForgeError::CommandFailed { .. } => "command_failed",
+            FedoraForgeError::ConfigParse { .. } => "config_parse",
+            FedoraForgeError::Validation(_) => "validation",
+            FedoraForgeError::MissingTool { .. } => "missing_tool",
+        }
+    }
+}
+
+/// Fedora package that ships a command we shell out to, for "not found" hints.
+fn providing_package(tool: &str) -> Option<&'static str> {
+    let package = match tool {
+        "nmcli" => "NetworkManager",
+        "cryptsetup" => "cryptsetup",
+        "flatpak" => "flatpak",
+        "podman" => "podman",
+        "git" => "git",
+        "curl" => "curl",
+        "dconf" => "dconf",
+        "gsettings" => "glib2",
+        "gnome-extensions" => "gnome-shell",
+        "pip" => "python3-pip",
+        "npm" => "nodejs-npm",
+        "cargo" => "cargo",
+        "wg" | "wg-quick" => "wireguard-tools",
+        "openvpn" => "openvpn",
+        "blkid" | "findmnt" | "mount" => "util-linux",
+        "hostnamectl" | "systemctl" | "systemd-analyze" => "systemd",
+        "restorecon" | "selinuxenabled" => "policycoreutils",
+        "rpm" => "rpm",
+        "dnf" => "dnf",
+        _ => return None,
+    };
+    Some(package)
+}
+
+// ========================= CONFIG =========================
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Distro {
+    Fedora,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub distro: Distro,
+    pub system: SystemConfig,
+    pub drives: Option<Vec<DriveConfig>>,
+    pub network_mounts: Option<Vec<NetworkMountConfig>>,
+    pub firewall: Option<FirewallConfig>,
+    pub desktop: Option<DesktopConfig>,
+    pub dconf: Option<DconfConfig>,
+    pub gnome_extensions: Option<GnomeExtensionsConfig>,
+    pub flatpak: Option<FlatpakConfig>,
+    pub podman: Option<PodmanConfig>,
+    pub vpn: Option<VpnConfig>,
+    pub dotfiles: Option<DotfilesConfig>,
+    pub custom_commands: Option<CustomCommandsConfig>,
+    pub packages: Option<ExtraPackagesConfig>,
+    pub apply_order: Option<Vec<Subsystem>>,
+    pub requires: Option<HashMap<Subsystem, Vec<Subsystem>>>,
+    pub files: Option<Vec<ManagedFile>>,
+    pub vars: Option<HashMap<String, String>>,
+}
+
+/// Top-level steps of a run, in the order `apply_order` can rearrange.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    SystemUpdate,
+    Hostname,
+    Drives,
+    Firewall,
+    SystemPackages,
+    Desktop,
+    GnomeExtensions,
+    Dconf,
+    Repos,
+    AmdGpu,
+    NvidiaGpu,
+    Flatpak,
+    LanguagePackages,
+    Services,
+    Users,
+    Files,
+    Containers,
+    Vpn,
+    Winapps,
+    Dotfiles,
+    CustomCommands,
+}
+
+pub const DEFAULT_APPLY_ORDER: [Subsystem; 21] = [
+    Subsystem::SystemUpdate,
+    Subsystem::Hostname,
+    Subsystem::Drives,
+    Subsystem::Firewall,
+    Subsystem::SystemPackages,
+    Subsystem::Desktop,
+    Subsystem::GnomeExtensions,
+    Subsystem::Dconf,
+    Subsystem::Repos,
+    Subsystem::AmdGpu,
+    Subsystem::NvidiaGpu,
+    Subsystem::Flatpak,
+    Subsystem::LanguagePackages,
+    Subsystem::Services,
+    Subsystem::Users,
+    Subsystem::Files,
+    Subsystem::Containers,
+    Subsystem::Vpn,
+    Subsystem::Winapps,
+    Subsystem::Dotfiles,
+    Subsystem::CustomCommands,
+];
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::SystemUpdate => "system_update",
+            Subsystem::Hostname => "hostname",
+            Subsystem::Drives => "drives",
+            Subsystem::Firewall => "firewall",
+            Subsystem::SystemPackages => "system_packages",
+            Subsystem::Desktop => "desktop",
+            Subsystem::GnomeExtensions => "gnome_extensions",
+            Subsystem::Dconf => "dconf",
+            Subsystem::Repos => "repos",
+            Subsystem::AmdGpu => "amd_gpu",
+            Subsystem::NvidiaGpu => "nvidia_gpu",
+            Subsystem::Flatpak => "flatpak",
+            Subsystem::LanguagePackages => "language_packages",
+            Subsystem::Services => "services",
+            Subsystem::Users => "users",
+            Subsystem::Files => "files",
+            Subsystem::Containers => "containers",
+            Subsystem::Vpn => "vpn",
+            Subsystem::Winapps => "winapps",
+            Subsystem::Dotfiles => "dotfiles",
+            Subsystem::CustomCommands => "custom_commands",
+        }
+    }
+}
+
+/// Packages declared in config.toml (usually from a profile) on top of the package files.
+/// They are installed when missing but never written back to the package files.
+#[derive(Deserialize, Debug, Default)]
+pub struct ExtraPackagesConfig {
+    pub system: Option<Vec<String>>,
+    pub flatpak: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ManagedFile {
+    pub path: String,                  // Absolute target path
+    pub content: Option<String>,       // Inline content...
+    pub source: Option<String>,        // ...or a file to copy it from
+    pub mode: Option<String>,          // Octal, e.g. "0644"
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub template: Option<bool>,        // Render {{ var }} placeholders (default: false)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SystemConfig {
+    pub hostname: Option<String>,
+    pub timezone: Option<String>,           // e.g. "Europe/Berlin", see `timedatectl list-timezones`
+    pub locale: Option<String>,             // System LANG, e.g. "en_US.UTF-8"
+    pub keymap: Option<String>,             // Console keymap, e.g. "us" or "de-nodeadkeys"
+    pub enable_amd_gpu: bool,
+    pub gpu_device_mode: Option<String>,    // Mode of /dev/kfd and DRM nodes in the AMD udev rule (default "0660")
+    #[serde(default)]
+    pub enable_nvidia_gpu: bool,            // akmod-nvidia from RPM Fusion nonfree (needs enable_rpm_fusion)
+    pub enable_nvidia_powerd: Option<bool>, // Enable nvidia-powerd (Dynamic Boost) with the driver (default: false)
+    pub enable_rpm_fusion: bool,
+    pub enable_winapps: bool,
+    pub rpm_fusion_sha256: Option<String>,  // Expected SHA-256 of the rpmfusion-free-release RPM
+    pub rpm_fusion_nonfree_sha256: Option<String>, // Expected SHA-256 of the rpmfusion-nonfree-release RPM
+    pub command_retries: Option<usize>,     // Attempts for network-bound dnf/flatpak/curl commands (default 1)
+    pub copr_repos: Option<Vec<String>>,    // COPR repos ("owner/project") enabled before package sync
+    pub package_manager_args: Option<Vec<String>>, // Extra dnf options for updates and installs
+    pub install_weak_deps: Option<bool>,    // Let dnf install recommended packages (default: true)
+    pub log_retention: Option<usize>,       // Per-run output logs to keep in ~/.config/fedoraforge/logs (default 20)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DesktopConfig {
+    pub environment: Option<String>,
+    pub packages: Option<Vec<String>>,
+    pub display_manager: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DconfConfig {
+    pub keys: Option<HashMap<String, String>>,                       // dconf path -> GVariant value
+    pub gsettings: Option<HashMap<String, HashMap<String, String>>>, // schema -> key -> GVariant value
+    pub dumps: Option<Vec<DconfDump>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DconfDump {
+    pub path: String, // dconf subtree, e.g. "/org/gnome/desktop/wm/keybindings/"
+    pub file: String, // keyfile produced by `dconf dump`
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GnomeExtensionsConfig {
+    pub extensions: Vec<String>, // UUIDs or extensions.gnome.org numeric IDs
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FlatpakConfig {
+    pub remotes: Option<Vec<FlatpakRemote>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FlatpakRemote {
+    pub name: String,
+    pub url: String,
+    pub gpg_key: Option<String>,   // Path or URL of a GPG key to import for the remote
+    pub priority: Option<i32>,
+    pub filter: Option<String>,    // Path to a flatpak filter file limiting which refs are visible
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PodmanConfig {
+    pub pre_container_setup: Option<Vec<SetupCommand>>,
+    pub containers: Option<Vec<Container>>,
+    pub prune_images: Option<bool>,
+    pub prune_all_images: Option<bool>,
+    /// Default for containers that don't set recreate_on_image_change
+    pub recreate_on_image_change: Option<bool>,
+}
+
+impl PodmanConfig {
+    /// Fills per-container settings left unset from their [podman] defaults.
+    pub fn apply_container_defaults(&mut self) {
+        for container in self.containers.iter_mut().flatten() {
+            container.recreate_on_image_change = container.recreate_on_image_change.or(self.recreate_on_image_change);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetupCommand {
+    pub description: String,
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Container {
+    pub name: String,
+    pub image: String,
+    pub raw_flags: Option<String>,
+    /// Start now without systemd management (mutually exclusive with autostart)
+    pub immediate_start: Option<bool>,
+    /// Deprecated alias for immediate_start
+    pub start_after_creation: Option<bool>,
+    /// Start at boot via a systemd Quadlet unit
+    pub autostart: Option<bool>,
+    /// Extra labels (managed-by=fedoraforge is always added)
+    pub labels: Option<HashMap<String, String>>,
+    pub annotations: Option<HashMap<String, String>>,
+    pub backup_volumes: Option<bool>,
+    /// Commands run once with `podman exec` after the container is created and running
+    pub post_create_exec: Option<Vec<String>>,
+    /// Recreate whenever the local image differs from the one the container was created from
+    pub recreate_on_image_change: Option<bool>,
+}
+
+impl Container {
+    pub fn immediate_start(&self) -> bool {
+        self.immediate_start.or(self.start_after_creation).unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VpnConfig {
+    #[serde(rename = "type")]
+    pub vpn_type: VpnType,
+    pub conf_path: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum VpnType {
+    Wireguard,
+    Openvpn,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DotfilesConfig {
+    pub setup_bashrc: bool,
+    pub setup_config_dirs: bool,
+    /// Sync snippet files from the project's .bashrc.d/ into ~/.bashrc.d/ instead of replacing .bashrc
+    #[serde(default)]
+    pub setup_bashrc_d: bool,
+    /// Shells whose startup files are migrated like .bashrc
+    #[serde(default)]
+    pub shells: Vec<Shell>,
+    /// Copy files into place, or symlink them to the project so later edits land in the repo
+    #[serde(default)]
+    pub mode: DotfilesMode,
+    /// Timestamped backups kept per file or directory; older ones are pruned (default: keep all)
+    pub max_backups: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DotfilesMode {
+    #[default]
+    Copy,
+    Symlink,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// Startup files relative to the home directory (and the project directory)
+    pub fn files(self) -> &'static [&'static str] {
+        match self {
+            Shell::Bash => &[".bashrc"],
+            Shell::Zsh => &[".zshrc", ".zshenv"],
+            Shell::Fish => &[".config/fish/config.fish"],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DriveConfig {
+    pub device: String,
+    pub mount_point: String,
+    pub encrypted: bool,
+    pub filesystem: Option<String>,
+    pub label: Option<String>,
+    pub force_update: Option<bool>,
+    pub options: Option<String>,          // fstab mount options (default: "defaults")
+    pub crypttab_options: Option<String>, // Extra crypttab options after "luks", e.g. "discard,nofail"
+    pub subvol: Option<String>,           // btrfs subvolume to mount, e.g. "@home"
+    pub keyfile: Option<String>,          // LUKS keyfile used instead of a passphrase prompt, should be mode 400/600
+}
+
+impl DriveConfig {
+    /// fstab options with `subvol=` merged in, so every subvolume of one filesystem gets its own line.
+    pub fn fstab_options(&self) -> String {
+        let options = self.options.as_deref().unwrap_or("defaults");
+        match &self.subvol {
+            Some(subvol) => format!("{},subvol={}", options, subvol),
+            None => options.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkFsType {
+    Nfs,
+    Cifs,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NetworkMountConfig {
+    #[serde(rename = "type")]
+    pub fs_type: NetworkFsType,
+    pub server: String,
+    pub share: String,                 // NFS export path or SMB share name
+    pub mount_point: String,
+    pub options: Option<String>,       // Extra mount options, appended to the defaults
+    pub credentials: Option<String>,   // CIFS credentials file (username=/password=), should be mode 600
+    pub force_update: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FirewallConfig {
+    pub zone: Option<String>,          // firewalld zone (default: the default zone)
+    pub services: Option<Vec<String>>, // firewalld service names, e.g. "ssh"
+    pub ports: Option<Vec<String>>,    // "8080/tcp", "60000-61000/udp"
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CustomCommandsConfig {
+    pub commands: Vec<String>,
+    pub run_once: Option<Vec<String>>,
+}
+
+/// Tables are merged key by key, arrays are appended, anything else is replaced. Array entries
+/// that are tables with a `name` (containers, remotes...) replace the base entry of the same name.
+pub fn merge_toml_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base_table), toml::Value::Table(overlay_table)) => {
+            for (key, value) in overlay_table {
+                match base_table.get_mut(&key) {
+                    Some(existing) => merge_toml_values(existing, value),
+                    None => {
+                        base_table.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base_array), toml::Value::Array(overlay_array)) => {
+            let entry_name = |value: &toml::Value| value.get("name").and_then(|n| n.as_str()).map(String::from);
+            for value in overlay_array {
+                let named = entry_name(&value)
+                    .and_then(|name| base_array.iter().position(|existing| entry_name(existing).as_deref() == Some(name.as_str())));
+                match named {
+                    Some(index) => base_array[index] = value,
+                    None if !base_array.contains(&value) => base_array.push(value),
+                    None => {}
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Reorders subsystems so each runs after everything it `requires`. Among subsystems whose
+/// requirements are met, the earliest in `order` goes first, so without `requires` the order
+/// is unchanged.
+pub fn sort_by_requirements(
+    order: Vec<Subsystem>,
+    requires: Option<&HashMap<Subsystem, Vec<Subsystem>>>,
+) -> Result<Vec<Subsystem>> {
+    let Some(requires) = requires else {
+        return Ok(order);
+    };
+
+    for (subsystem, deps) in requires {
+        if deps.contains(subsystem) {
+            return Err(FedoraForgeError::validation(format!("requires: '{}' cannot depend on itself", subsystem.name())));
+        }
+    }
+
+    let mut pending = order;
+    let mut sorted: Vec<Subsystem> = Vec::new();
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|subsystem| {
+            requires
+                .get(subsystem)
+                .map(|deps| deps.iter().all(|dep| sorted.contains(dep)))
+                .unwrap_or(true)
+        });
+
+        match ready {
+            Some(index) => sorted.push(pending.remove(index)),
+            None => {
+                let blocked: Vec<&str> = pending.iter().map(|subsystem| subsystem.name()).collect();
+                return Err(FedoraForgeError::validation(format!("requires contains a dependency cycle between: {}", blocked.join(", "))));
+            }
+        }
+    }
+
+    Ok(sorted)
+}
+
+/// Replaces `{{ name }}` placeholders. Undefined variables are an error rather than rendering empty.
+pub fn render_template(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let re = regex::Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}")
+        .context("Failed to compile template regex")?;
+
+    let mut undefined: Vec<&str> = re.captures_iter(template)
+        .filter_map(|caps| caps.get(1))
+        .map(|name| name.as_str())
+        .filter(|name| !vars.contains_key(*name))
+        .collect();
+    if !undefined.is_empty() {
+        undefined.sort();
+        undefined.dedup();
+        return Err(FedoraForgeError::validation(format!("Undefined template variables: {}", undefined.join(", "))));
+    }
+
+    Ok(re.replace_all(template, |caps: &regex::Captures| vars[&caps[1]].clone()).into_owned())
+}
+
+// ========================= PACKAGE LISTS =========================
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PackageList {
+    pub packages: Vec<PackageEntry>,
+}
+
+/// A package file entry: a bare name, or a table pinning a dnf package to a version.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PackageEntry {
+    Name(String),
+    Pinned { name: String, version: String },
+}
+
+impl PackageEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            PackageEntry::Name(name) | PackageEntry::Pinned { name, .. } => name,
+        }
+    }
+
+    /// What to hand to dnf: `name-version` when pinned
+    pub fn spec(&self) -> String {
+        match self {
+            PackageEntry::Name(name) => name.clone(),
+            PackageEntry::Pinned { name, version } => format!("{}-{}", name, version),
+        }
+    }
+}
+
+impl PackageList {
+    pub fn from_names(names: &[String]) -> Self {
+        PackageList { packages: names.iter().cloned().map(PackageEntry::Name).collect() }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.packages.iter().map(|p| p.name().to_string()).collect()
+    }
+}
+
+// ========================= DRIFT =========================
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DriftItem {
+    pub subsystem: String,
+    pub item: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Declared-but-missing and present-but-undeclared entries of one list-shaped subsystem.
+pub fn push_list_drift(items: &mut Vec<DriftItem>, subsystem: &str, declared: &[String], installed: &[String]) {
+    for pkg in declared.iter().filter(|p| !installed.contains(p)) {
+        items.push(DriftItem {
+            subsystem: subsystem.to_string(),
+            item: pkg.clone(),
+            expected: "installed".to_string(),
+            actual: "missing".to_string(),
+        });
+    }
+    for pkg in installed.iter().filter(|p| !declared.contains(p)) {
+        items.push(DriftItem {
+            subsystem: subsystem.to_string(),
+            item: pkg.clone(),
+            expected: "not declared".to_string(),
+            actual: "installed".to_string(),
+        });
+    }
+}
+
+// ========================= USERS AND GROUPS =========================
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserConfig {
+    pub uid: Option<u32>,              // User ID (auto-assign if None)
+    pub gid: Option<u32>,              // Primary group ID
+    pub groups: Option<Vec<String>>,   // Supplementary groups
+    pub home: Option<String>,          // Home directory
+    pub shell: Option<String>,         // Login shell
+    pub comment: Option<String>,       // GECOS field (full name, etc.)
+    pub create_home: Option<bool>,     // Create home directory (default: true)
+    pub system: Option<bool>,          // Is system user (default: false)
+    pub homed: Option<bool>,           // Manage with systemd-homed instead of useradd (default: false)
+    pub storage: Option<String>,       // homed storage backend: luks, fscrypt, directory, subvolume
+    pub password_hash: Option<String>, // crypt(3) hash for /etc/shadow, never plaintext (openssl passwd -6)
+    pub locked: Option<bool>,          // Lock (true) or unlock (false) the password; unset leaves it as is
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GroupConfig {
+    pub gid: Option<u32>,              // Group ID (auto-assign if None)
+    pub members: Option<Vec<String>>,  // Group members
+    pub system: Option<bool>,          // Is system group (default: false)
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrentUserInfo {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<String>,
+    pub home: String,
+    pub shell: String,
+    pub comment: String,
+    pub homed: bool,
+    pub shadow_password: Option<String>, // /etc/shadow password field, read only for users that manage it
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrentGroupInfo {
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// One field that differs between a declared user/group and the system.
+#[derive(Debug, PartialEq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub current: String,
+    pub desired: String,
+}
+
+fn scalar_diff<T: PartialEq + ToString>(field: &'static str, current: &T, desired: Option<&T>) -> Option<FieldDiff> {
+    desired.filter(|d| *d != current).map(|d| FieldDiff {
+        field,
+        current: current.to_string(),
+        desired: d.to_string(),
+    })
+}
+
+/// Lists compare as sets, since usermod -G and gpasswd don't keep an order.
+fn list_diff(field: &'static str, current: &[String], desired: Option<&Vec<String>>) -> Option<FieldDiff> {
+    let desired = desired?;
+    let current_set: std::collections::HashSet<_> = current.iter().collect();
+    let desired_set: std::collections::HashSet<_> = desired.iter().collect();
+    (current_set != desired_set).then(|| FieldDiff {
+        field,
+        current: format!("[{}]", current.join(", ")),
+        desired: format!("[{}]", desired.join(", ")),
+    })
+}
+
+pub fn user_field_diffs(desired: &UserConfig, current: &CurrentUserInfo) -> Vec<FieldDiff> {
+    [
+        scalar_diff("uid", &current.uid, desired.uid.as_ref()),
+        scalar_diff("gid", &current.gid, desired.gid.as_ref()),
+        scalar_diff("home", &current.home, desired.home.as_ref()),
+        scalar_diff("shell", &current.shell, desired.shell.as_ref()),
+        scalar_diff("comment", &current.comment, desired.comment.as_ref()),
+        list_diff("groups", &current.groups, desired.groups.as_ref()),
+        password_diff(desired, current),
+        current.shadow_password.as_ref()
+            .and_then(|password| scalar_diff("locked", &password.starts_with('!'), desired.locked.as_ref())),
+    ].into_iter().flatten().collect()
+}
+
+/// Hashes are compared, never printed.
+fn password_diff(desired: &UserConfig, current: &CurrentUserInfo) -> Option<FieldDiff> {
+    let hash = desired.password_hash.as_deref()?;
+    let current_hash = current.shadow_password.as_deref()?.trim_start_matches('!');
+    (current_hash != hash).then(|| FieldDiff {
+        field: "password",
+        current: if current_hash.is_empty() { "not set" } else { "different hash" }.to_string(),
+        desired: "password_hash".to_string(),
+    })
+}
+
+pub fn group_field_diffs(desired: &GroupConfig, current: &CurrentGroupInfo) -> Vec<FieldDiff> {
+    [
+        scalar_diff("gid", &current.gid, desired.gid.as_ref()),
+        list_diff("members", &current.members, desired.members.as_ref()),
+    ].into_iter().flatten().collect()
+}
+
+// ========================= DISCOVERY PARSERS =========================
+
+// Text-in, data-out halves of the discovery functions, run against bundled fixtures by --self-test
+
+fn sorted_unique(mut items: Vec<String>) -> Vec<String> {
+    items.sort();
+    items.dedup();
+    items
+}
+
+/// One name per line, e.g. `dnf repoquery --qf '%{name}\n'`.
+pub fn parse_name_lines(stdout: &str) -> Vec<String> {
+    sorted_unique(stdout.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect())
+}
+
+/// `flatpak list --app --columns=application`, without the header some versions print.
+pub fn parse_flatpak_list(stdout: &str) -> Vec<String> {
+    sorted_unique(stdout.lines().map(str::trim).filter(|l| !l.is_empty() && *l != "Application ID").map(String::from).collect())
+}
+
+/// `pip list --format=freeze`: `name==version` lines.
+pub fn parse_pip_freeze(stdout: &str) -> Vec<String> {
+    sorted_unique(stdout.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| l.split("==").next().map(String::from))
+        .collect())
+}
+
+/// `npm list -g --depth=0 --json`: top-level dependencies, except npm itself.
+pub fn parse_npm_list(stdout: &str) -> serde_json::Result<Vec<String>> {
+    let json: serde_json::Value = serde_json::from_str(stdout)?;
+    let packages = json.get("dependencies")
+        .and_then(|d| d.as_object())
+        .map(|deps| deps.keys().filter(|name| *name != "npm").cloned().collect())
+        .unwrap_or_default();
+    Ok(sorted_unique(packages))
+}
+
+/// `cargo install --list`: `name v1.2.3:` lines, each followed by its indented binaries.
+pub fn parse_cargo_install_list(stdout: &str) -> Vec<String> {
+    sorted_unique(stdout.lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with(char::is_whitespace))
+        .filter_map(|l| l.split_whitespace().next().map(String::from))
+        .collect())
+}
+
+/// `remote:app.id`, or a bare `app.id` from Flathub.
+pub fn parse_flatpak_package(package: &str) -> (&str, &str) {
+    match package.split_once(':') {
+        Some((remote, app_id)) => (remote, app_id),
+        None => ("flathub", package),
+    }
+}
+
+/// `firewall-cmd --list-all`: the zone's (services, ports).
+pub fn parse_firewall_list_all(stdout: &str) -> (Vec<String>, Vec<String>) {
+    let field = |name: &str| -> Vec<String> {
+        stdout.lines()
+            .find_map(|l| l.trim().strip_prefix(name)?.strip_prefix(':'))
+            .map(|values| sorted_unique(values.split_whitespace().map(String::from).collect()))
+            .unwrap_or_default()
+    };
+    (field("services"), field("ports"))
+}
+
+/// `localectl status`: the system LANG and the console keymap ("(unset)" counts as none).
+pub fn parse_localectl_status(stdout: &str) -> (Option<String>, Option<String>) {
+    let value = |key: &str| stdout.lines()
+        .find_map(|l| l.trim().strip_prefix(key)?.trim().strip_prefix(':'))
+        .map(str::trim);
+    let lang = value("System Locale")
+        .and_then(|v| v.split_whitespace().find_map(|setting| setting.strip_prefix("LANG=")))
+        .map(String::from);
+    let keymap = value("VC Keymap").filter(|k| !k.is_empty() && *k != "(unset)" && *k != "n/a").map(String::from);
+    (lang, keymap)
+}
+
+/// `image:` values in a compose file, in the order the services declare them.
+pub fn parse_compose_images(compose: &str) -> Vec<String> {
+    let mut images: Vec<String> = Vec::new();
+    for image in compose.lines().filter_map(|l| l.trim().strip_prefix("image:")) {
+        let image = image.split(" #").next().unwrap_or_default().trim().trim_matches(|c| c == '"' || c == '\'');
+        if !image.is_empty() && !images.iter().any(|i| i == image) {
+            images.push(image.to_string());
+        }
+    }
+    images
+}
+
+/// A volume backup file name, `<container>__<volume>__<timestamp>.tar`, as (container, volume, timestamp).
+/// The container name ends at the first `__` and the timestamp starts after the last one.
+pub fn parse_volume_backup_name(file_name: &str) -> Option<(&str, &str, u64)> {
+    let stem = file_name.strip_suffix(".tar")?;
+    let (rest, timestamp) = stem.rsplit_once("__")?;
+    let (container, volume) = rest.split_once("__")?;
+    if container.is_empty() || volume.is_empty() {
+        return None;
+    }
+    Some((container, volume, timestamp.parse().ok()?))
+}
+
+/// `systemctl list-unit-files --type=service --plain`: manageable services and whether they are enabled.
+/// Static, generated, masked and similar states are left out, as are D-Bus activated and autostart units.
+pub fn parse_service_unit_files(stdout: &str) -> Vec<(String, bool)> {
+    let mut services = Vec::new();
+    for line in stdout.lines() {
+        if line.trim().is_empty() || line.starts_with("UNIT FILE") {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 {
+            continue;
+        }
+        let name = parts[0].trim_end_matches(".service");
+        let state = parts[1];
+        if matches!(state, "static" | "transient" | "generated" | "indirect" | "alias" | "masked" | "enabled-runtime") {
+            continue;
+        }
+        if name.starts_with("dbus-:") || (name.starts_with("app-") && name.contains("@autostart")) {
+            continue;
+        }
+        services.push((name.to_string(), state == "enabled"));
+    }
+    services
+}
+
+/// `systemctl list-units --type=service --plain`: loaded services and whether they are active.
+pub fn parse_active_services(stdout: &str) -> HashMap<String, bool> {
+    stdout.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with("UNIT"))
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            (parts.len() >= 4).then(|| (parts[0].trim_end_matches(".service").to_string(), parts[2] == "active"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswdEntry {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub comment: String,
+    pub home: String,
+    pub shell: String,
+}
+
+/// One `/etc/passwd` line. Split into at most seven fields so anything after the sixth colon stays
+/// in the shell; None for lines missing fields or with an unparsable UID or GID.
+pub fn parse_passwd_line(line: &str) -> Option<PasswdEntry> {
+    let parts: Vec<&str> = line.splitn(7, ':').collect();
+    if parts.len() < 7 {
+        return None;
+    }
+    Some(PasswdEntry {
+        name: parts[0].to_string(),
+        uid: parts[2].parse().ok()?,
+        gid: parts[3].parse().ok()?,
+        comment: parts[4].to_string(),
+        home: parts[5].to_string(),
+        shell: parts[6].to_string(),
+    })
+}
+
+/// `/etc/passwd` entries, skipping malformed lines.
+pub fn parse_passwd(content: &str) -> Vec<PasswdEntry> {
+    content.lines().filter_map(parse_passwd_line).collect()
+}
+
+/// A crypt(3) hash as stored in /etc/shadow: `$id$[params$]salt$hash`, e.g. from `openssl passwd -6`.
+/// Plaintext, empty and `!`-locked values are not hashes.
+pub fn is_crypt_hash(value: &str) -> bool {
+    let Some(rest) = value.strip_prefix('$') else {
+        return false;
+    };
+    let fields: Vec<&str> = rest.split('$').collect();
+    fields.len() >= 3
+        && ["1", "2a", "2b", "2y", "5", "6", "7", "y", "gy", "sha1"].contains(&fields[0])
+        && fields[1..].iter().all(|field| {
+            !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || "./=,".contains(c))
+        })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupEntry {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// `/etc/group` entries. Lines without all four fields or with an unparsable GID are skipped.
+pub fn parse_group(content: &str) -> Vec<GroupEntry> {
+    content.lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split(':').collect();
+            if parts.len() < 4 {
+                return None;
+            }
+            Some(GroupEntry {
+                name: parts[0].to_string(),
+                gid: parts[2].parse().ok()?,
+                members: parts[3].split(',').filter(|m| !m.is_empty()).map(String::from).collect(),
+            })
+        })
+        .collect()
+}
+
+pub fn parse_dconf_keyfile(content: &str) -> HashMap<String, String> {
+    // Flatten a dconf keyfile into "section/key" -> value
+    let mut keys = HashMap::new();
+    let mut section = String::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            keys.insert(format!("{}/{}", section, key.trim()), value.trim().to_string());
+        }
+    }
+
+    keys
+}
+
+// ========================= TABLE FILES =========================
+
+/// New content for a line-per-entry table file such as /etc/fstab or /etc/crypttab: lines matching
+/// `is_entry` are dropped and `entry` is appended verbatim. Always ends with a newline.
+pub fn upsert_table_entry(content: &str, entry: &str, is_entry: impl Fn(&str) -> bool) -> String {
+    let mut lines: Vec<&str> = content.lines().filter(|line| !is_entry(line)).collect();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.push(entry);
+    lines.join("\n") + "\n"
+}
+
+// ========================= FLATPAK PERMISSIONS =========================
+
+// Filesystem grants that expose most of the host, with or without a :ro/:rw/:create suffix
+const HIGH_RISK_FLATPAK_FILESYSTEMS: [&str; 4] = ["host", "host-os", "host-etc", "home"];
+
+/// High-risk permissions in a Flatpak metadata file (`flatpak remote-info --show-metadata`):
+/// broad filesystem access, all devices, and talking to the Flatpak portal (sandbox escape).
+pub fn flatpak_high_risk_permissions(metadata: &str) -> Vec<String> {
+    let mut risky = Vec::new();
+    let mut section = "";
+    for line in metadata.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let values = value.split(';').map(str::trim).filter(|v| !v.is_empty());
+        match (section, key.trim()) {
+            ("Context", "filesystems") => {
+                for fs in values {
+                    let base = fs.split(':').next().unwrap_or(fs);
+                    if HIGH_RISK_FLATPAK_FILESYSTEMS.contains(&base) {
+                        risky.push(format!("filesystem={}", fs));
+                    }
+                }
+            }
+            ("Context", "devices") if value.split(';').any(|d| d.trim() == "all") => {
+                risky.push("device=all".to_string());
+            }
+            ("Session Bus Policy", "org.freedesktop.Flatpak") if value.trim() == "talk" => {
+                risky.push("talk-name=org.freedesktop.Flatpak".to_string());
+            }
+            _ => {}
+        }
+    }
+    risky
+}
+
+// ========================= HASHING AND QUOTING =========================
+
+pub fn generate_service_hash(service_def: &str, timer_def: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(service_def.trim().as_bytes());
+    if let Some(timer) = timer_def {
+        hasher.update(timer.trim().as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Package names out of `rpm -qa --recommends` output. Versions and comparison operators are
+/// dropped, and rich dependencies like `(foo if bar)` contribute every package they name.
+pub fn parse_recommends_names(output: &str) -> Vec<String> {
+    const KEYWORDS: [&str; 7] = ["and", "or", "if", "else", "with", "without", "unless"];
+    let mut names: Vec<String> = output
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|token| token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '/'))
+        .filter(|token| !KEYWORDS.contains(token))
+        .map(String::from)
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Splits `line` into words the way sh would, honouring single and double quotes and backslash
+/// escapes but expanding nothing. `None` when a quote is left open.
+pub fn split_shell_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            '\n' => {}
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => word.get_or_insert_with(String::new).push('\\'),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+pub fn sorted_entries(map: &Option<HashMap<String, String>>) -> Vec<(&String, &String)> {
+    let mut entries: Vec<_> = map.iter().flatten().collect();
+    entries.sort();
+    entries
+}
+
+pub fn generate_container_config_hash(container: &Container) -> String {
+    hash_container_config(container, &container.name)
+}
+
+/// Every field of the container as a JSON object with sorted keys. Unset fields are dropped so
+/// adding a new optional field doesn't change existing hashes, and settings that don't affect the
+/// container itself are normalized away.
+fn container_config_value(container: &Container, name: &str) -> serde_json::Map<String, serde_json::Value> {
+    let mut normalized = container.clone();
+    normalized.name = name.to_string();
+    normalized.immediate_start = Some(container.immediate_start());
+    normalized.start_after_creation = None;
+    normalized.backup_volumes = None;
+    normalized.post_create_exec = None;
+    normalized.recreate_on_image_change = None;
+
+    let mut fields = match serde_json::to_value(&normalized) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    };
+    fields.retain(|_, value| !value.is_null());
+    fields
+}
+
+/// Config hash as if the container were named `name` (used to spot pure renames).
+pub fn hash_container_config(container: &Container, name: &str) -> String {
+    let fields = container_config_value(container, name);
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::Value::Object(fields).to_string().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash format used before the whole struct was hashed, kept to migrate existing state without recreating.
+pub fn legacy_container_config_hash(container: &Container) -> String {
+    let name = &container.name;
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update(container.image.as_bytes());
+    hasher.update(container.raw_flags.as_deref().unwrap_or("").as_bytes());
+    hasher.update([if container.immediate_start() { 1 } else { 0 }]);
+    hasher.update([if container.autostart.unwrap_or(false) { 1 } else { 0 }]);
+    // Only hashed when set so existing containers keep their hash
+    for (kind, map) in [("label", &container.labels), ("annotation", &container.annotations)] {
+        for (key, value) in sorted_entries(map) {
+            hasher.update(format!("{}:{}={}", kind, key, value).as_bytes());
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn container_config_fields(container: &Container) -> HashMap<String, String> {
+    container_config_value(container, &container.name)
+        .into_iter()
+        .map(|(field, value)| match value {
+            serde_json::Value::String(text) => (field, text),
+            other => (field, other.to_string()),
+        })
+        .collect()
+}
+
+pub fn generate_file_hash(file_path: &Path) -> Result<String> {
+    let content = fs::read(file_path)
+        .with_context(|| format!("Failed to read file {:?}", file_path))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&content);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes the tree's layout as well as its contents: every entry's relative path, file modes and
+/// contents, and symlink targets, so renames, removals and chmods change the hash too.
+pub fn generate_directory_hash(dir_path: &Path) -> Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut hasher = Sha256::new();
+
+    // Walk in sorted order for consistency
+    for entry in walkdir::WalkDir::new(dir_path).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir_path).unwrap_or(entry.path());
+        let file_type = entry.file_type();
+
+        // Directory modes are left out: copies are created with the umask, not the source mode
+        if file_type.is_dir() {
+            hasher.update(format!("d {}\0", relative.display()).as_bytes());
+        } else if file_type.is_symlink() {
+            // copy_dir_all recreates links rather than their targets
+            hasher.update(format!("l {}\0", relative.display()).as_bytes());
+            hasher.update(fs::read_link(entry.path())?.as_os_str().as_encoded_bytes());
+            hasher.update(b"\0");
+        } else {
+            let mode = entry.metadata()?.permissions().mode() & 0o7777;
+            let content = fs::read(entry.path())?;
+            hasher.update(format!("f {:o} {}\0", mode, relative.display()).as_bytes());
+            // Length prefix keeps one file's content from running into the next entry
+            hasher.update((content.len() as u64).to_le_bytes());
+            hasher.update(&content);
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_parsers_read_the_bundled_fixtures() {
+        let passwd = parse_passwd(include_str!("../fixtures/passwd"));
+        let names: Vec<&str> = passwd.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["root", "bin", "nobody", "alice", "bob", "carol"]);
+        assert_eq!(passwd[4], PasswdEntry {
+            name: "bob".to_string(),
+            uid: 1001,
+            gid: 1001,
+            comment: String::new(),
+            home: "/home/bob".to_string(),
+            shell: "/usr/bin/zsh".to_string(),
+        });
+
+        let mut unit_files = parse_service_unit_files(include_str!("../fixtures/systemctl-list-unit-files.txt"));
+        unit_files.sort();
+        assert_eq!(unit_files, [("cups".to_string(), false), ("getty@".to_string(), true), ("sshd".to_string(), true)]);
+
+        assert_eq!(parse_cargo_install_list(include_str!("../fixtures/cargo-install-list.txt")), ["bat", "ripgrep", "starship"]);
+        assert_eq!(parse_flatpak_list(include_str!("../fixtures/flatpak-list.txt")), ["com.valvesoftware.Steam", "org.mozilla.firefox"]);
+        assert_eq!(parse_flatpak_package("flathub-beta:com.valvesoftware.Steam"), ("flathub-beta", "com.valvesoftware.Steam"));
+        assert_eq!(parse_flatpak_package("org.mozilla.firefox"), ("flathub", "org.mozilla.firefox"));
+    }
+
+    #[test]
+    fn passwd_line_keeps_commas_in_gecos() {
+        let entry = parse_passwd_line("carol:x:1002:1002:Carol Smith,Room 12,555-1234,:/home/carol:/bin/bash").unwrap();
+        assert_eq!(entry.comment, "Carol Smith,Room 12,555-1234,");
+        assert_eq!(entry.home, "/home/carol");
+        assert_eq!(entry.shell, "/bin/bash");
+    }
+
+    #[test]
+    fn truncated_passwd_line_is_rejected() {
+        assert_eq!(parse_passwd_line("dave:x:1003:1003:Dave"), None);
+        assert_eq!(parse_passwd_line("dave:x:1003:1003:Dave:/home/dave"), None);
+        assert_eq!(parse_passwd_line(""), None);
+        assert_eq!(parse_passwd_line("dave:x:10o3:1003:Dave:/home/dave:/bin/bash"), None);
+        assert_eq!(parse_passwd_line("dave:x:1003::Dave:/home/dave:/bin/bash"), None);
+    }
+
+    #[test]
+    fn upsert_table_entry_replaces_a_quoted_mount_point() {
+        let entry = "LABEL=data /mnt/my'drive ext4 defaults 0 2";
+        let content = "UUID=1 / btrfs defaults 0 0\nLABEL=data /mnt/my'drive ext4 noauto 0 2\n\n";
+        let updated = upsert_table_entry(content, entry, |line| line.split_whitespace().nth(1) == Some("/mnt/my'drive"));
+        assert_eq!(updated, format!("UUID=1 / btrfs defaults 0 0\n{}\n", entry));
+        assert_eq!(upsert_table_entry("", entry, |_| false), format!("{}\n", entry));
+    }
+
+    #[test]
+    fn volume_backup_names_match_the_container_exactly() {
+        assert_eq!(parse_volume_backup_name("web__data__1700000000.tar"), Some(("web", "data", 1700000000)));
+        assert_eq!(parse_volume_backup_name("web__db__data__1700000000.tar"), Some(("web", "db__data", 1700000000)));
+        assert_eq!(parse_volume_backup_name("web__data__latest.tar"), None);
+        assert_eq!(parse_volume_backup_name("web__1700000000.tar"), None);
+        assert_eq!(parse_volume_backup_name("web__data__1700000000.tar.gz"), None);
+    }
+
+    #[test]
+    fn recommends_names_drop_versions_and_rich_dependency_keywords() {
+        let output = "flatpak-selinux = 2:1.15.8-1.fc40\n(gnome-tweaks if gnome-shell)\n/usr/bin/pinentry\nflatpak-selinux\n";
+        assert_eq!(parse_recommends_names(output), ["/usr/bin/pinentry", "flatpak-selinux", "gnome-shell", "gnome-tweaks"]);
+    }
+
+    #[test]
+    fn shell_words_follow_sh_quoting() {
+        assert_eq!(split_shell_words("-e PUID=1000  -p 3000:3000").unwrap(), ["-e", "PUID=1000", "-p", "3000:3000"]);
+        assert_eq!(split_shell_words(r#"-e 'TZ=Europe/A b' --label "note=it's \"ok\"""#).unwrap(), ["-e", "TZ=Europe/A b", "--label", r#"note=it's "ok""#]);
+        assert_eq!(split_shell_words(r"-v a\ b:/data ''").unwrap(), ["-v", "a b:/data", ""]);
+        assert_eq!(split_shell_words("-e 'unterminated"), None);
+    }
+
+    #[test]
+    fn directory_hash_tracks_modes_and_names() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("fedoraforge-hash-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin/run"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("notes.txt"), "notes").unwrap();
+        let initial = generate_directory_hash(&dir).unwrap();
+        assert_eq!(generate_directory_hash(&dir).unwrap(), initial);
+
+        let mut permissions = fs::metadata(dir.join("bin/run")).unwrap().permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        fs::set_permissions(dir.join("bin/run"), permissions).unwrap();
+        let chmodded = generate_directory_hash(&dir).unwrap();
+        assert_ne!(chmodded, initial);
+
+        fs::rename(dir.join("notes.txt"), dir.join("todo.txt")).unwrap();
+        let renamed = generate_directory_hash(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(renamed, chmodded);
+        assert_ne!(renamed, initial);
+    }
+
+    #[test]
+    fn container_hash_changes_with_every_container_field() {
+        let base: Container = toml::from_str(r#"
+            name = "web"
+            image = "docker.io/library/nginx:latest"
+            raw_flags = "-p 8080:80"
+            immediate_start = false
+            autostart = false
+            labels = { tier = "front" }
+            annotations = { owner = "ops" }
+        "#).expect("valid container");
+        let base_hash = generate_container_config_hash(&base);
+
+        type Edit = fn(&mut Container);
+        let edits: [(&str, Edit); 9] = [
+            ("name", |c| c.name = "web2".to_string()),
+            ("image", |c| c.image = "docker.io/library/nginx:1.27".to_string()),
+            ("raw_flags", |c| c.raw_flags = Some("-p 8081:80".to_string())),
+            ("raw_flags unset", |c| c.raw_flags = None),
+            ("immediate_start", |c| c.immediate_start = Some(true)),
+            ("start_after_creation", |c| {
+                c.immediate_start = None;
+                c.start_after_creation = Some(true);
+            }),
+            ("autostart", |c| c.autostart = Some(true)),
+            ("labels", |c| c.labels = Some(HashMap::from([("tier".to_string(), "back".to_string())]))),
+            ("annotations", |c| c.annotations = Some(HashMap::from([("owner".to_string(), "dev".to_string())]))),
+        ];
+        for (field, edit) in edits {
+            let mut changed = base.clone();
+            edit(&mut changed);
+            assert_ne!(generate_container_config_hash(&changed), base_hash, "changing {} kept the hash", field);
+        }
+
+        // Settings that only affect how fedoraforge manages the container leave it alone
+        let mut managed = base.clone();
+        managed.backup_volumes = Some(true);
+        managed.post_create_exec = Some(vec!["true".to_string()]);
+        managed.recreate_on_image_change = Some(true);
+        assert_eq!(generate_container_config_hash(&managed), base_hash);
+    }
+
+    #[test]
+    fn config_parses_a_minimal_file() {
+        let config: Config = toml::from_str(r#"
+            distro = "fedora"
+
+            [system]
+            hostname = "forge"
+            enable_amd_gpu = false
+            enable_rpm_fusion = true
+            enable_winapps = false
+
+            [[podman.containers]]
+            name = "web"
+            image = "docker.io/library/nginx:latest"
+            start_after_creation = true
+        "#).expect("valid config");
+        assert_eq!(config.distro, Distro::Fedora);
+        assert_eq!(config.system.hostname.as_deref(), Some("forge"));
+        let containers = config.podman.as_ref().and_then(|p| p.containers.as_ref()).unwrap();
+        assert!(containers[0].immediate_start());
+        assert!(toml::from_str::<Config>("distro = \"arch\"\n[system]\nenable_amd_gpu = false\nenable_rpm_fusion = false\nenable_winapps = false").is_err());
+    }
+
+    #[test]
+    fn profile_merge_replaces_named_array_entries() {
+        let mut base: toml::Value = toml::from_str(r#"
+            [system]
+            hostname = "base"
+            copr_repos = ["a/one"]
+
+            [[podman.containers]]
+            name = "web"
+            image = "nginx:1"
+        "#).unwrap();
+        let overlay: toml::Value = toml::from_str(r#"
+            [system]
+            hostname = "laptop"
+            copr_repos = ["a/one", "b/two"]
+
+            [[podman.containers]]
+            name = "web"
+            image = "nginx:2"
+
+            [[podman.containers]]
+            name = "db"
+            image = "postgres:16"
+        "#).unwrap();
+        merge_toml_values(&mut base, overlay);
+
+        assert_eq!(base["system"]["hostname"].as_str(), Some("laptop"));
+        assert_eq!(base["system"]["copr_repos"].as_array().unwrap().len(), 2);
+        let images: Vec<&str> = base["podman"]["containers"].as_array().unwrap().iter()
+            .map(|c| c["image"].as_str().unwrap())
+            .collect();
+        assert_eq!(images, ["nginx:2", "postgres:16"]);
+    }
+
+    #[test]
+    fn requirements_reorder_subsystems_and_reject_cycles() {
+        let order = vec![Subsystem::Containers, Subsystem::Users, Subsystem::Dotfiles];
+        assert_eq!(sort_by_requirements(order.clone(), None).unwrap(), order);
+
+        let requires = HashMap::from([(Subsystem::Containers, vec![Subsystem::Users])]);
+        assert_eq!(sort_by_requirements(order.clone(), Some(&requires)).unwrap(),
+                   [Subsystem::Users, Subsystem::Containers, Subsystem::Dotfiles]);
+
+        let cycle = HashMap::from([
+            (Subsystem::Containers, vec![Subsystem::Users]),
+            (Subsystem::Users, vec![Subsystem::Containers]),
+        ]);
+        let err = sort_by_requirements(order.clone(), Some(&cycle)).unwrap_err().to_string();
+        assert_eq!(err, "requires contains a dependency cycle between: containers, users");
+
+        let itself = HashMap::from([(Subsystem::Users, vec![Subsystem::Users])]);
+        assert!(sort_by_requirements(order, Some(&itself)).is_err());
+    }
+
+    #[test]
+    fn templates_render_defined_variables_only() {
+        let vars = HashMap::from([("user".to_string(), "alice".to_string()), ("home".to_string(), "/home/alice".to_string())]);
+        assert_eq!(render_template("{{user}} lives in {{ home }}", &vars).unwrap(), "alice lives in /home/alice");
+        let err = render_template("{{ user }} {{ shell }} {{ editor }} {{ shell }}", &vars).unwrap_err().to_string();
+        assert_eq!(err, "Undefined template variables: editor, shell");
+    }
+
+    #[test]
+    fn dconf_keyfile_flattens_sections() {
+        let keys = parse_dconf_keyfile("# dump\n[/]\nclose=['<Super>q']\n\n[switch-windows]\nbinding = ['<Alt>Tab']\n");
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["//close"], "['<Super>q']");
+        assert_eq!(keys["switch-windows/binding"], "['<Alt>Tab']");
+    }
+
+    #[test]
+    fn user_diffs_list_only_declared_fields_that_differ() {
+        let desired: UserConfig = toml::from_str("uid = 1000\nshell = \"/bin/zsh\"\ngroups = [\"video\", \"wheel\"]\npassword_hash = \"$6$salt$hash\"").unwrap();
+        let current = CurrentUserInfo {
+            uid: 1000,
+            gid: 1000,
+            groups: vec!["wheel".to_string(), "video".to_string()],
+            home: "/home/alice".to_string(),
+            shell: "/bin/bash".to_string(),
+            comment: String::new(),
+            homed: false,
+            shadow_password: Some("!$6$salt$other".to_string()),
+        };
+        assert_eq!(user_field_diffs(&desired, &current), [
+            FieldDiff { field: "shell", current: "/bin/bash".to_string(), desired: "/bin/zsh".to_string() },
+            FieldDiff { field: "password", current: "different hash".to_string(), desired: "password_hash".to_string() },
+        ]);
+
+        let group: GroupConfig = toml::from_str("gid = 2000\nmembers = [\"alice\"]").unwrap();
+        let current_group = CurrentGroupInfo { gid: 2001, members: vec!["alice".to_string()] };
+        assert_eq!(group_field_diffs(&group, &current_group), [
+            FieldDiff { field: "gid", current: "2001".to_string(), desired: "2000".to_string() },
+        ]);
+    }
+}
@@ -1,15 +1,49 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use colored::*;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::process::{Command, Output};
+use std::process::Output;
 use std::env;
 use std::io::{self, Write, BufRead};
 use std::path::Path;
 use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use sha2::{Sha256, Digest};
-use dirs;
+use fedoraforge::{
+    container_config_fields, DEFAULT_APPLY_ORDER, flatpak_high_risk_permissions, generate_container_config_hash, generate_directory_hash,
+    generate_file_hash, generate_service_hash, group_field_diffs, hash_container_config, is_crypt_hash, legacy_container_config_hash,
+    merge_toml_values, parse_active_services, parse_cargo_install_list, parse_compose_images, parse_dconf_keyfile, parse_firewall_list_all,
+    parse_flatpak_list, parse_flatpak_package, parse_group, parse_localectl_status, parse_name_lines, parse_npm_list, parse_passwd,
+    parse_passwd_line, parse_pip_freeze, parse_recommends_names, parse_service_unit_files, parse_volume_backup_name, push_list_drift,
+    render_template, shell_quote, sort_by_requirements, sorted_entries, split_shell_words, upsert_table_entry, user_field_diffs,
+    CommandRunner, Config, Container, CurrentGroupInfo, CurrentUserInfo, CustomCommandsConfig, DconfConfig, DconfDump, DesktopConfig,
+    Distro, DotfilesConfig, DotfilesMode, DriftItem, DriveConfig, FedoraForgeError, FieldDiff, FirewallConfig, FlatpakConfig, FlatpakRemote,
+    GnomeExtensionsConfig, GroupConfig, GroupEntry, ManagedFile, NetworkFsType, NetworkMountConfig, PackageEntry, PackageList,
+    PasswdEntry, PodmanConfig, Subsystem, SystemConfig, SystemRunner, UserConfig, VpnConfig, VpnType,
+};
+
+// Console output is also appended to the per-run output log (see RUN OUTPUT LOG)
+macro_rules! println {
+    () => { println!("") };
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        console_line(&line);
+        log_line(&line);
+    }};
+}
+
+/// A `[DEBUG]` line: always written to the run output log, shown on the console when `$show` is true.
+macro_rules! debug {
+    ($show:expr, $($arg:tt)*) => {{
+        let line = format!("{} {}", "[DEBUG]".cyan(), format!($($arg)*));
+        if $show {
+            console_line(&line);
+        }
+        log_line(&line);
+    }};
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about = "FedoraForge - Forge your perfect Fedora system with declarative configuration")]
@@ -45,72 +79,186 @@ struct Args {
     /// Never recreate containers (config/systemd only)
     #[arg(long)]
     no_recreate: bool,
-}
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum Distro {
-    Fedora,
-}
+    /// Restore the latest volume backups for the named container
+    #[arg(long, value_name = "CONTAINER")]
+    restore_volumes: Option<String>,
 
-#[derive(Deserialize, Debug)]
-struct Config {
-    distro: Distro,
-    system: SystemConfig,
-    drives: Option<Vec<DriveConfig>>,
-    desktop: Option<DesktopConfig>,
-    flatpak: Option<FlatpakConfig>,
-    podman: Option<PodmanConfig>,
-    vpn: Option<VpnConfig>,
-    dotfiles: Option<DotfilesConfig>,
-    custom_commands: Option<CustomCommandsConfig>,
-}
+    /// Upgrade to another Fedora release with dnf system-upgrade, then reboot into it
+    #[arg(long, value_name = "VERSION")]
+    fedora_upgrade: Option<u32>,
 
-#[derive(Deserialize, Debug)]
-struct SystemConfig {
-    hostname: Option<String>,
-    enable_amd_gpu: bool,
-    enable_rpm_fusion: bool,
-    enable_winapps: bool,
-}
+    /// Never remove, delete or disable anything (install/add/modify only)
+    #[arg(long)]
+    no_removals: bool,
 
-#[derive(Deserialize, Debug)]
-struct DesktopConfig {
-    environment: Option<String>,
-    packages: Option<Vec<String>>,
-    display_manager: Option<String>,
-}
+    /// Prune dangling container images after container management
+    #[arg(long)]
+    prune_images: bool,
 
-#[derive(Deserialize, Debug)]
-struct FlatpakConfig {
-    remotes: Option<Vec<FlatpakRemote>>,
-}
+    /// Remove FedoraForge's caches and clones (never state files) after confirmation, reporting reclaimed space
+    #[arg(long)]
+    clear_cache: bool,
 
-#[derive(Deserialize, Debug)]
-struct FlatpakRemote {
-    name: String,
-    url: String,
+    /// Reverse the actions recorded in the most recent run log
+    #[arg(long)]
+    undo_last: bool,
+
+    /// Compare two run logs (paths, or "latest"/"previous" from the runs directory)
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+    compare: Option<Vec<String>>,
+
+    /// Skip discovery when config is unchanged since the last successful run and a quick check finds no drift
+    #[arg(long)]
+    fast: bool,
+
+    /// Read-only drift report across all subsystems (exit code 2 when drift is found)
+    #[arg(long)]
+    report_drift: bool,
+
+    /// Run the discovery parsers against bundled fixtures and exit (needs no Fedora system)
+    #[arg(long)]
+    self_test: bool,
+
+    /// Show what an apply would install, remove, enable, create or modify, then exit (exit code 2 when out of sync)
+    #[arg(long)]
+    status: bool,
+
+    /// List everything FedoraForge considers orphaned, across all subsystems (read-only)
+    #[arg(long)]
+    list_orphans: bool,
+
+    /// Verify custom service and timer definitions with systemd-analyze without installing them
+    #[arg(long)]
+    check_only: bool,
+
+    /// List configured Flatpak remotes and whether config declares them (read-only)
+    #[arg(long)]
+    list_flatpak_remotes: bool,
+
+    /// Webhook URL that receives the drift report (--report-drift) or pull status (--pull) as JSON
+    #[arg(long, value_name = "URL")]
+    notify_webhook: Option<String>,
+
+    /// Print a container's Quadlet file, the unit systemd generated from it, and its status (read-only)
+    #[arg(long, value_name = "CONTAINER")]
+    show_quadlet: Option<String>,
+
+    /// Show the users/groups plan (create, field-level changes, orphans) without changing anything
+    #[arg(long)]
+    explain_plan: bool,
+
+    /// Explain the lifecycle action chosen for each container without changing anything
+    #[arg(long)]
+    explain_action: bool,
+
+    /// Apply a [profiles.<name>] override block (remembered for later runs, "none" clears it)
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Upper bound on concurrent operations such as cargo installs (defaults to the CPU count)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    max_parallel: Option<u16>,
+
+    /// Print every declared package (dnf, Flatpak, pip, npm, cargo) as one manifest and exit
+    #[arg(long)]
+    export_packages: bool,
+
+    /// Show a unified diff before overwriting managed files (dotfiles, [[files]], udev rules, package lists)
+    #[arg(long)]
+    show_diff: bool,
+
+    /// Like --show-diff, but only for this managed file (repeatable)
+    #[arg(long, value_name = "PATH")]
+    diff_file: Vec<std::path::PathBuf>,
+
+    /// Skip subsystems that completed in the last failed run (ignored if the config changed since)
+    #[arg(long)]
+    resume: bool,
+
+    /// Ignore stored state and don't write any: every run compares config against the live system only
+    #[arg(long, conflicts_with_all = ["resume", "fast", "undo_last"])]
+    no_state: bool,
+
+    /// Print the commands and file writes a run would perform without changing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Dry run that prints the commands it would run as a shell script on stdout, grouped by subsystem
+    #[arg(long)]
+    print_effective_commands: bool,
+
+    /// Treat warnings about the config or the system as errors (exit non-zero)
+    #[arg(long)]
+    strict: bool,
+
+    /// Queue changes that need a reboot (GPU drivers, display manager) in a one-shot unit that runs at next boot
+    #[arg(long)]
+    stage: bool,
+
+    /// Extra options passed to dnf update/install, e.g. "--setopt=install_weak_deps=False --best"
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    package_manager_args: Option<String>,
+
+    /// Allow running as root (user-scoped state, containers and dotfiles then belong to root)
+    #[arg(long)]
+    allow_root: bool,
+
+    /// Target another user for user-scoped setup (containers, user services, dotfiles, WinApps)
+    #[arg(long = "user", value_name = "NAME")]
+    target_user: Option<String>,
+
+    /// Show a consolidated plan of pending changes and confirm once before applying
+    #[arg(long)]
+    preflight: bool,
+
+    /// Compute the pending changes and save them as a plan file without changing anything
+    #[arg(long, value_name = "FILE", conflicts_with = "apply")]
+    plan: Option<String>,
+
+    /// Apply a plan saved by --plan; changes outside the plan are still prompted for (refused if the config changed since)
+    #[arg(long, value_name = "FILE")]
+    apply: Option<String>,
+
+    /// Fetch the config repo from git, apply it and write a status file (for timers across a fleet)
+    #[arg(long, value_name = "GIT_URL")]
+    pull: Option<String>,
+
+    /// Format for --export-packages
+    #[arg(long, value_enum, default_value_t = OutputFormat::Toml)]
+    output: OutputFormat,
 }
 
-#[derive(Deserialize, Debug)]
-struct PodmanConfig {
-    pre_container_setup: Option<Vec<SetupCommand>>,
-    containers: Option<Vec<Container>>,
+impl Args {
+    /// Modes that only report or write a plan: they don't care who runs them, and can run
+    /// alongside another instance.
+    fn is_read_only(&self) -> bool {
+        self.export_packages
+            || self.list_flatpak_remotes
+            || self.list_orphans
+            || self.check_only
+            || self.status
+            || self.report_drift
+            || self.explain_plan
+            || self.explain_action
+            || self.plan.is_some()
+            || self.compare.is_some()
+            || self.show_quadlet.is_some()
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct SetupCommand {
-    description: String,
-    command: String,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Toml,
+    Json,
 }
 
-#[derive(Deserialize, Debug)]
-struct Container {
+/// A remote as reported by `flatpak remotes`.
+struct FlatpakRemoteInfo {
     name: String,
-    image: String,
-    raw_flags: Option<String>,
-    start_after_creation: bool,
-    autostart: Option<bool>,
+    url: String,
+    priority: Option<i32>,
+    filter: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -124,45 +272,15 @@ struct ContainerInfo {
     image_hash: Option<String>,
     last_updated: u64,
     managed: bool,
+    // Per-field config values at last update, used to explain why a container changed
+    #[serde(default)]
+    config_fields: HashMap<String, String>,
+    // post_create_exec still has to run (set on create/recreate, cleared once it succeeded)
+    #[serde(default)]
+    post_create_pending: bool,
 }
 
 
-#[derive(Deserialize, Debug)]
-struct VpnConfig {
-    #[serde(rename = "type")]
-    vpn_type: VpnType,
-    conf_path: String,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum VpnType {
-    Wireguard,
-    Openvpn,
-}
-
-#[derive(Deserialize, Debug)]
-struct DotfilesConfig {
-    setup_bashrc: bool,
-    setup_config_dirs: bool,
-}
-
-#[derive(Deserialize, Debug)]
-struct DriveConfig {
-    device: String,
-    mount_point: String,
-    encrypted: bool,
-    filesystem: Option<String>,
-    label: Option<String>,
-    force_update: Option<bool>,
-}
-
-#[derive(Deserialize, Debug)]
-struct CustomCommandsConfig {
-    commands: Vec<String>,
-    run_once: Option<Vec<String>>,
-}
-
 #[derive(Deserialize, Debug)]
 struct WinAppsConfig {
     rdp_user: String,
@@ -177,6 +295,15 @@ struct WinAppsConfig {
     multimon: Option<bool>,
     rdp_flags: Option<String>,
     rdp_env: Option<String>,
+    winapps_ref: Option<String>,   // Commit, tag or branch of the WinApps repo to check out (default: its default branch)
+}
+
+// Commit of the WinApps clone after the last run, so the version in use is visible
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct WinAppsState {
+    commit: String,
+    winapps_ref: Option<String>,
+    updated_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -195,11 +322,200 @@ struct CommandExecutionRecord {
 struct DotfilesState {
     bashrc_hash: Option<String>,
     config_dirs: HashMap<String, String>, // dir_name -> hash
+    #[serde(default)]
+    bashrc_snippets: HashMap<String, String>, // snippet file name -> hash
+    #[serde(default)]
+    shell_files: HashMap<String, String>, // path relative to home (other than .bashrc) -> hash
+    #[serde(default)]
+    symlinks: HashMap<String, String>, // path relative to home -> project path it links to
+}
+
+impl DotfilesState {
+    fn shell_file_hash(&self, file: &str) -> Option<&String> {
+        // .bashrc predates the other shell files and keeps its own field
+        if file == ".bashrc" {
+            self.bashrc_hash.as_ref()
+        } else {
+            self.shell_files.get(file)
+        }
+    }
+
+    fn set_shell_file_hash(&mut self, file: &str, hash: String) {
+        if file == ".bashrc" {
+            self.bashrc_hash = Some(hash);
+        } else {
+            self.shell_files.insert(file.to_string(), hash);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ProfileState {
+    active_profile: Option<String>,
+}
+
+
+// Written by --plan, executed by --apply
+#[derive(Serialize, Deserialize, Debug)]
+struct PlanFile {
+    created_at: u64,
+    config: String,
+    config_hash: String,
+    items: Vec<DriftItem>,
+}
+
+// Items of the plan being applied, set once by --apply or by confirming the --preflight plan
+static APPLIED_PLAN: std::sync::OnceLock<Vec<DriftItem>> = std::sync::OnceLock::new();
+
+/// Whether the plan passed to --apply (or confirmed by --preflight) lists every one of `items`
+/// under `subsystem`, so a prompt about them counts as answered. Otherwise nothing is pre-approved.
+fn planned(subsystem: &str, items: &[&str]) -> bool {
+    let Some(plan) = APPLIED_PLAN.get() else {
+        return false;
+    };
+    !items.is_empty() && items.iter().all(|item| plan.iter().any(|i| i.subsystem == subsystem && i.item == *item))
+}
+
+// Result of the last --pull run, for fleet monitoring
+#[derive(Serialize, Debug)]
+struct PullStatus {
+    url: String,
+    commit: String,
+    hostname: String,
+    started_at: u64,
+    finished_at: u64,
+    success: bool,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DriftReport {
+    generated_at: u64,
+    hostname: String,
+    drift_found: bool,
+    items: Vec<DriftItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct FilesState {
+    files: HashMap<String, ManagedFileRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ManagedFileRecord {
+    hash: String,
+    // Content from before FedoraForge first wrote the file, restored when the entry is removed
+    backup: Option<String>,
+    backup_metadata: Option<String>,
+    managed_at: u64,
+}
+
+// Checkpoint of a run in progress, removed once a run completes
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RunCheckpoint {
+    config_hash: String,
+    completed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RunState {
+    config_hash: Option<String>,
+    last_success: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ActionCategory {
+    Package,
+    Flatpak,
+    Pip,
+    Npm,
+    Cargo,
+    Service,
+    Container,
+    User,
+    Group,
+    File,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ActionKind {
+    Install,
+    Remove,
+    Enable,
+    Disable,
+    Create,
+    Delete,
+    Overwrite,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RunAction {
+    category: ActionCategory,
+    kind: ActionKind,
+    target: String,
+    // Service scope for services, backup path for file overwrites
+    detail: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct PackageList {
-    packages: Vec<String>,
+struct RunLog {
+    started_at: u64,
+    finished_at: u64,
+    config: String,
+    success: bool,
+    error: Option<String>,
+    #[serde(default)]
+    error_kind: Option<String>,
+    #[serde(default)]
+    undone: bool,
+    actions: Vec<RunAction>,
+    #[serde(default)]
+    podman_storage: Option<PodmanStorageSummary>,
+    #[serde(default)]
+    timings: Vec<SubsystemTiming>,
+    #[serde(default)]
+    results: Vec<SubsystemReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubsystemTiming {
+    subsystem: String,
+    seconds: f64,
+}
+
+/// What a subsystem did during a run; summed per subsystem for the end-of-run table.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+struct SubsystemResult {
+    created: usize,
+    modified: usize,
+    removed: usize,
+    skipped: usize,
+}
+
+impl std::ops::AddAssign for SubsystemResult {
+    fn add_assign(&mut self, other: Self) {
+        self.created += other.created;
+        self.modified += other.modified;
+        self.removed += other.removed;
+        self.skipped += other.skipped;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubsystemReport {
+    subsystem: String,
+    #[serde(flatten)]
+    result: SubsystemResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PodmanStorageSummary {
+    images_bytes: u64,
+    containers_bytes: u64,
+    volumes_bytes: u64,
+    reclaimable_bytes: u64,
 }
 
 // Services configuration structures
@@ -269,43 +585,64 @@ enum ServiceScope {
 // Users and Groups configuration structures
 #[derive(Deserialize, Serialize, Debug)]
 struct UsersGroupsConfig {
+    // Override the managed ID ranges (default 1000-60000)
+    min_uid: Option<u32>,
+    max_uid: Option<u32>,
+    min_gid: Option<u32>,
+    max_gid: Option<u32>,
     users: Option<HashMap<String, UserConfig>>,
     groups: Option<HashMap<String, GroupConfig>>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct UserConfig {
-    uid: Option<u32>,              // User ID (auto-assign if None)
-    gid: Option<u32>,              // Primary group ID
-    groups: Option<Vec<String>>,   // Supplementary groups
-    home: Option<String>,          // Home directory
-    shell: Option<String>,         // Login shell
-    comment: Option<String>,       // GECOS field (full name, etc.)
-    create_home: Option<bool>,     // Create home directory (default: true)
-    system: Option<bool>,          // Is system user (default: false)
-}
+impl UsersGroupsConfig {
+    /// Makes the configured ranges the ones used by discovery and UID/GID validation.
+    fn apply_id_ranges(&self) -> Result<()> {
+        let (min_uid, max_uid) = (self.min_uid.unwrap_or(DEFAULT_MIN_USER_UID), self.max_uid.unwrap_or(DEFAULT_MAX_USER_UID));
+        let (min_gid, max_gid) = (self.min_gid.unwrap_or(DEFAULT_MIN_GROUP_GID), self.max_gid.unwrap_or(DEFAULT_MAX_GROUP_GID));
+        if min_uid > max_uid {
+            return Err(FedoraForgeError::validation(format!("min_uid {} is above max_uid {}", min_uid, max_uid)));
+        }
+        if min_gid > max_gid {
+            return Err(FedoraForgeError::validation(format!("min_gid {} is above max_gid {}", min_gid, max_gid)));
+        }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct GroupConfig {
-    gid: Option<u32>,              // Group ID (auto-assign if None)
-    members: Option<Vec<String>>,  // Group members
-    system: Option<bool>,          // Is system group (default: false)
+        MIN_USER_UID.store(min_uid, Ordering::SeqCst);
+        MAX_USER_UID.store(max_uid, Ordering::SeqCst);
+        MIN_GROUP_GID.store(min_gid, Ordering::SeqCst);
+        MAX_GROUP_GID.store(max_gid, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Declared users that discovery would skip: an explicit UID outside the managed range, or a system account.
+    fn out_of_range_users(&self) -> Vec<String> {
+        self.users.iter().flatten()
+            .filter(|(_, u)| u.system.unwrap_or(false) || u.uid.is_some_and(|uid| !user_uid_range().contains(&uid)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Declared groups that discovery would skip, as for users.
+    fn out_of_range_groups(&self) -> Vec<String> {
+        self.groups.iter().flatten()
+            .filter(|(_, g)| g.system.unwrap_or(false) || g.gid.is_some_and(|gid| !group_gid_range().contains(&gid)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
 }
 
-#[derive(Debug, Clone)]
-struct CurrentUserInfo {
-    uid: u32,
-    gid: u32,
-    groups: Vec<String>,
-    home: String,
-    shell: String,
-    comment: String,
+// Flatpak remotes FedoraForge added, so remotes the user added by hand are never removed
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct FlatpakRemotesState {
+    remotes: HashMap<String, ManagedFlatpakRemote>,
+    // Hash of the last imported GPG key per remote, so keys are only re-imported when they change
+    #[serde(default)]
+    gpg_keys: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
-struct CurrentGroupInfo {
-    gid: u32,
-    members: Vec<String>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ManagedFlatpakRemote {
+    url: String,
+    added_at: u64,
 }
 
 // State tracking for managed users and groups
@@ -313,6 +650,17 @@ struct CurrentGroupInfo {
 struct UsersGroupsState {
     managed_users: HashMap<String, ManagedUserInfo>,
     managed_groups: HashMap<String, ManagedGroupInfo>,
+    #[serde(default)]
+    managed_memberships: Vec<ManagedMembership>,
+}
+
+// Supplementary group memberships added on behalf of another subsystem (e.g. render for AMD GPUs)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ManagedMembership {
+    user: String,
+    group: String,
+    reason: String,
+    managed_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -327,18 +675,117 @@ struct ManagedGroupInfo {
     managed_at: u64,
 }
 
-// Constants for user/group filtering
-const MIN_USER_UID: u32 = 1000;
-const MIN_GROUP_GID: u32 = 1000;
-const MAX_USER_UID: u32 = 60000;
-const MAX_GROUP_GID: u32 = 60000;
+// Default range of users/groups that are discovered and managed
+const DEFAULT_MIN_USER_UID: u32 = 1000;
+const DEFAULT_MIN_GROUP_GID: u32 = 1000;
+const DEFAULT_MAX_USER_UID: u32 = 60000;
+const DEFAULT_MAX_GROUP_GID: u32 = 60000;
+
+// Effective ranges, overridden once from users-groups.toml (UsersGroupsConfig::apply_id_ranges)
+static MIN_USER_UID: AtomicU32 = AtomicU32::new(DEFAULT_MIN_USER_UID);
+static MAX_USER_UID: AtomicU32 = AtomicU32::new(DEFAULT_MAX_USER_UID);
+static MIN_GROUP_GID: AtomicU32 = AtomicU32::new(DEFAULT_MIN_GROUP_GID);
+static MAX_GROUP_GID: AtomicU32 = AtomicU32::new(DEFAULT_MAX_GROUP_GID);
+
+fn user_uid_range() -> std::ops::RangeInclusive<u32> {
+    MIN_USER_UID.load(Ordering::SeqCst)..=MAX_USER_UID.load(Ordering::SeqCst)
+}
+
+fn group_gid_range() -> std::ops::RangeInclusive<u32> {
+    MIN_GROUP_GID.load(Ordering::SeqCst)..=MAX_GROUP_GID.load(Ordering::SeqCst)
+}
+
+// ========================= COMMAND EXECUTION =========================
+
+// Runner behind spawn_output/spawn_streamed, wired up once in main. The sync functions take the
+// runner as a parameter instead (and hand it to the `_via` variants) so tests can pass a MockRunner.
+static COMMAND_RUNNER: std::sync::OnceLock<Box<dyn CommandRunner>> = std::sync::OnceLock::new();
+
+fn command_runner() -> &'static dyn CommandRunner {
+    COMMAND_RUNNER.get_or_init(|| Box::new(SystemRunner)).as_ref()
+}
+
+/// Spawns `cmd` and collects its output, turning a missing binary into a `MissingTool` error.
+fn spawn_output(cmd: &[&str]) -> Result<Output> {
+    spawn_output_via(command_runner(), cmd)
+}
+
+fn spawn_output_via(runner: &dyn CommandRunner, cmd: &[&str]) -> Result<Output> {
+    let cmd = user_command(cmd);
+    runner.output(&cmd).map_err(|e| spawn_error(&cmd[0], e))
+}
+
+/// Like `spawn_output`, but the command writes straight to the terminal so long runs show progress.
+/// The returned output has only the exit status.
+fn spawn_streamed(cmd: &[&str]) -> Result<Output> {
+    spawn_streamed_via(command_runner(), cmd)
+}
+
+fn spawn_streamed_via(runner: &dyn CommandRunner, cmd: &[&str]) -> Result<Output> {
+    let cmd = user_command(cmd);
+    let status = runner.status(&cmd).map_err(|e| spawn_error(&cmd[0], e))?;
+    Ok(Output { status, stdout: Vec::new(), stderr: Vec::new() })
+}
+
+/// Like `spawn_output`, with `input` fed to the command's stdin.
+fn spawn_input_via(runner: &dyn CommandRunner, cmd: &[&str], input: &[u8]) -> Result<Output> {
+    let cmd = user_command(cmd);
+    runner.input(&cmd, input).map_err(|e| spawn_error(&cmd[0], e))
+}
+
+fn spawn_error(tool: &str, e: io::Error) -> anyhow::Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        FedoraForgeError::missing_tool(tool).into()
+    } else {
+        anyhow::Error::new(e).context(format!("Failed to run {}", tool))
+    }
+}
+
+/// The category of the first `FedoraForgeError` in an error's chain, if any.
+fn error_kind(err: &anyhow::Error) -> Option<&'static str> {
+    err.downcast_ref::<FedoraForgeError>()
+        .or_else(|| err.chain().find_map(|e| e.downcast_ref::<FedoraForgeError>()))
+        .map(FedoraForgeError::kind)
+}
+
+/// FedoraForge expects a regular user with sudo: user services, Quadlet, dotfiles and state all
+/// live in the invoking user's home. Running as root would silently configure root instead.
+fn check_running_user(allow_root: bool) -> Result<()> {
+    let output = spawn_output(&["id", "-u"])?;
+    if String::from_utf8_lossy(&output.stdout).trim() != "0" {
+        return Ok(());
+    }
+
+    // Provisioning someone else's account from root is what --user is for
+    if let Some(target) = TARGET_USER.get() {
+        println!("{} Running as root, user-scoped setup targets {}", "[INFO]".blue(), target.name);
+        return Ok(());
+    }
+
+    if let Ok(sudo_user) = env::var("SUDO_USER") {
+        if !allow_root {
+            return Err(FedoraForgeError::validation(format!(
+                "FedoraForge was started with sudo. Run it as {} without sudo; it asks for sudo itself where needed (or pass --allow-root to configure root)",
+                sudo_user
+            )));
+        }
+    } else if !allow_root {
+        return Err(FedoraForgeError::validation(
+            "FedoraForge is running as root. Run it as your regular user, or pass --allow-root to configure the root account",
+        ));
+    }
+
+    warn(format!("Running as root: user services, containers, dotfiles and state will target root's home ({})",
+        dirs::home_dir().map(|h| h.display().to_string()).unwrap_or_default()))
+}
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    EFFECTIVE_COMMANDS_MODE.store(args.print_effective_commands, Ordering::SeqCst);
 
     // Validate flag conflicts
     if args.yes && args.no {
-        anyhow::bail!("Cannot specify both --yes and --no flags");
+        return Err(FedoraForgeError::validation("Cannot specify both --yes and --no flags"));
     }
 
     if args.verbose {
@@ -346,20 +793,42 @@ fn main() -> Result<()> {
         println!("{} Command line arguments: {:?}", "[DEBUG]".cyan(), args);
     }
 
+    let _ = COMMAND_RUNNER.set(Box::new(SystemRunner));
+    set_max_parallel(args.max_parallel.map(usize::from));
+    SHOW_DIFFS.store(args.show_diff, Ordering::SeqCst);
+    let _ = DIFF_FILES.set(args.diff_file.iter().map(|p| absolute_path(p)).collect());
+    NO_STATE.store(args.no_state, Ordering::SeqCst);
+    DRY_RUN.store(args.dry_run || args.print_effective_commands, Ordering::SeqCst);
+    VERBOSE.store(args.verbose, Ordering::SeqCst);
+    STRICT.store(args.strict, Ordering::SeqCst);
+    STAGE.store(args.stage, Ordering::SeqCst);
+    debug!(args.verbose, "Running up to {} operations concurrently", max_parallel());
+
+    if let Some(name) = &args.target_user {
+        set_target_user(name)?;
+    }
+
+    if args.self_test {
+        return run_self_test();
+    }
+
+    if !args.is_read_only() {
+        check_running_user(args.allow_root)?;
+        acquire_run_lock()?;
+    }
+
     // Handle --initial flag to generate package config files from current system state
     if args.initial {
         println!("{} Generating package configuration from current system state...", "[INFO]".blue());
 
-        if args.verbose {
-            println!("{} Creating config directory if it doesn't exist", "[DEBUG]".cyan());
-        }
+        debug!(args.verbose, "Creating config directory if it doesn't exist");
         // Create config directory if it doesn't exist
         fs::create_dir_all("config")
             .with_context(|| "Failed to create config directory")?;
 
         // Generate system packages config
-        let system_packages = get_user_installed_packages()?;
-        update_system_packages_file(&system_packages)?;
+        let system_packages = get_user_installed_packages(command_runner())?;
+        update_system_packages_file(&system_packages, &HashMap::new())?;
 
         // Generate flatpak packages config
         let flatpak_packages = get_installed_flatpaks().unwrap_or_else(|_| {
@@ -400,205 +869,607 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    println!("🔥 FedoraForge: Forging your perfect Fedora system...");
+    if args.undo_last {
+        return undo_last_run(args.yes, args.no, args.verbose);
+    }
 
-    let config_content = fs::read_to_string(&args.config)
-        .context(format!("Failed to read config file: {}", args.config))?;
-    let config: Config = toml::from_str(&config_content)
-        .context("Failed to parse TOML config")?;
+    if args.clear_cache {
+        return clear_cache(args.yes, args.no, args.verbose);
+    }
 
-    // Check if running on the correct distro
-    let os_release = fs::read_to_string("/etc/os-release")?;
-    let detected_distro = detect_distro(&os_release)?;
+    if let Some(runs) = &args.compare {
+        return compare_run_logs(&runs[0], &runs[1]);
+    }
 
-    if detected_distro != config.distro {
-        println!("{}", format!("Warning: Configuration is for {:?} but detected {:?}. Continuing...",
-                              config.distro, detected_distro).yellow());
+    if args.explain_action {
+        return explain_container_actions(&args);
     }
 
-    // Update system
-    if args.verbose {
-        println!("{} Updating system packages...", "[DEBUG]".cyan());
+    if let Some(container_name) = &args.show_quadlet {
+        return show_quadlet(container_name);
     }
-    update_system_packages(&config.distro, args.verbose)?;
 
-    // Set hostname
-    if let Some(hostname) = &config.system.hostname {
-        let current_hostname = run_command_output(&["hostnamectl", "--static"])?;
-        let current = String::from_utf8(current_hostname.stdout)?.trim().to_string();
-        if current != *hostname {
-            run_command(&["sudo", "hostnamectl", "set-hostname", hostname], &format!("Setting hostname to {}", hostname))?;
-            println!("{}", "You may need to reboot for hostname changes.".yellow());
-        }
+    if args.explain_plan {
+        return explain_users_groups_plan(args.verbose);
     }
 
-    // Setup drives early as other components may depend on them
-    if let Some(drives) = &config.drives {
-        setup_drives(drives, args.verbose)?;
+    if args.status {
+        let out_of_sync = show_status(&args)?;
+        std::process::exit(if out_of_sync { 2 } else { 0 });
     }
 
-    // Synchronize system packages with installed packages
-    sync_system_packages(args.yes, args.no, args.verbose)?;
+    if args.report_drift {
+        let drift_found = report_drift(&args)?;
+        std::process::exit(if drift_found { 2 } else { 0 });
+    }
 
-    // Desktop Environment Setup
-    if let Some(desktop_config) = &config.desktop {
-        setup_desktop_environment(&config.distro, desktop_config)?;
+    if args.export_packages {
+        return export_packages(args.output);
     }
 
-    // Enable additional repositories if configured
-    if config.system.enable_rpm_fusion {
-        enable_additional_repos(&config.distro)?;
+    if args.list_flatpak_remotes {
+        return list_flatpak_remotes(&args);
     }
 
-    // AMD GPU setup
-    if config.system.enable_amd_gpu {
-        setup_amd_gpu(&config.distro)?;
+    if args.check_only {
+        return check_custom_units();
     }
 
-    // Flatpak setup
-    setup_flatpak(&config.distro, config.flatpak.as_ref(), args.verbose)?;
+    if args.list_orphans {
+        return list_orphans(&args);
+    }
 
-    // Synchronize Flatpak packages with installed applications
-    let _flatpak_packages = sync_flatpak_packages(args.yes, args.no, args.verbose).unwrap_or_else(|e| {
-        println!("{} Flatpak synchronization failed: {}", "[WARNING]".yellow(), e);
-        Vec::new()
-    });
+    if let Some(plan_path) = &args.plan {
+        return write_plan(plan_path, &args);
+    }
 
-    // Synchronize pip packages with installed packages
-    let _pip_packages = sync_pip_packages(args.yes, args.no, args.verbose).unwrap_or_else(|e| {
-        println!("{} pip synchronization skipped: {}", "[WARNING]".yellow(), e);
-        Vec::new()
-    });
+    if let Some(container_name) = &args.restore_volumes {
+        return restore_container_volumes(container_name, args.yes, args.no, args.verbose);
+    }
 
-    // Synchronize npm packages with installed packages
-    let _npm_packages = sync_npm_packages(args.yes, args.no, args.verbose).unwrap_or_else(|e| {
-        println!("{} npm synchronization skipped: {}", "[WARNING]".yellow(), e);
-        Vec::new()
-    });
+    if let Some(version) = args.fedora_upgrade {
+        return upgrade_fedora_release(version, args.yes, args.no, args.verbose);
+    }
 
-    // Synchronize cargo packages with installed binaries
-    let _cargo_packages = sync_cargo_packages(args.yes, args.no, args.verbose).unwrap_or_else(|e| {
-        println!("{} cargo synchronization skipped: {}", "[WARNING]".yellow(), e);
-        Vec::new()
-    });
+    let started_at = get_current_timestamp();
+    if let Err(e) = open_output_log(started_at) {
+        println!("{} Failed to open run output log: {}", "[WARN]".yellow(), e);
+    }
+    let pull_commit = match &args.pull {
+        Some(url) => match checkout_pull_repo(url, args.verbose) {
+            Ok(commit) => Some(commit),
+            Err(e) => {
+                // A checkout that never happened is exactly what fleet monitoring needs to hear about
+                let result = Err(e);
+                if let Err(e) = report_pull_status(url, "", started_at, &result, args.notify_webhook.as_deref()) {
+                    println!("{} Failed to write pull status: {}", "[WARN]".yellow(), e);
+                }
+                close_output_log();
+                return result;
+            }
+        },
+        None => None,
+    };
+    let mut result = apply_configuration(&args);
 
-    // Synchronize services with system state
-    sync_services(args.yes, args.no, args.verbose)?;
+    // Unit files changed late in the run (Quadlet, removals) still need one reload
+    if let Err(e) = flush_daemon_reloads() {
+        if result.is_ok() {
+            result = Err(e);
+        }
+    }
 
-    // Synchronize users and groups with system state
-    sync_users_and_groups(args.yes, args.no, args.verbose)?;
+    // Persist the run log even when a step failed so a partial run can still be undone
+    if let Err(e) = save_run_log(&args.config, started_at, &result) {
+        println!("{} Failed to write run log: {}", "[WARN]".yellow(), e);
+    }
 
-    // Podman setup
-    if let Some(podman) = &config.podman {
-        // If podman config exists, ensure podman is installed
-        if args.verbose {
-            println!("{} Podman configuration found, ensuring podman is installed", "[DEBUG]".cyan());
+    if let (Some(url), Some(commit)) = (&args.pull, pull_commit) {
+        if let Err(e) = report_pull_status(url, &commit, started_at, &result, args.notify_webhook.as_deref()) {
+            println!("{} Failed to write pull status: {}", "[WARN]".yellow(), e);
         }
+    }
 
-        // Check if podman is installed, install if not
-        let podman_check = run_command_output(&["which", "podman"]);
-        if podman_check.is_err() {
-            if args.verbose {
-                println!("{} Podman not found, installing it", "[DEBUG]".cyan());
-            }
-            install_system_packages(&config.distro, &["podman".to_string()], args.verbose)?;
-        } else if args.verbose {
-            println!("{} Podman already installed", "[DEBUG]".cyan());
+    close_output_log();
+    if let Err(e) = prune_output_logs(LOG_RETENTION.load(Ordering::SeqCst), args.verbose) {
+        println!("{} Failed to prune old run output logs: {}", "[WARN]".yellow(), e);
+    }
+
+    result
+}
+
+/// Load config.toml with the active profile merged in. Returns the serialized effective config alongside it.
+fn load_effective_config(args: &Args) -> Result<(String, Config)> {
+    effective_config(args, true)
+}
+
+/// `load_effective_config` for read-only modes: `--profile` applies to this invocation only and
+/// the remembered profile is left as it is.
+fn read_effective_config(args: &Args) -> Result<(String, Config)> {
+    effective_config(args, false)
+}
+
+fn effective_config(args: &Args, remember_profile: bool) -> Result<(String, Config)> {
+    let config_content = fs::read_to_string(&args.config)
+        .context(format!("Failed to read config file: {}", args.config))?;
+    let mut config_value: toml::Value = toml::from_str(&config_content)
+        .map_err(|source| FedoraForgeError::ConfigParse { path: args.config.clone(), source })?;
+    apply_active_profile(&mut config_value, args.profile.as_deref(), remember_profile, args.verbose)?;
+    let effective_config = toml::to_string(&config_value)
+        .context("Failed to serialize effective config")?;
+    let mut config: Config = config_value.try_into()
+        .map_err(|source| FedoraForgeError::ConfigParse { path: args.config.clone(), source })?;
+    if let Some(podman) = config.podman.as_mut() {
+        podman.apply_container_defaults();
+    }
+    Ok((effective_config, config))
+}
+
+/// Subsystems in the order they run, from `apply_order` when set. Subsystems it doesn't list
+/// keep their default position relative to each other and run afterwards.
+fn resolve_apply_order(config: &Config, verbose: bool) -> Result<Vec<Subsystem>> {
+    let mut order: Vec<Subsystem> = Vec::new();
+    for subsystem in config.apply_order.iter().flatten() {
+        if order.contains(subsystem) {
+            return Err(FedoraForgeError::validation(format!("apply_order lists '{}' more than once", subsystem.name())));
+        }
+        order.push(*subsystem);
+    }
+
+    for subsystem in DEFAULT_APPLY_ORDER {
+        if !order.contains(&subsystem) {
+            if config.apply_order.is_some() {
+                debug!(verbose, "{} not in apply_order, running it after the listed subsystems", subsystem.name());
+            }
+            order.push(subsystem);
         }
+    }
 
-        run_command(&["systemctl", "--user", "enable", "--now", "podman.socket"], "Enabling Podman socket")?;
+    let sorted = sort_by_requirements(order, config.requires.as_ref())?;
+    if config.requires.is_some() {
+        let names: Vec<&str> = sorted.iter().map(|subsystem| subsystem.name()).collect();
+        debug!(verbose, "Subsystem order: {}", names.join(" -> "));
+    }
+    Ok(sorted)
+}
 
-            // Configure registries
-            let registries_conf = r#"[registries.search]
-registries = ['docker.io', 'registry.fedoraproject.org', 'quay.io', 'registry.redhat.io', 'ghcr.io']"#;
-            let config_dir = dirs::home_dir().unwrap().join(".config/containers");
-            fs::create_dir_all(&config_dir)?;
-            fs::write(config_dir.join("registries.conf"), registries_conf)?;
-
-            let home_dir = dirs::home_dir().context("Could not find home directory")?;
-            let home_path = home_dir.to_str().context("Invalid home directory path")?;
-
-            if let Some(setups) = &podman.pre_container_setup {
-                for setup in setups {
-                    let command = setup.command.replace("$HOME", home_path);
-                    let cmd_parts: Vec<&str> = command.split_whitespace().collect();
-                    run_command(&cmd_parts, &setup.description)?;
+fn run_subsystem(subsystem: Subsystem, config: &Config, args: &Args) -> Result<SubsystemResult> {
+    let mut result = SubsystemResult::default();
+    match subsystem {
+        Subsystem::SystemUpdate => {
+            // Update system
+            debug!(args.verbose, "Updating system packages...");
+            update_system_packages(&config.distro, args.verbose)?;
+        }
+        Subsystem::Hostname => {
+            // Set hostname
+            if let Some(hostname) = &config.system.hostname {
+                let current_hostname = run_command_output(&["hostnamectl", "--static"])?;
+                let current = String::from_utf8(current_hostname.stdout)?.trim().to_string();
+                if current != *hostname {
+                    run_command(&["sudo", "hostnamectl", "set-hostname", hostname], &format!("Setting hostname to {}", hostname))?;
+                    println!("{}", "You may need to reboot for hostname changes.".yellow());
+                }
+            }
+            setup_localization(&config.system, args.verbose)?;
+        }
+        Subsystem::Drives => {
+            // Setup drives early as other components may depend on them
+            if let Some(drives) = &config.drives {
+                setup_drives(drives, args.verbose)?;
+            }
+            if let Some(mounts) = &config.network_mounts {
+                setup_network_mounts(mounts, args.verbose)?;
+            }
+        }
+        Subsystem::Firewall => {
+            if let Some(firewall) = &config.firewall {
+                result += sync_firewall(firewall, args.yes, args.no, args.no_removals, args.verbose)?;
+            }
+        }
+        Subsystem::SystemPackages => {
+            // COPR repos first so their packages resolve
+            if let Some(repos) = &config.system.copr_repos {
+                enable_copr_repos(repos, args.verbose)?;
+            }
+
+            // Synchronize system packages with installed packages
+            let entries = load_package_entries("config/system-packages.toml")?;
+            let extra_system = config.packages.as_ref().and_then(|p| p.system.clone()).unwrap_or_default();
+            result += sync_system_packages(command_runner(), &entries, &extra_system, args.yes, args.no, args.no_removals, args.verbose)?;
+        }
+        Subsystem::Desktop => {
+            // Desktop Environment Setup
+            if let Some(desktop_config) = &config.desktop {
+                setup_desktop_environment(&config.distro, desktop_config)?;
+            }
+        }
+        Subsystem::GnomeExtensions => {
+            // GNOME Shell extensions (before dconf so extension schemas are available)
+            if let Some(extensions_config) = &config.gnome_extensions {
+                result += sync_gnome_extensions(extensions_config, args.yes, args.no, args.no_removals, args.verbose)?;
+            }
+        }
+        Subsystem::Dconf => {
+            // GNOME/dconf settings (user-level, after the desktop is installed)
+            if let Some(dconf_config) = &config.dconf {
+                setup_dconf(dconf_config, args.yes, args.no, args.verbose)?;
+            }
+        }
+        Subsystem::Repos => {
+            // Enable additional repositories if configured
+            if config.system.enable_rpm_fusion {
+                enable_additional_repos(&config.distro, config.system.rpm_fusion_sha256.as_deref())?;
+            }
+        }
+        Subsystem::AmdGpu => {
+            // AMD GPU setup
+            if config.system.enable_amd_gpu {
+                setup_amd_gpu(&config.distro, config.system.gpu_device_mode.as_deref(), args.verbose)?;
+            }
+        }
+        Subsystem::NvidiaGpu => {
+            // NVIDIA GPU setup (after Repos, the driver comes from RPM Fusion)
+            if config.system.enable_nvidia_gpu {
+                setup_nvidia_gpu(&config.system, args.verbose)?;
+            }
+        }
+        Subsystem::Flatpak => {
+            // Flatpak setup
+            setup_flatpak(&config.distro, config.flatpak.as_ref(), args.yes, args.no, args.no_removals, args.verbose)?;
+
+            // Synchronize Flatpak packages with installed applications
+            let extra_flatpak = config.packages.as_ref().and_then(|p| p.flatpak.clone()).unwrap_or_default();
+            result += sync_flatpak_packages(&extra_flatpak, args.yes, args.no, args.no_removals, args.verbose)
+                .or_else(|e| warn(format!("Flatpak synchronization failed: {}", e)).map(|_| SubsystemResult::default()))?;
+        }
+        Subsystem::LanguagePackages => {
+            // Synchronize pip packages with installed packages
+            result += sync_pip_packages(args.yes, args.no, args.no_removals, args.verbose)
+                .or_else(|e| warn(format!("pip synchronization skipped: {}", e)).map(|_| SubsystemResult::default()))?;
+
+            // Synchronize npm packages with installed packages
+            result += sync_npm_packages(args.yes, args.no, args.no_removals, args.verbose)
+                .or_else(|e| warn(format!("npm synchronization skipped: {}", e)).map(|_| SubsystemResult::default()))?;
+
+            // Synchronize cargo packages with installed binaries
+            result += sync_cargo_packages(args.yes, args.no, args.no_removals, args.verbose)
+                .or_else(|e| warn(format!("cargo synchronization skipped: {}", e)).map(|_| SubsystemResult::default()))?;
+        }
+        Subsystem::Services => {
+            // Synchronize services with system state
+            result += sync_services(args.yes, args.no, args.no_removals, args.verbose)?;
+        }
+        Subsystem::Users => {
+            // Synchronize users and groups with system state
+            let memberships = implied_group_memberships(config)?;
+            result += sync_users_and_groups(command_runner(), &memberships, args.yes, args.no, args.no_removals, args.verbose)?;
+        }
+        Subsystem::Files => {
+            // Declarative [[files]], including restoring entries removed from config
+            let vars = template_vars(config)?;
+            result += sync_managed_files(config.files.as_deref().unwrap_or_default(), &vars, args.yes, args.no, args.no_removals, args.verbose)?;
+        }
+        Subsystem::Containers => {
+            // Podman setup
+            if let Some(podman) = &config.podman {
+                result += setup_podman(command_runner(), podman, &config.distro, args)?;
+            }
+        }
+        Subsystem::Vpn => {
+            // VPN setup (WireGuard or OpenVPN)
+            if let Some(vpn) = &config.vpn {
+                match vpn.vpn_type {
+                    VpnType::Wireguard => setup_wireguard_vpn(vpn)?,
+                    VpnType::Openvpn => setup_openvpn_vpn(vpn)?,
                 }
             }
+        }
+        Subsystem::Winapps => {
+            // WinApps setup
+            setup_winapps(config.system.enable_winapps, args)?;
+        }
+        Subsystem::Dotfiles => {
+            // Dotfiles setup
+            if let Some(dotfiles) = &config.dotfiles {
+                setup_dotfiles(dotfiles, args.yes, args.no, args.no_removals, args.verbose)?;
+            }
+        }
+        Subsystem::CustomCommands => {
+            // Execute custom commands
+            if let Some(custom_commands) = &config.custom_commands {
+                execute_custom_commands(custom_commands, args.verbose)?;
+            }
+        }
+    }
+    Ok(result)
+}
 
-            // Reconciliation of managed containers
-            let managed_output = Command::new("podman").args(&["ps", "-a", "--filter", "label=managed-by=fedoraforge", "--format", "{{.Names}}"]).output()?;
-            let managed_containers = std::io::Cursor::new(managed_output.stdout).lines().collect::<Result<Vec<_>, _>>()?;
+fn setup_podman(runner: &dyn CommandRunner, podman: &PodmanConfig, distro: &Distro, args: &Args) -> Result<SubsystemResult> {
+    let mut result = SubsystemResult::default();
 
-            let configured_containers: Vec<String> = podman.containers.as_ref().unwrap_or(&Vec::new()).iter().map(|c| c.name.clone()).collect();
+    // If podman config exists, ensure podman is installed
+    debug!(args.verbose, "Podman configuration found, ensuring podman is installed");
 
-            // Load container state for cleanup
-            let mut container_state = load_container_state()?;
-            let mut state_modified = false;
-
-            for container_name in managed_containers {
-                if !configured_containers.contains(&container_name) {
-                    if ask_user_confirmation(&format!("Container '{}' is managed by this tool but not in the config. Remove it?", container_name), args.yes, args.no, args.verbose)? {
-                        // Stop systemd service if it exists
-                        let service_name = format!("{}.service", container_name);
-                        let _ = run_command(&["systemctl", "--user", "stop", &service_name], &format!("Stopping systemd service for {}", container_name));
-
-                        // Remove Quadlet file if it exists
-                        let quadlet_path = home_dir.join(".config").join("containers").join("systemd").join(format!("{}.container", container_name));
-                        if quadlet_path.exists() {
-                            fs::remove_file(&quadlet_path).context(format!("Failed to remove Quadlet file for {}", container_name))?;
-                            if args.verbose {
-                                println!("{} Removed Quadlet file: {:?}", "[DEBUG]".cyan(), quadlet_path);
-                            }
-                        }
+    // Check if podman is installed, install if not
+    let podman_check = run_command_output_via(runner, &["which", "podman"]);
+    if podman_check.is_err() {
+        debug!(args.verbose, "Podman not found, installing it");
+        install_system_packages(runner, distro, &["podman".to_string()], args.verbose)?;
+    } else if args.verbose {
+        println!("{} Podman already installed", "[DEBUG]".cyan());
+    }
 
-                        // Remove container
-                        run_command(&["podman", "rm", "-f", &container_name], &format!("Removing orphaned container {}", container_name))?;
+    run_command_via(runner, &["systemctl", "--user", "enable", "--now", "podman.socket"], "Enabling Podman socket")?;
 
-                        // Remove from state file
-                        if container_state.containers.remove(&container_name).is_some() {
-                            state_modified = true;
-                            if args.verbose {
-                                println!("{} Removed {} from container state", "[DEBUG]".cyan(), container_name);
-                            }
-                        }
+    // Configure registries
+    let registries_conf = r#"[registries.search]
+registries = ['docker.io', 'registry.fedoraproject.org', 'quay.io', 'registry.redhat.io', 'ghcr.io']"#;
+    let config_dir = user_home_dir()?.join(".config/containers");
+    create_dir(&config_dir)?;
+    preview_file_change(&config_dir.join("registries.conf"), registries_conf.as_bytes());
+    write_file(config_dir.join("registries.conf"), registries_conf)?;
+    chown_for_user(&config_dir)?;
 
-                        // Reload systemd daemon to pick up changes
-                        let _ = run_command(&["systemctl", "--user", "daemon-reload"], "Reloading systemd user daemon");
-                    }
-                }
+    let home_dir = user_home_dir()?;
+    let home_path = home_dir.to_str().context("Invalid home directory path")?;
+
+    if let Some(setups) = &podman.pre_container_setup {
+        for setup in setups {
+            let command = setup.command.replace("$HOME", home_path);
+            let cmd_parts: Vec<&str> = command.split_whitespace().collect();
+            run_command_via(runner, &cmd_parts, &setup.description)?;
+        }
+    }
+
+    // Reconciliation of managed containers
+    let managed_output = spawn_output_via(runner, &["podman", "ps", "-a", "--filter", "label=managed-by=fedoraforge", "--format", "{{.Names}}"])?;
+    let managed_containers = std::io::Cursor::new(managed_output.stdout).lines().collect::<Result<Vec<_>, _>>()?;
+
+    let configured_containers: Vec<String> = podman.containers.as_ref().unwrap_or(&Vec::new()).iter().map(|c| c.name.clone()).collect();
+
+    // Load container state for cleanup
+    let mut container_state = load_container_state()?;
+    let mut state_modified = false;
+    let mut existing_containers = get_existing_containers(runner)?;
+
+    for container_name in managed_containers {
+        if configured_containers.contains(&container_name) {
+            continue;
+        }
+
+        // A configured container that doesn't exist yet may just be this one under a new name
+        let declared = podman.containers.as_deref().unwrap_or(&[]);
+        if let Some((renamed, exact)) = find_rename_candidate(runner, &container_name, declared, &existing_containers, &container_state) {
+            println!("{} Container '{}' is not in the config, but '{}' looks like the same container{}",
+                "[INFO]".yellow(), container_name, renamed.name,
+                if exact { " (identical config)" } else { " (same image)" });
+            if ask_user_confirmation(&format!("Did you rename '{}' to '{}'? Keep the existing container under the new name?", container_name, renamed.name), args.yes, args.no, args.verbose)? {
+                rename_managed_container(runner, &container_name, renamed, exact, &mut container_state, args.verbose)?;
+                existing_containers.insert(renamed.name.clone(), renamed.name.clone());
+                state_modified = true;
+                continue;
             }
+        }
 
-            // Save updated state if modified
-            if state_modified {
-                save_container_state(&container_state)?;
+        if args.no_removals {
+            println!("{} --no-removals: keeping container '{}' that is not in the config", "[INFO]".blue(), container_name);
+            continue;
+        }
+        let confirmed = args.yes || planned("containers", &[&container_name]);
+        if ask_user_confirmation(&format!("Container '{}' is managed by this tool but not in the config. Remove it?", container_name), confirmed, args.no, args.verbose)? {
+            // Stop systemd service if it exists
+            let service_name = format!("{}.service", container_name);
+            let _ = run_command_via(runner, &["systemctl", "--user", "stop", &service_name], &format!("Stopping systemd service for {}", container_name));
+
+            // Remove Quadlet file if it exists
+            let quadlet_path = home_dir.join(".config").join("containers").join("systemd").join(format!("{}.container", container_name));
+            if quadlet_path.exists() {
+                delete_file(&quadlet_path).context(format!("Failed to remove Quadlet file for {}", container_name))?;
+                debug!(args.verbose, "Removed Quadlet file: {:?}", quadlet_path);
             }
 
-            // Smart container lifecycle management
-            if let Some(containers) = &podman.containers {
-                manage_containers_smart(containers, home_path, &args)?;
+            // Remove container
+            run_command_via(runner, &["podman", "rm", "-f", &container_name], &format!("Removing orphaned container {}", container_name))?;
+            record_action(ActionCategory::Container, ActionKind::Delete, &container_name, None);
+            result.removed += 1;
+
+            // Remove from state file
+            if container_state.containers.remove(&container_name).is_some() {
+                state_modified = true;
+                debug!(args.verbose, "Removed {} from container state", container_name);
+            }
+
+            // Reload systemd daemon to pick up changes
+            mark_daemon_reload(&ServiceScope::User);
+        }
+    }
+
+    // Save updated state if modified
+    if state_modified {
+        save_container_state(&container_state)?;
+    }
+
+    // Smart container lifecycle management
+    if let Some(containers) = &podman.containers {
+        // Saved even when it fails part way, so containers already handled aren't recreated next run
+        let managed = manage_containers_smart(runner, containers, &mut container_state, home_path, args);
+        save_container_state(&container_state)?;
+        result += managed?;
+    }
+
+    // Prune images left behind by recreated containers (only when requested)
+    if args.no_removals {
+        debug!(args.verbose, "--no-removals: skipping image pruning");
+    } else if args.prune_images || podman.prune_images.unwrap_or(false) || podman.prune_all_images.unwrap_or(false) {
+        prune_container_images(runner, podman, args)?;
+    }
+
+    Ok(result)
+}
+
+/// Subsystems slowest first, skipping ones that finished in under 50ms (nothing configured).
+fn print_timing_summary(total: std::time::Duration) {
+    let mut timings = RUN_TIMINGS.lock().map(|t| t.clone()).unwrap_or_default();
+    timings.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+
+    println!("⏱️  Timing (total {:.1}s):", total.as_secs_f64());
+    for timing in timings.iter().filter(|t| t.seconds >= 0.05) {
+        println!("  - {}: {:.1}s", timing.subsystem, timing.seconds);
+    }
+}
+
+/// One row per subsystem that reported anything, in the order they ran.
+fn print_results_summary() {
+    let results = RUN_RESULTS.lock().map(|r| r.clone()).unwrap_or_default();
+    let rows: Vec<&SubsystemReport> = results.iter()
+        .filter(|r| r.result != SubsystemResult::default())
+        .collect();
+    if rows.is_empty() {
+        return;
+    }
+
+    println!("🔁 Changes:");
+    println!("  {:<20} {:>8} {:>8} {:>8} {:>8}", "subsystem", "created", "modified", "removed", "skipped");
+    let mut total = SubsystemResult::default();
+    for row in rows {
+        println!("  {:<20} {:>8} {:>8} {:>8} {:>8}", row.subsystem,
+            row.result.created, row.result.modified, row.result.removed, row.result.skipped);
+        total += row.result;
+    }
+    println!("  {:<20} {:>8} {:>8} {:>8} {:>8}", "total", total.created, total.modified, total.removed, total.skipped);
+}
+
+/// Checks the files the config depends on before anything is changed, reporting every problem at
+/// once. Missing declarative files are fine (they are generated from the system), unparseable ones are not.
+fn validate_sub_configs(config: &Config) -> Result<()> {
+    let mut problems: Vec<String> = Vec::new();
+    let mut check = |result: Result<bool>, missing: Option<String>| match result {
+        Ok(true) => {}
+        Ok(false) => problems.extend(missing),
+        Err(e) => problems.push(format!("{:#}", e)),
+    };
+
+    for path in ["config/system-packages.toml", "config/flatpak-packages.toml", "config/pip-packages.toml",
+                 "config/npm-packages.toml", "config/cargo-packages.toml"] {
+        check(read_declarative_file::<PackageList>(path).map(|f| f.is_some()), None);
+    }
+    check(read_declarative_file::<SystemServicesConfig>("config/system-services.toml").map(|f| f.is_some()), None);
+    check(read_declarative_file::<UserServicesConfig>("config/user-services.toml").map(|f| f.is_some()), None);
+    match read_declarative_file::<UsersGroupsConfig>("config/users-groups.toml") {
+        Ok(users_groups) => {
+            for (name, user) in users_groups.iter().flat_map(|c| c.users.iter().flatten()) {
+                if let Some(hash) = &user.password_hash {
+                    check(validate_password_hash(name, hash).map(|_| true), None);
+                }
             }
+        }
+        Err(e) => check(Err(e), None),
     }
 
-    // VPN setup (WireGuard or OpenVPN)
+    if config.system.enable_winapps {
+        let path = "config/winapps-config.toml";
+        check(read_declarative_file::<WinAppsConfig>(path).map(|f| f.is_some()),
+              Some(format!("enable_winapps is set but {} does not exist", path)));
+    }
     if let Some(vpn) = &config.vpn {
-        match vpn.vpn_type {
-            VpnType::Wireguard => setup_wireguard_vpn(vpn)?,
-            VpnType::Openvpn => setup_openvpn_vpn(vpn)?,
+        check(Ok(Path::new(&vpn.conf_path).exists()),
+              Some(format!("VPN config {} does not exist", vpn.conf_path)));
+    }
+    for file in config.files.iter().flatten() {
+        if let Some(source) = &file.source {
+            check(Ok(Path::new(source).exists()),
+                  Some(format!("Source {} for {} does not exist", source, file.path)));
+        }
+    }
+    // Shares need the [[network_mounts]] handling (_netdev, automount, credentials), not a block device
+    for drive in config.drives.iter().flatten() {
+        if let Some(filesystem) = drive.filesystem.as_deref().filter(|fs| ["nfs", "nfs4", "cifs", "smb3"].contains(fs)) {
+            check(Ok(false), Some(format!("Drive {} uses filesystem '{}'; declare network shares under [[network_mounts]]", drive.device, filesystem)));
         }
     }
 
-    // WinApps setup
-    setup_winapps(config.system.enable_winapps, &args)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{} {}", "[ERROR]".red(), problem);
+    }
+    Err(FedoraForgeError::validation(format!("{} problem(s) with the config and the files it references", problems.len())))
+}
+
+fn apply_configuration(args: &Args) -> Result<()> {
+    println!("🔥 FedoraForge: Forging your perfect Fedora system...");
+
+    let (effective_config, config) = load_effective_config(args)?;
+    set_command_attempts(config.system.command_retries);
+    set_package_manager_args(config.system.package_manager_args.as_deref(), args.package_manager_args.as_deref())?;
+    INSTALL_WEAK_DEPS.store(config.system.install_weak_deps.unwrap_or(true), Ordering::SeqCst);
+    LOG_RETENTION.store(config.system.log_retention.unwrap_or(DEFAULT_LOG_RETENTION), Ordering::SeqCst);
+    validate_sub_configs(&config)?;
+
+    if args.fast && fast_path_applies(&effective_config, &config, args.verbose)? {
+        println!("{} System already in desired state (config unchanged since last successful run)", "[SUCCESS]".green());
+        return Ok(());
+    }
+
+    // Check if running on the correct distro
+    let os_release = fs::read_to_string("/etc/os-release")?;
+    let detected_distro = detect_distro(&os_release)?;
+
+    if detected_distro != config.distro {
+        warn(format!("Configuration is for {:?} but detected {:?}. Continuing...", config.distro, detected_distro))?;
+    }
 
-    // Dotfiles setup
-    if let Some(dotfiles) = &config.dotfiles {
-        setup_dotfiles(dotfiles, args.yes, args.no, args.verbose)?;
+    // One confirmation up front replaces the prompts for the planned changes; prompts for anything else stay
+    if let Some(plan_path) = &args.apply {
+        if !check_plan(plan_path, &effective_config, &config, args)? {
+            println!("{} Nothing was changed", "[INFO]".blue());
+            return Ok(());
+        }
+    } else if args.preflight && !preflight_confirm(&config, args)? {
+        println!("{} Nothing was changed", "[INFO]".blue());
+        return Ok(());
     }
 
-    // Execute custom commands
-    if let Some(custom_commands) = &config.custom_commands {
-        execute_custom_commands(custom_commands, args.verbose)?;
+    // Only config.toml (with profile) counts here: the package files are rewritten by the run itself
+    let mut hasher = Sha256::new();
+    hasher.update(effective_config.as_bytes());
+    let checkpoint_hash = format!("{:x}", hasher.finalize());
+    let mut checkpoint = RunCheckpoint { config_hash: checkpoint_hash.clone(), completed: Vec::new() };
+    let resumed = if args.resume { resumable_subsystems(&checkpoint_hash)? } else { Vec::new() };
+
+    let run_started = std::time::Instant::now();
+    for subsystem in resolve_apply_order(&config, args.verbose)? {
+        if resumed.iter().any(|name| name == subsystem.name()) {
+            println!("{} --resume: skipping {} (completed in the previous run)", "[INFO]".blue(), subsystem.name());
+            checkpoint.completed.push(subsystem.name().to_string());
+            continue;
+        }
+
+        let subsystem_started = std::time::Instant::now();
+        set_effective_section(subsystem.name());
+        let result = run_subsystem(subsystem, &config, args);
+        // Recorded before `?` so the run log shows how long a failing subsystem ran
+        if let Ok(mut timings) = RUN_TIMINGS.lock() {
+            timings.push(SubsystemTiming {
+                subsystem: subsystem.name().to_string(),
+                seconds: subsystem_started.elapsed().as_secs_f64(),
+            });
+        }
+        let result = result?;
+        if let Ok(mut results) = RUN_RESULTS.lock() {
+            results.push(SubsystemReport { subsystem: subsystem.name().to_string(), result });
+        }
+
+        checkpoint.completed.push(subsystem.name().to_string());
+        save_run_checkpoint(&checkpoint)?;
+    }
+    let run_elapsed = run_started.elapsed();
+    clear_run_checkpoint()?;
+    write_staged_commands()?;
+    if args.print_effective_commands {
+        print_effective_commands();
     }
 
     // Summary (similar to bash)
@@ -610,8 +1481,33 @@ registries = ['docker.io', 'registry.fedoraproject.org', 'quay.io', 'registry.re
     if config.vpn.is_some() {
         println!("✅ VPN configured with autoconnect");
     }
+    if config.podman.is_some() {
+        match get_podman_storage_summary() {
+            Ok(storage) => {
+                println!("📦 Container storage: images {}, containers {}, volumes {} ({} reclaimable)",
+                    format_bytes(storage.images_bytes),
+                    format_bytes(storage.containers_bytes),
+                    format_bytes(storage.volumes_bytes),
+                    format_bytes(storage.reclaimable_bytes));
+                if let Ok(mut slot) = RUN_PODMAN_STORAGE.lock() {
+                    *slot = Some(storage);
+                }
+            }
+            Err(e) => {
+                debug!(args.verbose, "Could not read podman storage usage: {}", e);
+            }
+        }
+    }
+    print_results_summary();
+    print_timing_summary(run_elapsed);
     // Add more summary items as needed...
 
+    // Package/service files may have been updated during the run, so hash them afterwards
+    save_run_state(&RunState {
+        config_hash: Some(generate_effective_config_hash(&effective_config)),
+        last_success: Some(get_current_timestamp()),
+    })?;
+
     println!("💡 Manual steps: Log out/in or reboot for full effect.");
     println!("{}", "Setup completed successfully!".green());
 
@@ -619,2001 +1515,6617 @@ registries = ['docker.io', 'registry.fedoraproject.org', 'quay.io', 'registry.re
 }
 
 fn run_command(cmd: &[&str], desc: &str) -> Result<()> {
+    run_command_via(command_runner(), cmd, desc)
+}
+
+fn run_command_via(runner: &dyn CommandRunner, cmd: &[&str], desc: &str) -> Result<()> {
+    println!("{} {}", "[INFO]".blue(), desc);
+    let argv = user_command(cmd);
+    let argv = format_argv(&argv.iter().map(String::as_str).collect::<Vec<_>>());
+    if dry_run_skip(&format!("run: {}", argv)) {
+        return Ok(());
+    }
+
+    // Verbose runs stream output live; otherwise it is captured, printed once the command exits and logged
+    debug!(verbose(), "Running: {}", argv);
+    let output = if verbose() {
+        spawn_streamed_via(runner, cmd)?
+    } else {
+        let output = spawn_output_via(runner, cmd)?;
+        io::stdout().write_all(&output.stdout)?;
+        io::stderr().write_all(&output.stderr)?;
+        log_line(String::from_utf8_lossy(&output.stdout).trim_end());
+        log_line(String::from_utf8_lossy(&output.stderr).trim_end());
+        output
+    };
+
+    if !output.status.success() {
+        println!("{} {}: Command failed", "[ERROR]".red(), desc);
+        return Err(FedoraForgeError::command_failed(cmd, &output).into());
+    }
+    println!("{} {}", "[SUCCESS]".green(), desc);
+    Ok(())
+}
+
+/// `run_command` for commands that read their input from stdin. `preview` is the shell line a dry
+/// run shows in place of the argv, since the input isn't part of it.
+fn run_command_with_input(cmd: &[&str], input: &[u8], preview: &str, desc: &str) -> Result<()> {
+    run_command_with_input_via(command_runner(), cmd, input, preview, desc)
+}
+
+fn run_command_with_input_via(runner: &dyn CommandRunner, cmd: &[&str], input: &[u8], preview: &str, desc: &str) -> Result<()> {
     println!("{} {}", "[INFO]".blue(), desc);
-    // Note: We can't access verbose flag here easily, would need refactoring for full verbose support
-    let output = Command::new(cmd[0]).args(&cmd[1..]).output()?;
+    if dry_run_skip(&format!("run: {}", preview)) {
+        return Ok(());
+    }
 
+    let output = spawn_input_via(runner, cmd, input)?;
     io::stdout().write_all(&output.stdout)?;
     io::stderr().write_all(&output.stderr)?;
-
+    log_line(String::from_utf8_lossy(&output.stdout).trim_end());
+    log_line(String::from_utf8_lossy(&output.stderr).trim_end());
     if !output.status.success() {
         println!("{} {}: Command failed", "[ERROR]".red(), desc);
-        anyhow::bail!("Command failed");
+        return Err(FedoraForgeError::command_failed(cmd, &output).into());
     }
     println!("{} {}", "[SUCCESS]".green(), desc);
     Ok(())
 }
 
 fn run_command_output(cmd: &[&str]) -> Result<Output> {
-    let output = Command::new(cmd[0]).args(&cmd[1..]).output().context("Command failed")?;
-    io::stdout().write_all(&output.stdout)?;
+    run_command_output_via(command_runner(), cmd)
+}
+
+fn run_command_output_via(runner: &dyn CommandRunner, cmd: &[&str]) -> Result<Output> {
+    let output = spawn_output_via(runner, cmd)?;
+    if EFFECTIVE_COMMANDS_MODE.load(Ordering::SeqCst) {
+        io::stderr().write_all(&output.stdout)?;
+    } else {
+        io::stdout().write_all(&output.stdout)?;
+    }
     io::stderr().write_all(&output.stderr)?;
     Ok(output)
 }
 
-fn setup_dotfiles(config: &DotfilesConfig, yes: bool, no: bool, verbose: bool) -> Result<()> {
-    println!("{} Setting up dotfiles...", "[INFO]".blue());
+// ========================= TARGET USER =========================
 
-    let current_dir = env::current_dir()?;
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
+// User that user-scoped operations act on, set from --user. Unset means the invoking user.
+struct TargetUser {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: std::path::PathBuf,
+}
 
-    // Load dotfiles state
-    let mut state = load_dotfiles_state()?;
+static TARGET_USER: std::sync::OnceLock<TargetUser> = std::sync::OnceLock::new();
 
-    // Setup .bashrc
-    if config.setup_bashrc {
-        setup_bashrc(&current_dir, &home_dir, &mut state, yes, no, verbose)?;
+fn set_target_user(name: &str) -> Result<()> {
+    validate_username(name)?;
+    let output = spawn_output(&["getent", "passwd", name])?;
+    let line = String::from_utf8_lossy(&output.stdout);
+    let parts: Vec<&str> = line.trim().split(':').collect();
+    if !output.status.success() || parts.len() < 7 {
+        return Err(FedoraForgeError::validation(format!("--user: user '{}' does not exist", name)));
     }
 
-    // Setup .config directories
-    if config.setup_config_dirs {
-        setup_config_dirs(&current_dir, &home_dir, &mut state, yes, no, verbose)?;
-    }
+    let target = TargetUser {
+        name: name.to_string(),
+        uid: parts[2].parse().context("Invalid UID in passwd entry")?,
+        gid: parts[3].parse().context("Invalid GID in passwd entry")?,
+        home: std::path::PathBuf::from(parts[5]),
+    };
+    println!("{} User-scoped setup targets {} ({})", "[INFO]".blue(), target.name, target.home.display());
+    let _ = TARGET_USER.set(target);
+    Ok(())
+}
 
-    // Save updated state
-    save_dotfiles_state(&state)?;
+/// Home directory for user-scoped files (Quadlet, user units, dotfiles). FedoraForge's own
+/// state stays in the invoking user's home.
+fn user_home_dir() -> Result<std::path::PathBuf> {
+    match TARGET_USER.get() {
+        Some(target) => Ok(target.home.clone()),
+        None => dirs::home_dir().context("Could not find home directory"),
+    }
+}
 
-    println!("{} Dotfiles setup completed!", "[SUCCESS]".green());
-    Ok(())
+fn user_name() -> Result<String> {
+    match TARGET_USER.get() {
+        Some(target) => Ok(target.name.clone()),
+        None => env::var("USER").context("USER is not set"),
+    }
 }
 
-fn setup_bashrc(project_dir: &Path, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, verbose: bool) -> Result<()> {
-    let project_bashrc = project_dir.join(".bashrc");
-    let home_bashrc = home_dir.join(".bashrc");
+/// Rewrites user-scoped commands for --user: `systemctl --user` talks to the target's manager
+/// and podman runs as the target (rootless storage lives in their home).
+fn user_command(cmd: &[&str]) -> Vec<String> {
+    let mut rewritten: Vec<String> = cmd.iter().map(|s| s.to_string()).collect();
+    let Some(target) = TARGET_USER.get() else {
+        return rewritten;
+    };
 
-    if !project_bashrc.exists() {
-        if verbose {
-            println!("{} No .bashrc found in project directory, skipping", "[DEBUG]".cyan());
-        }
+    if cmd.len() >= 2 && cmd[0] == "systemctl" && cmd[1] == "--user" {
+        rewritten.insert(2, format!("{}@", target.name));
+        rewritten.insert(2, "-M".to_string());
+    } else if cmd.first() == Some(&"podman") {
+        let prefix = [
+            "sudo".to_string(),
+            "-u".to_string(),
+            target.name.clone(),
+            "env".to_string(),
+            format!("XDG_RUNTIME_DIR=/run/user/{}", target.uid),
+        ];
+        rewritten.splice(0..0, prefix);
+    }
+    rewritten
+}
+
+/// Hands files written into the target user's home over to them.
+fn chown_for_user(path: &Path) -> Result<()> {
+    let Some(target) = TARGET_USER.get() else {
         return Ok(());
+    };
+    let path_str = path.to_str().context("Invalid path")?;
+    let owner = format!("{}:{}", target.uid, target.gid);
+    run_command(&["sudo", "chown", "-R", &owner, path_str], &format!("Setting owner of {} to {}", path_str, target.name))
+}
+
+// ========================= STATELESS MODE =========================
+
+// Set once from --no-state: state files are neither read nor written
+static NO_STATE: AtomicBool = AtomicBool::new(false);
+
+fn state_disabled() -> bool {
+    NO_STATE.load(Ordering::SeqCst)
+}
+
+// ========================= VERBOSE OUTPUT =========================
+
+// Set once from --verbose, for helpers like run_command that don't take the flag
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+fn verbose() -> bool {
+    VERBOSE.load(Ordering::SeqCst)
+}
+
+// ========================= DRY RUN =========================
+
+// Set once from --dry-run: commands and file writes are printed instead of performed
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Prints what would happen and returns true when --dry-run is set.
+fn dry_run_skip(action: &str) -> bool {
+    if dry_run() {
+        println!("{} Would {}", "[DRY-RUN]".magenta(), action);
+        record_effective_command(action);
     }
+    dry_run()
+}
 
-    // Generate hash of project .bashrc
-    let project_hash = generate_file_hash(&project_bashrc)?;
+/// argv joined for display, shell-quoting arguments that need it so the line can be pasted into a shell.
+fn format_argv(cmd: &[&str]) -> String {
+    cmd.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,@+%^".contains(c));
+            if plain { arg.to_string() } else { shell_quote(arg) }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-    // Check if home .bashrc exists and matches
-    if home_bashrc.exists() {
-        let home_hash = generate_file_hash(&home_bashrc)?;
+// ========================= EFFECTIVE COMMANDS =========================
 
-        // Compare with stored state
-        if let Some(stored_hash) = &state.bashrc_hash {
-            if stored_hash == &project_hash && &home_hash == &project_hash {
-                if verbose {
-                    println!("{} .bashrc is up to date, skipping", "[DEBUG]".cyan());
-                }
-                return Ok(());
-            }
-        }
+// --print-effective-commands: console output moves to stderr so stdout carries only the script
+static EFFECTIVE_COMMANDS_MODE: AtomicBool = AtomicBool::new(false);
+// (subsystem, line) in the order the dry run reached them
+static EFFECTIVE_COMMANDS: Mutex<Vec<(&'static str, String)>> = Mutex::new(Vec::new());
+static EFFECTIVE_SECTION: Mutex<&'static str> = Mutex::new("setup");
 
-        // Files differ - ask to update
-        println!("{} .bashrc has changed since last sync", "[INFO]".blue());
-        if ask_user_confirmation("Do you want to update your .bashrc with the version from this project?", yes, no, verbose)? {
-            // Backup existing .bashrc
-            let backup_path = home_dir.join(".bashrc.backup");
-            fs::copy(&home_bashrc, &backup_path)
-                .context("Failed to backup existing .bashrc")?;
-            println!("{} Backed up existing .bashrc to .bashrc.backup", "[INFO]".blue());
-
-            // Copy project .bashrc
-            fs::copy(&project_bashrc, &home_bashrc)
-                .context("Failed to copy project .bashrc")?;
-            state.bashrc_hash = Some(project_hash);
-            println!("{} Successfully updated .bashrc", "[SUCCESS]".green());
-        } else {
-            println!("{} Skipping .bashrc update", "[INFO]".blue());
-        }
+fn console_line(line: &str) {
+    if EFFECTIVE_COMMANDS_MODE.load(Ordering::SeqCst) {
+        eprintln!("{}", line);
     } else {
-        println!("{} No existing .bashrc found, copying from project", "[INFO]".blue());
-        fs::copy(&project_bashrc, &home_bashrc)
-            .context("Failed to copy .bashrc from project")?;
-        state.bashrc_hash = Some(project_hash);
-        println!("{} Successfully installed .bashrc", "[SUCCESS]".green());
+        std::println!("{}", line);
     }
+}
 
-    Ok(())
+fn set_effective_section(section: &'static str) {
+    if let Ok(mut current) = EFFECTIVE_SECTION.lock() {
+        *current = section;
+    }
 }
 
-fn setup_config_dirs(project_dir: &Path, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, verbose: bool) -> Result<()> {
-    let project_config = project_dir.join(".config");
-    let home_config = home_dir.join(".config");
+/// Keeps a dry-run action for the script: commands verbatim, file changes FedoraForge makes
+/// itself as comments. Prompts are left out, the dry run answers them with the default.
+fn record_effective_command(action: &str) {
+    if !EFFECTIVE_COMMANDS_MODE.load(Ordering::SeqCst) || action.starts_with("ask: ") {
+        return;
+    }
+    let line = match action.strip_prefix("run: ") {
+        Some(command) => command.to_string(),
+        None => format!("# would {}", action),
+    };
+    let section = EFFECTIVE_SECTION.lock().map(|s| *s).unwrap_or("setup");
+    if let Ok(mut commands) = EFFECTIVE_COMMANDS.lock() {
+        commands.push((section, line));
+    }
+}
 
-    if !project_config.exists() {
-        if verbose {
-            println!("{} No .config directory found in project, skipping", "[DEBUG]".cyan());
+/// Writes the collected commands to stdout as a script, one block per subsystem.
+fn print_effective_commands() {
+    let commands = EFFECTIVE_COMMANDS.lock().map(|mut c| std::mem::take(&mut *c)).unwrap_or_default();
+    std::println!("#!/bin/sh");
+    std::println!("# Commands fedoraforge would run for this config on this machine (--print-effective-commands).");
+    std::println!("# Read-only queries are not listed; \"# would\" lines are file changes fedoraforge makes itself.");
+    std::println!("set -e");
+    let mut section = "";
+    for (subsystem, line) in &commands {
+        if *subsystem != section {
+            section = subsystem;
+            std::println!("\n# --- {} ---", section);
         }
+        std::println!("{}", line);
+    }
+}
+
+fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> io::Result<()> {
+    if dry_run_skip(&format!("write {:?}", path.as_ref())) {
         return Ok(());
     }
+    fs::write(path, content)
+}
 
-    // Create ~/.config if it doesn't exist
-    fs::create_dir_all(&home_config)
-        .context("Failed to create ~/.config directory")?;
+fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<u64> {
+    if dry_run_skip(&format!("copy {:?} to {:?}", from.as_ref(), to.as_ref())) {
+        return Ok(0);
+    }
+    fs::copy(from, to)
+}
 
-    // Process each subdirectory in project .config
-    for entry in fs::read_dir(&project_config)? {
-        let entry = entry?;
-        let path = entry.path();
+fn delete_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    if dry_run_skip(&format!("remove {:?}", path.as_ref())) {
+        return Ok(());
+    }
+    fs::remove_file(path)
+}
 
-        if path.is_dir() {
-            let dir_name = path.file_name()
-                .context("Invalid directory name")?
-                .to_str()
-                .context("Non-UTF8 directory name")?
-                .to_string();
+fn create_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    if path.as_ref().is_dir() || dry_run_skip(&format!("create directory {:?}", path.as_ref())) {
+        return Ok(());
+    }
+    fs::create_dir_all(path)
+}
 
-            let target_dir = home_config.join(&dir_name);
+fn delete_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    if dry_run_skip(&format!("remove directory {:?}", path.as_ref())) {
+        return Ok(());
+    }
+    fs::remove_dir_all(path)
+}
 
-            // Generate hash of project config directory
-            let project_hash = generate_directory_hash(&path)?;
+fn rename_path<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    if dry_run_skip(&format!("move {:?} to {:?}", from.as_ref(), to.as_ref())) {
+        return Ok(());
+    }
+    fs::rename(from, to)
+}
 
-            // Check if target dir exists and compare hashes
-            if target_dir.exists() {
-                let home_hash = generate_directory_hash(&target_dir)?;
+fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> io::Result<()> {
+    if dry_run_skip(&format!("link {:?} to {:?}", link.as_ref(), original.as_ref())) {
+        return Ok(());
+    }
+    std::os::unix::fs::symlink(original, link)
+}
 
-                // Compare with stored state
-                if let Some(stored_hash) = state.config_dirs.get(&dir_name) {
-                    if stored_hash == &project_hash && &home_hash == &project_hash {
-                        if verbose {
-                            println!("{} {} config is up to date, skipping", "[DEBUG]".cyan(), dir_name);
-                        }
-                        continue;
-                    }
-                }
+// ========================= STRICT MODE =========================
 
-                // Configs differ - ask to update
-                println!("{} {} config has changed since last sync", "[INFO]".blue(), dir_name);
-                if ask_user_confirmation(&format!("Do you want to update your {} config with the version from this project?", dir_name), yes, no, verbose)? {
-                    // Backup existing config
-                    let backup_path = home_config.join(format!("{}.backup", dir_name));
-                    if backup_path.exists() {
-                        fs::remove_dir_all(&backup_path)?;
-                    }
-                    fs::rename(&target_dir, &backup_path)
-                        .with_context(|| format!("Failed to backup existing {} config", dir_name))?;
-                    println!("{} Backed up existing {} config to {}.backup", "[INFO]".blue(), dir_name, dir_name);
+// Set once from --strict: warn() fails instead of continuing
+static STRICT: AtomicBool = AtomicBool::new(false);
 
-                    // Copy project config
-                    copy_dir_all(&path, &target_dir)
-                        .with_context(|| format!("Failed to copy {} config from project", dir_name))?;
-                    state.config_dirs.insert(dir_name.clone(), project_hash);
-                    println!("{} Successfully updated {} config", "[SUCCESS]".green(), dir_name);
-                } else {
-                    println!("{} Skipping {} config update", "[INFO]".blue(), dir_name);
-                }
-            } else {
-                println!("{} No existing {} config found, copying from project", "[INFO]".blue(), dir_name);
-                copy_dir_all(&path, &target_dir)
-                    .with_context(|| format!("Failed to copy {} config from project", dir_name))?;
-                state.config_dirs.insert(dir_name.clone(), project_hash);
-                println!("{} Successfully installed {} config", "[SUCCESS]".green(), dir_name);
-            }
-        }
+/// Prints a warning about the config or the system, or fails with it under --strict.
+fn warn(message: impl std::fmt::Display) -> Result<()> {
+    if STRICT.load(Ordering::SeqCst) {
+        return Err(FedoraForgeError::validation(format!("--strict: {}", message)));
     }
-
+    println!("{} {}", "[WARN]".yellow(), message);
     Ok(())
 }
 
-fn ask_user_confirmation(prompt: &str, yes: bool, no: bool, verbose: bool) -> Result<bool> {
-    if yes {
-        if verbose {
-            println!("{} Auto-answering YES: {}", "[DEBUG]".cyan(), prompt);
-        }
-        println!("{} (y/n): y", prompt);
-        return Ok(true);
-    }
+// ========================= STAGED CHANGES =========================
 
-    if no {
-        if verbose {
-            println!("{} Auto-answering NO: {}", "[DEBUG]".cyan(), prompt);
-        }
-        println!("{} (y/n): n", prompt);
-        return Ok(false);
-    }
+// Set once from --stage: reboot-bound commands are queued instead of run
+static STAGE: AtomicBool = AtomicBool::new(false);
 
-    loop {
-        print!("{} (y/n): ", prompt);
-        io::stdout().flush()?;
+// (description, shell line) pairs queued during this run
+static STAGED_COMMANDS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+fn staging() -> bool {
+    STAGE.load(Ordering::SeqCst)
+}
 
-        match input.trim().to_lowercase().as_str() {
-            "y" | "yes" => return Ok(true),
-            "n" | "no" => return Ok(false),
-            _ => println!("Please answer yes (y) or no (n)."),
-        }
+const STAGED_SCRIPT_PATH: &str = "/var/lib/fedoraforge/staged.sh";
+const STAGED_UNIT_NAME: &str = "fedoraforge-staged";
+
+/// Runs a command whose effect only lands after a reboot, or queues it for the next boot under --stage.
+fn run_reboot_command(cmd: &[&str], desc: &str) -> Result<()> {
+    if !staging() {
+        return run_command(cmd, desc);
     }
-}
 
-fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        if ty.is_dir() {
-            copy_dir_all(&entry.path(), &dst.join(entry.file_name()))?;
-        } else {
-            fs::copy(entry.path(), dst.join(entry.file_name()))?;
-        }
+    // The staged script already runs as root
+    let argv = cmd.strip_prefix(&["sudo"]).unwrap_or(cmd);
+    let line = argv.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ");
+    println!("{} Staged for next boot: {}", "[INFO]".blue(), desc);
+    if let Ok(mut staged) = STAGED_COMMANDS.lock() {
+        staged.push((desc.to_string(), line));
     }
     Ok(())
 }
 
-fn generate_command_hash(command: &str) -> String {
-    let normalized_command = command.trim();
-    let mut hasher = Sha256::new();
-    hasher.update(normalized_command.as_bytes());
-    format!("{:x}", hasher.finalize())
-}
-
-fn get_current_timestamp() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
-}
+/// Appends this run's staged commands to the boot script and enables the one-shot unit that runs it.
+fn write_staged_commands() -> Result<()> {
+    let staged = STAGED_COMMANDS.lock().map(|mut s| std::mem::take(&mut *s)).unwrap_or_default();
+    if staged.is_empty() {
+        return Ok(());
+    }
 
-fn get_state_file_path() -> Result<std::path::PathBuf> {
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
-    let config_dir = home_dir.join(".config").join("fedoraforge");
-    fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
-    Ok(config_dir.join("executed_commands.json"))
-}
+    // Commands staged by an earlier run that hasn't rebooted yet are kept
+    let mut script = fs::read_to_string(STAGED_SCRIPT_PATH).unwrap_or_else(|_| {
+        "#!/bin/sh\n# Generated by FedoraForge --stage, runs once at next boot\n".to_string()
+    });
+    for (desc, line) in &staged {
+        script.push_str(&format!("\n# {}\n{}\n", desc, line));
+    }
 
-fn get_container_state_file_path() -> Result<std::path::PathBuf> {
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
-    let config_dir = home_dir.join(".config").join("fedoraforge");
-    fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
-    Ok(config_dir.join("container_state.json"))
-}
+    let unit = format!(
+        "[Unit]\nDescription=FedoraForge changes staged for next boot\nConditionPathExists={script}\nWants=network-online.target\nAfter=network-online.target\n\n\
+         [Service]\nType=oneshot\nExecStart=/bin/sh {script}\nExecStopPost=/usr/bin/rm -f {script}\nExecStopPost=/usr/bin/systemctl disable {unit}.service\n\n\
+         [Install]\nWantedBy=multi-user.target\n",
+        script = STAGED_SCRIPT_PATH,
+        unit = STAGED_UNIT_NAME,
+    );
+    let unit_file = format!("/etc/systemd/system/{}.service", STAGED_UNIT_NAME);
 
-fn load_container_state() -> Result<ContainerState> {
-    let state_file = get_container_state_file_path()?;
+    // The script runs as root at boot, so it is staged privately and owned by root before it lands
+    run_command(&["sudo", "mkdir", "-p", "/var/lib/fedoraforge"], "Creating /var/lib/fedoraforge")?;
+    replace_root_file_with_mode(STAGED_SCRIPT_PATH, &script, 0o755, "Installing staged boot script")?;
+    replace_root_file_with_mode(&unit_file, &unit, 0o644, &format!("Installing {} unit", STAGED_UNIT_NAME))?;
+    mark_daemon_reload(&ServiceScope::System);
+    run_command(&["sudo", "systemctl", "enable", &format!("{}.service", STAGED_UNIT_NAME)], &format!("Enabling {}", STAGED_UNIT_NAME))?;
 
-    if state_file.exists() {
-        let content = fs::read_to_string(&state_file)
-            .context("Failed to read container state file")?;
-        let state: ContainerState = serde_json::from_str(&content)
-            .context("Failed to parse container state file")?;
-        Ok(state)
-    } else {
-        Ok(ContainerState::default())
+    println!("\n{} Staged {} change(s) for next boot:", "[INFO]".blue(), staged.len());
+    for (desc, _) in &staged {
+        println!("  - {}", desc);
     }
-}
-
-fn save_container_state(state: &ContainerState) -> Result<()> {
-    let state_file = get_container_state_file_path()?;
-    let content = serde_json::to_string_pretty(state)
-        .context("Failed to serialize container state")?;
-    fs::write(&state_file, content)
-        .context("Failed to write container state file")?;
+    println!("{} Reboot to apply them; output goes to 'journalctl -u {}'", "[INFO]".blue(), STAGED_UNIT_NAME);
     Ok(())
 }
 
-fn generate_container_config_hash(container: &Container) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(container.name.as_bytes());
-    hasher.update(container.image.as_bytes());
-    hasher.update(container.raw_flags.as_deref().unwrap_or("").as_bytes());
-    hasher.update(&[if container.start_after_creation { 1 } else { 0 }]);
-    hasher.update(&[if container.autostart.unwrap_or(false) { 1 } else { 0 }]);
-    format!("{:x}", hasher.finalize())
-}
+// ========================= FILE DIFFS =========================
 
-fn load_executed_commands_state() -> Result<ExecutedCommandsState> {
-    let state_file = get_state_file_path()?;
+// Set once from --show-diff and --diff-file
+static SHOW_DIFFS: AtomicBool = AtomicBool::new(false);
+static DIFF_FILES: std::sync::OnceLock<Vec<std::path::PathBuf>> = std::sync::OnceLock::new();
 
-    if state_file.exists() {
-        let content = fs::read_to_string(&state_file)
-            .context("Failed to read executed commands state file")?;
-        let state: ExecutedCommandsState = serde_json::from_str(&content)
-            .context("Failed to parse executed commands state file")?;
-        Ok(state)
+/// Relative paths (the config/*.toml lists) are taken from the working directory.
+fn absolute_path(path: &Path) -> std::path::PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
     } else {
-        Ok(ExecutedCommandsState::default())
+        env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
     }
 }
 
-fn save_executed_commands_state(state: &ExecutedCommandsState) -> Result<()> {
-    let state_file = get_state_file_path()?;
-    let content = serde_json::to_string_pretty(state)
-        .context("Failed to serialize executed commands state")?;
-    fs::write(&state_file, content)
-        .context("Failed to write executed commands state file")?;
-    Ok(())
+/// Whether changes to `path` get a diff: every file under --show-diff, otherwise the --diff-file ones.
+fn diff_wanted(path: &Path) -> bool {
+    if SHOW_DIFFS.load(Ordering::SeqCst) {
+        return true;
+    }
+    DIFF_FILES.get().is_some_and(|files| !files.is_empty() && files.contains(&absolute_path(path)))
 }
 
-fn get_dotfiles_state_path() -> Result<std::path::PathBuf> {
-    let config_dir = dirs::home_dir()
-        .context("Failed to get home directory")?
-        .join(".config")
-        .join("fedoraforge");
-    fs::create_dir_all(&config_dir)?;
-    Ok(config_dir.join("dotfiles_state.json"))
-}
+/// Prints a unified diff of `old` -> `new` for `label` when --show-diff or --diff-file asks for it.
+fn show_file_diff(label: &str, old: &[u8], new: &[u8]) {
+    if !diff_wanted(Path::new(label)) || old == new {
+        return;
+    }
 
-fn load_dotfiles_state() -> Result<DotfilesState> {
-    let state_file = get_dotfiles_state_path()?;
+    let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(new)) else {
+        println!("{} {}: binary content differs", "[DIFF]".magenta(), label);
+        return;
+    };
 
-    if state_file.exists() {
-        let content = fs::read_to_string(&state_file)
-            .context("Failed to read dotfiles state file")?;
-        let state: DotfilesState = serde_json::from_str(&content)
-            .context("Failed to parse dotfiles state file")?;
-        Ok(state)
-    } else {
-        Ok(DotfilesState::default())
+    println!("{} {}", "[DIFF]".magenta(), label);
+    let diff = similar::TextDiff::from_lines(old, new);
+    let old_header = format!("a/{}", label.trim_start_matches('/'));
+    let new_header = format!("b/{}", label.trim_start_matches('/'));
+    for line in diff.unified_diff().context_radius(3).header(&old_header, &new_header).to_string().lines() {
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", line.bold());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line);
+        }
     }
 }
 
-fn save_dotfiles_state(state: &DotfilesState) -> Result<()> {
-    let state_file = get_dotfiles_state_path()?;
-    let content = serde_json::to_string_pretty(state)
-        .context("Failed to serialize dotfiles state")?;
-    fs::write(&state_file, content)
-        .context("Failed to write dotfiles state file")?;
-    Ok(())
+/// Diff of the current content at `path` (empty when missing) against what is about to be written.
+fn preview_file_change(path: &Path, new: &[u8]) {
+    if !diff_wanted(path) {
+        return;
+    }
+    let old = if path.exists() { read_file_maybe_privileged(path).unwrap_or_default() } else { Vec::new() };
+    show_file_diff(&path.to_string_lossy(), &old, new);
 }
 
-fn generate_file_hash(file_path: &Path) -> Result<String> {
-    let content = fs::read(file_path)
-        .with_context(|| format!("Failed to read file {:?}", file_path))?;
-    let mut hasher = Sha256::new();
-    hasher.update(&content);
-    Ok(format!("{:x}", hasher.finalize()))
+// ========================= CONCURRENCY =========================
+
+// Shared limit for every concurrent operation, set once from --max-parallel (0 until then)
+static MAX_PARALLEL: AtomicUsize = AtomicUsize::new(0);
+
+fn set_max_parallel(requested: Option<usize>) {
+    if let Some(limit) = requested {
+        MAX_PARALLEL.store(limit.max(1), Ordering::SeqCst);
+    }
 }
 
-fn generate_directory_hash(dir_path: &Path) -> Result<String> {
-    let mut hasher = Sha256::new();
+/// The --max-parallel limit, or the CPU count when none was given.
+fn max_parallel() -> usize {
+    match MAX_PARALLEL.load(Ordering::SeqCst) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        limit => limit,
+    }
+}
 
-    // Walk directory and hash all files in sorted order for consistency
-    let mut files: Vec<_> = Vec::new();
-    for entry in walkdir::WalkDir::new(dir_path).sort_by_file_name() {
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            files.push(entry.path().to_path_buf());
+/// Runs `task` for every item on at most `max_parallel()` threads. All items are attempted;
+/// the first error (in item order) is returned once every task has finished.
+fn run_bounded<T, F>(items: &[T], task: F) -> Result<()>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<()> + Sync,
+{
+    let workers = max_parallel().min(items.len());
+    if workers <= 1 {
+        let mut first_error = None;
+        for item in items {
+            if let Err(e) = task(item) {
+                first_error.get_or_insert(e);
+            }
         }
+        return first_error.map_or(Ok(()), Err);
     }
 
-    for file in files {
-        let content = fs::read(&file)?;
-        hasher.update(&content);
-    }
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<(usize, anyhow::Error)>> = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(index) else { break };
+                if let Err(e) = task(item) {
+                    results.lock().unwrap().push((index, e));
+                }
+            });
+        }
+    });
 
-    Ok(format!("{:x}", hasher.finalize()))
+    let mut errors = results.into_inner().unwrap();
+    errors.sort_by_key(|(index, _)| *index);
+    errors.into_iter().next().map_or(Ok(()), |(_, e)| Err(e))
 }
 
-fn load_package_list(file_path: &str) -> Result<Vec<String>> {
-    if !std::path::Path::new(file_path).exists() {
-        println!("{} Package file {} not found, creating from current system state...", "[INFO]".blue(), file_path);
+// ========================= RETRIES =========================
 
-        // Discover packages based on file type
-        let discovered = if file_path.contains("system-packages") {
-            get_user_installed_packages()?
-        } else if file_path.contains("flatpak") {
-            get_installed_flatpaks()?
-        } else if file_path.contains("pip") {
-            get_installed_pip_packages()?
-        } else if file_path.contains("npm") {
-            get_installed_npm_packages()?
-        } else if file_path.contains("cargo") {
-            get_installed_cargo_packages()?
-        } else {
-            Vec::new()
-        };
+// Attempts for commands that tend to fail on flaky networks, set once from [system] command_retries
+static COMMAND_ATTEMPTS: AtomicUsize = AtomicUsize::new(1);
 
-        // Create the package file
-        if !discovered.is_empty() {
-            let content = format!(
-                "# Package list\npackages = [\n{}\n]\n",
-                discovered.iter()
-                    .map(|p| format!("    \"{}\",", p))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            );
-            fs::write(file_path, content)?;
-            println!("{} Created {} with {} packages", "[SUCCESS]".green(), file_path, discovered.len());
-        } else {
-            println!("{} No packages found, creating empty config", "[INFO]".blue());
-            fs::write(file_path, "# Package list\npackages = []\n")?;
-        }
-    }
+// Delay before the first retry; doubled for every further one
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
 
-    let content = fs::read_to_string(file_path)
-        .context(format!("Failed to read package file: {}", file_path))?;
-    let package_list: PackageList = toml::from_str(&content)
-        .context("Failed to parse package TOML file")?;
-    Ok(package_list.packages)
+fn set_command_attempts(attempts: Option<usize>) {
+    COMMAND_ATTEMPTS.store(attempts.unwrap_or(1).max(1), Ordering::SeqCst);
 }
 
-fn get_user_installed_packages() -> Result<Vec<String>> {
-    println!("{} Getting list of user-installed packages...", "[INFO]".blue());
-
-    let output = Command::new("dnf")
-        .args(&["repoquery", "--leaves", "--userinstalled", "--qf", "%{name}\\n"])
-        .output()
-        .context("Failed to run dnf repoquery command")?;
+fn command_attempts() -> usize {
+    COMMAND_ATTEMPTS.load(Ordering::SeqCst)
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("dnf repoquery failed: {}", stderr);
+/// `run_command` with up to `attempts` tries and exponential backoff. Only commands that ran and
+/// failed are retried; spawn errors such as a missing binary are returned immediately.
+fn run_command_retry(cmd: &[&str], desc: &str, attempts: usize, base_delay: std::time::Duration) -> Result<()> {
+    let mut delay = base_delay;
+    let mut attempt = 1;
+    loop {
+        match run_command(cmd, desc) {
+            Err(e) if attempt < attempts && matches!(e.downcast_ref::<FedoraForgeError>(), Some(FedoraForgeError::CommandFailed { .. })) => {
+                println!("{} {} failed (attempt {}/{}), retrying in {}s",
+                         "[WARN]".yellow(), desc, attempt, attempts, delay.as_secs());
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
     }
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut packages: Vec<String> = stdout
-        .lines()
-        .map(|line| line.trim().to_string())
-        .filter(|line| !line.is_empty())
-        .collect();
+// Image pulls are large enough that one dropped connection shouldn't end the run, whatever command_retries says
+const IMAGE_PULL_MIN_ATTEMPTS: usize = 3;
 
-    packages.sort();
-    packages.dedup();
+/// `podman pull` with progress on the terminal, retried with backoff. Layers that finished before an
+/// interruption stay in local storage, so each retry only fetches what is still missing.
+fn pull_image(runner: &dyn CommandRunner, image: &str) -> Result<()> {
+    let desc = format!("Pulling image {}", image);
+    println!("{} {}", "[INFO]".blue(), desc);
+    if dry_run_skip(&format!("run: podman pull {}", image)) {
+        return Ok(());
+    }
 
-    println!("{} Found {} user-installed packages", "[INFO]".blue(), packages.len());
-    Ok(packages)
+    let attempts = command_attempts().max(IMAGE_PULL_MIN_ATTEMPTS);
+    let mut delay = RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        let output = spawn_streamed_via(runner, &["podman", "pull", image])?;
+        if output.status.success() {
+            println!("{} {}", "[SUCCESS]".green(), desc);
+            return Ok(());
+        }
+        if attempt == attempts {
+            println!("{} {}: Command failed", "[ERROR]".red(), desc);
+            return Err(FedoraForgeError::command_failed(&["podman", "pull", image], &output).into());
+        }
+        println!("{} {} failed (attempt {}/{}), resuming in {}s",
+                 "[WARN]".yellow(), desc, attempt, attempts, delay.as_secs());
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
 }
 
-fn get_installed_flatpaks() -> Result<Vec<String>> {
-    println!("{} Getting list of installed Flatpak applications...", "[INFO]".blue());
+// ========================= PACKAGE MANAGER ARGS =========================
 
-    let output = Command::new("flatpak")
-        .args(&["list", "--app", "--columns=application"])
-        .output()
-        .context("Failed to run flatpak list command")?;
+// Extra dnf arguments from [system] package_manager_args and --package-manager-args, set once per run
+static PACKAGE_MANAGER_ARGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("flatpak list failed: {}", stderr);
-    }
+// Flags FedoraForge passes itself or that would stop dnf from running unattended
+const RESERVED_DNF_ARGS: [&str; 4] = ["-y", "--assumeyes", "--assumeno", "--skip-unavailable"];
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut apps: Vec<String> = stdout
-        .lines()
-        .map(|line| line.trim().to_string())
-        .filter(|line| !line.is_empty() && line != "Application ID") // Skip header
+fn set_package_manager_args(from_config: Option<&[String]>, from_cli: Option<&str>) -> Result<()> {
+    let args: Vec<String> = from_config.unwrap_or_default().iter()
+        .cloned()
+        .chain(from_cli.unwrap_or_default().split_whitespace().map(str::to_string))
         .collect();
 
-    apps.sort();
-    apps.dedup();
+    for arg in &args {
+        if !arg.starts_with('-') {
+            return Err(FedoraForgeError::validation(format!(
+                "Package manager argument '{}' is not an option; declare packages in system-packages.toml instead", arg
+            )));
+        }
+        let flag = arg.split('=').next().unwrap_or(arg);
+        if RESERVED_DNF_ARGS.contains(&flag) {
+            return Err(FedoraForgeError::validation(format!(
+                "Package manager argument '{}' conflicts with the flags FedoraForge passes to dnf", arg
+            )));
+        }
+    }
 
-    println!("{} Found {} installed Flatpak applications", "[INFO]".blue(), apps.len());
-    Ok(apps)
+    if let Ok(mut slot) = PACKAGE_MANAGER_ARGS.lock() {
+        *slot = args;
+    }
+    Ok(())
 }
 
-fn update_system_packages_file(packages: &[String]) -> Result<()> {
-    let package_list = PackageList {
-        packages: packages.to_vec(),
-    };
-
-    let content = format!("# System packages to install via dnf\n{}",
-        toml::to_string_pretty(&package_list)
-        .context("Failed to serialize package list to TOML")?);
+fn package_manager_args() -> Vec<String> {
+    PACKAGE_MANAGER_ARGS.lock().map(|a| a.clone()).unwrap_or_default()
+}
 
-    fs::write("config/system-packages.toml", content)
-        .context("Failed to write system-packages.toml file")?;
+// [system] install_weak_deps, only applied to installs
+static INSTALL_WEAK_DEPS: AtomicBool = AtomicBool::new(true);
 
-    println!("{} Updated config/system-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
-    Ok(())
+fn install_weak_deps() -> bool {
+    INSTALL_WEAK_DEPS.load(Ordering::SeqCst)
 }
 
-fn update_flatpak_packages_file(packages: &[String]) -> Result<()> {
-    let package_list = PackageList {
-        packages: packages.to_vec(),
+/// Installed packages that another installed package recommends, i.e. that dnf pulled in as weak
+/// dependencies. Two queries for the whole system: every Recommends of the installed packages, then
+/// the installed packages providing them. Empty when rpm can't be queried.
+fn weak_dependency_set(runner: &dyn CommandRunner) -> std::collections::HashSet<String> {
+    let mut weak = std::collections::HashSet::new();
+    let Ok(output) = spawn_output_via(runner, &["rpm", "-qa", "--recommends"]) else {
+        return weak;
     };
+    let recommended = parse_recommends_names(&String::from_utf8_lossy(&output.stdout));
+    if recommended.is_empty() {
+        return weak;
+    }
 
-    let content = format!("# Flatpak applications to install from Flathub\n{}",
-        toml::to_string_pretty(&package_list)
-        .context("Failed to serialize flatpak list to TOML")?);
-
-    fs::write("config/flatpak-packages.toml", content)
-        .context("Failed to write flatpak-packages.toml file")?;
-
-    println!("{} Updated config/flatpak-packages.toml with {} applications", "[SUCCESS]".green(), packages.len());
-    Ok(())
+    let mut cmd = vec!["rpm", "-q", "--qf", "%{NAME}\\n", "--whatprovides"];
+    cmd.extend(recommended.iter().map(String::as_str));
+    // Capabilities nothing provides are reported as "no package provides ..." and fail the query
+    if let Ok(output) = spawn_output_via(runner, &cmd) {
+        weak.extend(String::from_utf8_lossy(&output.stdout).lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.contains(' '))
+            .map(String::from));
+    }
+    weak
 }
 
-fn sync_system_packages(yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
-    println!("{} Synchronizing system packages with installed packages...", "[INFO]".blue());
+fn setup_dotfiles(config: &DotfilesConfig, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<()> {
+    println!("{} Setting up dotfiles...", "[INFO]".blue());
 
-    // Get currently installed user packages
-    let installed_packages = get_user_installed_packages()?;
-    if verbose {
-        println!("{} Found {} installed packages", "[DEBUG]".cyan(), installed_packages.len());
-    }
+    let current_dir = env::current_dir()?;
+    let home_dir = user_home_dir()?;
 
-    // Load packages from config file
-    let mut config_packages = load_package_list("config/system-packages.toml")?;
-    if verbose {
-        println!("{} Loaded {} packages from config", "[DEBUG]".cyan(), config_packages.len());
-    }
+    // Load dotfiles state
+    let mut state = load_dotfiles_state()?;
 
-    // Find packages to install (in config but not installed)
-    let mut packages_to_install = Vec::new();
-    for pkg in &config_packages {
-        if !installed_packages.contains(pkg) {
-            packages_to_install.push(pkg.clone());
+    // Links whose project source is gone, or all of them when back in copy mode
+    remove_stale_dotfile_links(config.mode, &home_dir, &mut state, yes, no, no_removals, verbose)?;
+
+    // Setup shell startup files (.bashrc, .zshrc, config.fish, ...)
+    let shell_files = configured_shell_files(config);
+    for file in &shell_files {
+        if config.mode == DotfilesMode::Symlink {
+            let source = current_dir.join(file);
+            if source.exists() {
+                link_dotfile(file, &source, &home_dir, &mut state, yes, no, verbose)?;
+            } else if verbose {
+                println!("{} No {} found in project directory, skipping", "[DEBUG]".cyan(), file);
+            }
+        } else {
+            setup_shell_file(file, &current_dir, &home_dir, &mut state, yes, no, verbose)?;
         }
     }
 
-    // Find packages to potentially remove (installed but not in config)
-    let mut packages_to_keep = Vec::new();
-    let mut packages_to_remove = Vec::new();
-
-    for pkg in &installed_packages {
-        if !config_packages.contains(pkg) {
-            println!("\n{} Package '{}' is installed but not in system-packages.toml", "[INFO]".yellow(), pkg);
-            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes, no, verbose)? {
-                packages_to_keep.push(pkg.clone());
-                config_packages.push(pkg.clone());
-            } else {
-                packages_to_remove.push(pkg.clone());
-            }
-        }
+    // Setup ~/.bashrc.d snippets
+    if config.setup_bashrc_d {
+        setup_bashrc_snippets(&current_dir, &home_dir, &mut state, yes, no, no_removals, verbose)?;
     }
 
-    // Install missing packages
-    if !packages_to_install.is_empty() {
-        println!("{} Installing {} packages from config...", "[INFO]".blue(), packages_to_install.len());
-        install_system_packages(&Distro::Fedora, &packages_to_install, verbose)?;
+    // Setup .config directories
+    if config.setup_config_dirs {
+        setup_config_dirs(&current_dir, &home_dir, config.mode, &mut state, yes, no, verbose)?;
     }
 
-    // Remove unwanted packages
-    if !packages_to_remove.is_empty() {
-        println!("{} Removing {} unwanted packages...", "[INFO]".blue(), packages_to_remove.len());
-        for pkg in &packages_to_remove {
-            run_command(&["sudo", "dnf", "remove", "-y", pkg], &format!("Removing package {}", pkg))?;
+    if let Some(max_backups) = config.max_backups {
+        let mut backed_up: Vec<std::path::PathBuf> = shell_files.iter().map(|file| home_dir.join(file)).collect();
+        if config.setup_config_dirs && current_dir.join(".config").is_dir() {
+            for entry in fs::read_dir(current_dir.join(".config"))? {
+                backed_up.push(home_dir.join(".config").join(entry?.file_name()));
+            }
+        }
+        for path in &backed_up {
+            prune_dotfile_backups(path, max_backups, verbose)?;
         }
     }
 
-    // Update config file if there were changes
-    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
-        config_packages.sort();
-        config_packages.dedup();
-        update_system_packages_file(&config_packages)?;
+    if TARGET_USER.get().is_some() {
+        // Links point into the project, whose ownership isn't ours to change
+        for file in shell_files.iter().filter(|_| config.mode == DotfilesMode::Copy) {
+            if home_dir.join(file).exists() {
+                chown_for_user(&home_dir.join(file))?;
+            }
+        }
+        if config.setup_bashrc_d && home_dir.join(".bashrc.d").exists() {
+            chown_for_user(&home_dir.join(".bashrc.d"))?;
+        }
+        if config.setup_config_dirs {
+            chown_for_user(&home_dir.join(".config"))?;
+        }
     }
 
-    println!("{} Package synchronization completed", "[SUCCESS]".green());
-    println!("  - Installed: {} packages", packages_to_install.len());
-    println!("  - Kept: {} packages", packages_to_keep.len());
-    println!("  - Removed: {} packages", packages_to_remove.len());
+    // Save updated state
+    save_dotfiles_state(&state)?;
 
-    Ok(config_packages)
+    println!("{} Dotfiles setup completed!", "[SUCCESS]".green());
+    Ok(())
 }
 
-fn sync_flatpak_packages(yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
-    println!("{} Synchronizing Flatpak packages with installed applications...", "[INFO]".blue());
-
-    // Get currently installed Flatpak applications
-    let installed_flatpaks = get_installed_flatpaks()?;
+/// `<path>.<unix timestamp>.backup` next to the original, so earlier backups are never overwritten.
+fn dotfile_backup_path(path: &Path) -> std::path::PathBuf {
+    std::path::PathBuf::from(format!("{}.{}.backup", path.display(), get_current_timestamp()))
+}
 
-    // Load packages from config file
-    let mut config_flatpaks = load_package_list("config/flatpak-packages.toml")?;
+/// Deletes the oldest timestamped backups of `path` beyond `max_backups`.
+fn prune_dotfile_backups(path: &Path, max_backups: usize, verbose: bool) -> Result<()> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Ok(());
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Ok(());
+    };
 
-    // Find packages to install (in config but not installed)
-    let mut flatpaks_to_install = Vec::new();
-    for app in &config_flatpaks {
-        if !installed_flatpaks.contains(app) {
-            flatpaks_to_install.push(app.clone());
+    let prefix = format!("{}.", name);
+    let mut backups: Vec<(u64, std::path::PathBuf)> = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let timestamp = file_name.strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".backup"))
+            .and_then(|ts| ts.parse::<u64>().ok());
+        if let Some(timestamp) = timestamp {
+            backups.push((timestamp, entry.path()));
         }
     }
+    if backups.len() <= max_backups {
+        return Ok(());
+    }
 
-    // Find packages to potentially remove (installed but not in config)
-    let mut flatpaks_to_keep = Vec::new();
-    let mut flatpaks_to_remove = Vec::new();
-
-    for app in &installed_flatpaks {
-        if !config_flatpaks.contains(app) {
-            println!("\n{} Flatpak application '{}' is installed but not in flatpak-packages.toml", "[INFO]".yellow(), app);
-            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", app), yes, no, verbose)? {
-                flatpaks_to_keep.push(app.clone());
-                config_flatpaks.push(app.clone());
-            } else {
-                flatpaks_to_remove.push(app.clone());
-            }
+    backups.sort();
+    for (_, backup) in &backups[..backups.len() - max_backups] {
+        debug!(verbose, "Pruning old backup {}", backup.display());
+        if fs::symlink_metadata(backup)?.is_dir() {
+            delete_dir(backup)?;
+        } else {
+            delete_file(backup)?;
         }
     }
+    Ok(())
+}
 
-    // Install missing Flatpak applications
-    if !flatpaks_to_install.is_empty() {
-        println!("{} Installing {} Flatpak applications from config...", "[INFO]".blue(), flatpaks_to_install.len());
-        install_flatpak_packages(&flatpaks_to_install)?;
+/// Shell files to migrate: .bashrc from `setup_bashrc` plus the files of each listed shell.
+fn configured_shell_files(config: &DotfilesConfig) -> Vec<&'static str> {
+    let mut files: Vec<&'static str> = Vec::new();
+    if config.setup_bashrc {
+        files.push(".bashrc");
     }
-
-    // Remove unwanted Flatpak applications
-    if !flatpaks_to_remove.is_empty() {
-        println!("{} Removing {} unwanted Flatpak applications...", "[INFO]".blue(), flatpaks_to_remove.len());
-        for app in &flatpaks_to_remove {
-            run_command(&["flatpak", "uninstall", "-y", app], &format!("Removing Flatpak application {}", app))?;
+    for shell in &config.shells {
+        for file in shell.files() {
+            if !files.contains(file) {
+                files.push(file);
+            }
         }
     }
-
-    // Update config file if there were changes
-    if !flatpaks_to_keep.is_empty() || !flatpaks_to_remove.is_empty() {
-        config_flatpaks.sort();
-        config_flatpaks.dedup();
-        update_flatpak_packages_file(&config_flatpaks)?;
-    }
-
-    println!("{} Flatpak synchronization completed", "[SUCCESS]".green());
-    println!("  - Installed: {} applications", flatpaks_to_install.len());
-    println!("  - Kept: {} applications", flatpaks_to_keep.len());
-    println!("  - Removed: {} applications", flatpaks_to_remove.len());
-
-    Ok(config_flatpaks)
+    files
 }
 
-// ========== Pip Package Management ==========
-
-fn get_installed_pip_packages() -> Result<Vec<String>> {
-    println!("{} Getting list of installed pip packages...", "[INFO]".blue());
+fn setup_shell_file(file: &str, project_dir: &Path, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let project_file = project_dir.join(file);
+    let home_file = home_dir.join(file);
 
-    let output = Command::new("pip")
-        .args(&["list", "--format=freeze", "--user"])
-        .output()
-        .context("pip is not installed or not in PATH")?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("pip list failed: {}", stderr);
+    if !project_file.exists() {
+        debug!(verbose, "No {} found in project directory, skipping", file);
+        return Ok(());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut packages: Vec<String> = stdout
-        .lines()
-        .filter_map(|line| {
-            let line = line.trim();
-            if line.is_empty() {
-                return None;
-            }
-            // Parse "package==version" format and extract package name
-            line.split("==").next().map(|s| s.to_string())
-        })
-        .collect();
+    // Generate hash of project file
+    let project_hash = generate_file_hash(&project_file)?;
 
-    packages.sort();
-    packages.dedup();
-
-    println!("{} Found {} installed pip packages", "[INFO]".blue(), packages.len());
-    Ok(packages)
-}
+    // Check if home file exists and matches
+    if home_file.exists() {
+        let home_hash = generate_file_hash(&home_file)?;
 
-fn install_pip_packages(packages: &[String]) -> Result<()> {
-    if packages.is_empty() {
-        return Ok(());
-    }
+        // Compare with stored state
+        if let Some(stored_hash) = state.shell_file_hash(file) {
+            if stored_hash == &project_hash && home_hash == project_hash {
+                debug!(verbose, "{} is up to date, skipping", file);
+                return Ok(());
+            }
+        }
 
-    println!("{} Installing {} pip packages...", "[INFO]".blue(), packages.len());
-    for pkg in packages {
-        run_command(&["pip", "install", "--user", pkg], &format!("Installing pip package {}", pkg))?;
+        // Files differ - ask to update
+        println!("{} {} has changed since last sync", "[INFO]".blue(), file);
+        preview_file_change(&home_file, &fs::read(&project_file)?);
+        if ask_user_confirmation(&format!("Do you want to update your {} with the version from this project?", file), yes, no, verbose)? {
+            // Backup existing file
+            let backup_path = dotfile_backup_path(&home_file);
+            copy_file(&home_file, &backup_path)
+                .with_context(|| format!("Failed to backup existing {}", file))?;
+            println!("{} Backed up existing {} to {}", "[INFO]".blue(), file, backup_path.display());
+
+            // Copy project file
+            copy_file(&project_file, &home_file)
+                .with_context(|| format!("Failed to copy project {}", file))?;
+            record_action(ActionCategory::File, ActionKind::Overwrite, &home_file.to_string_lossy(), Some(backup_path.to_string_lossy().to_string()));
+            state.set_shell_file_hash(file, project_hash);
+            println!("{} Successfully updated {}", "[SUCCESS]".green(), file);
+        } else {
+            println!("{} Skipping {} update", "[INFO]".blue(), file);
+        }
+    } else {
+        println!("{} No existing {} found, copying from project", "[INFO]".blue(), file);
+        if let Some(parent) = home_file.parent() {
+            create_dir(parent)?;
+        }
+        copy_file(&project_file, &home_file)
+            .with_context(|| format!("Failed to copy {} from project", file))?;
+        record_action(ActionCategory::File, ActionKind::Create, &home_file.to_string_lossy(), None);
+        state.set_shell_file_hash(file, project_hash);
+        println!("{} Successfully installed {}", "[SUCCESS]".green(), file);
     }
 
     Ok(())
 }
 
-fn update_pip_packages_file(packages: &[String]) -> Result<()> {
-    let package_list = PackageList {
-        packages: packages.to_vec(),
-    };
-
-    let content = format!("# Python packages to install via pip\n# List user-installed packages here\n{}",
-        toml::to_string_pretty(&package_list)
-        .context("Failed to serialize pip package list to TOML")?);
-
-    fs::write("config/pip-packages.toml", content)
-        .context("Failed to write pip-packages.toml file")?;
+// Appended to .bashrc when it doesn't source ~/.bashrc.d yet (Fedora's default .bashrc already does)
+const BASHRC_D_SOURCE_BLOCK: &str = r#"
+# BEGIN fedoraforge bashrc.d
+if [ -d ~/.bashrc.d ]; then
+    for rc in ~/.bashrc.d/*; do
+        [ -f "$rc" ] && . "$rc"
+    done
+    unset rc
+fi
+# END fedoraforge bashrc.d
+"#;
+
+/// Syncs each file in the project's .bashrc.d/ to ~/.bashrc.d/ independently; snippets removed
+/// from the project are removed from the home directory.
+fn setup_bashrc_snippets(project_dir: &Path, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<()> {
+    let project_snippets = project_dir.join(".bashrc.d");
+    let home_snippets = home_dir.join(".bashrc.d");
+
+    let mut snippets: Vec<String> = Vec::new();
+    if project_snippets.is_dir() {
+        for entry in fs::read_dir(&project_snippets)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                snippets.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    } else if verbose {
+        println!("{} No .bashrc.d found in project directory", "[DEBUG]".cyan());
+    }
+    snippets.sort();
 
-    println!("{} Updated config/pip-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
-    Ok(())
-}
+    if !snippets.is_empty() {
+        create_dir(&home_snippets)
+            .context("Failed to create ~/.bashrc.d")?;
+    }
 
-fn sync_pip_packages(yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
-    println!("{} Synchronizing pip packages with installed packages...", "[INFO]".blue());
+    for name in &snippets {
+        let source = project_snippets.join(name);
+        let target = home_snippets.join(name);
+        let hash = generate_file_hash(&source)?;
 
-    // Get currently installed pip packages
-    let installed_packages = get_installed_pip_packages()?;
+        if target.exists() {
+            if generate_file_hash(&target)? == hash {
+                debug!(verbose, ".bashrc.d/{} is up to date", name);
+                state.bashrc_snippets.insert(name.clone(), hash);
+                continue;
+            }
 
-    // Load packages from config file
-    let mut config_packages = load_package_list("config/pip-packages.toml")?;
+            println!("{} .bashrc.d/{} differs from the project version", "[INFO]".blue(), name);
+            preview_file_change(&target, &fs::read(&source)?);
+            if !ask_user_confirmation(&format!("Update .bashrc.d/{}?", name), yes, no, verbose)? {
+                println!("{} Skipping .bashrc.d/{}", "[INFO]".blue(), name);
+                continue;
+            }
 
-    // Find packages to install (in config but not installed)
-    let mut packages_to_install = Vec::new();
-    for pkg in &config_packages {
-        if !installed_packages.contains(pkg) {
-            packages_to_install.push(pkg.clone());
+            // Backups can't live in ~/.bashrc.d, everything there gets sourced
+            let backup_path = get_file_backups_dir()?.join(format!("bashrc.d__{}__{}", name, get_current_timestamp()));
+            copy_file(&target, &backup_path)
+                .with_context(|| format!("Failed to back up .bashrc.d/{}", name))?;
+            copy_file(&source, &target)
+                .with_context(|| format!("Failed to copy .bashrc.d/{}", name))?;
+            record_action(ActionCategory::File, ActionKind::Overwrite, &target.to_string_lossy(), Some(backup_path.to_string_lossy().to_string()));
+        } else {
+            copy_file(&source, &target)
+                .with_context(|| format!("Failed to copy .bashrc.d/{}", name))?;
+            record_action(ActionCategory::File, ActionKind::Create, &target.to_string_lossy(), None);
         }
+        println!("{} Installed .bashrc.d/{}", "[SUCCESS]".green(), name);
+        state.bashrc_snippets.insert(name.clone(), hash);
     }
 
-    // Find packages to potentially remove (installed but not in config)
-    let mut packages_to_keep = Vec::new();
-    let mut packages_to_remove = Vec::new();
-
-    for pkg in &installed_packages {
-        if !config_packages.contains(pkg) {
-            println!("\n{} Pip package '{}' is installed but not in pip-packages.toml", "[INFO]".yellow(), pkg);
-            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes, no, verbose)? {
-                packages_to_keep.push(pkg.clone());
-                config_packages.push(pkg.clone());
-            } else {
-                packages_to_remove.push(pkg.clone());
+    // Snippets we installed that are gone from the project
+    let mut stale: Vec<String> = state.bashrc_snippets.keys()
+        .filter(|name| !snippets.contains(name))
+        .cloned()
+        .collect();
+    stale.sort();
+    if !stale.is_empty() && no_removals {
+        println!("{} --no-removals: keeping {} .bashrc.d snippets removed from the project", "[INFO]".blue(), stale.len());
+    } else if !stale.is_empty() {
+        println!("{} Found {} .bashrc.d snippets removed from the project:", "[INFO]".blue(), stale.len());
+        for name in &stale {
+            println!("  - {}", name);
+        }
+        if ask_user_confirmation("Remove these snippets from ~/.bashrc.d?", yes, no, verbose)? {
+            for name in &stale {
+                let target = home_snippets.join(name);
+                if target.exists() {
+                    delete_file(&target)
+                        .with_context(|| format!("Failed to remove .bashrc.d/{}", name))?;
+                    record_action(ActionCategory::File, ActionKind::Delete, &target.to_string_lossy(), None);
+                }
+                state.bashrc_snippets.remove(name);
             }
         }
     }
 
-    // Install missing packages
-    if !packages_to_install.is_empty() {
-        println!("{} Installing {} pip packages from config...", "[INFO]".blue(), packages_to_install.len());
-        install_pip_packages(&packages_to_install)?;
+    if !snippets.is_empty() {
+        ensure_bashrc_sources_snippets(home_dir, yes, no, verbose)?;
     }
+    Ok(())
+}
 
-    // Remove unwanted packages
-    if !packages_to_remove.is_empty() {
-        println!("{} Removing {} unwanted pip packages...", "[INFO]".blue(), packages_to_remove.len());
-        for pkg in &packages_to_remove {
-            run_command(&["pip", "uninstall", "-y", pkg], &format!("Removing pip package {}", pkg))?;
-        }
+fn ensure_bashrc_sources_snippets(home_dir: &Path, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let bashrc = home_dir.join(".bashrc");
+    let content = fs::read_to_string(&bashrc).unwrap_or_default();
+    if content.contains(".bashrc.d") {
+        debug!(verbose, ".bashrc already sources ~/.bashrc.d");
+        return Ok(());
     }
 
-    // Update config file if there were changes
-    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
-        config_packages.sort();
-        config_packages.dedup();
-        update_pip_packages_file(&config_packages)?;
+    println!("{} .bashrc doesn't source ~/.bashrc.d", "[INFO]".blue());
+    if !ask_user_confirmation("Append a block to .bashrc that sources ~/.bashrc.d?", yes, no, verbose)? {
+        return warn("Snippets in ~/.bashrc.d won't be loaded until .bashrc sources them");
     }
 
-    println!("{} Pip synchronization completed", "[SUCCESS]".green());
-    println!("  - Installed: {} packages", packages_to_install.len());
-    println!("  - Kept: {} packages", packages_to_keep.len());
-    println!("  - Removed: {} packages", packages_to_remove.len());
-
-    Ok(config_packages)
+    let updated = format!("{}{}", content, BASHRC_D_SOURCE_BLOCK);
+    preview_file_change(&bashrc, updated.as_bytes());
+    write_file(&bashrc, updated)
+        .context("Failed to update .bashrc")?;
+    println!("{} .bashrc now sources ~/.bashrc.d", "[SUCCESS]".green());
+    Ok(())
 }
 
-// ========== NPM Package Management ==========
-
-fn get_installed_npm_packages() -> Result<Vec<String>> {
-    println!("{} Getting list of globally installed npm packages...", "[INFO]".blue());
-
-    let output = Command::new("npm")
-        .args(&["list", "-g", "--depth=0", "--json"])
-        .output()
-        .context("npm is not installed or not in PATH")?;
+fn setup_config_dirs(project_dir: &Path, home_dir: &Path, mode: DotfilesMode, state: &mut DotfilesState, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let project_config = project_dir.join(".config");
+    let home_config = home_dir.join(".config");
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("npm list failed: {}", stderr);
+    if !project_config.exists() {
+        debug!(verbose, "No .config directory found in project, skipping");
+        return Ok(());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    // Parse JSON output
-    let json: serde_json::Value = serde_json::from_str(&stdout)
-        .context("Failed to parse npm list JSON output")?;
-
-    let mut packages = Vec::new();
-    if let Some(deps) = json.get("dependencies").and_then(|d| d.as_object()) {
-        for (pkg_name, _) in deps {
-            // Skip npm itself
-            if pkg_name != "npm" {
-                packages.push(pkg_name.clone());
-            }
-        }
-    }
+    // Create ~/.config if it doesn't exist
+    create_dir(&home_config)
+        .context("Failed to create ~/.config directory")?;
 
-    packages.sort();
-    packages.dedup();
+    // Process each subdirectory in project .config
+    for entry in fs::read_dir(&project_config)? {
+        let entry = entry?;
+        let path = entry.path();
 
-    println!("{} Found {} globally installed npm packages", "[INFO]".blue(), packages.len());
-    Ok(packages)
-}
+        if path.is_dir() {
+            let dir_name = path.file_name()
+                .context("Invalid directory name")?
+                .to_str()
+                .context("Non-UTF8 directory name")?
+                .to_string();
 
-fn install_npm_packages(packages: &[String]) -> Result<()> {
-    if packages.is_empty() {
-        return Ok(());
-    }
+            let target_dir = home_config.join(&dir_name);
 
-    println!("{} Installing {} npm packages globally...", "[INFO]".blue(), packages.len());
-    for pkg in packages {
-        run_command(&["npm", "install", "-g", pkg], &format!("Installing npm package {}", pkg))?;
+            // Linked directories follow the project by themselves, so there is no hash to track
+            if mode == DotfilesMode::Symlink {
+                link_dotfile(&format!(".config/{}", dir_name), &path, home_dir, state, yes, no, verbose)?;
+                continue;
+            }
+
+            // Generate hash of project config directory
+            let project_hash = generate_directory_hash(&path)?;
+
+            // Check if target dir exists and compare hashes
+            if target_dir.exists() {
+                let home_hash = generate_directory_hash(&target_dir)?;
+
+                // Compare with stored state
+                if let Some(stored_hash) = state.config_dirs.get(&dir_name) {
+                    if stored_hash == &project_hash && home_hash == project_hash {
+                        debug!(verbose, "{} config is up to date, skipping", dir_name);
+                        continue;
+                    }
+                }
+
+                // Configs differ - ask to update
+                println!("{} {} config has changed since last sync", "[INFO]".blue(), dir_name);
+                if ask_user_confirmation(&format!("Do you want to update your {} config with the version from this project?", dir_name), yes, no, verbose)? {
+                    // Backup existing config
+                    let backup_path = dotfile_backup_path(&target_dir);
+                    rename_path(&target_dir, &backup_path)
+                        .with_context(|| format!("Failed to backup existing {} config", dir_name))?;
+                    println!("{} Backed up existing {} config to {}", "[INFO]".blue(), dir_name, backup_path.display());
+
+                    // Copy project config
+                    copy_dir_all(&path, &target_dir)
+                        .with_context(|| format!("Failed to copy {} config from project", dir_name))?;
+                    record_action(ActionCategory::File, ActionKind::Overwrite, &target_dir.to_string_lossy(), Some(backup_path.to_string_lossy().to_string()));
+                    state.config_dirs.insert(dir_name.clone(), project_hash);
+                    println!("{} Successfully updated {} config", "[SUCCESS]".green(), dir_name);
+                } else {
+                    println!("{} Skipping {} config update", "[INFO]".blue(), dir_name);
+                }
+            } else {
+                println!("{} No existing {} config found, copying from project", "[INFO]".blue(), dir_name);
+                copy_dir_all(&path, &target_dir)
+                    .with_context(|| format!("Failed to copy {} config from project", dir_name))?;
+                record_action(ActionCategory::File, ActionKind::Create, &target_dir.to_string_lossy(), None);
+                state.config_dirs.insert(dir_name.clone(), project_hash);
+                println!("{} Successfully installed {} config", "[SUCCESS]".green(), dir_name);
+            }
+        }
     }
 
     Ok(())
 }
 
-fn update_npm_packages_file(packages: &[String]) -> Result<()> {
-    let package_list = PackageList {
-        packages: packages.to_vec(),
-    };
+/// Symlink mode: makes `~/<file>` a link to `source` in the project, backing up whatever was there.
+fn link_dotfile(file: &str, source: &Path, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let home_path = home_dir.join(file);
 
-    let content = format!("# Node.js global packages to install via npm\n# List globally installed packages here\n{}",
-        toml::to_string_pretty(&package_list)
-        .context("Failed to serialize npm package list to TOML")?);
+    if fs::read_link(&home_path).is_ok_and(|target| target == source) {
+        debug!(verbose, "~/{} already links to the project", file);
+        state.symlinks.insert(file.to_string(), source.display().to_string());
+        return Ok(());
+    }
 
-    fs::write("config/npm-packages.toml", content)
-        .context("Failed to write npm-packages.toml file")?;
+    let mut backup = None;
+    if let Ok(metadata) = fs::symlink_metadata(&home_path) {
+        println!("{} ~/{} is not linked to the project", "[INFO]".blue(), file);
+        if !ask_user_confirmation(&format!("Replace ~/{} with a symlink to {}?", file, source.display()), yes, no, verbose)? {
+            println!("{} Skipping ~/{}", "[INFO]".blue(), file);
+            return Ok(());
+        }
 
-    println!("{} Updated config/npm-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
+        if metadata.file_type().is_symlink() {
+            // Another link has nothing worth keeping
+            delete_file(&home_path).with_context(|| format!("Failed to remove ~/{}", file))?;
+        } else {
+            let backup_path = dotfile_backup_path(&home_path);
+            rename_path(&home_path, &backup_path)
+                .with_context(|| format!("Failed to back up existing ~/{}", file))?;
+            println!("{} Backed up existing ~/{} to {}", "[INFO]".blue(), file, backup_path.display());
+            backup = Some(backup_path.to_string_lossy().to_string());
+        }
+    } else if let Some(parent) = home_path.parent() {
+        create_dir(parent)?;
+    }
+
+    create_symlink(source, &home_path)
+        .with_context(|| format!("Failed to link ~/{} to {}", file, source.display()))?;
+    let kind = if backup.is_some() { ActionKind::Overwrite } else { ActionKind::Create };
+    record_action(ActionCategory::File, kind, &home_path.to_string_lossy(), backup);
+    state.symlinks.insert(file.to_string(), source.display().to_string());
+    println!("{} Linked ~/{} to {}", "[SUCCESS]".green(), file, source.display());
     Ok(())
 }
 
-fn sync_npm_packages(yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
-    println!("{} Synchronizing npm packages with installed packages...", "[INFO]".blue());
+/// Offers to remove links whose project source no longer exists. In copy mode every link we made
+/// is removed so the copy doesn't write through it into the project.
+fn remove_stale_dotfile_links(mode: DotfilesMode, home_dir: &Path, state: &mut DotfilesState, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<()> {
+    let mut entries: Vec<(String, String)> = state.symlinks.iter()
+        .map(|(file, source)| (file.clone(), source.clone()))
+        .collect();
+    entries.sort();
 
-    // Get currently installed npm packages
-    let installed_packages = get_installed_npm_packages()?;
+    for (file, source) in entries {
+        let home_path = home_dir.join(&file);
+        // Only touch the link if it is still the one we created
+        let ours = fs::read_link(&home_path).is_ok_and(|target| target == Path::new(&source));
 
-    // Load packages from config file
-    let mut config_packages = load_package_list("config/npm-packages.toml")?;
+        if mode == DotfilesMode::Copy {
+            if ours {
+                println!("{} Dotfiles are in copy mode, replacing the link ~/{} with a copy", "[INFO]".blue(), file);
+                delete_file(&home_path).with_context(|| format!("Failed to remove link ~/{}", file))?;
+            }
+            state.symlinks.remove(&file);
+            continue;
+        }
 
-    // Find packages to install (in config but not installed)
-    let mut packages_to_install = Vec::new();
-    for pkg in &config_packages {
-        if !installed_packages.contains(pkg) {
-            packages_to_install.push(pkg.clone());
+        if Path::new(&source).exists() {
+            continue;
+        }
+        if !ours {
+            state.symlinks.remove(&file);
+            continue;
+        }
+        if no_removals {
+            println!("{} --no-removals: keeping dangling link ~/{}", "[INFO]".blue(), file);
+            continue;
+        }
+        println!("{} ~/{} links to {}, which is no longer in the project", "[INFO]".yellow(), file, source);
+        if ask_user_confirmation(&format!("Remove the dangling link ~/{}?", file), yes, no, verbose)? {
+            delete_file(&home_path).with_context(|| format!("Failed to remove link ~/{}", file))?;
+            record_action(ActionCategory::File, ActionKind::Delete, &home_path.to_string_lossy(), None);
+            state.symlinks.remove(&file);
         }
     }
+    Ok(())
+}
 
-    // Find packages to potentially remove (installed but not in config)
-    let mut packages_to_keep = Vec::new();
-    let mut packages_to_remove = Vec::new();
+fn ask_user_confirmation(prompt: &str, yes: bool, no: bool, verbose: bool) -> Result<bool> {
+    if yes {
+        debug!(verbose, "Auto-answering YES: {}", prompt);
+        println!("{} (y/n): y", prompt);
+        return Ok(true);
+    }
 
-    for pkg in &installed_packages {
-        if !config_packages.contains(pkg) {
-            println!("\n{} npm package '{}' is installed but not in npm-packages.toml", "[INFO]".yellow(), pkg);
-            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes, no, verbose)? {
-                packages_to_keep.push(pkg.clone());
-                config_packages.push(pkg.clone());
-            } else {
-                packages_to_remove.push(pkg.clone());
-            }
-        }
+    if no {
+        debug!(verbose, "Auto-answering NO: {}", prompt);
+        println!("{} (y/n): n", prompt);
+        return Ok(false);
     }
 
-    // Install missing packages
-    if !packages_to_install.is_empty() {
-        println!("{} Installing {} npm packages from config...", "[INFO]".blue(), packages_to_install.len());
-        install_npm_packages(&packages_to_install)?;
+    // Assume yes so the preview covers everything a confirmed run would do
+    if dry_run_skip(&format!("ask: {}", prompt)) {
+        return Ok(true);
     }
 
-    // Remove unwanted packages
-    if !packages_to_remove.is_empty() {
-        println!("{} Removing {} unwanted npm packages...", "[INFO]".blue(), packages_to_remove.len());
-        for pkg in &packages_to_remove {
-            run_command(&["npm", "uninstall", "-g", pkg], &format!("Removing npm package {}", pkg))?;
+    loop {
+        print!("{} (y/n): ", prompt);
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+
+        match input.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes (y) or no (n)."),
         }
     }
+}
 
-    // Update config file if there were changes
-    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
-        config_packages.sort();
-        config_packages.dedup();
-        update_npm_packages_file(&config_packages)?;
+/// Reads the 1-3 choice offered for undeclared users or groups. A dry run doesn't ask and takes
+/// "3", leaving them as-is.
+fn ask_undeclared_choice(kind: &str) -> Result<String> {
+    if dry_run_skip(&format!("ask: what to do with these {} (leaving them as-is)", kind)) {
+        return Ok("3".to_string());
     }
 
-    println!("{} npm synchronization completed", "[SUCCESS]".green());
-    println!("  - Installed: {} packages", packages_to_install.len());
-    println!("  - Kept: {} packages", packages_to_keep.len());
-    println!("  - Removed: {} packages", packages_to_remove.len());
-
-    Ok(config_packages)
+    print!("Enter choice [1-3]: ");
+    io::stdout().flush()?;
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+    Ok(choice.trim().to_string())
 }
 
-// ========== Cargo Package Management ==========
+fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
+    if dry_run_skip(&format!("copy {:?} to {:?}", src, dst)) {
+        return Ok(());
+    }
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        let target = dst.join(entry.file_name());
+        if ty.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else if ty.is_symlink() {
+            // Recreate the link itself; following it could copy data from outside the tree
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)
+                .with_context(|| format!("Failed to create symlink {:?}", target))?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+            fs::set_permissions(&target, entry.metadata()?.permissions())?;
+        }
+    }
+    Ok(())
+}
 
-fn get_installed_cargo_packages() -> Result<Vec<String>> {
-    println!("{} Getting list of cargo-installed binaries...", "[INFO]".blue());
+fn generate_command_hash(command: &str) -> String {
+    let normalized_command = command.trim();
+    let mut hasher = Sha256::new();
+    hasher.update(normalized_command.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
 
-    let output = Command::new("cargo")
-        .args(&["install", "--list"])
-        .output()
-        .context("cargo is not installed or not in PATH")?;
+fn get_current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("cargo install --list failed: {}", stderr);
-    }
+/// `~/.config/fedoraforge`, home of every state file, run log and backup. Created on first use.
+fn state_dir() -> Result<std::path::PathBuf> {
+    let dir = dirs::home_dir()
+        .context("Could not find home directory")?
+        .join(".config")
+        .join("fedoraforge");
+    fs::create_dir_all(&dir).context("Failed to create state directory")?;
+    Ok(dir)
+}
+
+// Held for the whole process; the kernel drops the flock when it exits, successfully or not
+static RUN_LOCK: std::sync::OnceLock<fs::File> = std::sync::OnceLock::new();
+
+/// Takes an exclusive flock on `~/.config/fedoraforge/lock` so overlapping runs (a timer firing
+/// during a manual run) can't race on dnf, /etc/fstab, /etc/crypttab or the state files.
+fn acquire_run_lock() -> Result<()> {
+    let path = state_dir()?.join("lock");
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file {:?}", path))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            return Err(FedoraForgeError::validation(format!(
+                "Another FedoraForge instance is running (lock held on {:?})", path
+            )));
+        }
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(anyhow::Error::new(e).context(format!("Failed to lock {:?}", path)));
+        }
+    }
+    let _ = RUN_LOCK.set(file);
+    Ok(())
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut packages = Vec::new();
+fn get_state_file_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("executed_commands.json"))
+}
 
-    // Parse cargo install --list output
-    // Format: "package_name v0.1.0:"
-    for line in stdout.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with(' ') {
-            continue;
-        }
+fn get_container_state_file_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("container_state.json"))
+}
 
-        // Extract package name (before the version)
-        if let Some(pkg_name) = line.split_whitespace().next() {
-            packages.push(pkg_name.to_string());
-        }
+fn load_container_state() -> Result<ContainerState> {
+    if state_disabled() {
+        return Ok(ContainerState::default());
     }
 
-    packages.sort();
-    packages.dedup();
+    let state_file = get_container_state_file_path()?;
 
-    println!("{} Found {} cargo-installed binaries", "[INFO]".blue(), packages.len());
-    Ok(packages)
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read container state file")?;
+        let state: ContainerState = serde_json::from_str(&content)
+            .context("Failed to parse container state file")?;
+        Ok(state)
+    } else {
+        Ok(ContainerState::default())
+    }
 }
 
-fn install_cargo_packages(packages: &[String]) -> Result<()> {
-    if packages.is_empty() {
+fn save_container_state(state: &ContainerState) -> Result<()> {
+    if state_disabled() || dry_run() {
         return Ok(());
     }
 
-    println!("{} Installing {} cargo packages...", "[INFO]".blue(), packages.len());
-    for pkg in packages {
-        run_command(&["cargo", "install", pkg], &format!("Installing cargo package {}", pkg))?;
-    }
-
+    let state_file = get_container_state_file_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize container state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write container state file")?;
     Ok(())
 }
 
-fn update_cargo_packages_file(packages: &[String]) -> Result<()> {
-    let package_list = PackageList {
-        packages: packages.to_vec(),
-    };
-
-    let content = format!("# Rust binaries to install via cargo\n# List cargo-installed binaries here\n{}",
-        toml::to_string_pretty(&package_list)
-        .context("Failed to serialize cargo package list to TOML")?);
-
-    fs::write("config/cargo-packages.toml", content)
-        .context("Failed to write cargo-packages.toml file")?;
 
-    println!("{} Updated config/cargo-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
-    Ok(())
+/// --label/--annotation flags for podman create/run, after the managed-by label.
+fn container_metadata_flags(container: &Container) -> Vec<String> {
+    let mut flags = Vec::new();
+    for (key, value) in sorted_entries(&container.labels) {
+        if key == "managed-by" {
+            continue;
+        }
+        flags.extend(["--label".to_string(), format!("{}={}", key, value)]);
+    }
+    for (key, value) in sorted_entries(&container.annotations) {
+        flags.extend(["--annotation".to_string(), format!("{}={}", key, value)]);
+    }
+    flags
 }
 
-fn sync_cargo_packages(yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
-    println!("{} Synchronizing cargo packages with installed binaries...", "[INFO]".blue());
-
-    // Get currently installed cargo packages
-    let installed_packages = get_installed_cargo_packages()?;
-
-    // Load packages from config file
-    let mut config_packages = load_package_list("config/cargo-packages.toml")?;
-
-    // Find packages to install (in config but not installed)
-    let mut packages_to_install = Vec::new();
-    for pkg in &config_packages {
-        if !installed_packages.contains(pkg) {
-            packages_to_install.push(pkg.clone());
+fn validate_containers(containers: &[Container]) -> Result<()> {
+    for container in containers {
+        // `__` separates container, volume and timestamp in volume backup names
+        if container.name.contains("__") {
+            return Err(FedoraForgeError::validation(format!("Container name '{}' must not contain '__'", container.name)));
         }
-    }
 
-    // Find packages to potentially remove (installed but not in config)
-    let mut packages_to_keep = Vec::new();
-    let mut packages_to_remove = Vec::new();
+        if container.raw_flags.as_deref().is_some_and(|flags| split_shell_words(flags).is_none()) {
+            return Err(FedoraForgeError::validation(format!("Container {}: unterminated quote in raw_flags", container.name)));
+        }
 
-    for pkg in &installed_packages {
-        if !config_packages.contains(pkg) {
-            println!("\n{} Cargo package '{}' is installed but not in cargo-packages.toml", "[INFO]".yellow(), pkg);
-            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes, no, verbose)? {
-                packages_to_keep.push(pkg.clone());
-                config_packages.push(pkg.clone());
-            } else {
-                packages_to_remove.push(pkg.clone());
+        if container.start_after_creation.is_some() {
+            warn(format!("Container '{}' uses deprecated 'start_after_creation', use 'immediate_start' instead", container.name))?;
+            if container.immediate_start.is_some() {
+                println!("  Both are set; 'immediate_start' takes precedence.");
             }
         }
+
+        if container.immediate_start() && container.autostart.unwrap_or(false) {
+            return Err(FedoraForgeError::validation(format!(
+                "Container '{}' sets both immediate_start and autostart. Use autostart for systemd-managed boot start or immediate_start to start now without systemd, not both",
+                container.name
+            )));
+        }
     }
+    Ok(())
+}
 
-    // Install missing packages
-    if !packages_to_install.is_empty() {
-        println!("{} Installing {} cargo packages from config...", "[INFO]".blue(), packages_to_install.len());
-        install_cargo_packages(&packages_to_install)?;
-    }
-
-    // Remove unwanted packages
-    if !packages_to_remove.is_empty() {
-        println!("{} Removing {} unwanted cargo packages...", "[INFO]".blue(), packages_to_remove.len());
-        for pkg in &packages_to_remove {
-            run_command(&["cargo", "uninstall", pkg], &format!("Removing cargo package {}", pkg))?;
-        }
-    }
-
-    // Update config file if there were changes
-    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
-        config_packages.sort();
-        config_packages.dedup();
-        update_cargo_packages_file(&config_packages)?;
+fn load_executed_commands_state() -> Result<ExecutedCommandsState> {
+    if state_disabled() {
+        return Ok(ExecutedCommandsState::default());
     }
 
-    println!("{} Cargo synchronization completed", "[SUCCESS]".green());
-    println!("  - Installed: {} packages", packages_to_install.len());
-    println!("  - Kept: {} packages", packages_to_keep.len());
-    println!("  - Removed: {} packages", packages_to_remove.len());
-
-    Ok(config_packages)
-}
+    let state_file = get_state_file_path()?;
 
-fn detect_distro(os_release: &str) -> Result<Distro> {
-    if os_release.contains("Fedora") {
-        Ok(Distro::Fedora)
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read executed commands state file")?;
+        let state: ExecutedCommandsState = serde_json::from_str(&content)
+            .context("Failed to parse executed commands state file")?;
+        Ok(state)
     } else {
-        anyhow::bail!("Unsupported distribution. Only Fedora is supported.");
+        Ok(ExecutedCommandsState::default())
     }
 }
 
-fn update_system_packages(_distro: &Distro, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Running: sudo dnf update -y", "[DEBUG]".cyan());
+fn save_executed_commands_state(state: &ExecutedCommandsState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
     }
-    run_command(&["sudo", "dnf", "update", "-y"], "Updating system packages")?;
+
+    let state_file = get_state_file_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize executed commands state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write executed commands state file")?;
     Ok(())
 }
 
-fn install_system_packages(_distro: &Distro, packages: &[String], verbose: bool) -> Result<()> {
-    if packages.is_empty() {
-        return Ok(());
-    }
+fn get_dotfiles_state_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("dotfiles_state.json"))
+}
 
-    if verbose {
-        println!("{} Installing {} system packages: {}", "[DEBUG]".cyan(), packages.len(), packages.join(", "));
+fn load_dotfiles_state() -> Result<DotfilesState> {
+    if state_disabled() {
+        return Ok(DotfilesState::default());
     }
 
-    let mut cmd: Vec<&str> = vec!["sudo", "dnf", "install", "-y", "--skip-unavailable"];
-    for pkg in packages {
-        cmd.push(pkg);
+    let state_file = get_dotfiles_state_path()?;
+
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read dotfiles state file")?;
+        let state: DotfilesState = serde_json::from_str(&content)
+            .context("Failed to parse dotfiles state file")?;
+        Ok(state)
+    } else {
+        Ok(DotfilesState::default())
     }
-    run_command(&cmd, "Installing system packages")?;
-    Ok(())
 }
 
-fn enable_additional_repos(_distro: &Distro) -> Result<()> {
-    let output = std::process::Command::new("rpm")
-        .args(["-E", "%fedora"])
-        .output()
-        .map_err(|e| anyhow::anyhow!("Failed to get Fedora version: {}", e))?;
-
-    let fedora_version = String::from_utf8(output.stdout)
-        .map_err(|e| anyhow::anyhow!("Invalid UTF-8 format: {}", e))?
-        .trim()
-        .to_string();
-
-    let rpmfusion_url = format!("https://mirrors.rpmfusion.org/free/fedora/rpmfusion-free-release-{}.noarch.rpm", fedora_version);
+fn save_dotfiles_state(state: &DotfilesState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
 
-    run_command(&["sudo", "dnf", "install", "-y", &rpmfusion_url], "Enabling RPM Fusion")?;
+    let state_file = get_dotfiles_state_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize dotfiles state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write dotfiles state file")?;
     Ok(())
 }
 
-fn setup_amd_gpu(_distro: &Distro) -> Result<()> {
-    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "rocm-opencl", "rocm-clinfo", "mesa-dri-drivers"], "Installing ROCm and AMD drivers")?;
-
-    // Common GPU setup
-    run_command(&["sudo", "usermod", "-aG", "render", &env::var("USER")?], "Adding user to render group")?;
-    let udev_content = r#"KERNEL=="kfd", GROUP="render", MODE="0666"
-SUBSYSTEM=="drm", GROUP="render", MODE="0666""#;
-    let tee_cmd = format!("echo '{}' | sudo tee /etc/udev/rules.d/70-kfd.rules > /dev/null", udev_content);
-    run_command(&["sh", "-c", &tee_cmd], "Configuring GPU device permissions")?;
-    run_command(&["sudo", "udevadm", "control", "--reload-rules"], "Reloading udev rules")?;
-    run_command(&["sudo", "udevadm", "trigger"], "Triggering udev")?;
-    println!("{}", "Reboot recommended for AMD GPU.".yellow());
-
-    Ok(())
+fn load_package_list(file_path: &str) -> Result<Vec<String>> {
+    Ok(load_package_entries(file_path)?.iter().map(|p| p.name().to_string()).collect())
 }
 
-fn setup_flatpak(_distro: &Distro, flatpak_config: Option<&FlatpakConfig>, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Installing Flatpak and setting up remotes", "[DEBUG]".cyan());
-    }
+fn load_package_entries(file_path: &str) -> Result<Vec<PackageEntry>> {
+    if !std::path::Path::new(file_path).exists() {
+        println!("{} Package file {} not found, creating from current system state...", "[INFO]".blue(), file_path);
 
-    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "flatpak"], "Installing Flatpak")?;
+        // Discover packages based on file type
+        let discovered = if file_path.contains("system-packages") {
+            get_user_installed_packages(command_runner())?
+        } else if file_path.contains("flatpak") {
+            get_installed_flatpaks()?
+        } else if file_path.contains("pip") {
+            get_installed_pip_packages()?
+        } else if file_path.contains("npm") {
+            get_installed_npm_packages()?
+        } else if file_path.contains("cargo") {
+            get_installed_cargo_packages()?
+        } else {
+            Vec::new()
+        };
 
-    // Add default Flathub if no custom config is provided
-    if flatpak_config.is_none() {
-        run_command(&["flatpak", "remote-add", "--if-not-exists", "flathub", "https://flathub.org/repo/flathub.flatpakrepo"], "Adding Flathub")?;
-        return Ok(());
-    }
+        // Create the package file
+        if !discovered.is_empty() {
+            let content = format!(
+                "# Package list\npackages = [\n{}\n]\n",
+                discovered.iter()
+                    .map(|p| format!("    \"{}\",", p))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            write_file(file_path, content)?;
+            println!("{} Created {} with {} packages", "[SUCCESS]".green(), file_path, discovered.len());
+        } else {
+            println!("{} No packages found, creating empty config", "[INFO]".blue());
+            write_file(file_path, "# Package list\npackages = []\n")?;
+        }
 
-    // Add configured remotes
-    if let Some(config) = flatpak_config {
-        if let Some(remotes) = &config.remotes {
-            for remote in remotes {
-                if verbose {
-                    println!("{} Adding Flatpak remote: {} -> {}", "[DEBUG]".cyan(), remote.name, remote.url);
-                }
-                run_command(&["flatpak", "remote-add", "--if-not-exists", &remote.name, &remote.url],
-                          &format!("Adding Flatpak remote {}", remote.name))?;
-            }
+        // A dry run didn't write the file, so carry on with what was discovered
+        if dry_run() {
+            return Ok(discovered.into_iter().map(PackageEntry::Name).collect());
         }
     }
 
-    Ok(())
+    let content = fs::read_to_string(file_path)
+        .context(format!("Failed to read package file: {}", file_path))?;
+    let package_list: PackageList = toml::from_str(&content)
+        .context("Failed to parse package TOML file")?;
+    Ok(package_list.packages)
 }
 
+fn get_user_installed_packages(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    println!("{} Getting list of user-installed packages...", "[INFO]".blue());
 
-fn install_flatpak_packages(packages: &[String]) -> Result<()> {
-    if packages.is_empty() {
-        return Ok(());
-    }
-
-    println!("{} Installing Flatpak applications...", "[INFO]".blue());
+    let output = spawn_output_via(runner, &["dnf", "repoquery", "--leaves", "--userinstalled", "--qf", "%{name}\\n"])
+        .context("Failed to run dnf repoquery command")?;
 
-    for package in packages {
-        let (remote, app_id) = parse_flatpak_package(package);
-        println!("{} Installing Flatpak package: {} from {}", "[INFO]".blue(), app_id, remote);
-        run_command(&["flatpak", "install", "-y", remote, app_id], &format!("Installing {} from {}", app_id, remote))?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["dnf", "repoquery", "--leaves", "--userinstalled"], &output).into());
     }
 
-    println!("{} All Flatpak packages installed successfully!", "[SUCCESS]".green());
-    Ok(())
-}
+    let packages = parse_name_lines(&String::from_utf8_lossy(&output.stdout));
 
-fn parse_flatpak_package(package: &str) -> (&str, &str) {
-    if let Some(colon_pos) = package.find(':') {
-        let (remote, app_id) = package.split_at(colon_pos);
-        (remote, &app_id[1..]) // Skip the colon
-    } else {
-        ("flathub", package) // Default to flathub
-    }
+    println!("{} Found {} user-installed packages", "[INFO]".blue(), packages.len());
+    Ok(packages)
 }
 
-fn setup_wireguard_vpn(vpn: &VpnConfig) -> Result<()> {
-    // Install WireGuard tools
-    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "wireguard-tools"], "Installing WireGuard tools")?;
-
-    // Try to install NetworkManager WireGuard plugin gracefully
-    install_wireguard_packages_graceful()?;
-
-    // Derive interface name from conf_path (e.g., "wg0.conf" -> "wg0")
-    use std::path::Path;
-    let conf_path = Path::new(&vpn.conf_path);
-    let interface_name = conf_path.file_stem()
-        .context("Invalid conf_path: missing file stem")?
-        .to_str()
-        .context("Invalid conf_path: non-UTF8 stem")?
-        .to_string();
-
-    // Ensure system-connections dir exists
-    run_command(&["sudo", "mkdir", "-p", "/etc/NetworkManager/system-connections"], "Creating NetworkManager system-connections directory")?;
-
-    // Remove existing connection if present (idempotent)
-    let _ = run_command(&["nmcli", "connection", "delete", &interface_name], "Removing existing WireGuard connection if present");
+fn get_installed_flatpaks() -> Result<Vec<String>> {
+    println!("{} Getting list of installed Flatpak applications...", "[INFO]".blue());
 
-    // Import config into NetworkManager
-    run_command(&["nmcli", "connection", "import", "type", "wireguard", "file", &vpn.conf_path], &format!("Importing WireGuard config for {}", interface_name))?;
+    let output = spawn_output(&["flatpak", "list", "--app", "--columns=application"])?;
 
-    // Set autoconnect and priority
-    run_command(&["nmcli", "connection", "modify", &interface_name, "connection.autoconnect", "yes"], &format!("Enabling autoconnect for {}", interface_name))?;
-    run_command(&["nmcli", "connection", "modify", &interface_name, "connection.autoconnect-priority", "10"], &format!("Setting autoconnect priority for {}", interface_name))?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["flatpak", "list", "--app"], &output).into());
+    }
 
-    // Reload NetworkManager and activate
-    run_command(&["sudo", "systemctl", "reload", "NetworkManager"], "Reloading NetworkManager to apply connection")?;
-    run_command(&["nmcli", "connection", "up", &interface_name], &format!("Activating WireGuard connection {}", interface_name))?;
+    let apps = parse_flatpak_list(&String::from_utf8_lossy(&output.stdout));
 
-    Ok(())
+    println!("{} Found {} installed Flatpak applications", "[INFO]".blue(), apps.len());
+    Ok(apps)
 }
 
-fn setup_openvpn_vpn(vpn: &VpnConfig) -> Result<()> {
-    // Install OpenVPN and NetworkManager plugin
-    run_command(&["sudo", "dnf", "install", "-y", "openvpn", "NetworkManager-openvpn", "NetworkManager-openvpn-gnome"], "Installing OpenVPN and NetworkManager plugin")?;
-
-    // Derive connection name from conf_path
-    use std::path::Path;
-    let conf_path = Path::new(&vpn.conf_path);
-    let connection_name = conf_path.file_stem()
-        .context("Invalid conf_path: missing file stem")?
-        .to_str()
-        .context("Invalid conf_path: non-UTF8 stem")?
-        .to_string();
-
-    // Remove existing connection if present
-    let _ = run_command(&["nmcli", "connection", "delete", &connection_name], "Removing existing OpenVPN connection if present");
-
-    // Import OpenVPN config
-    run_command(&["nmcli", "connection", "import", "type", "openvpn", "file", &vpn.conf_path], &format!("Importing OpenVPN config for {}", connection_name))?;
+/// Writes the package names back, keeping the version of every pinned package.
+fn update_system_packages_file(packages: &[String], pins: &HashMap<String, String>) -> Result<()> {
+    let package_list = PackageList {
+        packages: packages.iter()
+            .map(|name| match pins.get(name) {
+                Some(version) => PackageEntry::Pinned { name: name.clone(), version: version.clone() },
+                None => PackageEntry::Name(name.clone()),
+            })
+            .collect(),
+    };
 
-    // Set autoconnect
-    run_command(&["nmcli", "connection", "modify", &connection_name, "connection.autoconnect", "yes"], &format!("Enabling autoconnect for {}", connection_name))?;
-    run_command(&["nmcli", "connection", "modify", &connection_name, "connection.autoconnect-priority", "10"], &format!("Setting autoconnect priority for {}", connection_name))?;
+    let content = format!("# System packages to install via dnf\n{}",
+        toml::to_string_pretty(&package_list)
+        .context("Failed to serialize package list to TOML")?);
 
-    // Activate the connection
-    run_command(&["nmcli", "connection", "up", &connection_name], &format!("Activating OpenVPN connection {}", connection_name))?;
+    preview_file_change(Path::new("config/system-packages.toml"), content.as_bytes());
+    write_file("config/system-packages.toml", content)
+        .context("Failed to write system-packages.toml file")?;
 
+    println!("{} Updated config/system-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
     Ok(())
 }
 
-fn install_wireguard_packages_graceful() -> Result<()> {
-    // Try to enable Copr repo, but don't fail if it's not available for current Fedora version
-    let copr_result = run_command(&["sudo", "dnf", "copr", "enable", "-y", "timn/NetworkManager-wireguard"], "Enabling Copr repo for NetworkManager WireGuard plugin");
-
-    if copr_result.is_err() {
-        println!("{} NetworkManager WireGuard plugin Copr repo not available for this Fedora version, using basic WireGuard tools only", "[WARNING]".yellow());
-        return Ok(());
-    }
+fn update_flatpak_packages_file(packages: &[String]) -> Result<()> {
+    let package_list = PackageList::from_names(packages);
 
-    // Try to install the plugin, but don't fail if it's not available
-    let plugin_result = run_command(&["sudo", "dnf", "install", "-y", "NetworkManager-wireguard-gtk"], "Installing NetworkManager WireGuard plugin");
+    let content = format!("# Flatpak applications to install from Flathub\n{}",
+        toml::to_string_pretty(&package_list)
+        .context("Failed to serialize flatpak list to TOML")?);
 
-    if plugin_result.is_err() {
-        println!("{} NetworkManager WireGuard plugin not available, using basic WireGuard tools only", "[WARNING]".yellow());
-    }
+    preview_file_change(Path::new("config/flatpak-packages.toml"), content.as_bytes());
+    write_file("config/flatpak-packages.toml", content)
+        .context("Failed to write flatpak-packages.toml file")?;
 
+    println!("{} Updated config/flatpak-packages.toml with {} applications", "[SUCCESS]".green(), packages.len());
     Ok(())
 }
 
-fn cleanup_winapps(yes: bool, no: bool, verbose: bool) -> Result<()> {
-    let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-    let winapps_config_dir = home_dir.join(".config").join("winapps");
-    let winapps_repo_dir = home_dir.join(".local").join("share").join("winapps");
-
-    // Check if WinApps is installed
-    let config_exists = winapps_config_dir.exists();
-    let repo_exists = winapps_repo_dir.exists();
+/// `entries` is the contents of system-packages.toml; pinned versions only matter when installing.
+#[allow(clippy::too_many_arguments)]
+fn sync_system_packages(runner: &dyn CommandRunner, entries: &[PackageEntry], extra_packages: &[String], yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    println!("{} Synchronizing system packages with installed packages...", "[INFO]".blue());
 
-    if !config_exists && !repo_exists {
-        if verbose {
-            println!("{} WinApps not installed, nothing to clean up", "[DEBUG]".cyan());
-        }
-        return Ok(());
-    }
+    // Get currently installed user packages
+    let installed_packages = get_user_installed_packages(runner)?;
+    debug!(verbose, "Found {} installed packages", installed_packages.len());
 
-    println!("{} WinApps installation detected", "[INFO]".blue());
+    let pins: HashMap<String, String> = entries.iter()
+        .filter_map(|entry| match entry {
+            PackageEntry::Pinned { name, version } => Some((name.clone(), version.clone())),
+            PackageEntry::Name(_) => None,
+        })
+        .collect();
+    let mut config_packages: Vec<String> = entries.iter().map(|p| p.name().to_string()).collect();
+    debug!(verbose, "Loaded {} packages from config", config_packages.len());
 
-    if verbose {
-        if config_exists {
-            println!("{} Found config directory: {:?}", "[DEBUG]".cyan(), winapps_config_dir);
+    // Find packages to install (in config or active profile but not installed)
+    let mut packages_to_install = Vec::new();
+    let mut local_rpm_names = Vec::new();
+    for pkg in config_packages.iter().chain(extra_packages) {
+        if packages_to_install.contains(pkg) {
+            continue;
         }
-        if repo_exists {
-            println!("{} Found repository: {:?}", "[DEBUG]".cyan(), winapps_repo_dir);
+        if is_local_rpm_entry(pkg) {
+            // Local RPMs are compared by the package names inside them
+            match local_rpm_package_names(runner, pkg) {
+                Ok(names) => {
+                    debug!(verbose, "{} provides: {}", pkg, names.join(", "));
+                    if names.iter().any(|name| !is_rpm_installed(runner, name)) {
+                        packages_to_install.push(pkg.clone());
+                    }
+                    local_rpm_names.extend(names);
+                }
+                Err(e) => warn(format!("Skipping local RPM entry {}: {}", pkg, e))?,
+            }
+        } else if !installed_packages.contains(pkg) {
+            packages_to_install.push(pkg.clone());
+        }
+    }
+
+    // Find packages to potentially remove (installed but not in config)
+    let mut packages_to_keep = Vec::new();
+    let mut packages_to_remove = Vec::new();
+    let mut weak_dependencies = 0;
+    let weak_dependency_names = weak_dependency_set(runner);
+
+    for pkg in &installed_packages {
+        if !config_packages.contains(pkg) && !extra_packages.contains(pkg) && !local_rpm_names.contains(pkg) {
+            // Recommended by something installed; leave it to dnf rather than prompting
+            if weak_dependency_names.contains(pkg) {
+                debug!(verbose, "Ignoring '{}' (weak dependency of an installed package)", pkg);
+                weak_dependencies += 1;
+                continue;
+            }
+            println!("\n{} Package '{}' is installed but not in system-packages.toml", "[INFO]".yellow(), pkg);
+            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes || planned("system-packages", &[pkg]), no, verbose)? {
+                packages_to_keep.push(pkg.clone());
+                config_packages.push(pkg.clone());
+            } else if no_removals {
+                println!("{} --no-removals: leaving '{}' installed", "[INFO]".blue(), pkg);
+            } else {
+                packages_to_remove.push(pkg.clone());
+            }
+        }
+    }
+
+    // Install missing packages
+    if !packages_to_install.is_empty() {
+        println!("{} Installing {} packages from config...", "[INFO]".blue(), packages_to_install.len());
+        let install_specs: Vec<String> = packages_to_install.iter()
+            .map(|pkg| match pins.get(pkg) {
+                Some(version) => format!("{}-{}", pkg, version),
+                None => pkg.clone(),
+            })
+            .collect();
+        install_system_packages(runner, &Distro::Fedora, &install_specs, verbose)?;
+        for pkg in &resolve_declared_system_packages(runner, &packages_to_install) {
+            record_action(ActionCategory::Package, ActionKind::Install, pkg, None);
+        }
+    }
+
+    // Remove unwanted packages
+    if !packages_to_remove.is_empty() {
+        println!("{} Removing {} unwanted packages...", "[INFO]".blue(), packages_to_remove.len());
+        for pkg in &packages_to_remove {
+            run_command_via(runner, &["sudo", "dnf", "remove", "-y", pkg], &format!("Removing package {}", pkg))?;
+            record_action(ActionCategory::Package, ActionKind::Remove, pkg, None);
+        }
+    }
+
+    // Update config file if there were changes
+    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
+        config_packages.sort();
+        config_packages.dedup();
+        update_system_packages_file(&config_packages, &pins)?;
+    }
+
+    if weak_dependencies > 0 {
+        println!("{} Ignored {} weak dependencies not in system-packages.toml", "[INFO]".blue(), weak_dependencies);
+    }
+
+    println!("{} Package synchronization completed", "[SUCCESS]".green());
+    Ok(SubsystemResult {
+        created: packages_to_install.len(),
+        removed: packages_to_remove.len(),
+        skipped: packages_to_keep.len() + weak_dependencies,
+        ..Default::default()
+    })
+}
+
+fn sync_flatpak_packages(extra_flatpaks: &[String], yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    println!("{} Synchronizing Flatpak packages with installed applications...", "[INFO]".blue());
+
+    // Get currently installed Flatpak applications
+    let installed_flatpaks = get_installed_flatpaks()?;
+
+    // Load packages from config file
+    let mut config_flatpaks = load_package_list("config/flatpak-packages.toml")?;
+
+    // Find packages to install (in config or active profile but not installed)
+    let mut flatpaks_to_install = Vec::new();
+    for app in config_flatpaks.iter().chain(extra_flatpaks) {
+        if !installed_flatpaks.contains(app) && !flatpaks_to_install.contains(app) {
+            flatpaks_to_install.push(app.clone());
+        }
+    }
+
+    // Find packages to potentially remove (installed but not in config)
+    let mut flatpaks_to_keep = Vec::new();
+    let mut flatpaks_to_remove = Vec::new();
+
+    for app in &installed_flatpaks {
+        if !config_flatpaks.contains(app) && !extra_flatpaks.contains(app) {
+            println!("\n{} Flatpak application '{}' is installed but not in flatpak-packages.toml", "[INFO]".yellow(), app);
+            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", app), yes || planned("flatpak", &[app]), no, verbose)? {
+                flatpaks_to_keep.push(app.clone());
+                config_flatpaks.push(app.clone());
+            } else if no_removals {
+                println!("{} --no-removals: leaving '{}' installed", "[INFO]".blue(), app);
+            } else {
+                flatpaks_to_remove.push(app.clone());
+            }
+        }
+    }
+
+    // Install missing Flatpak applications
+    if !flatpaks_to_install.is_empty() {
+        println!("{} Installing {} Flatpak applications from config...", "[INFO]".blue(), flatpaks_to_install.len());
+        flatpaks_to_install = install_flatpak_packages(&flatpaks_to_install, yes, no, verbose)?;
+        for app in &flatpaks_to_install {
+            record_action(ActionCategory::Flatpak, ActionKind::Install, app, None);
+        }
+    }
+
+    // Remove unwanted Flatpak applications
+    if !flatpaks_to_remove.is_empty() {
+        println!("{} Removing {} unwanted Flatpak applications...", "[INFO]".blue(), flatpaks_to_remove.len());
+        for app in &flatpaks_to_remove {
+            run_command(&["flatpak", "uninstall", "-y", app], &format!("Removing Flatpak application {}", app))?;
+            record_action(ActionCategory::Flatpak, ActionKind::Remove, app, None);
+        }
+    }
+
+    // Update config file if there were changes
+    if !flatpaks_to_keep.is_empty() || !flatpaks_to_remove.is_empty() {
+        config_flatpaks.sort();
+        config_flatpaks.dedup();
+        update_flatpak_packages_file(&config_flatpaks)?;
+    }
+
+    println!("{} Flatpak synchronization completed", "[SUCCESS]".green());
+    Ok(SubsystemResult {
+        created: flatpaks_to_install.len(),
+        removed: flatpaks_to_remove.len(),
+        skipped: flatpaks_to_keep.len(),
+        ..Default::default()
+    })
+}
+
+// ========== Pip Package Management ==========
+
+fn get_installed_pip_packages() -> Result<Vec<String>> {
+    println!("{} Getting list of installed pip packages...", "[INFO]".blue());
+
+    let output = spawn_output(&["pip", "list", "--format=freeze", "--user"])?;
+
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["pip", "list", "--format=freeze", "--user"], &output).into());
+    }
+
+    let packages = parse_pip_freeze(&String::from_utf8_lossy(&output.stdout));
+
+    println!("{} Found {} installed pip packages", "[INFO]".blue(), packages.len());
+    Ok(packages)
+}
+
+fn install_pip_packages(packages: &[String]) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    println!("{} Installing {} pip packages...", "[INFO]".blue(), packages.len());
+    for pkg in packages {
+        run_command(&["pip", "install", "--user", pkg], &format!("Installing pip package {}", pkg))?;
+    }
+
+    Ok(())
+}
+
+fn update_pip_packages_file(packages: &[String]) -> Result<()> {
+    let package_list = PackageList::from_names(packages);
+
+    let content = format!("# Python packages to install via pip\n# List user-installed packages here\n{}",
+        toml::to_string_pretty(&package_list)
+        .context("Failed to serialize pip package list to TOML")?);
+
+    preview_file_change(Path::new("config/pip-packages.toml"), content.as_bytes());
+    write_file("config/pip-packages.toml", content)
+        .context("Failed to write pip-packages.toml file")?;
+
+    println!("{} Updated config/pip-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
+    Ok(())
+}
+
+fn sync_pip_packages(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    println!("{} Synchronizing pip packages with installed packages...", "[INFO]".blue());
+
+    // Get currently installed pip packages
+    let installed_packages = get_installed_pip_packages()?;
+
+    // Load packages from config file
+    let mut config_packages = load_package_list("config/pip-packages.toml")?;
+
+    // Find packages to install (in config but not installed)
+    let mut packages_to_install = Vec::new();
+    for pkg in &config_packages {
+        if !installed_packages.contains(pkg) {
+            packages_to_install.push(pkg.clone());
+        }
+    }
+
+    // Find packages to potentially remove (installed but not in config)
+    let mut packages_to_keep = Vec::new();
+    let mut packages_to_remove = Vec::new();
+
+    for pkg in &installed_packages {
+        if !config_packages.contains(pkg) {
+            println!("\n{} Pip package '{}' is installed but not in pip-packages.toml", "[INFO]".yellow(), pkg);
+            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes || planned("pip", &[pkg]), no, verbose)? {
+                packages_to_keep.push(pkg.clone());
+                config_packages.push(pkg.clone());
+            } else if no_removals {
+                println!("{} --no-removals: leaving '{}' installed", "[INFO]".blue(), pkg);
+            } else {
+                packages_to_remove.push(pkg.clone());
+            }
+        }
+    }
+
+    // Install missing packages
+    if !packages_to_install.is_empty() {
+        println!("{} Installing {} pip packages from config...", "[INFO]".blue(), packages_to_install.len());
+        install_pip_packages(&packages_to_install)?;
+        for pkg in &packages_to_install {
+            record_action(ActionCategory::Pip, ActionKind::Install, pkg, None);
+        }
+    }
+
+    // Remove unwanted packages
+    if !packages_to_remove.is_empty() {
+        println!("{} Removing {} unwanted pip packages...", "[INFO]".blue(), packages_to_remove.len());
+        for pkg in &packages_to_remove {
+            run_command(&["pip", "uninstall", "-y", pkg], &format!("Removing pip package {}", pkg))?;
+            record_action(ActionCategory::Pip, ActionKind::Remove, pkg, None);
+        }
+    }
+
+    // Update config file if there were changes
+    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
+        config_packages.sort();
+        config_packages.dedup();
+        update_pip_packages_file(&config_packages)?;
+    }
+
+    println!("{} Pip synchronization completed", "[SUCCESS]".green());
+    Ok(SubsystemResult {
+        created: packages_to_install.len(),
+        removed: packages_to_remove.len(),
+        skipped: packages_to_keep.len(),
+        ..Default::default()
+    })
+}
+
+// ========== NPM Package Management ==========
+
+fn get_installed_npm_packages() -> Result<Vec<String>> {
+    println!("{} Getting list of globally installed npm packages...", "[INFO]".blue());
+
+    let output = spawn_output(&["npm", "list", "-g", "--depth=0", "--json"])?;
+
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["npm", "list", "-g", "--depth=0", "--json"], &output).into());
+    }
+
+    let packages = parse_npm_list(&String::from_utf8_lossy(&output.stdout))
+        .context("Failed to parse npm list JSON output")?;
+
+    println!("{} Found {} globally installed npm packages", "[INFO]".blue(), packages.len());
+    Ok(packages)
+}
+
+fn install_npm_packages(packages: &[String]) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    println!("{} Installing {} npm packages globally...", "[INFO]".blue(), packages.len());
+    for pkg in packages {
+        run_command(&["npm", "install", "-g", pkg], &format!("Installing npm package {}", pkg))?;
+    }
+
+    Ok(())
+}
+
+fn update_npm_packages_file(packages: &[String]) -> Result<()> {
+    let package_list = PackageList::from_names(packages);
+
+    let content = format!("# Node.js global packages to install via npm\n# List globally installed packages here\n{}",
+        toml::to_string_pretty(&package_list)
+        .context("Failed to serialize npm package list to TOML")?);
+
+    preview_file_change(Path::new("config/npm-packages.toml"), content.as_bytes());
+    write_file("config/npm-packages.toml", content)
+        .context("Failed to write npm-packages.toml file")?;
+
+    println!("{} Updated config/npm-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
+    Ok(())
+}
+
+fn sync_npm_packages(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    println!("{} Synchronizing npm packages with installed packages...", "[INFO]".blue());
+
+    // Get currently installed npm packages
+    let installed_packages = get_installed_npm_packages()?;
+
+    // Load packages from config file
+    let mut config_packages = load_package_list("config/npm-packages.toml")?;
+
+    // Find packages to install (in config but not installed)
+    let mut packages_to_install = Vec::new();
+    for pkg in &config_packages {
+        if !installed_packages.contains(pkg) {
+            packages_to_install.push(pkg.clone());
+        }
+    }
+
+    // Find packages to potentially remove (installed but not in config)
+    let mut packages_to_keep = Vec::new();
+    let mut packages_to_remove = Vec::new();
+
+    for pkg in &installed_packages {
+        if !config_packages.contains(pkg) {
+            println!("\n{} npm package '{}' is installed but not in npm-packages.toml", "[INFO]".yellow(), pkg);
+            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes || planned("npm", &[pkg]), no, verbose)? {
+                packages_to_keep.push(pkg.clone());
+                config_packages.push(pkg.clone());
+            } else if no_removals {
+                println!("{} --no-removals: leaving '{}' installed", "[INFO]".blue(), pkg);
+            } else {
+                packages_to_remove.push(pkg.clone());
+            }
+        }
+    }
+
+    // Install missing packages
+    if !packages_to_install.is_empty() {
+        println!("{} Installing {} npm packages from config...", "[INFO]".blue(), packages_to_install.len());
+        install_npm_packages(&packages_to_install)?;
+        for pkg in &packages_to_install {
+            record_action(ActionCategory::Npm, ActionKind::Install, pkg, None);
+        }
+    }
+
+    // Remove unwanted packages
+    if !packages_to_remove.is_empty() {
+        println!("{} Removing {} unwanted npm packages...", "[INFO]".blue(), packages_to_remove.len());
+        for pkg in &packages_to_remove {
+            run_command(&["npm", "uninstall", "-g", pkg], &format!("Removing npm package {}", pkg))?;
+            record_action(ActionCategory::Npm, ActionKind::Remove, pkg, None);
+        }
+    }
+
+    // Update config file if there were changes
+    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
+        config_packages.sort();
+        config_packages.dedup();
+        update_npm_packages_file(&config_packages)?;
+    }
+
+    println!("{} npm synchronization completed", "[SUCCESS]".green());
+    Ok(SubsystemResult {
+        created: packages_to_install.len(),
+        removed: packages_to_remove.len(),
+        skipped: packages_to_keep.len(),
+        ..Default::default()
+    })
+}
+
+// ========== Cargo Package Management ==========
+
+fn get_installed_cargo_packages() -> Result<Vec<String>> {
+    println!("{} Getting list of cargo-installed binaries...", "[INFO]".blue());
+
+    let output = spawn_output(&["cargo", "install", "--list"])?;
+
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["cargo", "install", "--list"], &output).into());
+    }
+
+    let packages = parse_cargo_install_list(&String::from_utf8_lossy(&output.stdout));
+
+    println!("{} Found {} cargo-installed binaries", "[INFO]".blue(), packages.len());
+    Ok(packages)
+}
+
+fn install_cargo_packages(packages: &[String]) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    println!("{} Installing {} cargo packages...", "[INFO]".blue(), packages.len());
+    // Concurrent installs split the CPUs between them instead of each building with -j <cpus>
+    let workers = max_parallel().min(packages.len());
+    let jobs = (std::thread::available_parallelism().map_or(1, |n| n.get()) / workers).max(1).to_string();
+    run_bounded(packages, |pkg| {
+        let mut cmd = vec!["cargo", "install"];
+        if workers > 1 {
+            cmd.extend(["-j", jobs.as_str()]);
+        }
+        cmd.push(pkg);
+        run_command(&cmd, &format!("Installing cargo package {}", pkg))
+    })?;
+
+    Ok(())
+}
+
+fn update_cargo_packages_file(packages: &[String]) -> Result<()> {
+    let package_list = PackageList::from_names(packages);
+
+    let content = format!("# Rust binaries to install via cargo\n# List cargo-installed binaries here\n{}",
+        toml::to_string_pretty(&package_list)
+        .context("Failed to serialize cargo package list to TOML")?);
+
+    preview_file_change(Path::new("config/cargo-packages.toml"), content.as_bytes());
+    write_file("config/cargo-packages.toml", content)
+        .context("Failed to write cargo-packages.toml file")?;
+
+    println!("{} Updated config/cargo-packages.toml with {} packages", "[SUCCESS]".green(), packages.len());
+    Ok(())
+}
+
+fn sync_cargo_packages(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    println!("{} Synchronizing cargo packages with installed binaries...", "[INFO]".blue());
+
+    // Get currently installed cargo packages
+    let installed_packages = get_installed_cargo_packages()?;
+
+    // Load packages from config file
+    let mut config_packages = load_package_list("config/cargo-packages.toml")?;
+
+    // Find packages to install (in config but not installed)
+    let mut packages_to_install = Vec::new();
+    for pkg in &config_packages {
+        if !installed_packages.contains(pkg) {
+            packages_to_install.push(pkg.clone());
+        }
+    }
+
+    // Find packages to potentially remove (installed but not in config)
+    let mut packages_to_keep = Vec::new();
+    let mut packages_to_remove = Vec::new();
+
+    for pkg in &installed_packages {
+        if !config_packages.contains(pkg) {
+            println!("\n{} Cargo package '{}' is installed but not in cargo-packages.toml", "[INFO]".yellow(), pkg);
+            if ask_user_confirmation(&format!("Do you want to keep '{}' installed?", pkg), yes || planned("cargo", &[pkg]), no, verbose)? {
+                packages_to_keep.push(pkg.clone());
+                config_packages.push(pkg.clone());
+            } else if no_removals {
+                println!("{} --no-removals: leaving '{}' installed", "[INFO]".blue(), pkg);
+            } else {
+                packages_to_remove.push(pkg.clone());
+            }
+        }
+    }
+
+    // Install missing packages
+    if !packages_to_install.is_empty() {
+        println!("{} Installing {} cargo packages from config...", "[INFO]".blue(), packages_to_install.len());
+        install_cargo_packages(&packages_to_install)?;
+        for pkg in &packages_to_install {
+            record_action(ActionCategory::Cargo, ActionKind::Install, pkg, None);
+        }
+    }
+
+    // Remove unwanted packages
+    if !packages_to_remove.is_empty() {
+        println!("{} Removing {} unwanted cargo packages...", "[INFO]".blue(), packages_to_remove.len());
+        for pkg in &packages_to_remove {
+            run_command(&["cargo", "uninstall", pkg], &format!("Removing cargo package {}", pkg))?;
+            record_action(ActionCategory::Cargo, ActionKind::Remove, pkg, None);
+        }
+    }
+
+    // Update config file if there were changes
+    if !packages_to_keep.is_empty() || !packages_to_remove.is_empty() {
+        config_packages.sort();
+        config_packages.dedup();
+        update_cargo_packages_file(&config_packages)?;
+    }
+
+    println!("{} Cargo synchronization completed", "[SUCCESS]".green());
+    Ok(SubsystemResult {
+        created: packages_to_install.len(),
+        removed: packages_to_remove.len(),
+        skipped: packages_to_keep.len(),
+        ..Default::default()
+    })
+}
+
+fn detect_distro(os_release: &str) -> Result<Distro> {
+    if os_release.contains("Fedora") {
+        Ok(Distro::Fedora)
+    } else {
+        anyhow::bail!("Unsupported distribution. Only Fedora is supported.");
+    }
+}
+
+fn update_system_packages(_distro: &Distro, verbose: bool) -> Result<()> {
+    check_package_database(verbose)?;
+    let extra_args = package_manager_args();
+    let mut cmd: Vec<&str> = vec!["sudo", "dnf", "update", "-y"];
+    cmd.extend(extra_args.iter().map(String::as_str));
+    run_command_retry(&cmd, "Updating system packages", command_attempts(), RETRY_BASE_DELAY)?;
+    Ok(())
+}
+
+/// Refuses to update on top of a staged offline update or an interrupted transaction,
+/// either of which leaves the package database in a state dnf may act on unexpectedly.
+fn check_package_database(verbose: bool) -> Result<()> {
+    if fs::symlink_metadata("/system-update").is_ok() {
+        return Err(FedoraForgeError::validation(
+            "An offline update is staged (/system-update exists). Reboot to apply it or run 'sudo dnf offline-upgrade clean', then re-run FedoraForge"
+        ));
+    }
+
+    // A failed last transaction shows up as a non-success return code (dnf4) or status (dnf5)
+    let output = match run_command_output(&["dnf", "history", "info", "last"]) {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            debug!(verbose, "Could not read dnf history; skipping transaction check");
+            return Ok(());
+        }
+    };
+    let info = String::from_utf8_lossy(&output.stdout);
+    for line in info.lines() {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let (key, value) = (key.trim(), value.trim());
+        let failed = match key {
+            "Return-Code" => !value.starts_with("Success"),
+            "Status" => !value.eq_ignore_ascii_case("ok"),
+            _ => continue,
+        };
+        if failed {
+            return Err(FedoraForgeError::validation(format!(
+                "The last dnf transaction did not complete ({}: {}). Inspect it with 'dnf history info last' and resolve it (e.g. 'sudo dnf history redo last' or 'sudo dnf distro-sync'), then re-run FedoraForge",
+                key, value
+            )));
+        }
+        debug!(verbose, "Last dnf transaction completed ({}: {})", key, value);
+        break;
+    }
+    Ok(())
+}
+
+fn install_system_packages(runner: &dyn CommandRunner, _distro: &Distro, packages: &[String], verbose: bool) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    debug!(verbose, "Installing {} system packages: {}", packages.len(), packages.join(", "));
+
+    // Local RPM entries become absolute file paths so dnf installs them and resolves their dependencies from repos
+    let mut targets: Vec<String> = Vec::new();
+    for pkg in packages {
+        if is_local_rpm_entry(pkg) {
+            for file in expand_local_rpm_entry(pkg)? {
+                targets.push(file.to_string_lossy().to_string());
+            }
+        } else {
+            targets.push(pkg.clone());
+        }
+    }
+
+    let extra_args = package_manager_args();
+    let mut cmd: Vec<&str> = vec!["sudo", "dnf", "install", "-y", "--skip-unavailable"];
+    if !install_weak_deps() {
+        cmd.push("--setopt=install_weak_deps=False");
+    }
+    cmd.extend(extra_args.iter().map(String::as_str));
+    for target in &targets {
+        cmd.push(target);
+    }
+    run_command_via(runner, &cmd, "Installing system packages")?;
+    Ok(())
+}
+
+/// Entries in system-packages.toml that point at .rpm files or directories of RPMs.
+fn is_local_rpm_entry(entry: &str) -> bool {
+    entry.ends_with(".rpm")
+        || entry.starts_with('/')
+        || entry.starts_with("./")
+        || entry.starts_with("../")
+        || entry.starts_with("~/")
+}
+
+fn expand_local_rpm_entry(entry: &str) -> Result<Vec<std::path::PathBuf>> {
+    let path = match entry.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir().context("Could not find home directory")?.join(rest),
+        None => std::path::PathBuf::from(entry),
+    };
+    let path = fs::canonicalize(&path)
+        .with_context(|| format!("Local RPM path {} does not exist", entry))?;
+
+    if path.is_dir() {
+        let mut files: Vec<std::path::PathBuf> = fs::read_dir(&path)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "rpm"))
+            .collect();
+        files.sort();
+        if files.is_empty() {
+            anyhow::bail!("No .rpm files found in {}", entry);
+        }
+        Ok(files)
+    } else {
+        Ok(vec![path])
+    }
+}
+
+fn local_rpm_package_names(runner: &dyn CommandRunner, entry: &str) -> Result<Vec<String>> {
+    let files = expand_local_rpm_entry(entry)?;
+    let mut cmd = vec!["rpm", "-qp", "--qf", "%{NAME}\\n"];
+    for file in &files {
+        cmd.push(file.to_str().context("Invalid RPM file path")?);
+    }
+    let output = spawn_output_via(runner, &cmd)?;
+
+    if !output.status.success() {
+        anyhow::bail!("rpm -qp failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+fn is_rpm_installed(runner: &dyn CommandRunner, name: &str) -> bool {
+    spawn_output_via(runner, &["rpm", "-q", "--quiet", name]).is_ok_and(|output| output.status.success())
+}
+
+/// Package names for declared system packages, with local RPM entries replaced by the names they provide.
+fn resolve_declared_system_packages(runner: &dyn CommandRunner, entries: &[String]) -> Vec<String> {
+    let mut names = Vec::new();
+    for entry in entries {
+        if is_local_rpm_entry(entry) {
+            names.extend(local_rpm_package_names(runner, entry).unwrap_or_default());
+        } else {
+            names.push(entry.clone());
+        }
+    }
+    names
+}
+
+/// Timezone, locale and console keymap, each changed only when it differs from what systemd reports.
+fn setup_localization(system: &SystemConfig, verbose: bool) -> Result<()> {
+    if let Some(timezone) = &system.timezone {
+        if !Path::new("/usr/share/zoneinfo").join(timezone).is_file() {
+            return Err(FedoraForgeError::validation(format!("Unknown timezone '{}' (see `timedatectl list-timezones`)", timezone)));
+        }
+        let output = spawn_output(&["timedatectl", "show", "--property=Timezone", "--value"])?;
+        let current = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if current == *timezone {
+            debug!(verbose, "Timezone already {}", timezone);
+        } else {
+            run_command(&["sudo", "timedatectl", "set-timezone", timezone], &format!("Setting timezone to {} (was {})", timezone, current))?;
+        }
+    }
+
+    if system.locale.is_none() && system.keymap.is_none() {
+        return Ok(());
+    }
+    let output = spawn_output(&["localectl", "status"])?;
+    let (current_lang, current_keymap) = parse_localectl_status(&String::from_utf8_lossy(&output.stdout));
+
+    if let Some(locale) = &system.locale {
+        let locale = locale.strip_prefix("LANG=").unwrap_or(locale);
+        if current_lang.as_deref() == Some(locale) {
+            debug!(verbose, "Locale already {}", locale);
+        } else {
+            run_command(&["sudo", "localectl", "set-locale", &format!("LANG={}", locale)], &format!("Setting locale to {}", locale))?;
+            println!("{}", "Log out and back in for the new locale to apply to your session.".yellow());
+        }
+    }
+
+    if let Some(keymap) = &system.keymap {
+        if current_keymap.as_deref() == Some(keymap.as_str()) {
+            debug!(verbose, "Keymap already {}", keymap);
+        } else {
+            run_command(&["sudo", "localectl", "set-keymap", keymap], &format!("Setting keymap to {}", keymap))?;
+        }
+    }
+    Ok(())
+}
+
+fn enable_additional_repos(_distro: &Distro, expected_sha256: Option<&str>) -> Result<()> {
+    let output = spawn_output(&["rpm", "-E", "%fedora"]).context("Failed to get Fedora version")?;
+
+    let fedora_version = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow::anyhow!("Invalid UTF-8 format: {}", e))?
+        .trim()
+        .to_string();
+
+    let rpmfusion_url = format!("https://mirrors.rpmfusion.org/free/fedora/rpmfusion-free-release-{}.noarch.rpm", fedora_version);
+
+    install_rpm_from_url(&rpmfusion_url, expected_sha256, "Enabling RPM Fusion")
+}
+
+/// Installs an RPM from a URL. With an expected SHA-256 the RPM is downloaded and verified
+/// first, and only the verified local file is handed to dnf.
+fn install_rpm_from_url(url: &str, expected_sha256: Option<&str>, desc: &str) -> Result<()> {
+    let Some(expected) = expected_sha256 else {
+        return run_command_retry(&["sudo", "dnf", "install", "-y", url], desc, command_attempts(), RETRY_BASE_DELAY);
+    };
+
+    let file_name = url.rsplit('/').next().filter(|n| n.ends_with(".rpm")).unwrap_or("download.rpm");
+    let download = cache_dir()?.join(format!("{}-{}", std::process::id(), file_name));
+    let download_str = download.to_str().context("Invalid temporary file path")?;
+
+    run_command_retry(&["curl", "-fsSL", "-o", download_str, url], &format!("Downloading {}", url), command_attempts(), RETRY_BASE_DELAY)?;
+    let actual = generate_file_hash(&download);
+    let result = match actual {
+        Ok(actual) if actual.eq_ignore_ascii_case(expected.trim()) => {
+            println!("{} SHA-256 verified for {}", "[SUCCESS]".green(), file_name);
+            run_command(&["sudo", "dnf", "install", "-y", download_str], desc)
+        }
+        Ok(actual) => Err(FedoraForgeError::validation(format!(
+            "SHA-256 mismatch for {}: expected {}, got {}. Refusing to install",
+            url, expected.trim(), actual
+        ))),
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_file(&download);
+    result
+}
+
+const KFD_UDEV_RULES_PATH: &str = "/etc/udev/rules.d/70-kfd.rules";
+// Access comes from the render group membership (see implied_group_memberships), not from other users
+const DEFAULT_GPU_DEVICE_MODE: &str = "0660";
+
+fn kfd_udev_rules(mode: &str) -> String {
+    format!("KERNEL==\"kfd\", GROUP=\"render\", MODE=\"{mode}\"\nSUBSYSTEM==\"drm\", GROUP=\"render\", MODE=\"{mode}\"\n")
+}
+
+fn setup_amd_gpu(_distro: &Distro, device_mode: Option<&str>, verbose: bool) -> Result<()> {
+    let device_mode = device_mode.unwrap_or(DEFAULT_GPU_DEVICE_MODE);
+    if device_mode.len() != 4 || !device_mode.starts_with('0') || !device_mode.chars().all(|c| ('0'..='7').contains(&c)) {
+        return Err(FedoraForgeError::validation(format!("gpu_device_mode '{}' must be a 4-digit octal mode such as \"0660\"", device_mode)));
+    }
+    let rules = kfd_udev_rules(device_mode);
+
+    let gpu_packages = ["rocm-opencl", "rocm-clinfo", "mesa-dri-drivers"];
+    if gpu_packages.iter().all(|pkg| is_rpm_installed(command_runner(), pkg)) {
+        debug!(verbose, "ROCm and AMD drivers already installed");
+    } else {
+        run_reboot_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "rocm-opencl", "rocm-clinfo", "mesa-dri-drivers"], "Installing ROCm and AMD drivers")?;
+    }
+
+    // Render group membership is handled by the users/groups subsystem (see implied_group_memberships)
+    let current_rules = fs::read_to_string(KFD_UDEV_RULES_PATH).unwrap_or_default();
+    if current_rules.trim() == rules.trim() {
+        debug!(verbose, "{} already up to date", KFD_UDEV_RULES_PATH);
+        return Ok(());
+    }
+
+    show_file_diff(KFD_UDEV_RULES_PATH, current_rules.as_bytes(), rules.as_bytes());
+    install_reboot_file(KFD_UDEV_RULES_PATH, &rules, "0644", "Configuring GPU device permissions")?;
+    run_reboot_command(&["sudo", "udevadm", "control", "--reload-rules"], "Reloading udev rules")?;
+    run_reboot_command(&["sudo", "udevadm", "trigger"], "Triggering udev")?;
+    if device_mode.ends_with(|c| c != '0') {
+        println!("{}", format!("Reboot recommended for AMD GPU. gpu_device_mode {} gives every local user, not just the render group, \
+                               direct access to the GPU (compute jobs, reading other users' GPU memory).", device_mode).yellow());
+    } else {
+        println!("{}", "Reboot recommended for AMD GPU.".yellow());
+    }
+
+    Ok(())
+}
+
+fn setup_nvidia_gpu(system: &SystemConfig, verbose: bool) -> Result<()> {
+    if !system.enable_rpm_fusion {
+        return Err(FedoraForgeError::validation("enable_nvidia_gpu needs enable_rpm_fusion = true (the driver comes from RPM Fusion nonfree)"));
+    }
+    if !is_rpm_installed(command_runner(), "rpmfusion-nonfree-release") {
+        let url = format!("https://mirrors.rpmfusion.org/nonfree/fedora/rpmfusion-nonfree-release-{}.noarch.rpm", current_fedora_version()?);
+        install_rpm_from_url(&url, system.rpm_fusion_nonfree_sha256.as_deref(), "Enabling RPM Fusion nonfree")?;
+    }
+
+    // The module is built locally by akmods, and an unsigned one won't load under Secure Boot
+    let secure_boot = spawn_output(&["mokutil", "--sb-state"])
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("SecureBoot enabled"));
+    if secure_boot {
+        warn("Secure Boot is enabled: the NVIDIA module must be signed with an enrolled key (see `sudo kmodgenca` and `mokutil --import`) or it will not load")?;
+    }
+
+    let gpu_packages = ["akmod-nvidia", "xorg-x11-drv-nvidia-cuda"];
+    let just_installed = if gpu_packages.iter().all(|pkg| is_rpm_installed(command_runner(), pkg)) {
+        debug!(verbose, "NVIDIA driver packages already installed");
+        false
+    } else {
+        run_reboot_command(&["sudo", "dnf", "install", "-y", "akmod-nvidia", "xorg-x11-drv-nvidia-cuda"], "Installing NVIDIA drivers")?;
+        true
+    };
+
+    // modinfo looks in the running kernel's module tree
+    let module_built = || spawn_output(&["modinfo", "-F", "version", "nvidia"]).is_ok_and(|output| output.status.success());
+
+    // akmods builds in the background after install; build now so the next boot has the module.
+    // The build takes minutes, so skip it when the running kernel already has one.
+    if !just_installed && module_built() {
+        debug!(verbose, "NVIDIA kernel module already built for the running kernel");
+    } else if !staging() && !dry_run() {
+        run_command(&["sudo", "akmods", "--force"], "Building the NVIDIA kernel module (this can take several minutes)")?;
+        if module_built() {
+            println!("{} NVIDIA kernel module built", "[SUCCESS]".green());
+        } else {
+            warn("NVIDIA kernel module not found after akmods; check `journalctl -u akmods` before rebooting")?;
+        }
+    }
+
+    if system.enable_nvidia_powerd.unwrap_or(false) {
+        if check_service_enabled("nvidia-powerd.service", &ServiceScope::System)? {
+            debug!(verbose, "nvidia-powerd already enabled");
+        } else {
+            run_reboot_command(&["sudo", "systemctl", "enable", "nvidia-powerd.service"], "Enabling nvidia-powerd")?;
+        }
+    }
+
+    println!("{}", "Reboot required for NVIDIA GPU.".yellow());
+    Ok(())
+}
+
+/// Whether `user` is a member of `group` in the group database (not just the current session).
+fn user_in_group(runner: &dyn CommandRunner, user: &str, group: &str) -> Result<bool> {
+    let output = spawn_output_via(runner, &["id", "-nG", user])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["id", "-nG", user], &output).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).split_whitespace().any(|g| g == group))
+}
+
+fn setup_flatpak(_distro: &Distro, flatpak_config: Option<&FlatpakConfig>, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<()> {
+    debug!(verbose, "Installing Flatpak and setting up remotes");
+
+    run_command_retry(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "flatpak"], "Installing Flatpak", command_attempts(), RETRY_BASE_DELAY)?;
+
+    let desired = declared_flatpak_remotes(flatpak_config);
+    let current = get_flatpak_remotes()?;
+    let mut state = load_flatpak_remotes_state()?;
+    let timestamp = get_current_timestamp();
+
+    for remote in &desired {
+        let key = match &remote.gpg_key {
+            Some(key) => Some(fetch_flatpak_gpg_key(&remote.name, key)?),
+            None => None,
+        };
+        // A dry run doesn't read the key, so it can't be compared and counts as changed
+        let key_changed = key.as_ref().is_some_and(|key| key.hash.is_none() || state.gpg_keys.get(&remote.name) != key.hash.as_ref());
+
+        let result = apply_flatpak_remote(remote, &current, key.as_ref().map(|key| key.path.as_path()), key_changed, &mut state, timestamp, verbose);
+        if let Some(key) = key.as_ref().filter(|key| key.downloaded) {
+            let _ = fs::remove_file(&key.path);
+        }
+        result?;
+
+        if let Some(hash) = key.and_then(|key| key.hash) {
+            state.gpg_keys.insert(remote.name.clone(), hash);
+        }
+    }
+
+    // Remotes we added that are no longer declared
+    let mut stale: Vec<String> = state.remotes.keys()
+        .filter(|name| !desired.iter().any(|remote| &remote.name == *name))
+        .cloned()
+        .collect();
+    stale.sort();
+    stale.retain(|name| {
+        let present = current.iter().any(|info| &info.name == name);
+        if !present {
+            state.remotes.remove(name);
+        }
+        present
+    });
+
+    if !stale.is_empty() && no_removals {
+        println!("{} --no-removals: keeping {} Flatpak remotes removed from config", "[INFO]".blue(), stale.len());
+    } else if !stale.is_empty() {
+        println!("{} Found {} Flatpak remotes removed from config:", "[INFO]".blue(), stale.len());
+        for name in &stale {
+            println!("  - {}", name);
+        }
+        if ask_user_confirmation("Remove these Flatpak remotes?", yes, no, verbose)? {
+            for name in &stale {
+                // Without --force flatpak refuses while apps from the remote are still installed
+                match run_command(&["flatpak", "remote-delete", name], &format!("Removing Flatpak remote {}", name)) {
+                    Ok(()) => {
+                        state.remotes.remove(name);
+                        state.gpg_keys.remove(name);
+                    }
+                    Err(e) => warn(format!("Could not remove Flatpak remote {}: {}", name, e))?,
+                }
+            }
+        }
+    }
+
+    save_flatpak_remotes_state(&state)?;
+    Ok(())
+}
+
+/// Remotes from `[flatpak]`, or Flathub when no Flatpak config is given.
+fn declared_flatpak_remotes(flatpak_config: Option<&FlatpakConfig>) -> Vec<FlatpakRemote> {
+    match flatpak_config {
+        None => vec![FlatpakRemote {
+            name: "flathub".to_string(),
+            url: "https://flathub.org/repo/flathub.flatpakrepo".to_string(),
+            gpg_key: None,
+            priority: None,
+            filter: None,
+        }],
+        Some(config) => config.remotes.clone().unwrap_or_default(),
+    }
+}
+
+/// Adds the remote, or brings an existing one's priority, filter and key in line with the config.
+#[allow(clippy::too_many_arguments)]
+fn apply_flatpak_remote(
+    remote: &FlatpakRemote,
+    current: &[FlatpakRemoteInfo],
+    key: Option<&Path>,
+    key_changed: bool,
+    state: &mut FlatpakRemotesState,
+    timestamp: u64,
+    verbose: bool,
+) -> Result<()> {
+    let mut options: Vec<String> = Vec::new();
+    if let Some(priority) = remote.priority {
+        options.push(format!("--prio={}", priority));
+    }
+    if let Some(filter) = &remote.filter {
+        options.push(format!("--filter={}", filter));
+    }
+
+    match current.iter().find(|info| info.name == remote.name) {
+        Some(info) => {
+            // remote-add stores the repo URL from a .flatpakrepo, so only compare plain repo URLs
+            if !remote.url.ends_with(".flatpakrepo") && info.url.trim_end_matches('/') != remote.url.trim_end_matches('/') {
+                warn(format!("Flatpak remote {} points to {} instead of {}", remote.name, info.url, remote.url))?;
+            }
+
+            let mut changes: Vec<String> = Vec::new();
+            if remote.priority.is_some_and(|p| info.priority != Some(p)) {
+                changes.push(format!("--prio={}", remote.priority.unwrap_or_default()));
+            }
+            if remote.filter.as_ref().is_some_and(|f| info.filter.as_ref() != Some(f)) {
+                changes.push(format!("--filter={}", remote.filter.as_deref().unwrap_or_default()));
+            }
+            if let (true, Some(path)) = (key_changed, key) {
+                changes.push(format!("--gpg-import={}", path.display()));
+            }
+
+            if changes.is_empty() {
+                debug!(verbose, "Flatpak remote {} already configured", remote.name);
+            } else {
+                let mut cmd: Vec<&str> = vec!["flatpak", "remote-modify"];
+                cmd.extend(changes.iter().map(String::as_str));
+                cmd.push(&remote.name);
+                run_command_retry(&cmd, &format!("Updating Flatpak remote {}", remote.name), command_attempts(), RETRY_BASE_DELAY)?;
+            }
+        }
+        None => {
+            debug!(verbose, "Adding Flatpak remote: {} -> {}", remote.name, remote.url);
+            if let Some(path) = key {
+                options.push(format!("--gpg-import={}", path.display()));
+            }
+            let mut cmd: Vec<&str> = vec!["flatpak", "remote-add", "--if-not-exists"];
+            cmd.extend(options.iter().map(String::as_str));
+            cmd.push(&remote.name);
+            cmd.push(&remote.url);
+            run_command_retry(&cmd, &format!("Adding Flatpak remote {}", remote.name), command_attempts(), RETRY_BASE_DELAY)?;
+            state.remotes.insert(remote.name.clone(), ManagedFlatpakRemote {
+                url: remote.url.clone(),
+                added_at: timestamp,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A remote's GPG key as a local file. `hash` is None in a dry run, which neither downloads nor reads it.
+struct FlatpakGpgKey {
+    path: std::path::PathBuf,
+    hash: Option<String>,
+    // Downloaded into a staging file that is deleted once imported
+    downloaded: bool,
+}
+
+/// Resolves a remote's GPG key to a local file (downloading URLs) and hashes it.
+fn fetch_flatpak_gpg_key(remote: &str, key: &str) -> Result<FlatpakGpgKey> {
+    let downloaded = key.starts_with("https://") || key.starts_with("http://");
+    if dry_run() {
+        if downloaded {
+            dry_run_skip(&format!("run: curl -fsSL -o <staged file> {}", key));
+        }
+        return Ok(FlatpakGpgKey { path: std::path::PathBuf::from(key), hash: None, downloaded: false });
+    }
+
+    let path = if downloaded {
+        // curl writes into a file only this run owns, not a guessable path in the shared temp dir
+        let path = stage_private_file(&format!("flatpak-{}.gpg", remote), &[])?;
+        let path_str = path.to_str().context("Invalid staging file path")?;
+        if let Err(e) = run_command_retry(&["curl", "-fsSL", "-o", path_str, key], &format!("Downloading GPG key for Flatpak remote {}", remote), command_attempts(), RETRY_BASE_DELAY) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        path
+    } else {
+        std::path::PathBuf::from(key)
+    };
+
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            if downloaded {
+                let _ = fs::remove_file(&path);
+            }
+            return Err(e).with_context(|| format!("Failed to read GPG key {:?} for Flatpak remote {}", path, remote));
+        }
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&content);
+    Ok(FlatpakGpgKey { path, hash: Some(format!("{:x}", hasher.finalize())), downloaded })
+}
+
+fn get_flatpak_remotes() -> Result<Vec<FlatpakRemoteInfo>> {
+    let output = run_command_output(&["flatpak", "remotes", "--columns=name,url,priority,filter"])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["flatpak", "remotes"], &output).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t').map(str::trim);
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            let url = fields.next().unwrap_or("");
+            let priority = fields.next().and_then(|p| p.parse().ok());
+            let filter = fields.next().filter(|f| !f.is_empty() && *f != "-");
+            Some(FlatpakRemoteInfo {
+                name: name.to_string(),
+                url: url.to_string(),
+                priority,
+                filter: filter.map(str::to_string),
+            })
+        })
+        .collect())
+}
+
+fn get_flatpak_remotes_state_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("flatpak_remotes_state.json"))
+}
+
+fn load_flatpak_remotes_state() -> Result<FlatpakRemotesState> {
+    if state_disabled() {
+        return Ok(FlatpakRemotesState::default());
+    }
+
+    let state_file = get_flatpak_remotes_state_path()?;
+
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read Flatpak remotes state file")?;
+        let state: FlatpakRemotesState = serde_json::from_str(&content)
+            .context("Failed to parse Flatpak remotes state file")?;
+        Ok(state)
+    } else {
+        Ok(FlatpakRemotesState::default())
+    }
+}
+
+fn save_flatpak_remotes_state(state: &FlatpakRemotesState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let state_file = get_flatpak_remotes_state_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize Flatpak remotes state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write Flatpak remotes state file")?;
+    Ok(())
+}
+
+fn list_flatpak_remotes(args: &Args) -> Result<()> {
+    let (_, config) = read_effective_config(args)?;
+    let desired = declared_flatpak_remotes(config.flatpak.as_ref());
+    let current = get_flatpak_remotes()?;
+    let state = load_flatpak_remotes_state()?;
+
+    println!("{} Flatpak remotes:", "[INFO]".blue());
+    for FlatpakRemoteInfo { name, url, .. } in &current {
+        let origin = if desired.iter().any(|remote| &remote.name == name) {
+            "declared"
+        } else if state.remotes.contains_key(name) {
+            "added by FedoraForge, no longer declared"
+        } else {
+            "not managed"
+        };
+        println!("  - {} ({}) [{}]", name, url, origin);
+    }
+    for remote in desired.iter().filter(|remote| !current.iter().any(|info| info.name == remote.name)) {
+        println!("  - {} ({}) [declared, missing]", remote.name, remote.url);
+    }
+    Ok(())
+}
+
+/// Installs the applications and returns the ones actually installed. High-risk permissions are shown
+/// and confirmed before each install; under --yes they are only logged.
+fn install_flatpak_packages(packages: &[String], yes: bool, no: bool, verbose: bool) -> Result<Vec<String>> {
+    if packages.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    println!("{} Installing Flatpak applications...", "[INFO]".blue());
+
+    let mut installed = Vec::new();
+    for package in packages {
+        let (remote, app_id) = parse_flatpak_package(package);
+        let risky = flatpak_requested_permissions(remote, app_id, verbose);
+        if !risky.is_empty() {
+            println!("{} {} requests high-risk permissions: {}", "[WARN]".yellow(), app_id, risky.join(", "));
+            if !ask_user_confirmation(&format!("Install '{}' with these permissions?", app_id), yes, no, verbose)? {
+                println!("{} Skipping {}", "[INFO]".blue(), app_id);
+                continue;
+            }
+        }
+        println!("{} Installing Flatpak package: {} from {}", "[INFO]".blue(), app_id, remote);
+        run_command(&["flatpak", "install", "-y", remote, app_id], &format!("Installing {} from {}", app_id, remote))?;
+        installed.push(package.clone());
+    }
+
+    println!("{} Installed {} of {} Flatpak applications", "[SUCCESS]".green(), installed.len(), packages.len());
+    Ok(installed)
+}
+
+/// High-risk permissions from the app's metadata on the remote. Empty when the metadata can't be fetched.
+fn flatpak_requested_permissions(remote: &str, app_id: &str, verbose: bool) -> Vec<String> {
+    match spawn_output(&["flatpak", "remote-info", "--show-metadata", remote, app_id]) {
+        Ok(output) if output.status.success() => flatpak_high_risk_permissions(&String::from_utf8_lossy(&output.stdout)),
+        Ok(output) => {
+            debug!(verbose, "Could not read permissions of {}: {}", app_id, String::from_utf8_lossy(&output.stderr).trim());
+            Vec::new()
+        }
+        Err(e) => {
+            debug!(verbose, "Could not read permissions of {}: {}", app_id, e);
+            Vec::new()
+        }
+    }
+}
+
+fn setup_wireguard_vpn(vpn: &VpnConfig) -> Result<()> {
+    // Install WireGuard tools
+    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "wireguard-tools"], "Installing WireGuard tools")?;
+
+    // Try to install NetworkManager WireGuard plugin gracefully
+    install_wireguard_packages_graceful()?;
+
+    // Derive interface name from conf_path (e.g., "wg0.conf" -> "wg0")
+    use std::path::Path;
+    let conf_path = Path::new(&vpn.conf_path);
+    let interface_name = conf_path.file_stem()
+        .context("Invalid conf_path: missing file stem")?
+        .to_str()
+        .context("Invalid conf_path: non-UTF8 stem")?
+        .to_string();
+
+    // Ensure system-connections dir exists
+    run_command(&["sudo", "mkdir", "-p", "/etc/NetworkManager/system-connections"], "Creating NetworkManager system-connections directory")?;
+
+    // Remove existing connection if present (idempotent)
+    let _ = run_command(&["nmcli", "connection", "delete", &interface_name], "Removing existing WireGuard connection if present");
+
+    // Import config into NetworkManager
+    run_command(&["nmcli", "connection", "import", "type", "wireguard", "file", &vpn.conf_path], &format!("Importing WireGuard config for {}", interface_name))?;
+
+    // Set autoconnect and priority
+    run_command(&["nmcli", "connection", "modify", &interface_name, "connection.autoconnect", "yes"], &format!("Enabling autoconnect for {}", interface_name))?;
+    run_command(&["nmcli", "connection", "modify", &interface_name, "connection.autoconnect-priority", "10"], &format!("Setting autoconnect priority for {}", interface_name))?;
+
+    // Reload NetworkManager and activate
+    run_command(&["sudo", "systemctl", "reload", "NetworkManager"], "Reloading NetworkManager to apply connection")?;
+    run_command(&["nmcli", "connection", "up", &interface_name], &format!("Activating WireGuard connection {}", interface_name))?;
+
+    Ok(())
+}
+
+fn setup_openvpn_vpn(vpn: &VpnConfig) -> Result<()> {
+    // Install OpenVPN and NetworkManager plugin
+    run_command(&["sudo", "dnf", "install", "-y", "openvpn", "NetworkManager-openvpn", "NetworkManager-openvpn-gnome"], "Installing OpenVPN and NetworkManager plugin")?;
+
+    // Derive connection name from conf_path
+    use std::path::Path;
+    let conf_path = Path::new(&vpn.conf_path);
+    let connection_name = conf_path.file_stem()
+        .context("Invalid conf_path: missing file stem")?
+        .to_str()
+        .context("Invalid conf_path: non-UTF8 stem")?
+        .to_string();
+
+    // Remove existing connection if present
+    let _ = run_command(&["nmcli", "connection", "delete", &connection_name], "Removing existing OpenVPN connection if present");
+
+    // Import OpenVPN config
+    run_command(&["nmcli", "connection", "import", "type", "openvpn", "file", &vpn.conf_path], &format!("Importing OpenVPN config for {}", connection_name))?;
+
+    // Set autoconnect
+    run_command(&["nmcli", "connection", "modify", &connection_name, "connection.autoconnect", "yes"], &format!("Enabling autoconnect for {}", connection_name))?;
+    run_command(&["nmcli", "connection", "modify", &connection_name, "connection.autoconnect-priority", "10"], &format!("Setting autoconnect priority for {}", connection_name))?;
+
+    // Activate the connection
+    run_command(&["nmcli", "connection", "up", &connection_name], &format!("Activating OpenVPN connection {}", connection_name))?;
+
+    Ok(())
+}
+
+/// Enables declared COPR repos that aren't enabled yet. A repo that can't be enabled (e.g. no
+/// build for this Fedora release) is reported and skipped rather than failing the run.
+fn enable_copr_repos(repos: &[String], verbose: bool) -> Result<()> {
+    let enabled = match get_enabled_copr_repos() {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            warn(format!("Could not list COPR repos: {}", e))?;
+            Vec::new()
+        }
+    };
+
+    for repo in repos {
+        // `dnf copr list` prints hub/owner/project; config may leave out the hub
+        if enabled.iter().any(|e| e == repo || e.ends_with(&format!("/{}", repo))) {
+            debug!(verbose, "COPR repo {} already enabled", repo);
+            continue;
+        }
+
+        let result = run_command_retry(&["sudo", "dnf", "copr", "enable", "-y", repo],
+                                       &format!("Enabling COPR repo {}", repo), command_attempts(), RETRY_BASE_DELAY);
+        if result.is_err() {
+            warn(format!("COPR repo {} is not available for this Fedora version, skipping", repo))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_enabled_copr_repos() -> Result<Vec<String>> {
+    let output = spawn_output(&["dnf", "copr", "list"])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["dnf", "copr", "list"], &output).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.contains("(disabled)"))
+        .map(String::from)
+        .collect())
+}
+
+fn install_wireguard_packages_graceful() -> Result<()> {
+    // Try to enable Copr repo, but don't fail if it's not available for current Fedora version
+    let copr_result = run_command(&["sudo", "dnf", "copr", "enable", "-y", "timn/NetworkManager-wireguard"], "Enabling Copr repo for NetworkManager WireGuard plugin");
+
+    if copr_result.is_err() {
+        return warn("NetworkManager WireGuard plugin Copr repo not available for this Fedora version, using basic WireGuard tools only");
+    }
+
+    // Try to install the plugin, but don't fail if it's not available
+    let plugin_result = run_command(&["sudo", "dnf", "install", "-y", "NetworkManager-wireguard-gtk"], "Installing NetworkManager WireGuard plugin");
+
+    if plugin_result.is_err() {
+        warn("NetworkManager WireGuard plugin not available, using basic WireGuard tools only")?;
+    }
+
+    Ok(())
+}
+
+fn cleanup_winapps(yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let home_dir = user_home_dir()?;
+    let winapps_config_dir = home_dir.join(".config").join("winapps");
+    let winapps_repo_dir = home_dir.join(".local").join("share").join("winapps");
+
+    // Check if WinApps is installed
+    let config_exists = winapps_config_dir.exists();
+    let repo_exists = winapps_repo_dir.exists();
+
+    if !config_exists && !repo_exists {
+        debug!(verbose, "WinApps not installed, nothing to clean up");
+        return Ok(());
+    }
+
+    println!("{} WinApps installation detected", "[INFO]".blue());
+
+    if verbose {
+        if config_exists {
+            println!("{} Found config directory: {:?}", "[DEBUG]".cyan(), winapps_config_dir);
+        }
+        if repo_exists {
+            println!("{} Found repository: {:?}", "[DEBUG]".cyan(), winapps_repo_dir);
         }
     }
 
     // Check for running containers
-    let container_check = std::process::Command::new("podman")
-        .args(["ps", "-a", "--filter", "name=RDPWindows", "--format", "{{.Names}}"])
-        .output();
+    let container_check = spawn_output(&["podman", "ps", "-a", "--filter", "name=RDPWindows", "--format", "{{.Names}}"]);
+
+    let has_container = if let Ok(output) = container_check {
+        let container_list = String::from_utf8_lossy(&output.stdout);
+        !container_list.trim().is_empty()
+    } else {
+        false
+    };
+
+    if has_container && verbose {
+        println!("{} Found RDPWindows container", "[DEBUG]".cyan());
+    }
+
+    // Prompt for confirmation
+    let should_cleanup = if yes {
+        true
+    } else if no {
+        false
+    } else {
+        println!("\n{} WinApps cleanup will:", "[WARNING]".yellow());
+        if has_container {
+            println!("  • Stop and remove the RDPWindows container");
+        }
+        if config_exists {
+            println!("  • Remove config directory: {:?}", winapps_config_dir);
+        }
+        if repo_exists {
+            println!("  • Remove repository: {:?}", winapps_repo_dir);
+        }
+        println!("\n{} Do you want to proceed with cleanup?", "[PROMPT]".yellow());
+        ask_user_confirmation("Cleanup WinApps", yes, no, verbose)?
+    };
+
+    if !should_cleanup {
+        println!("{} Skipping WinApps cleanup", "[INFO]".blue());
+        return Ok(());
+    }
+
+    // Stop and remove container
+    if has_container {
+        println!("{} Stopping and removing RDPWindows container...", "[INFO]".blue());
+
+        // Try to stop with compose first if compose.yaml exists
+        let compose_file = winapps_config_dir.join("compose.yaml");
+        if compose_file.exists() {
+            let result = run_command(
+                &["podman-compose", "--file", compose_file.to_str().unwrap(), "down"],
+                "Stopping container with compose"
+            );
+            if result.is_err() && verbose {
+                println!("{} podman-compose failed, trying direct container removal", "[WARNING]".yellow());
+            }
+        }
+
+        // Force remove the container if it still exists
+        let _ = run_command(
+            &["podman", "rm", "-f", "RDPWindows"],
+            "Removing RDPWindows container"
+        );
+
+        println!("{} Container removed", "[SUCCESS]".green());
+    }
+
+    // Remove config directory
+    if config_exists {
+        println!("{} Removing config directory: {:?}", "[INFO]".blue(), winapps_config_dir);
+        delete_dir(&winapps_config_dir)
+            .with_context(|| format!("Failed to remove config directory {:?}", winapps_config_dir))?;
+        println!("{} Config directory removed", "[SUCCESS]".green());
+    }
+
+    // Remove repository
+    if repo_exists {
+        println!("{} Removing repository: {:?}", "[INFO]".blue(), winapps_repo_dir);
+        delete_dir(&winapps_repo_dir)
+            .with_context(|| format!("Failed to remove repository {:?}", winapps_repo_dir))?;
+        println!("{} Repository removed", "[SUCCESS]".green());
+    }
+
+    println!("{} WinApps cleanup completed!", "[SUCCESS]".green().bold());
+    println!("{} Dependencies (freerdp, dialog, etc.) were NOT removed", "[INFO]".blue());
+    println!("     Run 'sudo dnf remove freerdp dialog libnotify nmap-ncat' to remove them manually if needed", );
+
+    Ok(())
+}
+
+/// Makes sure the rootless podman socket WinApps talks to is running and returns its path
+/// (None under a dry run that would only have started it).
+fn winapps_podman_socket(verbose: bool) -> Result<Option<String>> {
+    if check_service_active("podman.socket", &ServiceScope::User)? {
+        debug!(verbose, "podman.socket already active");
+    } else {
+        run_command(&["systemctl", "--user", "enable", "--now", "podman.socket"], "Enabling Podman socket for WinApps")?;
+        if dry_run() {
+            return Ok(None);
+        }
+        if !check_service_active("podman.socket", &ServiceScope::User)? {
+            anyhow::bail!("podman.socket is not active after enabling it; WinApps could not reach podman (see `systemctl --user status podman.socket`)");
+        }
+    }
+
+    let output = spawn_output(&["podman", "info", "--format", "{{.Host.RemoteSocket.Path}}"])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["podman", "info"], &output).into());
+    }
+    let socket = String::from_utf8_lossy(&output.stdout).trim().trim_start_matches("unix://").to_string();
+    if socket.is_empty() || !Path::new(&socket).exists() {
+        anyhow::bail!("podman reports socket '{}', which does not exist; WinApps could not reach podman", socket);
+    }
+    debug!(verbose, "Podman socket for WinApps: {}", socket);
+    Ok(Some(socket))
+}
+
+/// Fast-forwards the WinApps clone. Local edits are stashed after confirmation (or the pull is skipped),
+/// and a pull that still fails only warns, since the existing checkout is usable as it is.
+fn update_winapps_repo(repo_dir: &Path, winapps_ref: Option<&str>, args: &Args) -> Result<()> {
+    let repo = repo_dir.to_str().context("Invalid WinApps repository path")?;
+    println!("{} WinApps repository already exists at {:?}, pulling latest changes...", "[INFO]".blue(), repo_dir);
+
+    let status = spawn_output(&["git", "-C", repo, "status", "--porcelain"])?;
+    let changes = String::from_utf8_lossy(&status.stdout).to_string();
+    if !changes.trim().is_empty() {
+        println!("{} The WinApps clone has local changes:", "[WARN]".yellow());
+        for line in changes.lines() {
+            println!("  {}", line);
+        }
+        if !ask_user_confirmation("Stash them (git stash -u) and update?", args.yes, args.no, args.verbose)? {
+            return warn("Skipping the WinApps update, keeping the local changes");
+        }
+        let message = format!("fedoraforge {}", get_current_timestamp());
+        run_command(&["git", "-C", repo, "stash", "push", "--include-untracked", "-m", &message], "Stashing local WinApps changes")?;
+        println!("{} Restore them with: git -C {} stash pop", "[INFO]".blue(), repo);
+    }
+
+    if let Some(winapps_ref) = winapps_ref {
+        if let Err(e) = run_command(&["git", "-C", repo, "fetch", "--tags", "origin"], "Fetching WinApps refs") {
+            warn(format!("WinApps fetch failed, checking out {} from what is already local: {}", winapps_ref, e))?;
+        }
+        return checkout_winapps_ref(repo, winapps_ref);
+    }
+
+    // Back from a pin: the detached HEAD returns to the default branch before pulling
+    if !spawn_output(&["git", "-C", repo, "symbolic-ref", "-q", "HEAD"]).is_ok_and(|o| o.status.success()) {
+        let output = spawn_output(&["git", "-C", repo, "symbolic-ref", "--short", "refs/remotes/origin/HEAD"])?;
+        let remote_head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let branch = remote_head.strip_prefix("origin/").filter(|b| !b.is_empty()).unwrap_or("main");
+        run_command(&["git", "-C", repo, "checkout", branch], &format!("Returning WinApps to {}", branch))?;
+    }
+    if let Err(e) = run_command(&["git", "-C", repo, "pull", "--ff-only"], "Updating WinApps repository") {
+        warn(format!("WinApps update failed, continuing with the current checkout: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Detaches at `winapps_ref`. A branch name follows the fetched remote branch; tags and commits are used as-is.
+fn checkout_winapps_ref(repo: &str, winapps_ref: &str) -> Result<()> {
+    let remote_branch = format!("origin/{}", winapps_ref);
+    let is_branch = spawn_output(&["git", "-C", repo, "rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", remote_branch)])
+        .is_ok_and(|o| o.status.success());
+    let target = if is_branch { remote_branch.as_str() } else { winapps_ref };
+    run_command(&["git", "-C", repo, "checkout", "--detach", target], &format!("Checking out WinApps {}", winapps_ref))
+        .with_context(|| format!("winapps_ref '{}' was not found in the WinApps repository", winapps_ref))
+}
+
+fn get_winapps_state_path() -> Result<std::path::PathBuf> {
+    Ok(state_dir()?.join("winapps_state.json"))
+}
+
+fn load_winapps_state() -> Result<WinAppsState> {
+    if state_disabled() {
+        return Ok(WinAppsState::default());
+    }
+
+    let state_file = get_winapps_state_path()?;
+    if !state_file.exists() {
+        return Ok(WinAppsState::default());
+    }
+    let content = fs::read_to_string(&state_file)
+        .context("Failed to read WinApps state file")?;
+    serde_json::from_str(&content).context("Failed to parse WinApps state file")
+}
+
+fn save_winapps_state(state: &WinAppsState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize WinApps state")?;
+    fs::write(get_winapps_state_path()?, content)
+        .context("Failed to write WinApps state file")?;
+    Ok(())
+}
+
+/// Records the checked-out commit and reports when it changed since the last run.
+fn record_winapps_commit(repo_dir: &Path, winapps_ref: Option<&str>, verbose: bool) -> Result<()> {
+    let repo = repo_dir.to_str().context("Invalid WinApps repository path")?;
+    let output = spawn_output(&["git", "-C", repo, "rev-parse", "HEAD"])?;
+    if !output.status.success() {
+        // Nothing cloned yet (dry run)
+        return Ok(());
+    }
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let tracking = winapps_ref.unwrap_or("default branch");
+
+    let mut state = load_winapps_state()?;
+    if state.commit == commit {
+        debug!(verbose, "WinApps still at {} ({})", commit, tracking);
+    } else if state.commit.is_empty() {
+        println!("{} WinApps checked out at {} ({})", "[INFO]".blue(), commit, tracking);
+    } else {
+        println!("{} WinApps moved from {} to {} ({})", "[INFO]".blue(), state.commit, commit, tracking);
+    }
+
+    state.commit = commit;
+    state.winapps_ref = winapps_ref.map(str::to_string);
+    state.updated_at = get_current_timestamp();
+    save_winapps_state(&state)
+}
+
+fn setup_winapps(enable_winapps: bool, args: &Args) -> Result<()> {
+    if !enable_winapps {
+        // When disabled, offer to cleanup existing installation
+        if !args.no_removals {
+            cleanup_winapps(args.yes, args.no, args.verbose)?;
+        }
+        return Ok(());
+    }
+
+    println!("{} Setting up WinApps...", "[INFO]".blue());
+
+    // Check if winapps-config.toml exists
+    let winapps_config_path = "config/winapps-config.toml";
+    if !std::path::Path::new(winapps_config_path).exists() {
+        anyhow::bail!("WinApps is enabled but config file not found at {}. Please create the config file or set enable_winapps = false", winapps_config_path);
+    }
+
+    // Install WinApps dependencies for Fedora
+    println!("{} Installing WinApps dependencies...", "[INFO]".blue());
+    run_command(
+        &["sudo", "dnf", "install", "-y", "curl", "dialog", "freerdp", "git", "iproute", "libnotify", "nmap-ncat"],
+        "Installing WinApps dependencies"
+    )?;
+
+    // Load WinApps configuration
+    let winapps_config_content = fs::read_to_string(winapps_config_path)
+        .with_context(|| format!("Failed to read WinApps config from {}", winapps_config_path))?;
+
+    let winapps_config: WinAppsConfig = toml::from_str(&winapps_config_content)
+        .with_context(|| format!("Failed to parse WinApps config from {}", winapps_config_path))?;
+
+    // Verify that backend is set to podman
+    if winapps_config.waflavor != "podman" {
+        println!("{} WinApps backend is set to '{}', but only 'podman' is supported. Please set waflavor = \"podman\" in {}",
+                 "[WARNING]".yellow(), winapps_config.waflavor, winapps_config_path);
+        anyhow::bail!("Unsupported WinApps backend: {}. Only 'podman' is supported.", winapps_config.waflavor);
+    }
+
+    let podman_socket = winapps_podman_socket(args.verbose)?;
+
+    // Create WinApps config directory
+    let home_dir = user_home_dir()?;
+    let winapps_dir = home_dir.join(".config").join("winapps");
+
+    println!("{} Creating WinApps config directory at {:?}", "[INFO]".blue(), winapps_dir);
+    create_dir(&winapps_dir)
+        .with_context(|| format!("Failed to create WinApps config directory at {:?}", winapps_dir))?;
+
+    // Write winapps.conf file
+    let winapps_conf_path = winapps_dir.join("winapps.conf");
+    println!("{} Writing WinApps configuration to {:?}", "[INFO]".blue(), winapps_conf_path);
+
+    let mut conf_content = String::new();
+    conf_content.push_str(&format!("RDP_USER=\"{}\"\n", winapps_config.rdp_user));
+    conf_content.push_str(&format!("RDP_PASS=\"{}\"\n", winapps_config.rdp_pass));
+    conf_content.push_str(&format!("RDP_DOMAIN=\"{}\"\n", winapps_config.rdp_domain.as_deref().unwrap_or("")));
+    conf_content.push_str(&format!("RDP_IP=\"{}\"\n", winapps_config.rdp_ip));
+    conf_content.push_str(&format!("VM_NAME=\"{}\"\n", winapps_config.vm_name.as_deref().unwrap_or("RDPWindows")));
+    conf_content.push_str(&format!("WAFLAVOR=\"{}\"\n", winapps_config.waflavor));
+    conf_content.push_str(&format!("RDP_SCALE=\"{}\"\n", winapps_config.rdp_scale.as_deref().unwrap_or("100")));
+    conf_content.push_str(&format!("REMOVABLE_MEDIA=\"{}\"\n", winapps_config.removable_media.as_deref().unwrap_or("/run/media")));
+    conf_content.push_str(&format!("DEBUG=\"{}\"\n", if winapps_config.debug.unwrap_or(false) { "true" } else { "false" }));
+    conf_content.push_str(&format!("MULTIMON=\"{}\"\n", if winapps_config.multimon.unwrap_or(false) { "true" } else { "false" }));
+
+    if let Some(rdp_flags) = &winapps_config.rdp_flags {
+        conf_content.push_str(&format!("RDP_FLAGS=\"{}\"\n", rdp_flags));
+    }
+
+    // Detect Wayland and set RDP_ENV for compatibility
+    let rdp_env = if let Some(custom_env) = &winapps_config.rdp_env {
+        // Use custom environment from config if specified
+        custom_env.clone()
+    } else {
+        // Auto-detect Wayland session
+        if let Ok(session_type) = std::env::var("XDG_SESSION_TYPE") {
+            if session_type.to_lowercase() == "wayland" {
+                println!("{} Detected Wayland session, setting GDK_BACKEND=x11 for FreeRDP compatibility", "[INFO]".blue());
+                "GDK_BACKEND=x11".to_string()
+            } else {
+                String::new()
+            }
+        } else if std::env::var("WAYLAND_DISPLAY").is_ok() {
+            // Fallback check for Wayland
+            println!("{} Detected Wayland display, setting GDK_BACKEND=x11 for FreeRDP compatibility", "[INFO]".blue());
+            "GDK_BACKEND=x11".to_string()
+        } else {
+            String::new()
+        }
+    };
+
+    if !rdp_env.is_empty() {
+        conf_content.push_str(&format!("RDP_ENV=\"{}\"\n", rdp_env));
+    }
+
+    // Tools that speak the Docker API find rootless podman through this
+    if let Some(socket) = &podman_socket {
+        conf_content.push_str(&format!("DOCKER_HOST=\"unix://{}\"\n", socket));
+    }
+
+    // Holds the RDP password, so it is created with mode 600 rather than chmodded afterwards
+    let winapps_conf_str = winapps_conf_path.to_str().context("Invalid WinApps config path")?;
+    install_file_content(winapps_conf_str, conf_content.as_bytes(), "600", None, None, false)
+        .with_context(|| format!("Failed to write WinApps config to {:?}", winapps_conf_path))?;
+
+    // Clone WinApps repository
+    let winapps_repo_dir = home_dir.join(".local").join("share").join("winapps");
+
+    let winapps_ref = winapps_config.winapps_ref.as_deref();
+    let fresh_clone = !winapps_repo_dir.join(".git").exists();
+    if !fresh_clone {
+        update_winapps_repo(&winapps_repo_dir, winapps_ref, args)?;
+    } else if winapps_repo_dir.exists() {
+        warn(format!("{:?} exists but is not a git repository", winapps_repo_dir))?;
+        let aside = winapps_repo_dir.with_file_name(format!("winapps.bak-{}", get_current_timestamp()));
+        if ask_user_confirmation(&format!("Move it to {:?} and clone WinApps again?", aside), args.yes, args.no, args.verbose)? {
+            if !dry_run_skip(&format!("move {:?} to {:?}", winapps_repo_dir, aside)) {
+                fs::rename(&winapps_repo_dir, &aside)
+                    .with_context(|| format!("Failed to move {:?} aside", winapps_repo_dir))?;
+            }
+            run_command(
+                &["git", "clone", "https://github.com/winapps-org/winapps.git", winapps_repo_dir.to_str().unwrap()],
+                "Cloning WinApps repository"
+            )?;
+        }
+    } else {
+        println!("{} Cloning WinApps repository to {:?}...", "[INFO]".blue(), winapps_repo_dir);
+        create_dir(winapps_repo_dir.parent().unwrap())?;
+        run_command(
+            &["git", "clone", "https://github.com/winapps-org/winapps.git", winapps_repo_dir.to_str().unwrap()],
+            "Cloning WinApps repository"
+        )?;
+    }
+    if let Some(winapps_ref) = winapps_ref.filter(|_| fresh_clone && winapps_repo_dir.join(".git").exists()) {
+        checkout_winapps_ref(winapps_repo_dir.to_str().unwrap(), winapps_ref)?;
+    }
+    record_winapps_commit(&winapps_repo_dir, winapps_ref, args.verbose)?;
+
+    // Copy compose.yaml to winapps config directory
+    println!("{} Copying compose.yaml to WinApps config directory...", "[INFO]".blue());
+    let compose_src = winapps_repo_dir.join("compose.yaml");
+    let compose_dest = winapps_dir.join("compose.yaml");
+
+    // The instructions below start from this file, so a missing one means the clone failed or upstream moved it
+    // (a dry run never cloned, so there is nothing to check yet)
+    if !compose_src.exists() && (!dry_run() || winapps_repo_dir.exists()) {
+        return Err(FedoraForgeError::validation(format!(
+            "compose.yaml not found at {:?}; the WinApps clone may have failed or the upstream layout changed", compose_src
+        )));
+    }
+    copy_file(&compose_src, &compose_dest)
+        .with_context(|| format!("Failed to copy compose.yaml from {:?} to {:?}", compose_src, compose_dest))?;
+    if !dry_run() && fs::metadata(&compose_dest).map_or(true, |m| m.len() == 0) {
+        return Err(FedoraForgeError::validation(format!(
+            "{:?} is missing or empty after copying it from {:?}", compose_dest, compose_src
+        )));
+    }
+    println!("{} Copied compose.yaml successfully", "[SUCCESS]".green());
+
+    // Pull here, with progress and retries, so `podman-compose up` starts from local images
+    let compose_images = parse_compose_images(&fs::read_to_string(&compose_dest).unwrap_or_default());
+    for image in &compose_images {
+        if get_image_id(command_runner(), image).is_some() {
+            debug!(args.verbose, "WinApps image {} already present", image);
+        } else if let Err(e) = pull_image(command_runner(), image) {
+            warn(format!("Could not pull {} ({}); rerun to resume, finished layers are kept", image, e))?;
+        }
+    }
+
+    println!("{} WinApps dependencies and configuration prepared!", "[SUCCESS]".green());
+
+    println!("\n{} ═══════════════════════════════════════════════════════════════", "📋".blue());
+    println!("{} WinApps Setup Instructions", "[INFO]".blue().bold());
+    println!("{} ═══════════════════════════════════════════════════════════════", "📋".blue());
+
+    println!("\n{} STEP 1: Start the Windows Container", "1️⃣".green());
+    println!("     cd {:?}", winapps_dir);
+    println!("     podman-compose --file compose.yaml up -d");
+
+    println!("\n{} IMPORTANT: First-time setup takes 15-30 minutes:", "⏱️".yellow());
+    println!("  • Windows will download (~4-6 GB) inside the container");
+    println!("  • If that download is interrupted, run the same `up -d` again; the container image is already local");
+    println!("  • Windows will install automatically");
+    println!("  • Container will restart once installation completes");
+
+    println!("\n{} Monitor Progress:", "👀".blue());
+    println!("  • View logs:     podman logs -f WinApps");
+    println!("  • Web console:   http://127.0.0.1:8006");
+    println!("  • Check status:  podman ps | grep WinApps");
+
+    println!("\n{} RAM Configuration (in compose.yaml):", "⚙️".yellow());
+    println!("  • Default: 4GB RAM (may be too high for some systems)");
+    println!("  • If container crashes, edit RAM_SIZE in {:?}", compose_dest);
+    println!("  • Recommended: 2GB minimum, 4GB optimal");
+
+    println!("\n{} STEP 2: Run the WinApps Installer (after Windows boots)", "2️⃣".green());
+    println!("     bash {:?}", winapps_repo_dir.join("setup.sh"));
+    println!("\n  The installer will:");
+    println!("  • Install the winapps binary");
+    println!("  • Let you select which Windows applications to expose");
+    println!("  • Create desktop shortcuts for selected apps");
+
+    if !rdp_env.is_empty() {
+        println!("\n{} Wayland Compatibility Configured:", "🖥️".blue());
+        println!("  • Detected Wayland session");
+        println!("  • Automatically configured: {}", rdp_env);
+        println!("  • This fixes FreeRDP X11 compatibility issues");
+    }
+
+    if let Some(socket) = &podman_socket {
+        println!("\n{} Podman socket: {}", "🔌".blue(), socket);
+        println!("  • Written to winapps.conf as DOCKER_HOST; for docker-compose in a shell: export DOCKER_HOST=unix://{}", socket);
+    }
+
+    println!("\n{} Configuration saved to: {:?}", "✅".green(), winapps_conf_path);
+    println!("{} ═══════════════════════════════════════════════════════════════\n", "📋".blue());
+
+    Ok(())
+}
+
+fn execute_custom_commands(config: &CustomCommandsConfig, verbose: bool) -> Result<()> {
+    if config.commands.is_empty() && config.run_once.as_ref().is_none_or(|v| v.is_empty()) {
+        return Ok(());
+    }
+
+    println!("{} Executing custom commands...", "[INFO]".blue());
+
+    // Load state for run_once commands
+    let mut state = load_executed_commands_state()?;
+    let mut state_changed = false;
+
+    // Execute regular commands
+    for (index, command) in config.commands.iter().enumerate() {
+        println!("{} Executing command {} of {}: {}",
+                "[INFO]".blue(), index + 1, config.commands.len(), command);
+
+        execute_single_command(command)?;
+        println!("{} Command completed successfully", "[SUCCESS]".green());
+    }
+
+    // Execute run_once commands
+    if let Some(run_once_commands) = &config.run_once {
+        for (index, command) in run_once_commands.iter().enumerate() {
+            let command_hash = generate_command_hash(command);
+
+            debug!(verbose, "Command hash: {} for: {}", &command_hash[..8], command);
+
+            if state.executed_once_commands.contains_key(&command_hash) {
+                println!("{} Skipping run-once command {} of {} (already executed): {}",
+                        "[INFO]".blue(), index + 1, run_once_commands.len(), command);
+                continue;
+            }
+
+            println!("{} Executing run-once command {} of {}: {}",
+                    "[INFO]".blue(), index + 1, run_once_commands.len(), command);
+
+            execute_single_command(command)?;
+
+            // Mark command as executed with metadata
+            let execution_record = CommandExecutionRecord {
+                command_hash: command_hash.clone(),
+                original_command: command.clone(),
+                executed_at: get_current_timestamp(),
+            };
+            state.executed_once_commands.insert(command_hash, execution_record);
+            state_changed = true;
+
+            println!("{} Run-once command completed successfully", "[SUCCESS]".green());
+        }
+    }
+
+    // Save state if it changed
+    if state_changed {
+        save_executed_commands_state(&state)?;
+    }
+
+    println!("{} All custom commands executed successfully!", "[SUCCESS]".green());
+    Ok(())
+}
+
+fn execute_single_command(command: &str) -> Result<()> {
+    if dry_run_skip(&format!("run: sh -c {}", shell_quote(command))) {
+        return Ok(());
+    }
+
+    // Execute command through shell to support environment variables and shell features
+    let output = spawn_output(&["sh", "-c", command])?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        println!("{} Command failed: {}", "[ERROR]".red(), command);
+        println!("{} Error output: {}", "[ERROR]".red(), stderr);
+        return Err(FedoraForgeError::command_failed(&["sh", "-c", command], &output).into());
+    }
+
+    // Show stdout if there's any output
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        println!("{} Output: {}", "[INFO]".blue(), stdout.trim());
+    }
+
+    Ok(())
+}
+
+fn setup_desktop_environment(distro: &Distro, config: &DesktopConfig) -> Result<()> {
+    let de_env = config.environment.as_deref().unwrap_or("cosmic-desktop");
+    println!("{} Setting up desktop environment: {}", "[INFO]".blue(), de_env);
+
+    validate_desktop_environment(distro, de_env)?;
+
+    let mut packages_to_install = vec![de_env.to_string()];
+    if let Some(additional_packages) = &config.packages {
+        packages_to_install.extend_from_slice(additional_packages);
+    }
+
+    install_desktop_packages(distro, &packages_to_install)?;
+
+    set_default_desktop_environment(distro, de_env)?;
+
+    // Setup display manager if specified
+    if let Some(display_manager) = &config.display_manager {
+        setup_display_manager(distro, display_manager)?;
+    }
+
+    Ok(())
+}
+
+fn validate_desktop_environment(distro: &Distro, de: &str) -> Result<()> {
+    println!("{} Validating desktop environment: {}", "[INFO]".blue(), de);
+    let available_des = get_available_des(distro)?;
+    if !available_des.contains(&de.to_lowercase()) {
+        return Err(FedoraForgeError::validation(format!("Desktop environment '{}' is not valid. Available options: {:?}", de, available_des)));
+    }
+    Ok(())
+}
+
+fn get_available_des(_distro: &Distro) -> Result<Vec<String>> {
+    let output = run_command_output(&["dnf", "group", "list", "--available"])?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let des = stdout.lines()
+        .skip_while(|line| !line.trim().starts_with("ID"))
+        .skip(1) // Skip the header line itself
+        .filter_map(|line| {
+            line.split_whitespace().next().map(|s| s.to_lowercase())
+        })
+        .collect();
+    Ok(des)
+}
+
+fn install_desktop_packages(_distro: &Distro, packages: &[String]) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    println!("{} Installing desktop packages...", "[INFO]".blue());
+    let mut cmd: Vec<&str> = vec!["sudo", "dnf", "group", "install", "-y"];
+    for pkg in packages {
+        cmd.push(pkg);
+    }
+    run_command(&cmd, "Installing desktop environment group")?;
+    Ok(())
+}
+
+
+fn set_default_desktop_environment(_distro: &Distro, de_env: &str) -> Result<()> {
+    println!("{} Setting default desktop environment to {}", "[INFO]".blue(), de_env);
+    let session_name = de_env.split('-').next().unwrap_or(de_env);
+    let desktop_file_content = format!("DESKTOP={}\n", session_name);
+    install_file_content("/etc/sysconfig/desktop", desktop_file_content.as_bytes(), "0644", Some("root"), Some("root"), true)
+}
+
+fn setup_display_manager(_distro: &Distro, display_manager: &str) -> Result<()> {
+    println!("{} Setting up display manager: {}", "[INFO]".blue(), display_manager);
+
+    // Install the display manager package
+    let dm_package = match display_manager {
+        "gdm" => "gdm",
+        "lightdm" => "lightdm",
+        "sddm" => "sddm",
+        "cosmic-greeter" => "cosmic-greeter",
+        _ => {
+            return warn(format!("Unsupported display manager: {}. Supported: gdm, lightdm, sddm, cosmic-greeter", display_manager));
+        }
+    };
+
+    // Install the display manager
+    run_reboot_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", dm_package], &format!("Installing {}", dm_package))?;
+
+    // Disable current display manager
+    let _ = run_reboot_command(&["sudo", "systemctl", "disable", "gdm"], "Disabling GDM");
+    let _ = run_reboot_command(&["sudo", "systemctl", "disable", "lightdm"], "Disabling LightDM");
+    let _ = run_reboot_command(&["sudo", "systemctl", "disable", "sddm"], "Disabling SDDM");
+    let _ = run_reboot_command(&["sudo", "systemctl", "disable", "cosmic-greeter"], "Disabling COSMIC Greeter");
+
+    // Enable the selected display manager
+    let service_name = display_manager;
+
+    run_reboot_command(&["sudo", "systemctl", "enable", service_name], &format!("Enabling {}", service_name))?;
+
+    // Set as default display manager
+    run_reboot_command(&["sudo", "systemctl", "set-default", "graphical.target"], "Setting graphical target as default")?;
+
+    println!("{} Display manager {} configured successfully", "[SUCCESS]".green(), display_manager);
+    println!("{} Reboot required for display manager changes to take effect", "[INFO]".yellow());
+
+    Ok(())
+}
+
+// ========================= RUN LOG =========================
+
+// Actions recorded during the current run, written to the runs directory when it finishes
+static RUN_ACTIONS: Mutex<Vec<RunAction>> = Mutex::new(Vec::new());
+static RUN_PODMAN_STORAGE: Mutex<Option<PodmanStorageSummary>> = Mutex::new(None);
+static RUN_TIMINGS: Mutex<Vec<SubsystemTiming>> = Mutex::new(Vec::new());
+static RUN_RESULTS: Mutex<Vec<SubsystemReport>> = Mutex::new(Vec::new());
+
+fn record_action(category: ActionCategory, kind: ActionKind, target: &str, detail: Option<String>) {
+    if let Ok(mut actions) = RUN_ACTIONS.lock() {
+        actions.push(RunAction {
+            category,
+            kind,
+            target: target.to_string(),
+            detail,
+        });
+    }
+}
+
+fn get_runs_dir() -> Result<std::path::PathBuf> {
+    let runs_dir = state_dir()?
+        .join("runs");
+    fs::create_dir_all(&runs_dir)?;
+    Ok(runs_dir)
+}
+
+fn save_run_log(config_path: &str, started_at: u64, result: &Result<()>) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let actions = RUN_ACTIONS.lock().map(|a| a.clone()).unwrap_or_default();
+    let log = RunLog {
+        started_at,
+        finished_at: get_current_timestamp(),
+        config: config_path.to_string(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        error_kind: result.as_ref().err().and_then(error_kind).map(str::to_string),
+        undone: false,
+        actions,
+        podman_storage: RUN_PODMAN_STORAGE.lock().ok().and_then(|s| s.clone()),
+        timings: RUN_TIMINGS.lock().map(|t| t.clone()).unwrap_or_default(),
+        results: RUN_RESULTS.lock().map(|r| r.clone()).unwrap_or_default(),
+    };
+
+    let log_file = get_runs_dir()?.join(format!("run-{}.json", started_at));
+    let content = serde_json::to_string_pretty(&log)
+        .context("Failed to serialize run log")?;
+    fs::write(&log_file, content)
+        .context("Failed to write run log")?;
+    Ok(())
+}
+
+// ========================= RUN OUTPUT LOG =========================
+
+// Full output of the current run, including [DEBUG] lines hidden from the console
+static OUTPUT_LOG: Mutex<Option<fs::File>> = Mutex::new(None);
+
+const DEFAULT_LOG_RETENTION: usize = 20;
+
+// [system] log_retention, read at the end of the run
+static LOG_RETENTION: AtomicUsize = AtomicUsize::new(DEFAULT_LOG_RETENTION);
+
+fn get_logs_dir() -> Result<std::path::PathBuf> {
+    let logs_dir = state_dir()?
+        .join("logs");
+    fs::create_dir_all(&logs_dir)?;
+    Ok(logs_dir)
+}
+
+/// Starts `~/.config/fedoraforge/logs/<started_at>.log`. Like the run log, skipped for --no-state and --dry-run.
+fn open_output_log(started_at: u64) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+    let path = get_logs_dir()?.join(format!("{}.log", started_at));
+    let file = fs::File::create(&path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    if let Ok(mut slot) = OUTPUT_LOG.lock() {
+        *slot = Some(file);
+    }
+    println!("{} Logging this run to {:?}", "[INFO]".blue(), path);
+    Ok(())
+}
+
+fn close_output_log() {
+    if let Ok(mut slot) = OUTPUT_LOG.lock() {
+        *slot = None;
+    }
+}
+
+/// Appends a line to the run output log, without color codes. No-op when no log is open.
+fn log_line(line: &str) {
+    if line.is_empty() {
+        return;
+    }
+    static ANSI: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let Ok(mut slot) = OUTPUT_LOG.lock() else {
+        return;
+    };
+    if let Some(file) = slot.as_mut() {
+        let ansi = ANSI.get_or_init(|| regex::Regex::new(r"\x1b\[[0-9;]*m").expect("valid ANSI pattern"));
+        let _ = writeln!(file, "{}", ansi.replace_all(line, ""));
+    }
+}
+
+/// Deletes the oldest output logs beyond `keep`.
+fn prune_output_logs(keep: usize, verbose: bool) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+    let mut logs: Vec<(u64, std::path::PathBuf)> = fs::read_dir(get_logs_dir()?)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter_map(|path| {
+            let started_at = path.file_name()?.to_str()?.strip_suffix(".log")?.parse().ok()?;
+            Some((started_at, path))
+        })
+        .collect();
+    if logs.len() <= keep {
+        return Ok(());
+    }
+    logs.sort();
+    for (_, path) in &logs[..logs.len() - keep] {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))?;
+        debug!(verbose, "Removed old run output log {:?}", path);
+    }
+    Ok(())
+}
+
+/// Run logs sorted oldest to newest.
+fn list_run_logs() -> Result<Vec<std::path::PathBuf>> {
+    let mut logs: Vec<std::path::PathBuf> = fs::read_dir(get_runs_dir()?)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("run-") && n.ends_with(".json"))
+        })
+        .collect();
+    logs.sort();
+    Ok(logs)
+}
+
+fn load_run_log(path: &Path) -> Result<RunLog> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read run log {:?}", path))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse run log {:?}", path))
+}
+
+fn describe_action(action: &RunAction) -> String {
+    let verb = match action.kind {
+        ActionKind::Install => "installed",
+        ActionKind::Remove => "removed",
+        ActionKind::Enable => "enabled",
+        ActionKind::Disable => "disabled",
+        ActionKind::Create => "created",
+        ActionKind::Delete => "deleted",
+        ActionKind::Overwrite => "overwritten",
+    };
+    format!("{:?} {} {}", action.category, action.target, verb).to_lowercase()
+}
+
+fn is_reversible(action: &RunAction) -> bool {
+    match (action.category, action.kind) {
+        (ActionCategory::Package, _) | (ActionCategory::Flatpak, _) | (ActionCategory::Service, _) => true,
+        (ActionCategory::Pip, _) | (ActionCategory::Npm, _) | (ActionCategory::Cargo, _) => true,
+        (ActionCategory::Container, ActionKind::Create) => true,
+        (ActionCategory::User, ActionKind::Create) | (ActionCategory::Group, ActionKind::Create) => true,
+        (ActionCategory::File, ActionKind::Overwrite) => action.detail.is_some(),
+        _ => false,
+    }
+}
+
+fn reverse_action(action: &RunAction, verbose: bool) -> Result<()> {
+    let target = action.target.as_str();
+    match (action.category, action.kind) {
+        (ActionCategory::Package, ActionKind::Install) => {
+            run_command(&["sudo", "dnf", "remove", "-y", target], &format!("Removing package {}", target))?;
+        }
+        (ActionCategory::Package, _) => {
+            install_system_packages(command_runner(), &Distro::Fedora, std::slice::from_ref(&action.target), verbose)?;
+        }
+        (ActionCategory::Flatpak, ActionKind::Install) => {
+            let (_, app_id) = parse_flatpak_package(target);
+            run_command(&["flatpak", "uninstall", "-y", app_id], &format!("Removing Flatpak application {}", app_id))?;
+        }
+        (ActionCategory::Flatpak, _) => {
+            // It was installed before the run being undone, so its permissions were already accepted
+            install_flatpak_packages(std::slice::from_ref(&action.target), true, false, verbose)?;
+        }
+        (ActionCategory::Pip, ActionKind::Install) => {
+            run_command(&["pip", "uninstall", "-y", target], &format!("Removing pip package {}", target))?;
+        }
+        (ActionCategory::Pip, _) => {
+            install_pip_packages(std::slice::from_ref(&action.target))?;
+        }
+        (ActionCategory::Npm, ActionKind::Install) => {
+            run_command(&["npm", "uninstall", "-g", target], &format!("Removing npm package {}", target))?;
+        }
+        (ActionCategory::Npm, _) => {
+            install_npm_packages(std::slice::from_ref(&action.target))?;
+        }
+        (ActionCategory::Cargo, ActionKind::Install) => {
+            run_command(&["cargo", "uninstall", target], &format!("Removing cargo package {}", target))?;
+        }
+        (ActionCategory::Cargo, _) => {
+            install_cargo_packages(std::slice::from_ref(&action.target))?;
+        }
+        (ActionCategory::Service, kind) => {
+            let scope = if action.detail.as_deref() == Some("user") { ServiceScope::User } else { ServiceScope::System };
+            if kind == ActionKind::Enable {
+                disable_service(target, &scope)?;
+            } else {
+                enable_service(target, &scope)?;
+            }
+        }
+        (ActionCategory::Container, _) => {
+            let home_dir = user_home_dir()?;
+            let service_name = format!("{}.service", target);
+            let _ = run_command(&["systemctl", "--user", "stop", &service_name], &format!("Stopping systemd service for {}", target));
+
+            let quadlet_path = home_dir.join(".config").join("containers").join("systemd").join(format!("{}.container", target));
+            if quadlet_path.exists() {
+                delete_file(&quadlet_path).context(format!("Failed to remove Quadlet file for {}", target))?;
+                mark_daemon_reload(&ServiceScope::User);
+            }
+
+            run_command(&["podman", "rm", "-f", target], &format!("Removing container {}", target))?;
+
+            let mut container_state = load_container_state()?;
+            if container_state.containers.remove(target).is_some() {
+                save_container_state(&container_state)?;
+            }
+        }
+        (ActionCategory::User, _) => {
+            // Keep the home directory, it may hold data created since the run
+            delete_user(command_runner(), target, false, verbose)?;
+        }
+        (ActionCategory::Group, _) => {
+            delete_group(command_runner(), target)?;
+        }
+        (ActionCategory::File, _) => {
+            let backup = Path::new(action.detail.as_deref().context("No backup recorded")?);
+            let target_path = Path::new(target);
+            if !backup.exists() {
+                anyhow::bail!("Backup {:?} no longer exists", backup);
+            }
+            if backup.is_dir() {
+                if target_path.exists() {
+                    delete_dir(target_path)?;
+                }
+                copy_dir_all(backup, target_path)?;
+            } else {
+                copy_file(backup, target_path)?;
+            }
+            println!("{} Restored {:?} from {:?}", "[SUCCESS]".green(), target_path, backup);
+        }
+    }
+    Ok(())
+}
+
+fn undo_last_run(yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let Some(log_path) = list_run_logs()?.pop() else {
+        println!("{} No run logs found, nothing to undo", "[INFO]".blue());
+        return Ok(());
+    };
+
+    let mut log = load_run_log(&log_path)?;
+    if log.undone {
+        println!("{} Most recent run {:?} has already been undone", "[INFO]".blue(), log_path);
+        return Ok(());
+    }
+
+    println!("{} Undoing run {:?} ({} recorded actions)", "[INFO]".blue(), log_path, log.actions.len());
+
+    // Reverse in roughly the opposite order to how a run applies changes
+    let categories = [
+        ActionCategory::File,
+        ActionCategory::Container,
+        ActionCategory::User,
+        ActionCategory::Group,
+        ActionCategory::Service,
+        ActionCategory::Cargo,
+        ActionCategory::Npm,
+        ActionCategory::Pip,
+        ActionCategory::Flatpak,
+        ActionCategory::Package,
+    ];
+
+    let mut reversed = 0;
+    let mut manual: Vec<RunAction> = Vec::new();
+
+    for category in categories {
+        let (reversible, not_reversible): (Vec<&RunAction>, Vec<&RunAction>) = log.actions
+            .iter()
+            .rev()
+            .filter(|a| a.category == category)
+            .partition(|a| is_reversible(a));
+        manual.extend(not_reversible.into_iter().cloned());
+
+        if reversible.is_empty() {
+            continue;
+        }
+
+        println!("\n{} {} action(s) can be reversed:", "[INFO]".blue(), format!("{:?}", category).to_lowercase());
+        for action in &reversible {
+            println!("  - {}", describe_action(action));
+        }
+
+        if !ask_user_confirmation(&format!("Reverse these {} action(s)?", reversible.len()), yes, no, verbose)? {
+            println!("{} Skipping {} actions", "[INFO]".blue(), format!("{:?}", category).to_lowercase());
+            continue;
+        }
+
+        for action in reversible {
+            match reverse_action(action, verbose) {
+                Ok(()) => reversed += 1,
+                Err(e) => {
+                    println!("{} Failed to reverse {}: {}", "[WARN]".yellow(), describe_action(action), e);
+                    manual.push(action.clone());
+                }
+            }
+        }
+    }
+
+    if !manual.is_empty() {
+        println!("\n{} The following actions need to be reverted manually:", "[WARN]".yellow());
+        for action in &manual {
+            println!("  - {}", describe_action(action));
+        }
+    }
+
+    flush_daemon_reloads()?;
+
+    if reversed > 0 {
+        log.undone = true;
+        let content = serde_json::to_string_pretty(&log)
+            .context("Failed to serialize run log")?;
+        fs::write(&log_path, content)
+            .context("Failed to write run log")?;
+    }
+
+    println!("{} Undo completed", "[SUCCESS]".green());
+    println!("  - Reversed: {} actions", reversed);
+    println!("  - Manual: {} actions", manual.len());
+
+    Ok(())
+}
+
+fn resolve_run_log(spec: &str) -> Result<std::path::PathBuf> {
+    let offset = match spec {
+        "latest" => 1,
+        "previous" => 2,
+        path => return Ok(std::path::PathBuf::from(path)),
+    };
+
+    let logs = list_run_logs()?;
+    if logs.len() < offset {
+        anyhow::bail!("Cannot resolve '{}': only {} run log(s) in {:?}", spec, logs.len(), get_runs_dir()?);
+    }
+    Ok(logs[logs.len() - offset].clone())
+}
+
+fn compare_run_logs(old_spec: &str, new_spec: &str) -> Result<()> {
+    let old_path = resolve_run_log(old_spec)?;
+    let new_path = resolve_run_log(new_spec)?;
+    let old_log = load_run_log(&old_path)?;
+    let new_log = load_run_log(&new_path)?;
+
+    println!("{} Comparing runs:", "[INFO]".blue());
+    println!("  - Old: {:?} (started {}, {} actions)", old_path, old_log.started_at, old_log.actions.len());
+    println!("  - New: {:?} (started {}, {} actions)", new_path, new_log.started_at, new_log.actions.len());
+
+    let same = |a: &RunAction, b: &RunAction| a.category == b.category && a.kind == b.kind && a.target == b.target;
+    let only_new: Vec<&RunAction> = new_log.actions.iter()
+        .filter(|a| !old_log.actions.iter().any(|b| same(a, b)))
+        .collect();
+    let only_old: Vec<&RunAction> = old_log.actions.iter()
+        .filter(|a| !new_log.actions.iter().any(|b| same(a, b)))
+        .collect();
+
+    // Services whose enable/disable direction differs between the two runs
+    let flipped: Vec<(&RunAction, &RunAction)> = new_log.actions.iter()
+        .filter(|a| a.category == ActionCategory::Service)
+        .filter_map(|a| {
+            old_log.actions.iter()
+                .find(|b| b.category == ActionCategory::Service && b.target == a.target && b.kind != a.kind)
+                .map(|b| (b, a))
+        })
+        .collect();
+
+    for (label, actions) in [("Only in new run", &only_new), ("Only in old run", &only_old)] {
+        println!("\n{} {}:", "[INFO]".blue(), label);
+        if actions.is_empty() {
+            println!("  (none)");
+        }
+        for action in actions.iter() {
+            println!("  - {}", describe_action(action));
+        }
+    }
+
+    if !flipped.is_empty() {
+        println!("\n{} Services that flipped:", "[INFO]".blue());
+        for (old, new) in &flipped {
+            println!("  - {}: {:?} -> {:?}", new.target, old.kind, new.kind);
+        }
+    }
+
+    println!("\n{} Comparison completed", "[SUCCESS]".green());
+    println!("  - Only in new: {} actions", only_new.len());
+    println!("  - Only in old: {} actions", only_old.len());
+    println!("  - Flipped services: {}", flipped.len());
+
+    Ok(())
+}
+
+// ========================= FAST PATH =========================
+
+// Full discovery still runs at least this often even when config is unchanged
+const FAST_PATH_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+// Declarative files read during a run besides config.toml
+const DECLARATIVE_FILES: [&str; 9] = [
+    "config/system-packages.toml",
+    "config/flatpak-packages.toml",
+    "config/pip-packages.toml",
+    "config/npm-packages.toml",
+    "config/cargo-packages.toml",
+    "config/system-services.toml",
+    "config/user-services.toml",
+    "config/users-groups.toml",
+    "config/winapps-config.toml",
+];
+
+fn get_run_state_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("run_state.json"))
+}
+
+fn load_run_state() -> Result<RunState> {
+    if state_disabled() {
+        return Ok(RunState::default());
+    }
+
+    let state_file = get_run_state_path()?;
+
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read run state file")?;
+        let state: RunState = serde_json::from_str(&content)
+            .context("Failed to parse run state file")?;
+        Ok(state)
+    } else {
+        Ok(RunState::default())
+    }
+}
+
+fn save_run_state(state: &RunState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let state_file = get_run_state_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize run state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write run state file")?;
+    Ok(())
+}
+
+fn get_run_checkpoint_path() -> Result<std::path::PathBuf> {
+    Ok(get_run_state_path()?.with_file_name("last_run.json"))
+}
+
+fn save_run_checkpoint(checkpoint: &RunCheckpoint) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let content = serde_json::to_string_pretty(checkpoint)
+        .context("Failed to serialize run checkpoint")?;
+    fs::write(get_run_checkpoint_path()?, content)
+        .context("Failed to write run checkpoint")?;
+    Ok(())
+}
+
+fn clear_run_checkpoint() -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let path = get_run_checkpoint_path()?;
+    if path.exists() {
+        fs::remove_file(&path).context("Failed to remove run checkpoint")?;
+    }
+    Ok(())
+}
+
+/// Subsystems the last (failed) run completed, if it ran against the same effective config.
+fn resumable_subsystems(config_hash: &str) -> Result<Vec<String>> {
+    let path = get_run_checkpoint_path()?;
+    if !path.exists() {
+        println!("{} --resume: no interrupted run to resume, running everything", "[INFO]".blue());
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(&path)
+        .context("Failed to read run checkpoint")?;
+    let checkpoint: RunCheckpoint = serde_json::from_str(&content)
+        .context("Failed to parse run checkpoint")?;
+    if checkpoint.config_hash != config_hash {
+        println!("{} --resume: config changed since the interrupted run, running everything", "[WARN]".yellow());
+        return Ok(Vec::new());
+    }
+
+    Ok(checkpoint.completed)
+}
+
+/// Hash of the effective config (after profile merge) plus every declarative file.
+fn generate_effective_config_hash(effective_config: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(effective_config.as_bytes());
+    for file in DECLARATIVE_FILES {
+        hasher.update(file.as_bytes());
+        match fs::read(file) {
+            Ok(content) => hasher.update(&content),
+            Err(_) => hasher.update(b"<missing>"),
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn fast_path_applies(effective_config: &str, config: &Config, verbose: bool) -> Result<bool> {
+    let state = load_run_state()?;
+    let (Some(stored_hash), Some(last_success)) = (&state.config_hash, state.last_success) else {
+        println!("{} No previous successful run recorded, running full synchronization", "[INFO]".blue());
+        return Ok(false);
+    };
+
+    if *stored_hash != generate_effective_config_hash(effective_config) {
+        println!("{} Configuration changed since last successful run, running full synchronization", "[INFO]".blue());
+        return Ok(false);
+    }
+
+    if get_current_timestamp().saturating_sub(last_success) > FAST_PATH_MAX_AGE_SECS {
+        println!("{} Last full run is older than 24h, running full synchronization", "[INFO]".blue());
+        return Ok(false);
+    }
+
+    match verify_desired_state(config, verbose) {
+        Ok(None) => Ok(true),
+        Ok(Some(reason)) => {
+            println!("{} Drift suspected ({}), running full synchronization", "[INFO]".blue(), reason);
+            Ok(false)
+        }
+        Err(e) => {
+            println!("{} Quick verification failed ({}), running full synchronization", "[WARN]".yellow(), e);
+            Ok(false)
+        }
+    }
+}
+
+/// Cheap checks (one command per subsystem) that declared state is still in place.
+/// Returns the first mismatch found, if any.
+fn verify_desired_state(config: &Config, verbose: bool) -> Result<Option<String>> {
+    let extra = config.packages.as_ref();
+
+    // System packages: a single rpm query for everything declared
+    let mut packages = load_package_list("config/system-packages.toml")?;
+    packages.extend(extra.and_then(|p| p.system.clone()).unwrap_or_default());
+    let packages = resolve_declared_system_packages(command_runner(), &packages);
+    if !packages.is_empty() {
+        let mut cmd = vec!["rpm", "-q", "--quiet"];
+        cmd.extend(packages.iter().map(|p| p.as_str()));
+        let output = spawn_output(&cmd)?;
+        if !output.status.success() {
+            return Ok(Some("declared system packages missing".to_string()));
+        }
+    }
+    debug!(verbose, "{} system packages verified", packages.len());
+
+    // Flatpaks
+    let mut flatpaks = load_package_list("config/flatpak-packages.toml")?;
+    flatpaks.extend(extra.and_then(|p| p.flatpak.clone()).unwrap_or_default());
+    if !flatpaks.is_empty() {
+        let output = spawn_output(&["flatpak", "list", "--app", "--columns=application"])?;
+        let installed = String::from_utf8_lossy(&output.stdout);
+        let installed: Vec<&str> = installed.lines().map(|l| l.trim()).collect();
+        if let Some(app) = flatpaks.iter().find(|app| !installed.contains(&parse_flatpak_package(app).1)) {
+            return Ok(Some(format!("Flatpak {} not installed", app)));
+        }
+    }
+
+    // Services: one is-enabled query per unit; a missing unit prints nothing on stdout, so a batched
+    // query would shift every later state onto the wrong name
+    let system_services = load_system_services_config().ok().and_then(|c| c.services).unwrap_or_default();
+    let user_services = load_user_services_config().ok().and_then(|c| c.services).unwrap_or_default();
+    for (services, user) in [(&system_services, false), (&user_services, true)] {
+        if services.is_empty() {
+            continue;
+        }
+        for (name, desired) in services {
+            let mut cmd = vec!["systemctl"];
+            if user {
+                cmd.push("--user");
+            }
+            cmd.extend(["is-enabled", name.as_str()]);
+            let output = spawn_output(&cmd)?;
+            let state = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let enabled = matches!(state.as_str(), "enabled" | "enabled-runtime" | "static" | "alias" | "generated");
+            if enabled != desired.enabled {
+                return Ok(Some(format!("service {} is {}", name, if state.is_empty() { "not found" } else { &state })));
+            }
+        }
+    }
+
+    // Containers: all configured containers exist
+    if let Some(containers) = config.podman.as_ref().and_then(|p| p.containers.as_ref()) {
+        let output = spawn_output(&["podman", "ps", "-a", "--format", "{{.Names}}"])?;
+        let existing = String::from_utf8_lossy(&output.stdout);
+        let existing: Vec<&str> = existing.lines().collect();
+        if let Some(container) = containers.iter().find(|c| !existing.contains(&c.name.as_str())) {
+            return Ok(Some(format!("container {} does not exist", container.name)));
+        }
+    }
+
+    Ok(None)
+}
+
+// ========================= MANAGED FILES =========================
+
+fn get_files_state_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("files_state.json"))
+}
+
+fn load_files_state() -> Result<FilesState> {
+    if state_disabled() {
+        return Ok(FilesState::default());
+    }
+
+    let state_file = get_files_state_path()?;
+
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read files state file")?;
+        let state: FilesState = serde_json::from_str(&content)
+            .context("Failed to parse files state file")?;
+        Ok(state)
+    } else {
+        Ok(FilesState::default())
+    }
+}
+
+fn save_files_state(state: &FilesState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let state_file = get_files_state_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize files state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write files state file")?;
+    Ok(())
+}
+
+fn get_file_backups_dir() -> Result<std::path::PathBuf> {
+    let dir = state_dir()?
+        .join("file_backups");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Files outside $HOME, or with an explicit owner/group, are written through sudo.
+fn file_needs_sudo(file: &ManagedFile) -> bool {
+    let in_home = dirs::home_dir().is_some_and(|home| Path::new(&file.path).starts_with(home));
+    !in_home || file.owner.is_some() || file.group.is_some()
+}
+
+fn managed_file_content(file: &ManagedFile, vars: &HashMap<String, String>) -> Result<Vec<u8>> {
+    let content = match (&file.content, &file.source) {
+        (Some(content), None) => content.as_bytes().to_vec(),
+        (None, Some(source)) => fs::read(source)
+            .with_context(|| format!("Failed to read source {} for {}", source, file.path))?,
+        _ => return Err(FedoraForgeError::validation(format!(
+            "File entry {} must set exactly one of 'content' or 'source'",
+            file.path
+        ))),
+    };
+
+    if !file.template.unwrap_or(false) {
+        return Ok(content);
+    }
+    let template = String::from_utf8(content)
+        .with_context(|| format!("Template for {} is not valid UTF-8", file.path))?;
+    let rendered = render_template(&template, vars)
+        .with_context(|| format!("Failed to render template for {}", file.path))?;
+    Ok(rendered.into_bytes())
+}
+
+/// Variables available to templates: `hostname`, `user`, `home`, plus everything in `[vars]`
+/// (which may override the built-ins).
+fn template_vars(config: &Config) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    let hostname = match &config.system.hostname {
+        Some(hostname) => hostname.clone(),
+        None => fs::read_to_string("/etc/hostname").unwrap_or_default().trim().to_string(),
+    };
+    vars.insert("hostname".to_string(), hostname);
+    vars.insert("user".to_string(), user_name().unwrap_or_default());
+    let home_dir = user_home_dir()?;
+    vars.insert("home".to_string(), home_dir.to_string_lossy().to_string());
+    if let Some(declared) = &config.vars {
+        vars.extend(declared.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    Ok(vars)
+}
+
+/// Whether SELinux is enabled (`selinuxenabled` exits 0). Checked once per run.
+fn selinux_enabled() -> bool {
+    static ENABLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        spawn_output(&["selinuxenabled"]).is_ok_and(|output| output.status.success())
+    })
+}
+
+/// Resets the SELinux context of a system file we wrote. Files staged in a user directory and moved (or
+/// written through tee) can keep a label services are not allowed to read. Best effort: a
+/// failure is only a warning.
+fn restore_selinux_context(path: &str) {
+    if !selinux_enabled() {
+        return;
+    }
+    if let Err(e) = run_command(&["sudo", "restorecon", path], &format!("Restoring SELinux context of {}", path)) {
+        println!("{} Could not restore SELinux context of {}: {}", "[WARN]".yellow(), path, e);
+    }
+}
+
+/// Reads a file, falling back to `sudo cat` for root-only files such as /etc/crypttab.
+fn read_file_maybe_privileged(path: &Path) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let path_str = path.to_str().context("Invalid file path")?;
+            let output = spawn_output(&["sudo", "cat", path_str])?;
+            if !output.status.success() {
+                return Err(FedoraForgeError::command_failed(&["sudo", "cat", path_str], &output).into());
+            }
+            Ok(output.stdout)
+        }
+        Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to read {:?}", path))),
+    }
+}
+
+/// "<mode> <owner> <group>" as reported by stat, e.g. "644 root root".
+fn file_metadata(path: &Path) -> Result<String> {
+    let path_str = path.to_str().context("Invalid file path")?;
+    let output = spawn_output(&["stat", "-c", "%a %U %G", path_str])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["stat", "-c", "%a %U %G", path_str], &output).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn normalize_mode(mode: &str) -> String {
+    let trimmed = mode.trim_start_matches('0');
+    if trimmed.is_empty() { "0".to_string() } else { trimmed.to_string() }
+}
+
+fn file_metadata_matches(file: &ManagedFile, metadata: &str) -> bool {
+    let mut fields = metadata.split_whitespace();
+    let (mode, owner, group) = (fields.next().unwrap_or(""), fields.next().unwrap_or(""), fields.next().unwrap_or(""));
+    file.mode.as_deref().is_none_or(|m| normalize_mode(m) == mode)
+        && file.owner.as_deref().is_none_or(|o| o == owner)
+        && file.group.as_deref().is_none_or(|g| g == group)
+}
+
+// Numbers staged files so several can be in flight within one process
+static STAGED_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A new file only the invoking user can read, holding content on its way to its real path. It lives
+/// in the cache dir and is created exclusively, so nobody can read it or plant one at its path first.
+fn stage_private_file(name: &str, content: &[u8]) -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let dir = cache_dir()?.join("staging");
+    fs::create_dir_all(&dir).context("Failed to create staging directory")?;
+    let path = dir.join(format!("{}-{}-{}", name, std::process::id(), STAGED_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create staged file {:?}", path))?;
+    file.write_all(content).with_context(|| format!("Failed to write staged file {:?}", path))?;
+    Ok(path)
+}
+
+/// Like `stage_private_file`, but a new directory only the invoking user can enter (mode 0700).
+fn stage_private_dir(name: &str) -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+    let parent = cache_dir()?.join("staging");
+    fs::create_dir_all(&parent).context("Failed to create staging directory")?;
+    let dir = parent.join(format!("{}-{}-{}", name, std::process::id(), STAGED_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create staging directory {:?}", dir))?;
+    Ok(dir)
+}
+
+/// Installs `content` at `path` with `install -D`, so parent directories, mode and
+/// ownership are set in one step.
+fn install_file_content(path: &str, content: &[u8], mode: &str, owner: Option<&str>, group: Option<&str>, sudo: bool) -> Result<()> {
+    if dry_run_skip(&format!("write {} (mode {})", path, mode)) {
+        return Ok(());
+    }
+    let file_name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    let staging = stage_private_file(file_name, content)?;
+    let staging_str = staging.to_str().context("Invalid staging file path")?.to_string();
+
+    let mut cmd_args: Vec<&str> = Vec::new();
+    if sudo {
+        cmd_args.push("sudo");
+    }
+    cmd_args.extend(["install", "-D", "-m", mode]);
+    if let Some(owner) = owner {
+        cmd_args.extend(["-o", owner]);
+    }
+    if let Some(group) = group {
+        cmd_args.extend(["-g", group]);
+    }
+    cmd_args.extend([staging_str.as_str(), path]);
+
+    let result = run_command(&cmd_args, &format!("Writing {}", path));
+    let _ = fs::remove_file(&staging);
+    if result.is_ok() && sudo {
+        restore_selinux_context(path);
+    }
+    result
+}
+
+fn backup_managed_file(path: &Path) -> Result<(String, String)> {
+    let content = read_file_maybe_privileged(path)?;
+    let metadata = file_metadata(path)?;
+    let name = path.to_string_lossy().trim_start_matches('/').replace('/', "_");
+    let backup = get_file_backups_dir()?.join(format!("{}-{}", name, get_current_timestamp()));
+    write_file(&backup, content).with_context(|| format!("Failed to back up {:?}", path))?;
+    Ok((backup.to_string_lossy().to_string(), metadata))
+}
+
+fn sync_managed_files(files: &[ManagedFile], vars: &HashMap<String, String>, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    let mut state = load_files_state()?;
+    if files.is_empty() && state.files.is_empty() {
+        return Ok(SubsystemResult::default());
+    }
+
+    println!("{} Synchronizing managed files...", "[INFO]".blue());
+    let timestamp = get_current_timestamp();
+    let mut result = SubsystemResult::default();
+
+    for file in files {
+        let path = Path::new(&file.path);
+        if !path.is_absolute() {
+            return Err(FedoraForgeError::validation(format!("File path '{}' must be absolute", file.path)));
+        }
+
+        let content = managed_file_content(file, vars)?;
+        let mut hasher = Sha256::new();
+        hasher.update(&content);
+        let desired_hash = format!("{:x}", hasher.finalize());
+
+        let exists = path.exists();
+        let current_metadata = if exists { Some(file_metadata(path)?) } else { None };
+        if exists {
+            let mut hasher = Sha256::new();
+            hasher.update(read_file_maybe_privileged(path)?);
+            let current_hash = format!("{:x}", hasher.finalize());
+            let metadata_ok = current_metadata.as_deref().is_some_and(|m| file_metadata_matches(file, m));
+
+            if current_hash == desired_hash && metadata_ok {
+                debug!(verbose, "{} is up to date", file.path);
+                if !state.files.contains_key(&file.path) {
+                    // Already correct before we managed it: keep a copy so removal leaves it as found
+                    let (backup, backup_metadata) = backup_managed_file(path)?;
+                    state.files.insert(file.path.clone(), ManagedFileRecord {
+                        hash: desired_hash,
+                        backup: Some(backup),
+                        backup_metadata: Some(backup_metadata),
+                        managed_at: timestamp,
+                    });
+                }
+                result.skipped += 1;
+                continue;
+            }
+
+            println!("{} {} differs from config", "[INFO]".blue(), file.path);
+            preview_file_change(path, &content);
+            if !ask_user_confirmation(&format!("Overwrite {}?", file.path), yes, no, verbose)? {
+                println!("{} Skipping {}", "[INFO]".blue(), file.path);
+                result.skipped += 1;
+                continue;
+            }
+        }
+
+        // Only the pre-management content is worth keeping; later versions came from config
+        let previous = state.files.get(&file.path).cloned();
+        let (backup, backup_metadata) = match &previous {
+            Some(record) => (record.backup.clone(), record.backup_metadata.clone()),
+            None if exists => {
+                let (backup, metadata) = backup_managed_file(path)?;
+                (Some(backup), Some(metadata))
+            }
+            None => (None, None),
+        };
+
+        // Keep the existing mode when none is declared
+        let existing_mode = current_metadata.as_deref().and_then(|m| m.split_whitespace().next()).map(str::to_string);
+        let mode = file.mode.clone().or(existing_mode).unwrap_or_else(|| "0644".to_string());
+        install_file_content(&file.path, &content, &mode, file.owner.as_deref(), file.group.as_deref(), file_needs_sudo(file))?;
+
+        if exists {
+            record_action(ActionCategory::File, ActionKind::Overwrite, &file.path, backup.clone());
+            result.modified += 1;
+        } else {
+            record_action(ActionCategory::File, ActionKind::Create, &file.path, None);
+            result.created += 1;
+        }
+        state.files.insert(file.path.clone(), ManagedFileRecord {
+            hash: desired_hash,
+            backup,
+            backup_metadata,
+            managed_at: timestamp,
+        });
+    }
+
+    // Entries removed from config go back to how they were found
+    let stale: Vec<(String, ManagedFileRecord)> = state.files.iter()
+        .filter(|(path, _)| !files.iter().any(|f| &f.path == *path))
+        .map(|(path, record)| (path.clone(), record.clone()))
+        .collect();
+
+    if !stale.is_empty() && no_removals {
+        println!("{} --no-removals: leaving {} files removed from config in place", "[INFO]".blue(), stale.len());
+    } else if !stale.is_empty() {
+        println!("{} Found {} files removed from config:", "[INFO]".blue(), stale.len());
+        for (path, record) in &stale {
+            match &record.backup {
+                Some(_) => println!("  - {} (restore original)", path),
+                None => println!("  - {} (delete, created by FedoraForge)", path),
+            }
+        }
+
+        if ask_user_confirmation("Restore/delete these files?", yes, no, verbose)? {
+            for (path, record) in &stale {
+                let in_home = dirs::home_dir().is_some_and(|home| Path::new(path).starts_with(home));
+                match &record.backup {
+                    Some(backup) => {
+                        let content = fs::read(backup).with_context(|| format!("Failed to read backup {}", backup))?;
+                        let metadata = record.backup_metadata.clone().unwrap_or_default();
+                        let mut fields = metadata.split_whitespace();
+                        let mode = fields.next().unwrap_or("644");
+                        let (owner, group) = (fields.next(), fields.next());
+                        install_file_content(path, &content, mode, owner, group, !in_home || owner.is_some())?;
+                    }
+                    None if Path::new(path).exists() => {
+                        if in_home {
+                            delete_file(path).with_context(|| format!("Failed to remove {}", path))?;
+                        } else {
+                            run_command(&["sudo", "rm", "-f", path], &format!("Removing {}", path))?;
+                        }
+                    }
+                    None => {}
+                }
+                state.files.remove(path);
+                result.removed += 1;
+            }
+        }
+    }
+
+    save_files_state(&state)?;
+
+    println!("{} Managed files synchronized", "[SUCCESS]".green());
+    Ok(result)
+}
+
+// ========================= PACKAGE MANIFEST =========================
+
+#[derive(Serialize, Debug, Default)]
+struct PackageManifest {
+    system: Vec<String>,
+    flatpak: Vec<String>,
+    pip: Vec<String>,
+    npm: Vec<String>,
+    cargo: Vec<String>,
+}
+
+/// Read-only: aggregates the package files as declared, without discovery or auto-creation.
+fn export_packages(format: OutputFormat) -> Result<()> {
+    let read = |path: &str| -> Result<Vec<String>> {
+        let mut packages: Vec<String> = read_declarative_file::<PackageList>(path)?
+            .map(|list| list.packages.iter().map(PackageEntry::spec).collect())
+            .unwrap_or_default();
+        packages.sort();
+        packages.dedup();
+        Ok(packages)
+    };
+
+    let manifest = PackageManifest {
+        system: read("config/system-packages.toml")?,
+        flatpak: read("config/flatpak-packages.toml")?,
+        pip: read("config/pip-packages.toml")?,
+        npm: read("config/npm-packages.toml")?,
+        cargo: read("config/cargo-packages.toml")?,
+    };
+
+    let rendered = match format {
+        OutputFormat::Toml => toml::to_string_pretty(&manifest)
+            .context("Failed to serialize package manifest to TOML")?,
+        OutputFormat::Json => serde_json::to_string_pretty(&manifest)
+            .context("Failed to serialize package manifest to JSON")?,
+    };
+    println!("{}", rendered);
+    Ok(())
+}
+
+// ========================= SELF TEST =========================
+
+/// --self-test: runs the discovery parsers over sample command output and system files
+/// compiled into the binary, so parsing can be checked before pointing FedoraForge at a real machine.
+fn run_self_test() -> Result<()> {
+    println!("{} Running parser self-test against bundled fixtures...", "[INFO]".blue());
+
+    let mut checks: Vec<(&str, bool)> = vec![
+        ("dnf repoquery names", parse_name_lines(include_str!("../fixtures/dnf-repoquery.txt")) == ["git", "htop", "vim-enhanced"]),
+        ("flatpak list", parse_flatpak_list(include_str!("../fixtures/flatpak-list.txt")) == ["com.valvesoftware.Steam", "org.mozilla.firefox"]),
+        ("pip freeze", parse_pip_freeze(include_str!("../fixtures/pip-freeze.txt")) == ["black", "httpie", "requests"]),
+        ("npm list", parse_npm_list(include_str!("../fixtures/npm-list.json")).is_ok_and(|p| p == ["prettier", "typescript"])),
+        ("cargo install --list", parse_cargo_install_list(include_str!("../fixtures/cargo-install-list.txt")) == ["bat", "ripgrep", "starship"]),
+        ("flatpak package with remote", parse_flatpak_package("flathub-beta:com.valvesoftware.Steam") == ("flathub-beta", "com.valvesoftware.Steam")),
+        ("flatpak package without remote", parse_flatpak_package("org.mozilla.firefox") == ("flathub", "org.mozilla.firefox")),
+        ("compose images", parse_compose_images("services:\n  windows:\n    image: \"ghcr.io/dockur/windows\"  # 4-6 GB on first start\n  rdp:\n    image: ghcr.io/dockur/windows\n")
+            == ["ghcr.io/dockur/windows"]),
+    ];
+
+    let mut unit_files = parse_service_unit_files(include_str!("../fixtures/systemctl-list-unit-files.txt"));
+    unit_files.sort();
+    checks.push(("systemctl list-unit-files", unit_files == [
+        ("cups".to_string(), false),
+        ("getty@".to_string(), true),
+        ("sshd".to_string(), true),
+    ]));
+
+    let active = parse_active_services(include_str!("../fixtures/systemctl-list-units.txt"));
+    checks.push(("systemctl list-units", active.len() == 3
+        && active.get("sshd") == Some(&true)
+        && active.get("cups") == Some(&false)));
+
+    let passwd = parse_passwd(include_str!("../fixtures/passwd"));
+    checks.push(("/etc/passwd", passwd.len() == 6 && passwd.get(4) == Some(&PasswdEntry {
+        name: "bob".to_string(),
+        uid: 1001,
+        gid: 1001,
+        comment: String::new(),
+        home: "/home/bob".to_string(),
+        shell: "/usr/bin/zsh".to_string(),
+    })));
+    checks.push(("/etc/passwd GECOS with commas", passwd.get(5).is_some_and(|carol| {
+        carol.comment == "Carol Smith,Room 12,555-1234," && carol.shell == "/bin/fish"
+    })));
+    // Written verbatim; the old `echo '...' | sudo tee` path cut the line at the quote
+    let fstab_entry = "LABEL=data /mnt/my'drive ext4 defaults 0 2";
+    let fstab = upsert_table_entry("UUID=1 / btrfs defaults 0 0\nLABEL=data /mnt/my'drive ext4 noauto 0 2\n", fstab_entry, |line| {
+        line.split_whitespace().nth(1) == Some("/mnt/my'drive")
+    });
+    checks.push(("fstab entry with a quote in the mount point", fstab == format!("UUID=1 / btrfs defaults 0 0\n{}\n", fstab_entry)));
+    checks.push(("/etc/passwd truncated line", parse_passwd_line("dave:x:1003:1003:Dave").is_none()));
+    checks.push(("crypt(3) password hashes", is_crypt_hash("$6$rounds=5000$saltsalt$Kz3vB0k2A8m.H1m/9NnL0d")
+        && is_crypt_hash("$y$j9T$F5Jx5fExrKuPp53xLKQ..1$X3DX6M94c7o.9agCG9G317fhZg9SqC.5i5rd.RhAtQ7")
+        && !is_crypt_hash("correct horse battery staple")
+        && !is_crypt_hash("!$6$saltsalt$Kz3vB0k2A8m")
+        && !is_crypt_hash("$6$saltsalt$")));
+
+    checks.push(("localectl status", parse_localectl_status(include_str!("../fixtures/localectl-status.txt"))
+        == (Some("en_US.UTF-8".to_string()), Some("us".to_string()))));
+    checks.push(("localectl status with unset keymap", parse_localectl_status("System Locale: LANG=C.UTF-8\n    VC Keymap: (unset)\n")
+        == (Some("C.UTF-8".to_string()), None)));
+
+    let (services, ports) = parse_firewall_list_all(include_str!("../fixtures/firewall-cmd-list-all.txt"));
+    checks.push(("firewall-cmd --list-all", services == ["dhcpv6-client", "samba-client", "ssh"]
+        && ports == ["1025-65535/tcp", "1025-65535/udp"]));
+
+    let group = parse_group(include_str!("../fixtures/group"));
+    checks.push(("/etc/group", group.len() == 5
+        && group.first().is_some_and(|g| g.members.is_empty())
+        && group.get(2) == Some(&GroupEntry {
+            name: "render".to_string(),
+            gid: 105,
+            members: vec!["alice".to_string(), "bob".to_string()],
+        })));
+
+    let failed = checks.iter().filter(|(_, passed)| !passed).count();
+    for (name, passed) in &checks {
+        if *passed {
+            println!("{} {}", "[SUCCESS]".green(), name);
+        } else {
+            println!("{} {}", "[ERROR]".red(), name);
+        }
+    }
+
+    if failed > 0 {
+        return Err(FedoraForgeError::validation(format!("Self-test failed: {} of {} checks", failed, checks.len())));
+    }
+    println!("{} All {} parser checks passed", "[SUCCESS]".green(), checks.len());
+    Ok(())
+}
+
+// ========================= DRIFT REPORT =========================
+
+/// Parse a declarative file without creating it when missing (drift reporting is read-only).
+fn read_declarative_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<Option<T>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path))?;
+    let parsed = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path))?;
+    Ok(Some(parsed))
+}
+
+
+type PackageDiscovery = fn() -> Result<Vec<String>>;
+
+fn collect_drift(config: &Config, verbose: bool) -> Result<Vec<DriftItem>> {
+    let mut items = Vec::new();
+    let extra = config.packages.as_ref();
+
+    // Package managers
+    let package_sources: [(&str, &str, PackageDiscovery, Vec<String>); 5] = [
+        ("system-packages", "config/system-packages.toml", || get_user_installed_packages(command_runner()), extra.and_then(|p| p.system.clone()).unwrap_or_default()),
+        ("flatpak", "config/flatpak-packages.toml", get_installed_flatpaks, extra.and_then(|p| p.flatpak.clone()).unwrap_or_default()),
+        ("pip", "config/pip-packages.toml", get_installed_pip_packages, Vec::new()),
+        ("npm", "config/npm-packages.toml", get_installed_npm_packages, Vec::new()),
+        ("cargo", "config/cargo-packages.toml", get_installed_cargo_packages, Vec::new()),
+    ];
+    for (subsystem, path, discover, extra_packages) in package_sources {
+        let Some(list) = read_declarative_file::<PackageList>(path)? else {
+            debug!(verbose, "{} not found, skipping {}", path, subsystem);
+            continue;
+        };
+        let mut declared = list.names();
+        declared.extend(extra_packages);
+        if subsystem == "system-packages" {
+            declared = resolve_declared_system_packages(command_runner(), &declared);
+        }
+        match discover() {
+            Ok(installed) => push_list_drift(&mut items, subsystem, &declared, &installed),
+            Err(e) => println!("{} Skipping {} drift: {}", "[WARN]".yellow(), subsystem, e),
+        }
+    }
+
+    // Services (declared entries only)
+    let system_services = read_declarative_file::<SystemServicesConfig>("config/system-services.toml")?.and_then(|c| c.services);
+    let user_services = read_declarative_file::<UserServicesConfig>("config/user-services.toml")?.and_then(|c| c.services);
+    for (subsystem, declared, user) in [("system-services", system_services, false), ("user-services", user_services, true)] {
+        let Some(declared) = declared else {
+            continue;
+        };
+        let current = if user { get_current_user_services(verbose)? } else { get_current_system_services(verbose)? };
+        for (name, desired) in &declared {
+            let describe = |enabled: bool, active: bool| format!(
+                "{}, {}",
+                if enabled { "enabled" } else { "disabled" },
+                if active { "started" } else { "stopped" }
+            );
+            let actual = match current.get(name) {
+                Some(info) if info.enabled == desired.enabled && info.active == desired.started => continue,
+                Some(info) => describe(info.enabled, info.active),
+                None => "not found".to_string(),
+            };
+            items.push(DriftItem {
+                subsystem: subsystem.to_string(),
+                item: name.clone(),
+                expected: describe(desired.enabled, desired.started),
+                actual,
+            });
+        }
+    }
+
+    // Users and groups
+    if let Some(users_groups) = read_declarative_file::<UsersGroupsConfig>("config/users-groups.toml")? {
+        users_groups.apply_id_ranges()?;
+        let declared_users: Vec<String> = users_groups.users.as_ref().map(|u| u.keys().cloned().collect()).unwrap_or_default();
+        let current_users: Vec<String> = get_current_users(command_runner(), &users_groups.out_of_range_users(), verbose)?.into_keys().collect();
+        push_list_drift(&mut items, "users", &declared_users, &current_users);
+
+        let declared_groups: Vec<String> = users_groups.groups.as_ref().map(|g| g.keys().cloned().collect()).unwrap_or_default();
+        let current_groups: Vec<String> = get_current_groups(&users_groups.out_of_range_groups(), verbose)?.into_keys().collect();
+        push_list_drift(&mut items, "groups", &declared_groups, &current_groups);
+    }
+
+    // Containers
+    if let Some(podman) = &config.podman {
+        let configured: Vec<String> = podman.containers.as_ref()
+            .map(|c| c.iter().map(|c| c.name.clone()).collect())
+            .unwrap_or_default();
+        let output = spawn_output(&["podman", "ps", "-a", "--filter", "label=managed-by=fedoraforge", "--format", "{{.Names}}"]);
+        match output {
+            Ok(output) => {
+                let managed: Vec<String> = String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect();
+                push_list_drift(&mut items, "containers", &configured, &managed);
+            }
+            Err(e) => println!("{} Skipping container drift: {}", "[WARN]".yellow(), e),
+        }
+    }
+
+    items.sort_by(|a, b| (&a.subsystem, &a.item).cmp(&(&b.subsystem, &b.item)));
+    Ok(items)
+}
+
+/// --list-orphans: items FedoraForge manages that config no longer declares, plus items on the
+/// system that config doesn't declare, without the interactive removal prompts.
+fn list_orphans(args: &Args) -> Result<()> {
+    const REMOVED: &str = "managed, removed from config";
+    const UNDECLARED: &str = "on system, not declared";
+
+    let (_, config) = read_effective_config(args)?;
+    let mut orphans: Vec<(String, String, &str)> = Vec::new();
+    let mut push = |category: &str, item: &str, reason: &'static str| {
+        if !orphans.iter().any(|(c, i, _)| c == category && i == item) {
+            orphans.push((category.to_string(), item.to_string(), reason));
+        }
+    };
+
+    // Managed in state but gone from config
+    let configured_containers: Vec<&str> = config.podman.as_ref()
+        .and_then(|p| p.containers.as_ref())
+        .map(|c| c.iter().map(|c| c.name.as_str()).collect())
+        .unwrap_or_default();
+    for (name, info) in &load_container_state()?.containers {
+        if info.managed && !configured_containers.contains(&name.as_str()) {
+            push("containers", name, REMOVED);
+        }
+    }
+
+    let users_groups = read_declarative_file::<UsersGroupsConfig>("config/users-groups.toml")?;
+    let users_groups_state = load_users_groups_state()?;
+    let declared_users = users_groups.as_ref().and_then(|c| c.users.as_ref());
+    let declared_groups = users_groups.as_ref().and_then(|c| c.groups.as_ref());
+    for name in users_groups_state.managed_users.keys() {
+        if !declared_users.is_some_and(|u| u.contains_key(name)) {
+            push("users", name, REMOVED);
+        }
+    }
+    for name in users_groups_state.managed_groups.keys() {
+        if !declared_groups.is_some_and(|g| g.contains_key(name)) {
+            push("groups", name, REMOVED);
+        }
+    }
+
+    let system_services = read_declarative_file::<SystemServicesConfig>("config/system-services.toml")?;
+    let user_services = read_declarative_file::<UserServicesConfig>("config/user-services.toml")?;
+    let declared_system: Vec<String> = system_services.as_ref()
+        .and_then(|c| c.custom_services.as_ref())
+        .map(|s| s.iter().map(|s| s.name.clone()).collect())
+        .unwrap_or_default();
+    let mut declared_user: Vec<String> = user_services.as_ref()
+        .and_then(|c| c.custom_services.as_ref())
+        .map(|s| s.iter().map(|s| s.name.clone()).collect())
+        .unwrap_or_default();
+    // Application autostart entries are installed as <app>-autostart custom services
+    declared_user.extend(user_services.as_ref()
+        .and_then(|c| c.applications.as_ref())
+        .map(|apps| apps.keys().map(|app| format!("{}-autostart", app)).collect::<Vec<_>>())
+        .unwrap_or_default());
+    let services_state = load_custom_services_state()?;
+    for name in services_state.system_services.keys().filter(|n| !declared_system.contains(n)) {
+        push("custom-system-services", name, REMOVED);
+    }
+    for name in services_state.user_services.keys().filter(|n| !declared_user.contains(n)) {
+        push("custom-user-services", name, REMOVED);
+    }
+
+    let declared_files: Vec<&str> = config.files.iter().flatten().map(|f| f.path.as_str()).collect();
+    for path in load_files_state()?.files.keys() {
+        if !declared_files.contains(&path.as_str()) {
+            push("files", path, REMOVED);
+        }
+    }
+
+    let declared_remotes = declared_flatpak_remotes(config.flatpak.as_ref());
+    for name in load_flatpak_remotes_state()?.remotes.keys() {
+        if !declared_remotes.iter().any(|r| &r.name == name) {
+            push("flatpak-remotes", name, REMOVED);
+        }
+    }
+
+    for name in load_dotfiles_state()?.bashrc_snippets.keys() {
+        if !Path::new(".bashrc.d").join(name).is_file() {
+            push("bashrc.d", name, REMOVED);
+        }
+    }
+
+    // On the system but not declared (same detection as --report-drift)
+    for item in collect_drift(&config, args.verbose)? {
+        if item.expected == "not declared" {
+            push(&item.subsystem, &item.item, UNDECLARED);
+        }
+    }
+
+    if orphans.is_empty() {
+        println!("{} No orphans found", "[SUCCESS]".green());
+        return Ok(());
+    }
+
+    orphans.sort();
+    println!("{} {} orphaned items:", "[INFO]".blue(), orphans.len());
+    let mut current_category = "";
+    for (category, item, reason) in &orphans {
+        if category != current_category {
+            println!("{}:", category);
+            current_category = category;
+        }
+        println!("  - {} ({})", item, reason);
+    }
+    Ok(())
+}
+
+/// Prints the pending changes per subsystem and asks once whether to apply them. Confirming
+/// pre-approves exactly the listed items, like a plan passed to --apply.
+fn preflight_confirm(config: &Config, args: &Args) -> Result<bool> {
+    println!("{} Planning changes (read-only)...", "[INFO]".blue());
+    let items = collect_drift(config, args.verbose)?;
+    let users_groups_state = load_users_groups_state()?;
+
+    let mut subsystems: Vec<&str> = items.iter().map(|i| i.subsystem.as_str()).collect();
+    subsystems.dedup();
+
+    println!("📋 Plan:");
+    if items.is_empty() {
+        println!("  - Packages, services, users/groups and containers already match the config");
+    }
+    for subsystem in subsystems {
+        let in_subsystem: Vec<&DriftItem> = items.iter().filter(|i| i.subsystem == subsystem).collect();
+        let missing = in_subsystem.iter().filter(|i| i.actual == "missing").count();
+        let undeclared = in_subsystem.iter().filter(|i| i.expected == "not declared").count();
+        let changed = in_subsystem.len() - missing - undeclared;
+
+        let mut parts = Vec::new();
+        if missing > 0 {
+            parts.push(format!("{} to install/create", missing));
+        }
+        if changed > 0 {
+            parts.push(format!("{} to change", changed));
+        }
+        if undeclared > 0 {
+            parts.push(format!("{} undeclared", undeclared));
+        }
+        println!("  - {}: {}", subsystem, parts.join(", "));
+        for item in in_subsystem {
+            let outcome = if item.expected != "not declared" {
+                format!("{} -> {}", item.actual, item.expected)
+            } else if !preflight_removes(&users_groups_state, &item.subsystem, &item.item) {
+                "added to config".to_string()
+            } else if args.no_removals {
+                "kept, --no-removals".to_string()
+            } else {
+                "removed".to_string()
+            };
+            println!("      {} ({})", item.item, outcome);
+        }
+    }
+
+    // Everything else is reconciled in place and reported as it runs
+    let others: Vec<&str> = resolve_apply_order(config, false)?
+        .into_iter()
+        .map(|s| s.name())
+        .filter(|name| !matches!(*name, "system_packages" | "flatpak" | "language_packages" | "services" | "users" | "containers"))
+        .collect();
+    println!("  - Also reconciled, prompting as usual: {}", others.join(", "));
+
+    if !ask_user_confirmation("Apply this plan? (only the listed changes skip their prompts)", args.yes, args.no, args.verbose)? {
+        return Ok(false);
+    }
+    let _ = APPLIED_PLAN.set(items);
+    Ok(true)
+}
+
+/// What a pre-approved undeclared item turns into: packages are kept and added to config, managed
+/// containers and previously managed users/groups are removed, other users/groups are added to config.
+fn preflight_removes(users_groups_state: &UsersGroupsState, subsystem: &str, item: &str) -> bool {
+    match subsystem {
+        "containers" => true,
+        "users" => users_groups_state.managed_users.contains_key(item),
+        "groups" => users_groups_state.managed_groups.contains_key(item),
+        _ => false,
+    }
+}
+
+fn write_plan(plan_path: &str, args: &Args) -> Result<()> {
+    println!("{} Computing plan (read-only)...", "[INFO]".blue());
+    let (effective_config, config) = read_effective_config(args)?;
+    let plan = PlanFile {
+        created_at: get_current_timestamp(),
+        config: args.config.clone(),
+        config_hash: generate_effective_config_hash(&effective_config),
+        items: collect_drift(&config, args.verbose)?,
+    };
+
+    for item in &plan.items {
+        println!("  - {} {}: {} -> {}", item.subsystem, item.item, item.actual, item.expected);
+    }
+    let content = serde_json::to_string_pretty(&plan)
+        .context("Failed to serialize plan")?;
+    fs::write(plan_path, content)
+        .with_context(|| format!("Failed to write plan {}", plan_path))?;
+
+    println!("{} Saved plan with {} changes to {}", "[SUCCESS]".green(), plan.items.len(), plan_path);
+    println!("Review it, then run: fedoraforge --apply {}", plan_path);
+    Ok(())
+}
+
+/// Checks a saved plan against the current config and system. A changed config invalidates the
+/// plan; system changes since planning are shown and need confirmation.
+fn check_plan(plan_path: &str, effective_config: &str, config: &Config, args: &Args) -> Result<bool> {
+    let content = fs::read_to_string(plan_path)
+        .with_context(|| format!("Failed to read plan {}", plan_path))?;
+    let plan: PlanFile = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse plan {}", plan_path))?;
+
+    if plan.config_hash != generate_effective_config_hash(effective_config) {
+        return Err(FedoraForgeError::validation(format!(
+            "Configuration changed since {} was created; run --plan again",
+            plan_path
+        )));
+    }
+
+    let current = collect_drift(config, args.verbose)?;
+    let resolved: Vec<&DriftItem> = plan.items.iter().filter(|i| !current.contains(i)).collect();
+    let new: Vec<&DriftItem> = current.iter().filter(|i| !plan.items.contains(i)).collect();
+    let _ = APPLIED_PLAN.set(plan.items.iter().filter(|i| current.contains(i)).cloned().collect());
+
+    if resolved.is_empty() && new.is_empty() {
+        println!("{} System unchanged since the plan was made, applying {} changes", "[INFO]".blue(), plan.items.len());
+        return Ok(true);
+    }
+
+    println!("{} The system changed since the plan was made:", "[WARN]".yellow());
+    for item in resolved {
+        println!("  - no longer needed: {} {} ({} -> {})", item.subsystem, item.item, item.actual, item.expected);
+    }
+    for item in new {
+        println!("  - not in plan: {} {} ({} -> {})", item.subsystem, item.item, item.actual, item.expected);
+    }
+    println!("{} Changes not in the plan are asked about as they come up", "[INFO]".blue());
+    ask_user_confirmation("Apply the planned changes that are still needed?", false, args.no, args.verbose)
+}
+
+/// Returns whether drift was found. Never prompts or changes the system.
+fn report_drift(args: &Args) -> Result<bool> {
+    println!("{} Checking for configuration drift (read-only)...", "[INFO]".blue());
+
+    let (_, config) = read_effective_config(args)?;
+    let items = collect_drift(&config, args.verbose)?;
+
+    let hostname = run_command_output(&["hostname"])
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_default();
+    let report = DriftReport {
+        generated_at: get_current_timestamp(),
+        hostname,
+        drift_found: !items.is_empty(),
+        items,
+    };
+
+    let report_path = state_dir()?
+        .join("drift_report.json");
+    if let Some(parent) = report_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let content = serde_json::to_string_pretty(&report)
+        .context("Failed to serialize drift report")?;
+    fs::write(&report_path, &content)
+        .context("Failed to write drift report")?;
+
+    if report.drift_found {
+        println!("\n{} Drift detected in {} item(s):", "[WARN]".yellow(), report.items.len());
+        for item in &report.items {
+            println!("  - [{}] {}: expected {}, found {}", item.subsystem, item.item, item.expected, item.actual);
+        }
+
+        if let Some(url) = &args.notify_webhook {
+            post_json_webhook(url, &report_path, "Drift report");
+        }
+    } else {
+        println!("{} No drift detected, system matches configuration", "[SUCCESS]".green());
+    }
+
+    println!("{} Drift report written to {:?}", "[INFO]".blue(), report_path);
+    Ok(report.drift_found)
+}
+
+/// --status: drift grouped by what an apply would do about it. Returns whether anything is out of sync.
+fn show_status(args: &Args) -> Result<bool> {
+    const CATEGORIES: [&str; 5] = ["install", "remove", "enable", "create", "modify"];
+
+    let (_, config) = read_effective_config(args)?;
+    let mut pending: Vec<(&str, String)> = Vec::new();
+
+    // Containers are classified by determine_container_action below
+    for item in collect_drift(&config, args.verbose)?.into_iter().filter(|i| i.subsystem != "containers") {
+        let category = match (item.subsystem.as_str(), item.expected.as_str()) {
+            (_, "not declared") => "remove",
+            ("users" | "groups", _) => "create",
+            (_, "installed") => "install",
+            (_, expected) if expected.starts_with("enabled") && !item.actual.starts_with("enabled") => "enable",
+            _ => "modify",
+        };
+        let detail = if item.expected == "installed" || item.expected == "not declared" {
+            String::new()
+        } else {
+            format!(" ({} -> {})", item.actual, item.expected)
+        };
+        pending.push((category, format!("[{}] {}{}", item.subsystem, item.item, detail)));
+    }
+
+    if let Some(containers) = config.podman.as_ref().and_then(|p| p.containers.as_ref()) {
+        let state = load_container_state()?;
+        let existing_containers = get_existing_containers(command_runner())?;
+        for container in containers {
+            let line = |reason: &str| format!("[containers] {} ({})", container.name, reason);
+            match determine_container_action(command_runner(), container, &state, &existing_containers, args)? {
+                ContainerAction::Skip => {}
+                ContainerAction::Create => pending.push(("create", line("new container"))),
+                ContainerAction::Update => pending.push(("modify", line("config changed"))),
+                ContainerAction::Recreate => pending.push(("modify", line("recreate"))),
+            }
+        }
+        for (name, info) in &state.containers {
+            if info.managed && !containers.iter().any(|c| &c.name == name) && existing_containers.contains_key(name) {
+                pending.push(("remove", format!("[containers] {}", name)));
+            }
+        }
+    }
+
+    if pending.is_empty() {
+        println!("{} System is in sync with configuration", "[SUCCESS]".green());
+        return Ok(false);
+    }
+
+    println!("{} {} pending change(s):", "[INFO]".blue(), pending.len());
+    for category in CATEGORIES {
+        let mut lines: Vec<&String> = pending.iter().filter(|(c, _)| *c == category).map(|(_, l)| l).collect();
+        if lines.is_empty() {
+            continue;
+        }
+        lines.sort();
+        println!("\n{} ({}):", category.to_uppercase(), lines.len());
+        for line in lines {
+            println!("  - {}", line);
+        }
+    }
+    Ok(true)
+}
+
+/// POSTs a JSON file to a webhook. Failures are only warnings.
+fn post_json_webhook(url: &str, json_file: &Path, what: &str) {
+    let data = format!("@{}", json_file.to_string_lossy());
+    let output = spawn_streamed(&["curl", "-fsS", "-X", "POST", "-H", "Content-Type: application/json", "--data-binary", &data, url]);
+    match output {
+        Ok(output) if output.status.success() => println!("{} {} sent to webhook", "[INFO]".blue(), what),
+        _ => println!("{} Failed to send {} to webhook", "[WARN]".yellow(), what.to_lowercase()),
+    }
+}
+
+// ========================= CACHE =========================
+
+/// `~/.cache/fedoraforge`, for anything that can be fetched again: clones, downloads. New features
+/// that cache data should write here so --clear-cache finds it. Created on first use.
+fn cache_dir() -> Result<std::path::PathBuf> {
+    let dir = dirs::cache_dir()
+        .context("Failed to get cache directory")?
+        .join("fedoraforge");
+    fs::create_dir_all(&dir).context("Failed to create cache directory")?;
+    Ok(dir)
+}
+
+/// Bytes used by a file or directory tree, without following symlinks.
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| disk_usage(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Removes the cache directory, the WinApps clone and temp files left by interrupted runs.
+/// State files in ~/.config/fedoraforge are never touched.
+fn clear_cache(yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let mut targets: Vec<std::path::PathBuf> = fs::read_dir(cache_dir()?)?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    targets.push(user_home_dir()?.join(".local/share/winapps"));
+    targets.extend(fs::read_dir(env::temp_dir())?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("fedoraforge-"))
+        .map(|entry| entry.path()));
+    targets.retain(|path| path.exists());
+
+    if targets.is_empty() {
+        println!("{} No cached data to clear", "[INFO]".blue());
+        return Ok(());
+    }
+
+    let sizes: Vec<u64> = targets.iter().map(|path| disk_usage(path)).collect();
+    println!("{} Cached data:", "[INFO]".blue());
+    for (path, size) in targets.iter().zip(&sizes) {
+        println!("  - {} ({})", path.display(), format_bytes(*size));
+    }
+    if !ask_user_confirmation(&format!("Remove these ({} total)?", format_bytes(sizes.iter().sum())), yes, no, verbose)? {
+        println!("{} Cache left as-is", "[INFO]".blue());
+        return Ok(());
+    }
+
+    let mut reclaimed = 0;
+    for (path, size) in targets.iter().zip(&sizes) {
+        if dry_run_skip(&format!("remove {}", path.display())) {
+            continue;
+        }
+        let removed = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        match removed {
+            Ok(()) => {
+                debug!(verbose, "Removed {}", path.display());
+                reclaimed += size;
+            }
+            Err(e) => warn(format!("Failed to remove {}: {}", path.display(), e))?,
+        }
+    }
+    println!("{} Reclaimed {}", "[SUCCESS]".green(), format_bytes(reclaimed));
+    Ok(())
+}
+
+// ========================= PULL MODE =========================
+
+/// Clones or fast-forwards the config repo into the cache and makes it the working directory,
+/// so the relative `config/` paths resolve inside it. Returns the checked-out commit.
+fn checkout_pull_repo(url: &str, verbose: bool) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    let repo_id = format!("{:x}", hasher.finalize());
+    let cache_dir = cache_dir()?.join("pull");
+    fs::create_dir_all(&cache_dir)?;
+    let checkout = cache_dir.join(&repo_id[..16]);
+    let checkout_str = checkout.to_str().context("Invalid cache path")?;
+
+    if checkout.join(".git").exists() {
+        run_command(&["git", "-C", checkout_str, "pull", "--ff-only"], &format!("Updating config repo {}", url))?;
+    } else {
+        run_command(&["git", "clone", "--depth", "1", url, checkout_str], &format!("Cloning config repo {}", url))?;
+    }
+
+    if !checkout.join("config").is_dir() {
+        return Err(FedoraForgeError::validation(format!("{} has no config/ directory", url)));
+    }
+    env::set_current_dir(&checkout)
+        .with_context(|| format!("Failed to enter {:?}", checkout))?;
+    debug!(verbose, "Running against {:?}", checkout);
+
+    let output = run_command_output(&["git", "rev-parse", "HEAD"])?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn report_pull_status(url: &str, commit: &str, started_at: u64, result: &Result<()>, webhook: Option<&str>) -> Result<()> {
+    let status = PullStatus {
+        url: url.to_string(),
+        commit: commit.to_string(),
+        hostname: fs::read_to_string("/etc/hostname").unwrap_or_default().trim().to_string(),
+        started_at,
+        finished_at: get_current_timestamp(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+    };
+
+    let status_path = get_run_state_path()?.with_file_name("pull_status.json");
+    let content = serde_json::to_string_pretty(&status)
+        .context("Failed to serialize pull status")?;
+    fs::write(&status_path, content)
+        .context("Failed to write pull status")?;
+    println!("{} Pull status written to {:?}", "[INFO]".blue(), status_path);
+
+    if let Some(url) = webhook {
+        post_json_webhook(url, &status_path, "Pull status");
+    }
+    Ok(())
+}
+
+// ========================= FEDORA UPGRADE =========================
+
+// Free space the system-upgrade download needs in /var/cache (packages) and / (installation)
+const UPGRADE_MIN_FREE_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+fn current_fedora_version() -> Result<u32> {
+    let os_release = fs::read_to_string("/etc/os-release")?;
+    detect_distro(&os_release)?;
+    os_release.lines()
+        .find_map(|line| line.strip_prefix("VERSION_ID="))
+        .and_then(|v| v.trim_matches('"').parse().ok())
+        .context("Failed to read VERSION_ID from /etc/os-release")
+}
+
+fn available_disk_space(path: &str) -> Result<u64> {
+    let output = run_command_output(&["df", "-B1", "--output=avail", path])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["df", "-B1", "--output=avail", path], &output).into());
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|line| line.trim().parse().ok())
+        .with_context(|| format!("Failed to parse free space for {}", path))
+}
+
+fn check_disk_space(paths: &[&str], required: u64) -> Result<()> {
+    for path in paths {
+        let available = available_disk_space(path)?;
+        if available < required {
+            return Err(FedoraForgeError::validation(format!(
+                "Not enough free space on {}: {} MiB available, {} MiB required",
+                path, available / (1024 * 1024), required / (1024 * 1024)
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Downloads the target release with `dnf system-upgrade` and reboots into the offline upgrade.
+fn upgrade_fedora_release(target: u32, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let current = current_fedora_version()?;
+    // Fedora supports upgrading at most two releases at a time
+    if target <= current || target > current + 2 {
+        return Err(FedoraForgeError::validation(format!(
+            "Cannot upgrade from Fedora {} to {}: the target must be {} or {}",
+            current, target, current + 1, current + 2
+        )));
+    }
+
+    check_package_database(verbose)?;
+    check_disk_space(&["/", "/var/cache"], UPGRADE_MIN_FREE_BYTES)?;
+
+    println!("{} Upgrading Fedora {} -> {}", "[INFO]".blue(), current, target);
+    if !ask_user_confirmation(&format!("Download Fedora {} and prepare the system upgrade?", target), yes, no, verbose)? {
+        println!("{} Fedora upgrade cancelled", "[INFO]".blue());
+        return Ok(());
+    }
+
+    run_command(&["sudo", "dnf", "upgrade", "--refresh", "-y"], "Updating the current release")?;
+
+    // system-upgrade is built into dnf5 but a plugin for dnf4
+    let dnf_version = run_command_output(&["dnf", "--version"])?;
+    if !String::from_utf8_lossy(&dnf_version.stdout).contains("dnf5") {
+        run_command(&["sudo", "dnf", "install", "-y", "dnf-plugin-system-upgrade"], "Installing the system-upgrade plugin")?;
+    }
+
+    let releasever = format!("--releasever={}", target);
+    run_command(&["sudo", "dnf", "system-upgrade", "download", &releasever, "-y"],
+                &format!("Downloading Fedora {}", target))?;
+    println!("{} Fedora {} is downloaded and ready to install", "[SUCCESS]".green(), target);
+
+    if ask_user_confirmation("Reboot now to install the upgrade?", yes, no, verbose)? {
+        run_command(&["sudo", "dnf", "system-upgrade", "reboot"], "Rebooting into the system upgrade")?;
+    } else {
+        println!("{} Run 'sudo dnf system-upgrade reboot' when ready to install the upgrade", "[INFO]".blue());
+    }
+    Ok(())
+}
+
+// ========================= PROFILES =========================
+
+fn get_profile_state_path() -> Result<std::path::PathBuf> {
+    let config_dir = state_dir()?;
+    Ok(config_dir.join("profile_state.json"))
+}
+
+fn load_profile_state() -> Result<ProfileState> {
+    if state_disabled() {
+        return Ok(ProfileState::default());
+    }
+
+    let state_file = get_profile_state_path()?;
+
+    if state_file.exists() {
+        let content = fs::read_to_string(&state_file)
+            .context("Failed to read profile state file")?;
+        let state: ProfileState = serde_json::from_str(&content)
+            .context("Failed to parse profile state file")?;
+        Ok(state)
+    } else {
+        Ok(ProfileState::default())
+    }
+}
+
+fn save_profile_state(state: &ProfileState) -> Result<()> {
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let state_file = get_profile_state_path()?;
+    let content = serde_json::to_string_pretty(state)
+        .context("Failed to serialize profile state")?;
+    fs::write(&state_file, content)
+        .context("Failed to write profile state file")?;
+    Ok(())
+}
+
+/// Resolve the profile for this run (`--profile` wins, otherwise the last active one)
+/// and merge its `[profiles.<name>]` block over the base config. With `remember`, the
+/// resolved profile is saved as the active one for later runs.
+fn apply_active_profile(config_value: &mut toml::Value, requested: Option<&str>, remember: bool, verbose: bool) -> Result<()> {
+    let mut state = load_profile_state()?;
+
+    let profile = match requested {
+        Some("none") => {
+            if state.active_profile.take().is_some() && remember {
+                save_profile_state(&state)?;
+                println!("{} Cleared active profile, using base configuration", "[INFO]".blue());
+            }
+            None
+        }
+        Some(name) => Some(name.to_string()),
+        None => state.active_profile.clone(),
+    };
+
+    let profiles = config_value
+        .as_table_mut()
+        .and_then(|table| table.remove("profiles"));
+
+    let Some(name) = profile else {
+        return Ok(());
+    };
+
+    let available: Vec<String> = profiles
+        .as_ref()
+        .and_then(|p| p.as_table())
+        .map(|t| t.keys().cloned().collect())
+        .unwrap_or_default();
+
+    let overrides = profiles
+        .and_then(|p| p.as_table().and_then(|t| t.get(&name).cloned()))
+        .with_context(|| format!(
+            "Profile '{}' not found in config (available: {}). Use --profile none to clear it",
+            name,
+            if available.is_empty() { "none defined".to_string() } else { available.join(", ") }
+        ))?;
+
+    if remember && state.active_profile.as_deref() != Some(name.as_str()) {
+        state.active_profile = Some(name.clone());
+        save_profile_state(&state)?;
+    }
+
+    println!("{} Using profile '{}'", "[INFO]".blue(), name);
+    debug!(verbose, "Merging [profiles.{}] over base configuration", name);
+
+    merge_toml_values(config_value, overrides);
+    Ok(())
+}
+
+// ========================= GNOME SHELL EXTENSIONS =========================
+
+fn sync_gnome_extensions(config: &GnomeExtensionsConfig, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    if run_command_output(&["which", "gnome-extensions"]).map_or(true, |o| !o.status.success()) {
+        warn("gnome-extensions is not available (is GNOME Shell installed?), skipping extensions")?;
+        return Ok(SubsystemResult::default());
+    }
+
+    println!("{} Synchronizing GNOME Shell extensions...", "[INFO]".blue());
+
+    let shell_version = get_gnome_shell_version()?;
+    debug!(verbose, "Detected GNOME Shell version {}", shell_version);
+
+    let installed = list_gnome_extensions(false)?;
+    let enabled = list_gnome_extensions(true)?;
+
+    // Resolve extensions.gnome.org IDs to UUIDs
+    let mut declared = Vec::new();
+    for entry in &config.extensions {
+        if entry.chars().all(|c| c.is_ascii_digit()) {
+            let info = fetch_extension_info(&format!("pk={}", entry), &shell_version)?;
+            let uuid = info.get("uuid")
+                .and_then(|u| u.as_str())
+                .with_context(|| format!("extensions.gnome.org returned no UUID for extension {}", entry))?
+                .to_string();
+            debug!(verbose, "Resolved extension {} to {}", entry, uuid);
+            declared.push(uuid);
+        } else {
+            declared.push(entry.clone());
+        }
+    }
+
+    let mut installed_count = 0;
+    let mut enabled_count = 0;
+    let mut disabled_count = 0;
+
+    // Install and enable declared extensions
+    for uuid in &declared {
+        let just_installed = !installed.contains(uuid);
+        if just_installed {
+            install_gnome_extension(uuid, &shell_version)?;
+            installed_count += 1;
+        }
+
+        if !enabled.contains(uuid) {
+            match run_command(&["gnome-extensions", "enable", uuid], &format!("Enabling extension {}", uuid)) {
+                Ok(()) => enabled_count += 1,
+                // On Wayland the running shell only sees new extensions after a re-login
+                Err(_) if just_installed => warn(format!(
+                    "Extension {} was just installed and can't be enabled until you log out and back in; the next run enables it",
+                    uuid
+                ))?,
+                Err(e) => return Err(e),
+            }
+        } else if verbose {
+            println!("{} Extension {} already enabled", "[DEBUG]".cyan(), uuid);
+        }
+    }
+
+    // Disable enabled extensions that are no longer declared
+    for uuid in &enabled {
+        if declared.contains(uuid) {
+            continue;
+        }
+        println!("\n{} Extension '{}' is enabled but not in gnome_extensions", "[INFO]".yellow(), uuid);
+        if no_removals {
+            println!("{} --no-removals: leaving '{}' enabled", "[INFO]".blue(), uuid);
+            continue;
+        }
+        if !ask_user_confirmation(&format!("Do you want to keep '{}' enabled?", uuid), yes, no, verbose)? {
+            run_command(&["gnome-extensions", "disable", uuid], &format!("Disabling extension {}", uuid))?;
+            disabled_count += 1;
+        }
+    }
+
+    println!("{} GNOME Shell extension synchronization completed", "[SUCCESS]".green());
+
+    if installed_count > 0 {
+        println!("{}", "Log out/in to load newly installed extensions.".yellow());
+    }
+
+    Ok(SubsystemResult {
+        created: installed_count,
+        modified: enabled_count,
+        removed: disabled_count,
+        skipped: 0,
+    })
+}
+
+fn get_gnome_shell_version() -> Result<String> {
+    let output = spawn_output(&["gnome-shell", "--version"])?;
+
+    // Output format: "GNOME Shell 46.2"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.split_whitespace()
+        .last()
+        .context("Could not parse GNOME Shell version")?;
+
+    // extensions.gnome.org matches on the major version for GNOME 40+
+    Ok(version.split('.').next().unwrap_or(version).to_string())
+}
+
+fn list_gnome_extensions(enabled_only: bool) -> Result<Vec<String>> {
+    let mut cmd = vec!["gnome-extensions", "list"];
+    if enabled_only {
+        cmd.push("--enabled");
+    }
+
+    let output = spawn_output(&cmd)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("gnome-extensions list failed: {}", stderr);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+fn fetch_extension_info(query: &str, shell_version: &str) -> Result<serde_json::Value> {
+    let url = format!("https://extensions.gnome.org/extension-info/?{}&shell_version={}", query, shell_version);
+    let output = spawn_output(&["curl", "-fsSL", &url])?;
+
+    if !output.status.success() {
+        anyhow::bail!("No extension found on extensions.gnome.org for {} (GNOME Shell {})", query, shell_version);
+    }
+
+    serde_json::from_slice(&output.stdout).context("Failed to parse extensions.gnome.org response")
+}
+
+fn install_gnome_extension(uuid: &str, shell_version: &str) -> Result<()> {
+    let info = fetch_extension_info(&format!("uuid={}", uuid), shell_version)?;
+    let download_path = info.get("download_url")
+        .and_then(|u| u.as_str())
+        .with_context(|| format!("No compatible version of {} for GNOME Shell {}", uuid, shell_version))?;
+
+    let url = format!("https://extensions.gnome.org{}", download_path);
+    let zip_path = cache_dir()?.join(format!("{}.zip", uuid));
+    let zip_str = zip_path.to_str().context("Invalid temp path")?;
+
+    run_command(&["curl", "-fsSL", "-o", zip_str, &url], &format!("Downloading extension {}", uuid))?;
+    run_command(&["gnome-extensions", "install", "--force", zip_str], &format!("Installing extension {}", uuid))?;
+    let _ = fs::remove_file(&zip_path);
+
+    Ok(())
+}
 
-    let has_container = if let Ok(output) = container_check {
-        let container_list = String::from_utf8_lossy(&output.stdout);
-        !container_list.trim().is_empty()
-    } else {
-        false
-    };
+// ========================= DCONF SETTINGS =========================
 
-    if has_container && verbose {
-        println!("{} Found RDPWindows container", "[DEBUG]".cyan());
+fn setup_dconf(config: &DconfConfig, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    if run_command_output(&["which", "dconf"]).map_or(true, |o| !o.status.success()) {
+        return warn("dconf is not installed, skipping dconf settings");
     }
 
-    // Prompt for confirmation
-    let should_cleanup = if yes {
-        true
-    } else if no {
-        false
-    } else {
-        println!("\n{} WinApps cleanup will:", "[WARNING]".yellow());
-        if has_container {
-            println!("  • Stop and remove the RDPWindows container");
-        }
-        if config_exists {
-            println!("  • Remove config directory: {:?}", winapps_config_dir);
+    println!("{} Applying dconf settings...", "[INFO]".blue());
+    let mut changed = 0;
+
+    // Individual dconf keys
+    if let Some(keys) = &config.keys {
+        let mut sorted: Vec<_> = keys.iter().collect();
+        sorted.sort();
+        for (key, value) in sorted {
+            let current = read_dconf_key(key)?;
+            if current.as_deref() == Some(value.trim()) {
+                debug!(verbose, "dconf key {} is up to date", key);
+                continue;
+            }
+            run_command(&["dconf", "write", key, value], &format!("Setting dconf key {}", key))?;
+            changed += 1;
+        }
+    }
+
+    // gsettings schema keys
+    if let Some(schemas) = &config.gsettings {
+        let mut sorted: Vec<_> = schemas.iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        for (schema, keys) in sorted {
+            let mut sorted_keys: Vec<_> = keys.iter().collect();
+            sorted_keys.sort();
+            for (key, value) in sorted_keys {
+                let output = run_command_output(&["gsettings", "get", schema, key])?;
+                if !output.status.success() {
+                    warn(format!("gsettings key {} {} does not exist, skipping", schema, key))?;
+                    continue;
+                }
+                let current = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if current == value.trim() {
+                    debug!(verbose, "gsettings key {} {} is up to date", schema, key);
+                    continue;
+                }
+                run_command(&["gsettings", "set", schema, key, value], &format!("Setting gsettings key {} {}", schema, key))?;
+                changed += 1;
+            }
         }
-        if repo_exists {
-            println!("  • Remove repository: {:?}", winapps_repo_dir);
+    }
+
+    // Whole subtrees via dconf dump/load
+    if let Some(dumps) = &config.dumps {
+        for dump in dumps {
+            if sync_dconf_dump(dump, yes, no, verbose)? {
+                changed += 1;
+            }
         }
-        println!("\n{} Do you want to proceed with cleanup?", "[PROMPT]".yellow());
-        ask_user_confirmation("Cleanup WinApps", yes, no, verbose)?
-    };
+    }
 
-    if !should_cleanup {
-        println!("{} Skipping WinApps cleanup", "[INFO]".blue());
-        return Ok(());
+    println!("{} dconf settings applied ({} changed)", "[SUCCESS]".green(), changed);
+    Ok(())
+}
+
+fn read_dconf_key(key: &str) -> Result<Option<String>> {
+    let output = spawn_output(&["dconf", "read", key])?;
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() {
+        Ok(None) // Key is unset (schema default)
+    } else {
+        Ok(Some(value))
     }
+}
 
-    // Stop and remove container
-    if has_container {
-        println!("{} Stopping and removing RDPWindows container...", "[INFO]".blue());
+fn dump_dconf_subtree(path: &str) -> Result<String> {
+    let output = spawn_output(&["dconf", "dump", path])?;
 
-        // Try to stop with compose first if compose.yaml exists
-        let compose_file = winapps_config_dir.join("compose.yaml");
-        if compose_file.exists() {
-            let result = run_command(
-                &["podman-compose", "--file", compose_file.to_str().unwrap(), "down"],
-                "Stopping container with compose"
-            );
-            if result.is_err() && verbose {
-                println!("{} podman-compose failed, trying direct container removal", "[WARNING]".yellow());
-            }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("dconf dump {} failed: {}", path, stderr);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn sync_dconf_dump(dump: &DconfDump, yes: bool, no: bool, verbose: bool) -> Result<bool> {
+    if !dump.path.starts_with('/') || !dump.path.ends_with('/') {
+        return Err(FedoraForgeError::validation(format!("Invalid dconf dump path '{}': must start and end with '/'", dump.path)));
+    }
+
+    let current = dump_dconf_subtree(&dump.path)?;
+
+    // Capture the current subtree when the file doesn't exist yet
+    if !Path::new(&dump.file).exists() {
+        println!("{} {} not found, capturing current dconf state of {}", "[INFO]".blue(), dump.file, dump.path);
+        if let Some(parent) = Path::new(&dump.file).parent() {
+            create_dir(parent)?;
         }
+        write_file(&dump.file, &current)
+            .with_context(|| format!("Failed to write {}", dump.file))?;
+        println!("{} Created {} from current dconf state", "[SUCCESS]".green(), dump.file);
+        return Ok(false);
+    }
 
-        // Force remove the container if it still exists
-        let _ = run_command(
-            &["podman", "rm", "-f", "RDPWindows"],
-            "Removing RDPWindows container"
-        );
+    let desired = fs::read_to_string(&dump.file)
+        .with_context(|| format!("Failed to read {}", dump.file))?;
 
-        println!("{} Container removed", "[SUCCESS]".green());
+    // dconf load merges, so only the keys declared in the file need to match
+    let desired_keys = parse_dconf_keyfile(&desired);
+    let current_keys = parse_dconf_keyfile(&current);
+    let differing: Vec<_> = desired_keys.iter()
+        .filter(|(key, value)| current_keys.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    if differing.is_empty() {
+        debug!(verbose, "dconf subtree {} is up to date", dump.path);
+        return Ok(false);
     }
 
-    // Remove config directory
-    if config_exists {
-        println!("{} Removing config directory: {:?}", "[INFO]".blue(), winapps_config_dir);
-        fs::remove_dir_all(&winapps_config_dir)
-            .with_context(|| format!("Failed to remove config directory {:?}", winapps_config_dir))?;
-        println!("{} Config directory removed", "[SUCCESS]".green());
+    println!("{} dconf subtree {} differs from {} ({} keys):", "[INFO]".blue(), dump.path, dump.file, differing.len());
+    for key in &differing {
+        println!("  - {}", key);
     }
 
-    // Remove repository
-    if repo_exists {
-        println!("{} Removing repository: {:?}", "[INFO]".blue(), winapps_repo_dir);
-        fs::remove_dir_all(&winapps_repo_dir)
-            .with_context(|| format!("Failed to remove repository {:?}", winapps_repo_dir))?;
-        println!("{} Repository removed", "[SUCCESS]".green());
+    if !ask_user_confirmation(&format!("Load {} into {}?", dump.file, dump.path), yes, no, verbose)? {
+        println!("{} Skipping dconf load for {}", "[INFO]".blue(), dump.path);
+        return Ok(false);
     }
 
-    println!("{} WinApps cleanup completed!", "[SUCCESS]".green().bold());
-    println!("{} Dependencies (freerdp, dialog, etc.) were NOT removed", "[INFO]".blue());
-    println!("     Run 'sudo dnf remove freerdp dialog libnotify nmap-ncat' to remove them manually if needed", );
+    let cmd = ["dconf", "load", dump.path.as_str()];
+    let preview = format!("{} < {}", format_argv(&cmd), shell_quote(&dump.file));
+    run_command_with_input(&cmd, desired.as_bytes(), &preview, &format!("Loading dconf subtree {}", dump.path))?;
+    Ok(true)
+}
 
-    Ok(())
+/// Mounts `source` at the drive's mount point, selecting the subvolume like the fstab line does.
+fn mount_drive(drive: &DriveConfig, source: &str, desc: &str) -> Result<()> {
+    match &drive.subvol {
+        Some(subvol) => run_command(&["sudo", "mount", "-o", &format!("subvol={}", subvol), source, &drive.mount_point], desc),
+        None => run_command(&["sudo", "mount", source, &drive.mount_point], desc),
+    }
 }
 
-fn setup_winapps(enable_winapps: bool, args: &Args) -> Result<()> {
-    if !enable_winapps {
-        // When disabled, offer to cleanup existing installation
-        cleanup_winapps(args.yes, args.no, args.verbose)?;
+fn setup_drives(drives: &[DriveConfig], verbose: bool) -> Result<()> {
+    if drives.is_empty() {
         return Ok(());
     }
 
-    println!("{} Setting up WinApps...", "[INFO]".blue());
+    println!("{} Setting up drive mounting...", "[INFO]".blue());
 
-    // Check if winapps-config.toml exists
-    let winapps_config_path = "config/winapps-config.toml";
-    if !std::path::Path::new(winapps_config_path).exists() {
-        anyhow::bail!("WinApps is enabled but config file not found at {}. Please create the config file or set enable_winapps = false", winapps_config_path);
+    // Install required packages for drive mounting
+    install_drive_packages(verbose)?;
+
+    for drive in drives {
+        setup_single_drive(drive, verbose)?;
     }
 
-    // Install WinApps dependencies for Fedora
-    println!("{} Installing WinApps dependencies...", "[INFO]".blue());
-    run_command(
-        &["sudo", "dnf", "install", "-y", "curl", "dialog", "freerdp", "git", "iproute", "libnotify", "nmap-ncat"],
-        "Installing WinApps dependencies"
-    )?;
+    println!("{} All drives configured successfully!", "[SUCCESS]".green());
+    Ok(())
+}
 
-    // Load WinApps configuration
-    let winapps_config_content = fs::read_to_string(winapps_config_path)
-        .with_context(|| format!("Failed to read WinApps config from {}", winapps_config_path))?;
+fn install_drive_packages(verbose: bool) -> Result<()> {
+    debug!(verbose, "Installing drive mounting packages");
 
-    let winapps_config: WinAppsConfig = toml::from_str(&winapps_config_content)
-        .with_context(|| format!("Failed to parse WinApps config from {}", winapps_config_path))?;
+    // Install cryptsetup for encrypted drives and other utilities
+    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "cryptsetup", "util-linux"], "Installing drive mounting utilities")?;
+    Ok(())
+}
 
-    // Verify that backend is set to podman
-    if winapps_config.waflavor != "podman" {
-        println!("{} WinApps backend is set to '{}', but only 'podman' is supported. Please set waflavor = \"podman\" in {}",
-                 "[WARNING]".yellow(), winapps_config.waflavor, winapps_config_path);
-        anyhow::bail!("Unsupported WinApps backend: {}. Only 'podman' is supported.", winapps_config.waflavor);
-    }
+fn setup_single_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
+    println!("{} Configuring drive {} -> {}", "[INFO]".blue(), drive.device, drive.mount_point);
 
-    // Create WinApps config directory
-    let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-    let winapps_dir = home_dir.join(".config").join("winapps");
+    // Check if device exists
+    if !std::path::Path::new(&drive.device).exists() {
+        return warn(format!("Device {} does not exist, skipping", drive.device));
+    }
 
-    println!("{} Creating WinApps config directory at {:?}", "[INFO]".blue(), winapps_dir);
-    fs::create_dir_all(&winapps_dir)
-        .with_context(|| format!("Failed to create WinApps config directory at {:?}", winapps_dir))?;
+    // Options end up as single whitespace-separated fields in fstab/crypttab
+    for options in [&drive.options, &drive.crypttab_options].into_iter().flatten() {
+        if options.is_empty() || options.contains(char::is_whitespace) {
+            return Err(FedoraForgeError::validation(format!(
+                "Drive {}: mount options '{}' must be a non-empty comma-separated list without spaces", drive.device, options
+            )));
+        }
+    }
 
-    // Write winapps.conf file
-    let winapps_conf_path = winapps_dir.join("winapps.conf");
-    println!("{} Writing WinApps configuration to {:?}", "[INFO]".blue(), winapps_conf_path);
+    if let Some(subvol) = &drive.subvol {
+        if !matches!(drive.filesystem.as_deref(), None | Some("btrfs") | Some("auto")) {
+            return Err(FedoraForgeError::validation(format!(
+                "Drive {}: subvol requires filesystem = \"btrfs\" (or unset/auto), not '{}'", drive.device, drive.filesystem.as_deref().unwrap_or_default()
+            )));
+        }
+        if subvol.is_empty() || subvol.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(FedoraForgeError::validation(format!("Drive {}: invalid subvol '{}'", drive.device, subvol)));
+        }
+        if drive.options.as_deref().is_some_and(|o| o.split(',').any(|o| o.starts_with("subvol=") || o.starts_with("subvolid="))) {
+            return Err(FedoraForgeError::validation(format!(
+                "Drive {}: set the subvolume with subvol, not in options", drive.device
+            )));
+        }
+    }
 
-    let mut conf_content = String::new();
-    conf_content.push_str(&format!("RDP_USER=\"{}\"\n", winapps_config.rdp_user));
-    conf_content.push_str(&format!("RDP_PASS=\"{}\"\n", winapps_config.rdp_pass));
-    conf_content.push_str(&format!("RDP_DOMAIN=\"{}\"\n", winapps_config.rdp_domain.as_deref().unwrap_or("")));
-    conf_content.push_str(&format!("RDP_IP=\"{}\"\n", winapps_config.rdp_ip));
-    conf_content.push_str(&format!("VM_NAME=\"{}\"\n", winapps_config.vm_name.as_deref().unwrap_or("RDPWindows")));
-    conf_content.push_str(&format!("WAFLAVOR=\"{}\"\n", winapps_config.waflavor));
-    conf_content.push_str(&format!("RDP_SCALE=\"{}\"\n", winapps_config.rdp_scale.as_deref().unwrap_or("100")));
-    conf_content.push_str(&format!("REMOVABLE_MEDIA=\"{}\"\n", winapps_config.removable_media.as_deref().unwrap_or("/run/media")));
-    conf_content.push_str(&format!("DEBUG=\"{}\"\n", if winapps_config.debug.unwrap_or(false) { "true" } else { "false" }));
-    conf_content.push_str(&format!("MULTIMON=\"{}\"\n", if winapps_config.multimon.unwrap_or(false) { "true" } else { "false" }));
+    // Create mount point
+    run_command(&["sudo", "mkdir", "-p", &drive.mount_point], &format!("Creating mount point {}", drive.mount_point))?;
 
-    if let Some(rdp_flags) = &winapps_config.rdp_flags {
-        conf_content.push_str(&format!("RDP_FLAGS=\"{}\"\n", rdp_flags));
+    if drive.encrypted {
+        setup_encrypted_drive(drive, verbose)?;
+    } else {
+        setup_unencrypted_drive(drive, verbose)?;
     }
 
-    // Detect Wayland and set RDP_ENV for compatibility
-    let rdp_env = if let Some(custom_env) = &winapps_config.rdp_env {
-        // Use custom environment from config if specified
-        custom_env.clone()
+    Ok(())
+}
+
+fn setup_unencrypted_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
+    debug!(verbose, "Setting up unencrypted drive {}", drive.device);
+
+    // Get filesystem type if not specified
+    let filesystem = drive.filesystem.as_deref().unwrap_or("auto");
+
+    // Get UUID of the device
+    let uuid_output = run_command_output(&["sudo", "blkid", "-s", "UUID", "-o", "value", &drive.device])?;
+    let uuid = String::from_utf8_lossy(&uuid_output.stdout).trim().to_string();
+
+    if uuid.is_empty() {
+        warn(format!("Could not get UUID for {}, using device path", drive.device))?;
+        add_to_fstab(&drive.device, &drive.mount_point, filesystem, &drive.fstab_options(), 2, drive.force_update.unwrap_or(false), verbose)?;
     } else {
-        // Auto-detect Wayland session
-        if let Ok(session_type) = std::env::var("XDG_SESSION_TYPE") {
-            if session_type.to_lowercase() == "wayland" {
-                println!("{} Detected Wayland session, setting GDK_BACKEND=x11 for FreeRDP compatibility", "[INFO]".blue());
-                "GDK_BACKEND=x11".to_string()
-            } else {
-                String::new()
-            }
-        } else if std::env::var("WAYLAND_DISPLAY").is_ok() {
-            // Fallback check for Wayland
-            println!("{} Detected Wayland display, setting GDK_BACKEND=x11 for FreeRDP compatibility", "[INFO]".blue());
-            "GDK_BACKEND=x11".to_string()
-        } else {
-            String::new()
-        }
-    };
+        let uuid_device = format!("UUID={}", uuid);
+        add_to_fstab(&uuid_device, &drive.mount_point, filesystem, &drive.fstab_options(), 2, drive.force_update.unwrap_or(false), verbose)?;
+    }
 
-    if !rdp_env.is_empty() {
-        conf_content.push_str(&format!("RDP_ENV=\"{}\"\n", rdp_env));
+    // Mount the drive
+    mount_drive(drive, &drive.device, &format!("Mounting {} to {}", drive.device, drive.mount_point))?;
+
+    println!("{} Unencrypted drive {} mounted successfully", "[SUCCESS]".green(), drive.device);
+    Ok(())
+}
+
+fn setup_encrypted_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
+    debug!(verbose, "Setting up encrypted drive {}", drive.device);
+
+    // Generate a mapper name based on the label or device name
+    let default_name = drive.device.replace("/dev/", "").replace("/", "_");
+    let mapper_name = drive.label.as_deref().unwrap_or(&default_name);
+    let mapper_path = format!("/dev/mapper/{}", mapper_name);
+
+    // Get UUID of the encrypted device
+    let uuid_output = run_command_output(&["sudo", "blkid", "-s", "UUID", "-o", "value", &drive.device])?;
+    let uuid = String::from_utf8_lossy(&uuid_output.stdout).trim().to_string();
+
+    if uuid.is_empty() {
+        anyhow::bail!("Could not get UUID for encrypted device {}", drive.device);
     }
 
-    fs::write(&winapps_conf_path, conf_content)
-        .with_context(|| format!("Failed to write WinApps config to {:?}", winapps_conf_path))?;
+    if let Some(keyfile) = &drive.keyfile {
+        check_keyfile(keyfile, &drive.device)?;
+    }
+
+    // Add to crypttab
+    add_to_crypttab(mapper_name, &uuid, drive.keyfile.as_deref(), drive.crypttab_options.as_deref(), drive.force_update.unwrap_or(false), verbose)?;
 
-    // Set secure permissions on config file (600)
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&winapps_conf_path)?.permissions();
-        perms.set_mode(0o600);
-        fs::set_permissions(&winapps_conf_path, perms)?;
-        println!("{} Set secure permissions (600) on {:?}", "[INFO]".blue(), winapps_conf_path);
+    // Check if the encrypted device is already opened
+    if !std::path::Path::new(&mapper_path).exists() {
+        match &drive.keyfile {
+            Some(keyfile) => run_command(&["sudo", "cryptsetup", "open", "--key-file", keyfile, &drive.device, mapper_name],
+                                         &format!("Opening encrypted device {} with {}", drive.device, keyfile))?,
+            None => {
+                println!("{} Opening encrypted device {} (you may need to enter passphrase)", "[INFO]".blue(), drive.device);
+                run_command(&["sudo", "cryptsetup", "open", &drive.device, mapper_name], &format!("Opening encrypted device {}", drive.device))?
+            }
+        }
     }
 
-    // Clone WinApps repository
-    let winapps_repo_dir = home_dir.join(".local").join("share").join("winapps");
+    // Get filesystem type if not specified
+    let filesystem = drive.filesystem.as_deref().unwrap_or("auto");
 
-    if winapps_repo_dir.exists() {
-        println!("{} WinApps repository already exists at {:?}, pulling latest changes...", "[INFO]".blue(), winapps_repo_dir);
-        run_command(
-            &["git", "-C", winapps_repo_dir.to_str().unwrap(), "pull"],
-            "Updating WinApps repository"
-        )?;
-    } else {
-        println!("{} Cloning WinApps repository to {:?}...", "[INFO]".blue(), winapps_repo_dir);
-        fs::create_dir_all(winapps_repo_dir.parent().unwrap())?;
-        run_command(
-            &["git", "clone", "https://github.com/winapps-org/winapps.git", winapps_repo_dir.to_str().unwrap()],
-            "Cloning WinApps repository"
-        )?;
+    // Add to fstab using the mapper path
+    add_to_fstab(&mapper_path, &drive.mount_point, filesystem, &drive.fstab_options(), 2, drive.force_update.unwrap_or(false), verbose)?;
+
+    // Mount the decrypted drive
+    mount_drive(drive, &mapper_path, &format!("Mounting decrypted {} to {}", mapper_path, drive.mount_point))?;
+
+    println!("{} Encrypted drive {} mounted successfully", "[SUCCESS]".green(), drive.device);
+    Ok(())
+}
+
+/// A root-owned file that only matters after a reboot: written with `install_file_content`, or under
+/// --stage queued for the boot script (which runs as root) along with the commands around it.
+fn install_reboot_file(path: &str, content: &str, mode: &str, desc: &str) -> Result<()> {
+    if !staging() {
+        println!("{} {}", "[INFO]".blue(), desc);
+        return install_file_content(path, content.as_bytes(), mode, Some("root"), Some("root"), true);
     }
+    let line = format!("printf '%s' {content} > {path} && chmod {mode} {path}", content = shell_quote(content), path = shell_quote(path), mode = mode);
+    run_reboot_command(&["sh", "-c", &line], desc)
+}
 
-    // Copy compose.yaml to winapps config directory
-    println!("{} Copying compose.yaml to WinApps config directory...", "[INFO]".blue());
-    let compose_src = winapps_repo_dir.join("compose.yaml");
-    let compose_dest = winapps_dir.join("compose.yaml");
+/// Replaces a root-owned file through a temp file and `sudo mv`, keeping its mode. Nothing passes
+/// through a shell, so quotes or metacharacters in the content are written as-is.
+fn replace_root_file(path: &str, content: &str, desc: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path).map(|m| m.permissions().mode() & 0o7777).unwrap_or(0o644);
+    replace_root_file_with_mode(path, content, mode, desc)
+}
 
-    if compose_src.exists() {
-        fs::copy(&compose_src, &compose_dest)
-            .with_context(|| format!("Failed to copy compose.yaml from {:?} to {:?}", compose_src, compose_dest))?;
-        println!("{} Copied compose.yaml successfully", "[SUCCESS]".green());
-    } else {
-        println!("{} compose.yaml not found in repository, skipping", "[WARNING]".yellow());
+/// `replace_root_file` with a fixed mode, for files whose mode FedoraForge decides.
+fn replace_root_file_with_mode(path: &str, content: &str, mode: u32, desc: &str) -> Result<()> {
+    if dry_run_skip(&format!("write {} (mode {:o})", path, mode)) {
+        return Ok(());
     }
+    let file_name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    let temp = stage_private_file(file_name, content.as_bytes())?;
+    let temp = temp.to_str().context("Invalid staging file path")?.to_string();
 
-    println!("{} WinApps dependencies and configuration prepared!", "[SUCCESS]".green());
-
-    println!("\n{} ═══════════════════════════════════════════════════════════════", "📋".blue());
-    println!("{} WinApps Setup Instructions", "[INFO]".blue().bold());
-    println!("{} ═══════════════════════════════════════════════════════════════", "📋".blue());
+    let result = run_command(&["sudo", "chown", "root:root", &temp], &format!("Setting owner of staged {}", path))
+        .and_then(|_| run_command(&["sudo", "chmod", &format!("{:o}", mode), &temp], &format!("Setting mode of staged {}", path)))
+        .and_then(|_| run_command(&["sudo", "mv", &temp, path], desc));
+    if result.is_err() {
+        let _ = run_command(&["sudo", "rm", "-f", &temp], "Removing staged file");
+    }
+    result?;
+    restore_selinux_context(path);
+    Ok(())
+}
 
-    println!("\n{} STEP 1: Start the Windows Container", "1️⃣".green());
-    println!("     cd {:?}", winapps_dir);
-    println!("     podman-compose --file compose.yaml up -d");
+/// Keyfiles usually live under /etc/cryptsetup-keys.d, so the mode is read through sudo.
+fn check_keyfile(keyfile: &str, device: &str) -> Result<()> {
+    if keyfile.contains(char::is_whitespace) {
+        return Err(FedoraForgeError::validation(format!("Drive {}: keyfile path '{}' must not contain spaces", device, keyfile)));
+    }
+    let output = spawn_output(&["sudo", "stat", "-c", "%a", keyfile])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::validation(format!("Drive {}: keyfile {} not found", device, keyfile)));
+    }
+    let mode = u32::from_str_radix(String::from_utf8_lossy(&output.stdout).trim(), 8).unwrap_or(0);
+    if mode & 0o004 != 0 {
+        println!("{} Keyfile {} is WORLD-READABLE (mode {:o}): any local user can unlock {}", "[WARN]".yellow().bold(), keyfile, mode, device);
+        warn(format!("Fix it with: sudo chmod 400 {}", keyfile))?;
+    } else if mode & 0o077 != 0 {
+        warn(format!("Keyfile {} has mode {:o}, consider: sudo chmod 400 {}", keyfile, mode, keyfile))?;
+    }
+    Ok(())
+}
 
-    println!("\n{} IMPORTANT: First-time setup takes 15-30 minutes:", "⏱️".yellow());
-    println!("  • Windows will download (~4-6 GB)");
-    println!("  • Windows will install automatically");
-    println!("  • Container will restart once installation completes");
+fn add_to_crypttab(mapper_name: &str, uuid: &str, keyfile: Option<&str>, extra_options: Option<&str>, force_update: bool, verbose: bool) -> Result<()> {
+    debug!(verbose, "Adding {} to /etc/crypttab", mapper_name);
 
-    println!("\n{} Monitor Progress:", "👀".blue());
-    println!("  • View logs:     podman logs -f WinApps");
-    println!("  • Web console:   http://127.0.0.1:8006");
-    println!("  • Check status:  podman ps | grep WinApps");
+    let options = match extra_options {
+        Some(extra) if extra.split(',').any(|o| o == "luks") => extra.to_string(),
+        Some(extra) => format!("luks,{}", extra),
+        None => "luks".to_string(),
+    };
+    let crypttab_entry = format!("{} UUID={} {} {}", mapper_name, uuid, keyfile.unwrap_or("none"), options);
 
-    println!("\n{} RAM Configuration (in compose.yaml):", "⚙️".yellow());
-    println!("  • Default: 4GB RAM (may be too high for some systems)");
-    println!("  • If container crashes, edit RAM_SIZE in {:?}", compose_dest);
-    println!("  • Recommended: 2GB minimum, 4GB optimal");
+    // Read current crypttab content
+    let crypttab_content = std::fs::read_to_string("/etc/crypttab").unwrap_or_default();
 
-    println!("\n{} STEP 2: Run the WinApps Installer (after Windows boots)", "2️⃣".green());
-    println!("     bash {:?}", winapps_repo_dir.join("setup.sh"));
-    println!("\n  The installer will:");
-    println!("  • Install the winapps binary");
-    println!("  • Let you select which Windows applications to expose");
-    println!("  • Create desktop shortcuts for selected apps");
+    // Check if entry already exists
+    let entry_exists = crypttab_content.lines().any(|line| {
+        line.trim().starts_with(&format!("{} ", mapper_name)) || line.trim() == mapper_name
+    });
 
-    if !rdp_env.is_empty() {
-        println!("\n{} Wayland Compatibility Configured:", "🖥️".blue());
-        println!("  • Detected Wayland session");
-        println!("  • Automatically configured: {}", rdp_env);
-        println!("  • This fixes FreeRDP X11 compatibility issues");
+    if entry_exists && !force_update {
+        println!("{} Entry for {} already exists in /etc/crypttab", "[INFO]".blue(), mapper_name);
+        return Ok(());
     }
 
-    println!("\n{} Configuration saved to: {:?}", "✅".green(), winapps_conf_path);
-    println!("{} ═══════════════════════════════════════════════════════════════\n", "📋".blue());
+    if entry_exists {
+        debug!(verbose, "Updating existing {} entry in /etc/crypttab", mapper_name);
+    }
 
+    // Any existing entry is replaced; otherwise the new one is appended
+    let final_content = upsert_table_entry(&crypttab_content, &crypttab_entry, |line| {
+        line.trim().starts_with(&format!("{} ", mapper_name)) || line.trim() == mapper_name
+    });
+    replace_root_file("/etc/crypttab", &final_content, &format!("Writing {} to /etc/crypttab", mapper_name))?;
+    println!("{} Added {} to /etc/crypttab", "[SUCCESS]".green(), mapper_name);
     Ok(())
 }
 
-fn execute_custom_commands(config: &CustomCommandsConfig, verbose: bool) -> Result<()> {
-    if config.commands.is_empty() && config.run_once.as_ref().map_or(true, |v| v.is_empty()) {
-        return Ok(());
-    }
+/// Returns whether /etc/fstab was changed.
+fn add_to_fstab(device: &str, mount_point: &str, filesystem: &str, options: &str, passno: u8, force_update: bool, verbose: bool) -> Result<bool> {
+    debug!(verbose, "Adding {} to /etc/fstab", device);
 
-    println!("{} Executing custom commands...", "[INFO]".blue());
+    let fstab_entry = format!("{} {} {} {} 0 {}", device, mount_point, filesystem, options, passno);
 
-    // Load state for run_once commands
-    let mut state = load_executed_commands_state()?;
-    let mut state_changed = false;
+    // Read current fstab content
+    let fstab_content = std::fs::read_to_string("/etc/fstab").unwrap_or_default();
 
-    // Execute regular commands
-    for (index, command) in config.commands.iter().enumerate() {
-        println!("{} Executing command {} of {}: {}",
-                "[INFO]".blue(), index + 1, config.commands.len(), command);
+    // Check if entry already exists (check for mount point since device might change)
+    let entry_exists = fstab_content.lines().any(|line| {
+        line.split_whitespace().nth(1) == Some(mount_point)
+    });
 
-        execute_single_command(command)?;
-        println!("{} Command completed successfully", "[SUCCESS]".green());
+    if entry_exists && !force_update {
+        println!("{} Entry for {} already exists in /etc/fstab", "[INFO]".blue(), mount_point);
+        return Ok(false);
     }
 
-    // Execute run_once commands
-    if let Some(run_once_commands) = &config.run_once {
-        for (index, command) in run_once_commands.iter().enumerate() {
-            let command_hash = generate_command_hash(command);
+    // Backup fstab
+    run_command(&["sudo", "cp", "/etc/fstab", "/etc/fstab.backup"], "Backing up /etc/fstab")?;
 
-            if verbose {
-                println!("{} Command hash: {} for: {}", "[DEBUG]".cyan(), &command_hash[..8], command);
-            }
+    if entry_exists {
+        debug!(verbose, "Updating existing {} entry in /etc/fstab", mount_point);
+    }
 
-            if state.executed_once_commands.contains_key(&command_hash) {
-                println!("{} Skipping run-once command {} of {} (already executed): {}",
-                        "[INFO]".blue(), index + 1, run_once_commands.len(), command);
-                continue;
-            }
+    // Any existing entry is replaced; otherwise the new one is appended
+    let final_content = upsert_table_entry(&fstab_content, &fstab_entry, |line| {
+        line.split_whitespace().nth(1) == Some(mount_point)
+    });
+    replace_root_file("/etc/fstab", &final_content, &format!("Writing {} to /etc/fstab", mount_point))?;
+    verify_fstab(verbose)?;
+    println!("{} Added {} to /etc/fstab", "[SUCCESS]".green(), mount_point);
+    Ok(true)
+}
 
-            println!("{} Executing run-once command {} of {}: {}",
-                    "[INFO]".blue(), index + 1, run_once_commands.len(), command);
+/// A bad fstab line can stop the next boot, so the new file is checked with `findmnt --verify` and
+/// /etc/fstab.backup is put back when it fails. Problems the backup already had only warn.
+fn verify_fstab(verbose: bool) -> Result<()> {
+    if dry_run() {
+        return Ok(());
+    }
 
-            execute_single_command(command)?;
+    let verify = |path: &str| spawn_output(&["sudo", "findmnt", "--verify", "--tab-file", path]);
+    let output = verify("/etc/fstab")?;
+    if output.status.success() {
+        debug!(verbose, "findmnt --verify accepted /etc/fstab");
+        return Ok(());
+    }
+    let report = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
 
-            // Mark command as executed with metadata
-            let execution_record = CommandExecutionRecord {
-                command_hash: command_hash.clone(),
-                original_command: command.clone(),
-                executed_at: get_current_timestamp(),
-            };
-            state.executed_once_commands.insert(command_hash, execution_record);
-            state_changed = true;
+    if !verify("/etc/fstab.backup")?.status.success() {
+        return warn(format!("findmnt --verify reports problems in /etc/fstab that were already there before this change:\n{}", report.trim_end()));
+    }
 
-            println!("{} Run-once command completed successfully", "[SUCCESS]".green());
-        }
+    run_command(&["sudo", "cp", "/etc/fstab.backup", "/etc/fstab"], "Restoring /etc/fstab from backup")?;
+    restore_selinux_context("/etc/fstab");
+    anyhow::bail!("findmnt --verify rejected the new /etc/fstab, restored /etc/fstab.backup:\n{}", report.trim_end())
+}
+
+fn setup_network_mounts(mounts: &[NetworkMountConfig], verbose: bool) -> Result<()> {
+    if mounts.is_empty() {
+        return Ok(());
     }
 
-    // Save state if it changed
-    if state_changed {
-        save_executed_commands_state(&state)?;
+    println!("{} Setting up network mounts...", "[INFO]".blue());
+
+    for (fs_type, package) in [(NetworkFsType::Nfs, "nfs-utils"), (NetworkFsType::Cifs, "cifs-utils")] {
+        if mounts.iter().any(|m| m.fs_type == fs_type) && !is_rpm_installed(command_runner(), package) {
+            install_system_packages(command_runner(), &Distro::Fedora, &[package.to_string()], verbose)?;
+        }
     }
 
-    println!("{} All custom commands executed successfully!", "[SUCCESS]".green());
-    Ok(())
-}
+    let mut fstab_changed = false;
+    for mount in mounts {
+        let (device, filesystem) = match mount.fs_type {
+            NetworkFsType::Nfs => (format!("{}:{}", mount.server, mount.share), "nfs"),
+            NetworkFsType::Cifs => (format!("//{}/{}", mount.server, mount.share.trim_start_matches('/')), "cifs"),
+        };
 
-fn execute_single_command(command: &str) -> Result<()> {
-    // Execute command through shell to support environment variables and shell features
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()
-        .with_context(|| format!("Failed to execute command: {}", command))?;
+        // Mounted on first access, so an unreachable server can't hang boot
+        let mut options = vec!["_netdev", "noauto", "x-systemd.automount", "x-systemd.mount-timeout=30"];
+        let credentials_option;
+        if let Some(credentials) = &mount.credentials {
+            if mount.fs_type != NetworkFsType::Cifs {
+                warn(format!("'credentials' only applies to CIFS mounts, ignoring it for {}", mount.mount_point))?;
+            } else {
+                check_credentials_file(credentials)?;
+                credentials_option = format!("credentials={}", credentials);
+                options.push(&credentials_option);
+            }
+        }
+        if let Some(extra) = &mount.options {
+            options.push(extra);
+        }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        println!("{} Command failed: {}", "[ERROR]".red(), command);
-        println!("{} Error output: {}", "[ERROR]".red(), stderr);
-        anyhow::bail!("Custom command failed: {}", command);
+        run_command(&["sudo", "mkdir", "-p", &mount.mount_point], &format!("Creating mount point {}", mount.mount_point))?;
+        fstab_changed |= add_to_fstab(&device, &mount.mount_point, filesystem, &options.join(","), 0, mount.force_update.unwrap_or(false), verbose)?;
     }
 
-    // Show stdout if there's any output
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    if !stdout.trim().is_empty() {
-        println!("{} Output: {}", "[INFO]".blue(), stdout.trim());
+    // systemd generates the automount units from fstab
+    if fstab_changed {
+        mark_daemon_reload(&ServiceScope::System);
+        flush_daemon_reload(&ServiceScope::System)?;
+        run_command(&["sudo", "systemctl", "restart", "remote-fs.target"], "Activating network mount automounts")?;
     }
 
+    println!("{} Network mounts configured", "[SUCCESS]".green());
     Ok(())
 }
 
-fn setup_desktop_environment(distro: &Distro, config: &DesktopConfig) -> Result<()> {
-    let de_env = config.environment.as_deref().unwrap_or("cosmic-desktop");
-    println!("{} Setting up desktop environment: {}", "[INFO]".blue(), de_env);
+// ========================= FIREWALL =========================
 
-    validate_desktop_environment(distro, de_env)?;
+fn validate_firewall_port(port: &str) -> Result<()> {
+    let valid = port.split_once('/').is_some_and(|(range, protocol)| {
+        let numbers: Vec<&str> = range.split('-').collect();
+        numbers.len() <= 2
+            && numbers.iter().all(|n| n.parse::<u16>().is_ok_and(|n| n > 0))
+            && ["tcp", "udp", "sctp", "dccp"].contains(&protocol)
+    });
+    if !valid {
+        return Err(FedoraForgeError::validation(format!(
+            "Firewall port '{}' must look like \"8080/tcp\" or \"60000-61000/udp\"", port
+        )));
+    }
+    Ok(())
+}
 
-    let mut packages_to_install = vec![de_env.to_string()];
-    if let Some(additional_packages) = &config.packages {
-        packages_to_install.extend_from_slice(additional_packages);
+/// Syncs the permanent services and ports of one zone. Undeclared entries get the same
+/// keep-or-remove prompt as other sync paths, so --yes keeps them (think ssh).
+fn sync_firewall(config: &FirewallConfig, yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    let mut result = SubsystemResult::default();
+    if !is_rpm_installed(command_runner(), "firewalld") {
+        println!("{} firewalld is not installed, skipping firewall configuration", "[INFO]".blue());
+        return Ok(result);
+    }
+    if !check_service_active("firewalld", &ServiceScope::System)? {
+        warn("firewalld is installed but not running, skipping firewall configuration")?;
+        return Ok(result);
     }
 
-    install_desktop_packages(distro, &packages_to_install)?;
+    let declared_services = config.services.clone().unwrap_or_default();
+    let declared_ports = config.ports.clone().unwrap_or_default();
+    for port in &declared_ports {
+        validate_firewall_port(port)?;
+    }
 
-    set_default_desktop_environment(distro, de_env)?;
+    println!("{} Synchronizing firewall rules...", "[INFO]".blue());
+    let zone_arg = config.zone.as_ref().map(|zone| format!("--zone={}", zone));
+    let firewall_cmd = |args: &[&str]| -> Vec<String> {
+        let mut cmd: Vec<String> = ["sudo", "firewall-cmd", "--permanent"].iter().map(|s| s.to_string()).collect();
+        cmd.extend(zone_arg.clone());
+        cmd.extend(args.iter().map(|s| s.to_string()));
+        cmd
+    };
+    let run = |args: &[&str], desc: &str| -> Result<()> {
+        let cmd = firewall_cmd(args);
+        run_command(&cmd.iter().map(String::as_str).collect::<Vec<_>>(), desc)
+    };
 
-    // Setup display manager if specified
-    if let Some(display_manager) = &config.display_manager {
-        setup_display_manager(distro, display_manager)?;
+    let list_cmd = firewall_cmd(&["--list-all"]);
+    let output = spawn_output(&list_cmd.iter().map(String::as_str).collect::<Vec<_>>())?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&list_cmd.iter().map(String::as_str).collect::<Vec<_>>(), &output).into());
+    }
+    let (current_services, current_ports) = parse_firewall_list_all(&String::from_utf8_lossy(&output.stdout));
+    let zone_name = config.zone.as_deref().unwrap_or("default zone");
+
+    let mut changed = false;
+    for (kind, declared, current) in [
+        ("service", &declared_services, &current_services),
+        ("port", &declared_ports, &current_ports),
+    ] {
+        for item in declared.iter().filter(|item| !current.contains(item)) {
+            run(&[&format!("--add-{}={}", kind, item)], &format!("Allowing {} {} in the {}", kind, item, zone_name))?;
+            result.created += 1;
+            changed = true;
+        }
+        for item in current.iter().filter(|item| !declared.contains(item)) {
+            println!("\n{} Firewall {} '{}' is allowed but not declared in [firewall]", "[INFO]".yellow(), kind, item);
+            if no_removals {
+                println!("{} --no-removals: keeping '{}'", "[INFO]".blue(), item);
+                result.skipped += 1;
+                continue;
+            }
+            if ask_user_confirmation(&format!("Do you want to keep {} '{}' allowed?", kind, item), yes, no, verbose)? {
+                result.skipped += 1;
+            } else {
+                run(&[&format!("--remove-{}={}", kind, item)], &format!("Removing {} {} from the {}", kind, item, zone_name))?;
+                result.removed += 1;
+                changed = true;
+            }
+        }
     }
 
-    Ok(())
+    if changed {
+        run_command(&["sudo", "firewall-cmd", "--reload"], "Reloading firewalld")?;
+    } else {
+        debug!(verbose, "Firewall rules already match config");
+    }
+    println!("{} Firewall synchronization complete", "[SUCCESS]".green());
+    Ok(result)
 }
 
-fn validate_desktop_environment(distro: &Distro, de: &str) -> Result<()> {
-    println!("{} Validating desktop environment: {}", "[INFO]".blue(), de);
-    let available_des = get_available_des(distro)?;
-    if !available_des.contains(&de.to_lowercase()) {
-        anyhow::bail!("Desktop environment '{}' is not valid. Available options: {:?}", de, available_des);
+/// CIFS credentials hold a plain-text password, so refuse a missing file and warn when others can read it.
+fn check_credentials_file(path: &str) -> Result<()> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("CIFS credentials file {} not found", path))?;
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        warn(format!("Credentials file {} is readable by other users, consider: sudo chmod 600 {}", path, path))?;
     }
     Ok(())
 }
 
-fn get_available_des(_distro: &Distro) -> Result<Vec<String>> {
-    let output = run_command_output(&["dnf", "group", "list", "--available"])?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let des = stdout.lines()
-        .skip_while(|line| !line.trim().starts_with("ID"))
-        .skip(1) // Skip the header line itself
-        .filter_map(|line| {
-            line.split_whitespace().next().map(|s| s.to_lowercase())
-        })
-        .collect();
-    Ok(des)
-}
+/// Creates, updates or recreates the containers as needed. `state` is updated in place; the caller saves it.
+fn manage_containers_smart(runner: &dyn CommandRunner, containers: &[Container], state: &mut ContainerState, home_path: &str, args: &Args) -> Result<SubsystemResult> {
+    println!("{} Managing containers with smart lifecycle", "[INFO]".blue());
 
-fn install_desktop_packages(_distro: &Distro, packages: &[String]) -> Result<()> {
-    if packages.is_empty() {
-        return Ok(());
+    validate_containers(containers)?;
+
+    // Move state written with the old hash format over to the current one
+    for container in containers {
+        if let Some(info) = state.containers.get_mut(&container.name) {
+            if info.config_hash == legacy_container_config_hash(container) {
+                info.config_hash = generate_container_config_hash(container);
+                info.config_fields = container_config_fields(container);
+            }
+        }
     }
-    println!("{} Installing desktop packages...", "[INFO]".blue());
-    let mut cmd: Vec<&str> = vec!["sudo", "dnf", "group", "install", "-y"];
-    for pkg in packages {
-        cmd.push(pkg);
+
+    // Get existing containers
+    let existing_containers = get_existing_containers(runner)?;
+
+    if args.update_images {
+        pull_container_images(runner, containers)?;
     }
-    run_command(&cmd, "Installing desktop environment group")?;
-    Ok(())
-}
 
+    // Analyze what needs to be done
+    let mut actions = Vec::new();
 
-fn set_default_desktop_environment(_distro: &Distro, de_env: &str) -> Result<()> {
-    println!("{} Setting default desktop environment to {}", "[INFO]".blue(), de_env);
-    let session_name = de_env.split('-').next().unwrap_or(de_env);
-    let desktop_file_content = format!("DESKTOP={}", session_name);
-    let cmd = format!("echo '{}' | sudo tee /etc/sysconfig/desktop", desktop_file_content);
-    run_command(&["sh", "-c", &cmd], "Setting default desktop session")?;
-    Ok(())
-}
+    for container in containers {
+        let action = determine_container_action(runner, container, state, &existing_containers, args)?;
+        if args.verbose {
+            explain_container_action(container, &action, state, &existing_containers, args);
+        }
+        actions.push((container, action));
+    }
 
-fn setup_display_manager(_distro: &Distro, display_manager: &str) -> Result<()> {
-    println!("{} Setting up display manager: {}", "[INFO]".blue(), display_manager);
+    // Show summary of actions (only if there are non-Skip actions)
+    let has_actions = actions.iter().any(|(_, action)| !matches!(action, ContainerAction::Skip));
+    // A plan only lists containers to create; updates and recreates are still confirmed
+    let planned_only = actions.iter().all(|(container, action)| match action {
+        ContainerAction::Skip => true,
+        ContainerAction::Create => planned("containers", &[&container.name]),
+        _ => false,
+    });
+    if has_actions && !args.yes && !planned_only {
+        show_container_action_summary(&actions);
+        if !ask_user_confirmation("Proceed with container operations?", args.yes, args.no, args.verbose)? {
+            println!("{} Container operations cancelled", "[INFO]".blue());
+            return Ok(SubsystemResult { skipped: actions.len(), ..Default::default() });
+        }
+    }
 
-    // Install the display manager package
-    let dm_package = match display_manager {
-        "gdm" => "gdm",
-        "lightdm" => "lightdm",
-        "sddm" => "sddm",
-        "cosmic-greeter" => "cosmic-greeter",
-        _ => {
-            println!("{} Unsupported display manager: {}. Supported: gdm, lightdm, sddm, cosmic-greeter", "[WARN]".yellow(), display_manager);
-            return Ok(());
+    // Execute actions
+    let mut result = SubsystemResult::default();
+    for (container, action) in &actions {
+        execute_container_action(runner, container, action, home_path, state, args)?;
+        match action {
+            ContainerAction::Skip => result.skipped += 1,
+            ContainerAction::Create => result.created += 1,
+            ContainerAction::Update | ContainerAction::Recreate => result.modified += 1,
         }
-    };
+    }
 
-    // Install the display manager
-    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", dm_package], &format!("Installing {}", dm_package))?;
+    // Setup autostart for containers that need it
+    let autostart_containers: Vec<_> = containers.iter()
+        .filter(|c| c.autostart.unwrap_or(false))
+        .collect();
 
-    // Disable current display manager
-    let _ = run_command(&["sudo", "systemctl", "disable", "gdm"], "Disabling GDM");
-    let _ = run_command(&["sudo", "systemctl", "disable", "lightdm"], "Disabling LightDM");
-    let _ = run_command(&["sudo", "systemctl", "disable", "sddm"], "Disabling SDDM");
-    let _ = run_command(&["sudo", "systemctl", "disable", "cosmic-greeter"], "Disabling COSMIC Greeter");
+    if !autostart_containers.is_empty() {
+        setup_container_autostart(runner, &autostart_containers, args.verbose)?;
+    }
 
-    // Enable the selected display manager
-    let service_name = match display_manager {
-        "cosmic-greeter" => "cosmic-greeter",
-        _ => display_manager,
-    };
+    run_post_create_hooks(runner, containers, state, args.verbose)?;
+
+    Ok(result)
+}
 
-    run_command(&["sudo", "systemctl", "enable", service_name], &format!("Enabling {}", service_name))?;
+// How long to wait for a new container to be running before its post_create_exec commands
+const POST_CREATE_WAIT_SECS: u64 = 30;
 
-    // Set as default display manager
-    run_command(&["sudo", "systemctl", "set-default", "graphical.target"], "Setting graphical target as default")?;
+/// Runs pending post_create_exec commands inside containers once they are running.
+fn run_post_create_hooks(runner: &dyn CommandRunner, containers: &[Container], state: &mut ContainerState, verbose: bool) -> Result<()> {
+    for container in containers {
+        let Some(commands) = &container.post_create_exec else { continue };
+        let Some(info) = state.containers.get_mut(&container.name) else { continue };
+        if !info.post_create_pending {
+            continue;
+        }
 
-    println!("{} Display manager {} configured successfully", "[SUCCESS]".green(), display_manager);
-    println!("{} Reboot required for display manager changes to take effect", "[INFO]".yellow());
+        // Containers that are only created won't start by themselves, so don't wait for them
+        let starts = container.immediate_start() || container.autostart.unwrap_or(false);
+        let wait_secs = if starts { POST_CREATE_WAIT_SECS } else { 0 };
+        if !wait_for_container_running(runner, &container.name, wait_secs, verbose) {
+            warn(format!("Container {} is not running; post_create_exec will run once it is (next run)", container.name))?;
+            continue;
+        }
+
+        for command in commands {
+            run_command_via(runner, &["podman", "exec", &container.name, "sh", "-c", command],
+                        &format!("Running post-create command in {}: {}", container.name, command))?;
+        }
+        info.post_create_pending = false;
+        println!("{} Post-create commands finished for {}", "[SUCCESS]".green(), container.name);
+    }
 
     Ok(())
 }
 
-fn setup_drives(drives: &[DriveConfig], verbose: bool) -> Result<()> {
-    if drives.is_empty() {
-        return Ok(());
+fn wait_for_container_running(runner: &dyn CommandRunner, name: &str, wait_secs: u64, verbose: bool) -> bool {
+    for attempt in 0..=wait_secs {
+        let running = spawn_output_via(runner, &["podman", "inspect", "--format", "{{.State.Running}}", name])
+            .is_ok_and(|o| o.status.success() && String::from_utf8_lossy(&o.stdout).trim() == "true");
+        if running {
+            return true;
+        }
+        if attempt == wait_secs {
+            break;
+        }
+        if verbose && attempt == 0 {
+            println!("{} Waiting up to {}s for {} to start", "[DEBUG]".cyan(), wait_secs, name);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
     }
+    false
+}
 
-    println!("{} Setting up drive mounting...", "[INFO]".blue());
+fn get_volume_backup_dir() -> Result<std::path::PathBuf> {
+    let backup_dir = state_dir()?
+        .join("volume_backups");
+    fs::create_dir_all(&backup_dir)?;
+    Ok(backup_dir)
+}
 
-    // Install required packages for drive mounting
-    install_drive_packages(verbose)?;
+/// Named volumes referenced by -v/--volume/--mount in raw_flags (bind mounts are skipped).
+fn get_named_volumes(raw_flags: &str) -> Vec<String> {
+    let flags: Vec<&str> = raw_flags.split_whitespace().collect();
+    let mut volumes = Vec::new();
+
+    for (i, flag) in flags.iter().enumerate() {
+        let source = match *flag {
+            "-v" | "--volume" => flags.get(i + 1).and_then(|spec| spec.split(':').next()).map(String::from),
+            "--mount" => flags.get(i + 1).and_then(|spec| {
+                let is_volume = spec.split(',').any(|part| part == "type=volume");
+                spec.split(',')
+                    .find_map(|part| part.strip_prefix("source=").or_else(|| part.strip_prefix("src=")))
+                    .filter(|_| is_volume)
+                    .map(String::from)
+            }),
+            _ => flag.strip_prefix("--volume=").or_else(|| flag.strip_prefix("-v="))
+                .and_then(|spec| spec.split(':').next())
+                .map(String::from),
+        };
 
-    for drive in drives {
-        setup_single_drive(drive, verbose)?;
+        if let Some(source) = source {
+            let is_path = source.starts_with('/') || source.starts_with('.') || source.starts_with('$') || source.starts_with('~');
+            if !source.is_empty() && !is_path && !volumes.contains(&source) {
+                volumes.push(source);
+            }
+        }
     }
 
-    println!("{} All drives configured successfully!", "[SUCCESS]".green());
-    Ok(())
+    volumes
 }
 
-fn install_drive_packages(verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Installing drive mounting packages", "[DEBUG]".cyan());
+/// Bind mounts referenced by -v/--volume/--mount in raw_flags, as (host path, options).
+fn get_bind_mounts(raw_flags: &str) -> Vec<(String, String)> {
+    let flags: Vec<&str> = raw_flags.split_whitespace().collect();
+    let mut mounts = Vec::new();
+
+    for (i, flag) in flags.iter().enumerate() {
+        let mount = match *flag {
+            "-v" | "--volume" => flags.get(i + 1).map(|spec| split_volume_spec(spec)),
+            "--mount" => flags.get(i + 1).and_then(|spec| {
+                if !spec.split(',').any(|part| part == "type=bind") {
+                    return None;
+                }
+                spec.split(',')
+                    .find_map(|part| part.strip_prefix("source=").or_else(|| part.strip_prefix("src=")))
+                    .map(|source| (source.to_string(), spec.to_string()))
+            }),
+            _ => flag.strip_prefix("--volume=").or_else(|| flag.strip_prefix("-v="))
+                .map(split_volume_spec),
+        };
+
+        if let Some((source, options)) = mount {
+            if source.starts_with('/') || source.starts_with('~') {
+                mounts.push((source, options));
+            }
+        }
     }
 
-    // Install cryptsetup for encrypted drives and other utilities
-    run_command(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "cryptsetup", "util-linux"], "Installing drive mounting utilities")?;
-    Ok(())
+    mounts
 }
 
-fn setup_single_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
-    println!("{} Configuring drive {} -> {}", "[INFO]".blue(), drive.device, drive.mount_point);
+/// Splits `host:container[:options]` into the host part and the options.
+fn split_volume_spec(spec: &str) -> (String, String) {
+    let mut parts = spec.splitn(3, ':');
+    let source = parts.next().unwrap_or("").to_string();
+    let options = parts.nth(1).unwrap_or("").to_string();
+    (source, options)
+}
 
-    // Check if device exists
-    if !std::path::Path::new(&drive.device).exists() {
-        println!("{} Device {} does not exist, skipping", "[WARN]".yellow(), drive.device);
-        return Ok(());
-    }
+/// Creates missing bind-mount host directories before podman does, since podman
+/// creates them root-owned and a rootless container then can't write to them.
+fn prepare_bind_mounts(container: &Container, home_path: &str, verbose: bool) -> Result<()> {
+    let raw_flags = container.raw_flags.as_deref().unwrap_or("").replace("$HOME", home_path);
 
-    // Create mount point
-    run_command(&["sudo", "mkdir", "-p", &drive.mount_point], &format!("Creating mount point {}", drive.mount_point))?;
+    for (source, options) in get_bind_mounts(&raw_flags) {
+        let source = match source.strip_prefix('~') {
+            Some(rest) => format!("{}{}", home_path, rest),
+            None => source,
+        };
+        let path = Path::new(&source);
 
-    if drive.encrypted {
-        setup_encrypted_drive(drive, verbose)?;
-    } else {
-        setup_unencrypted_drive(drive, verbose)?;
+        if !path.exists() {
+            println!("{} Creating volume directory {} for {}", "[INFO]".blue(), source, container.name);
+            create_dir(path)
+                .with_context(|| format!("Failed to create volume directory {} for container {}", source, container.name))?;
+            chown_for_user(path)?;
+        } else if verbose {
+            println!("{} Volume directory {} exists", "[DEBUG]".cyan(), source);
+        }
+
+        let relabeled = options.split(',').any(|opt| opt == "z" || opt == "Z" || opt.starts_with("relabel="));
+        if selinux_enabled() && !relabeled {
+            warn(format!("Bind mount {} in {} has no :z/:Z option; SELinux may deny the container access",
+                         source, container.name))?;
+        }
     }
 
     Ok(())
 }
 
-fn setup_unencrypted_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Setting up unencrypted drive {}", "[DEBUG]".cyan(), drive.device);
+fn backup_container_volumes(runner: &dyn CommandRunner, container: &Container, verbose: bool) -> Result<()> {
+    let volumes = get_named_volumes(container.raw_flags.as_deref().unwrap_or(""));
+    if volumes.is_empty() {
+        debug!(verbose, "No named volumes to back up for {}", container.name);
+        return Ok(());
     }
 
-    // Get filesystem type if not specified
-    let filesystem = drive.filesystem.as_deref().unwrap_or("auto");
+    let backup_dir = get_volume_backup_dir()?;
+    let timestamp = get_current_timestamp();
 
-    // Get UUID of the device
-    let uuid_output = run_command_output(&["sudo", "blkid", "-s", "UUID", "-o", "value", &drive.device])?;
-    let uuid = String::from_utf8_lossy(&uuid_output.stdout).trim().to_string();
+    for volume in &volumes {
+        let exists = spawn_output_via(runner, &["podman", "volume", "exists", volume]).is_ok_and(|output| output.status.success());
+        if !exists {
+            debug!(verbose, "Volume {} does not exist yet, nothing to back up", volume);
+            continue;
+        }
 
-    if uuid.is_empty() {
-        println!("{} Could not get UUID for {}, using device path", "[WARN]".yellow(), drive.device);
-        add_to_fstab(&drive.device, &drive.mount_point, filesystem, "defaults", drive.force_update.unwrap_or(false), verbose)?;
-    } else {
-        let uuid_device = format!("UUID={}", uuid);
-        add_to_fstab(&uuid_device, &drive.mount_point, filesystem, "defaults", drive.force_update.unwrap_or(false), verbose)?;
+        let tarball = backup_dir.join(format!("{}__{}__{}.tar", container.name, volume, timestamp));
+        let tarball_str = tarball.to_string_lossy().to_string();
+        run_command_via(
+            runner,
+            &["podman", "volume", "export", volume, "--output", &tarball_str],
+            &format!("Backing up volume {} to {}", volume, tarball_str)
+        ).with_context(|| format!("Volume backup failed for {}, not recreating container {}", volume, container.name))?;
     }
 
-    // Mount the drive
-    run_command(&["sudo", "mount", &drive.device, &drive.mount_point], &format!("Mounting {} to {}", drive.device, drive.mount_point))?;
-
-    println!("{} Unencrypted drive {} mounted successfully", "[SUCCESS]".green(), drive.device);
     Ok(())
 }
 
-fn setup_encrypted_drive(drive: &DriveConfig, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Setting up encrypted drive {}", "[DEBUG]".cyan(), drive.device);
-    }
-
-    // Generate a mapper name based on the label or device name
-    let default_name = drive.device.replace("/dev/", "").replace("/", "_");
-    let mapper_name = drive.label.as_deref().unwrap_or(&default_name);
-    let mapper_path = format!("/dev/mapper/{}", mapper_name);
-
-    // Get UUID of the encrypted device
-    let uuid_output = run_command_output(&["sudo", "blkid", "-s", "UUID", "-o", "value", &drive.device])?;
-    let uuid = String::from_utf8_lossy(&uuid_output.stdout).trim().to_string();
+fn restore_container_volumes(container_name: &str, yes: bool, no: bool, verbose: bool) -> Result<()> {
+    let backup_dir = get_volume_backup_dir()?;
 
-    if uuid.is_empty() {
-        anyhow::bail!("Could not get UUID for encrypted device {}", drive.device);
+    // Latest backup per volume, matching the container name exactly so `web` skips `web__db`'s backups
+    let mut latest: HashMap<String, (u64, std::path::PathBuf)> = HashMap::new();
+    for entry in fs::read_dir(&backup_dir)?.flatten() {
+        let path = entry.path();
+        let Some((container, volume, timestamp)) = path.file_name().and_then(|n| n.to_str()).and_then(parse_volume_backup_name) else {
+            continue;
+        };
+        if container != container_name {
+            continue;
+        }
+        if latest.get(volume).is_none_or(|(current, _)| timestamp > *current) {
+            latest.insert(volume.to_string(), (timestamp, path.clone()));
+        }
     }
 
-    // Add to crypttab
-    add_to_crypttab(mapper_name, &uuid, drive.force_update.unwrap_or(false), verbose)?;
+    if latest.is_empty() {
+        println!("{} No volume backups found for {} in {:?}", "[INFO]".blue(), container_name, backup_dir);
+        return Ok(());
+    }
 
-    // Check if the encrypted device is already opened
-    if !std::path::Path::new(&mapper_path).exists() {
-        println!("{} Opening encrypted device {} (you may need to enter passphrase)", "[INFO]".blue(), drive.device);
-        run_command(&["sudo", "cryptsetup", "open", &drive.device, mapper_name], &format!("Opening encrypted device {}", drive.device))?;
+    println!("{} Latest volume backups for {}:", "[INFO]".blue(), container_name);
+    for (volume, (_, path)) in &latest {
+        println!("  - {} <- {:?}", volume, path);
     }
 
-    // Get filesystem type if not specified
-    let filesystem = drive.filesystem.as_deref().unwrap_or("auto");
+    if !ask_user_confirmation(&format!("Stop {} and restore these volumes?", container_name), yes, no, verbose)? {
+        println!("{} Skipping volume restore", "[INFO]".blue());
+        return Ok(());
+    }
 
-    // Add to fstab using the mapper path
-    add_to_fstab(&mapper_path, &drive.mount_point, filesystem, "defaults", drive.force_update.unwrap_or(false), verbose)?;
+    let _ = run_command(&["podman", "stop", container_name], &format!("Stopping container {}", container_name));
 
-    // Mount the decrypted drive
-    run_command(&["sudo", "mount", &mapper_path, &drive.mount_point], &format!("Mounting decrypted {} to {}", mapper_path, drive.mount_point))?;
+    for (volume, (_, path)) in &latest {
+        let exists = spawn_output(&["podman", "volume", "exists", volume]).is_ok_and(|output| output.status.success());
+        if !exists {
+            run_command(&["podman", "volume", "create", volume], &format!("Creating volume {}", volume))?;
+        }
+        let path_str = path.to_string_lossy().to_string();
+        run_command(&["podman", "volume", "import", volume, &path_str], &format!("Restoring volume {}", volume))?;
+    }
 
-    println!("{} Encrypted drive {} mounted successfully", "[SUCCESS]".green(), drive.device);
+    println!("{} Restored {} volumes for {}; start the container again when ready", "[SUCCESS]".green(), latest.len(), container_name);
     Ok(())
 }
 
-fn add_to_crypttab(mapper_name: &str, uuid: &str, force_update: bool, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Adding {} to /etc/crypttab", "[DEBUG]".cyan(), mapper_name);
+fn get_podman_images(runner: &dyn CommandRunner) -> Result<Vec<serde_json::Value>> {
+    let output = spawn_output_via(runner, &["podman", "images", "--format", "json"])?;
+    if !output.status.success() {
+        anyhow::bail!("podman images failed: {}", String::from_utf8_lossy(&output.stderr));
     }
+    let images: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+        .context("Failed to parse podman images output")?;
+    Ok(images)
+}
 
-    let crypttab_entry = format!("{} UUID={} none luks", mapper_name, uuid);
+fn image_size(image: &serde_json::Value) -> u64 {
+    image["Size"].as_u64().unwrap_or(0)
+}
 
-    // Read current crypttab content
-    let crypttab_content = std::fs::read_to_string("/etc/crypttab").unwrap_or_default();
+fn image_names(image: &serde_json::Value) -> Vec<String> {
+    image["Names"]
+        .as_array()
+        .map(|names| names.iter().filter_map(|n| n.as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
 
-    // Check if entry already exists
-    let entry_exists = crypttab_content.lines().any(|line| {
-        line.trim().starts_with(&format!("{} ", mapper_name)) || line.trim() == mapper_name
-    });
+/// Whether a local image name (e.g. docker.io/library/nginx:latest) matches a configured image reference.
+fn image_matches_reference(name: &str, reference: &str) -> bool {
+    let reference = if reference.rsplit('/').next().is_some_and(|last| last.contains(':') || last.contains('@')) {
+        reference.to_string()
+    } else {
+        format!("{}:latest", reference)
+    };
+    name == reference || name.ends_with(&format!("/{}", reference))
+}
 
-    if entry_exists && !force_update {
-        println!("{} Entry for {} already exists in /etc/crypttab", "[INFO]".blue(), mapper_name);
-        return Ok(());
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
     }
-
-    if entry_exists && force_update {
-        if verbose {
-            println!("{} Updating existing {} entry in /etc/crypttab", "[DEBUG]".cyan(), mapper_name);
-        }
-
-        // Remove existing entry and add new one
-        let updated_content = crypttab_content
-            .lines()
-            .filter(|line| !line.trim().starts_with(&format!("{} ", mapper_name)) && line.trim() != mapper_name)
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        let final_content = if updated_content.trim().is_empty() {
-            crypttab_entry
-        } else {
-            format!("{}\n{}", updated_content, crypttab_entry)
-        };
-
-        // Write updated content
-        let write_cmd = format!("echo '{}' | sudo tee /etc/crypttab > /dev/null", final_content);
-        run_command(&["sh", "-c", &write_cmd], &format!("Updating {} in /etc/crypttab", mapper_name))?;
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
     } else {
-        // Append new entry
-        let append_cmd = format!("echo '{}' | sudo tee -a /etc/crypttab > /dev/null", crypttab_entry);
-        run_command(&["sh", "-c", &append_cmd], &format!("Adding {} to /etc/crypttab", mapper_name))?;
+        format!("{:.1} {}", size, UNITS[unit])
     }
-
-    println!("{} Added {} to /etc/crypttab", "[SUCCESS]".green(), mapper_name);
-    Ok(())
 }
 
-fn add_to_fstab(device: &str, mount_point: &str, filesystem: &str, options: &str, force_update: bool, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Adding {} to /etc/fstab", "[DEBUG]".cyan(), device);
+fn get_podman_storage_summary() -> Result<PodmanStorageSummary> {
+    let output = spawn_output(&["podman", "system", "df", "--format", "json"])?;
+    if !output.status.success() {
+        anyhow::bail!("podman system df failed: {}", String::from_utf8_lossy(&output.stderr));
     }
 
-    let fstab_entry = format!("{} {} {} {} 0 2", device, mount_point, filesystem, options);
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+        .context("Failed to parse podman system df output")?;
 
-    // Read current fstab content
-    let fstab_content = std::fs::read_to_string("/etc/fstab").unwrap_or_default();
+    let mut summary = PodmanStorageSummary::default();
+    for entry in &entries {
+        let size = entry["RawSize"].as_u64().unwrap_or(0);
+        summary.reclaimable_bytes += entry["RawReclaimable"].as_u64().unwrap_or(0);
+        match entry["Type"].as_str().unwrap_or("") {
+            "Images" => summary.images_bytes = size,
+            "Containers" => summary.containers_bytes = size,
+            "Local Volumes" | "Volumes" => summary.volumes_bytes = size,
+            _ => {}
+        }
+    }
+    Ok(summary)
+}
 
-    // Check if entry already exists (check for mount point since device might change)
-    let entry_exists = fstab_content.lines().any(|line| {
-        line.trim().split_whitespace().nth(1) == Some(mount_point)
-    });
+fn prune_container_images(runner: &dyn CommandRunner, podman: &PodmanConfig, args: &Args) -> Result<()> {
+    println!("{} Pruning unused container images...", "[INFO]".blue());
 
-    if entry_exists && !force_update {
-        println!("{} Entry for {} already exists in /etc/fstab", "[INFO]".blue(), mount_point);
-        return Ok(());
-    }
+    let images_before = get_podman_images(runner)?;
+    let size_before: u64 = images_before.iter().map(image_size).sum();
 
-    // Backup fstab
-    run_command(&["sudo", "cp", "/etc/fstab", "/etc/fstab.backup"], "Backing up /etc/fstab")?;
+    // Dangling images are never referenced by name, and podman keeps images used by any container (running or stopped)
+    run_command_via(runner, &["podman", "image", "prune", "-f"], "Pruning dangling images")?;
 
-    if entry_exists && force_update {
-        if verbose {
-            println!("{} Updating existing {} entry in /etc/fstab", "[DEBUG]".cyan(), mount_point);
-        }
+    if podman.prune_all_images.unwrap_or(false) {
+        let configured_images: Vec<&str> = podman.containers
+            .as_ref()
+            .map(|containers| containers.iter().map(|c| c.image.as_str()).collect())
+            .unwrap_or_default();
 
-        // Remove existing entry and add new one
-        let updated_content = fstab_content
-            .lines()
-            .filter(|line| {
-                line.trim().split_whitespace().nth(1) != Some(mount_point)
+        // Unused tagged images, excluding anything a configured container still points at
+        let candidates: Vec<serde_json::Value> = get_podman_images(runner)?
+            .into_iter()
+            .filter(|image| image["Containers"].as_u64().unwrap_or(0) == 0)
+            .filter(|image| {
+                !image_names(image).iter().any(|name| configured_images.iter().any(|reference| image_matches_reference(name, reference)))
             })
-            .collect::<Vec<_>>()
-            .join("\n");
+            .collect();
 
-        let final_content = if updated_content.trim().is_empty() {
-            fstab_entry
-        } else {
-            format!("{}\n{}", updated_content, fstab_entry)
-        };
+        if !candidates.is_empty() {
+            println!("\n{} {} unused images are not referenced by any configured container:", "[INFO]".yellow(), candidates.len());
+            for image in &candidates {
+                let names = image_names(image);
+                let label = if names.is_empty() { image["Id"].as_str().unwrap_or("<unknown>").to_string() } else { names.join(", ") };
+                println!("  - {} ({})", label, format_bytes(image_size(image)));
+            }
 
-        // Write updated content
-        let write_cmd = format!("echo '{}' | sudo tee /etc/fstab > /dev/null", final_content);
-        run_command(&["sh", "-c", &write_cmd], &format!("Updating {} in /etc/fstab", mount_point))?;
-    } else {
-        // Append new entry
-        let append_cmd = format!("echo '{}' | sudo tee -a /etc/fstab > /dev/null", fstab_entry);
-        run_command(&["sh", "-c", &append_cmd], &format!("Adding {} to /etc/fstab", mount_point))?;
+            if ask_user_confirmation("Do you want to remove these images?", args.yes, args.no, args.verbose)? {
+                for image in &candidates {
+                    if let Some(id) = image["Id"].as_str() {
+                        if let Err(e) = run_command_via(runner, &["podman", "rmi", id], &format!("Removing image {}", id)) {
+                            println!("{} Failed to remove image {}: {}", "[WARN]".yellow(), id, e);
+                        }
+                    }
+                }
+            }
+        } else if args.verbose {
+            println!("{} No unused tagged images to remove", "[DEBUG]".cyan());
+        }
     }
 
-    println!("{} Added {} to /etc/fstab", "[SUCCESS]".green(), mount_point);
-    Ok(())
-}
-
-fn manage_containers_smart(containers: &[Container], home_path: &str, args: &Args) -> Result<()> {
-    println!("{} Managing containers with smart lifecycle", "[INFO]".blue());
+    let images_after = get_podman_images(runner)?;
+    let size_after: u64 = images_after.iter().map(image_size).sum();
 
-    // Load container state
-    let mut state = load_container_state()?;
+    println!("{} Image pruning completed", "[SUCCESS]".green());
+    println!("  - Removed: {} images", images_before.len().saturating_sub(images_after.len()));
+    println!("  - Reclaimed: {}", format_bytes(size_before.saturating_sub(size_after)));
 
-    // Get existing containers
-    let existing_containers = get_existing_containers()?;
+    Ok(())
+}
 
-    // Analyze what needs to be done
-    let mut actions = Vec::new();
+#[derive(Debug, Clone)]
+enum ContainerAction {
+    Skip,
+    Create,
+    Update,
+    Recreate,
+}
 
-    for container in containers {
-        let action = determine_container_action(container, &state, &existing_containers, args)?;
-        actions.push((container, action));
+fn find_rename_candidate<'a>(
+    runner: &dyn CommandRunner,
+    old_name: &str,
+    containers: &'a [Container],
+    existing_containers: &HashMap<String, String>,
+    state: &ContainerState,
+) -> Option<(&'a Container, bool)> {
+    let missing: Vec<&Container> = containers.iter()
+        .filter(|c| !existing_containers.contains_key(&c.name))
+        .collect();
+    if missing.is_empty() {
+        return None;
     }
 
-    // Show summary of actions (only if there are non-Skip actions)
-    let has_actions = actions.iter().any(|(_, action)| !matches!(action, ContainerAction::Skip));
-    if has_actions && !args.yes {
-        show_container_action_summary(&actions);
-        if !ask_user_confirmation("Proceed with container operations?", args.yes, args.no, args.verbose)? {
-            println!("{} Container operations cancelled", "[INFO]".blue());
-            return Ok(());
+    // Exact match: the stored hash equals the new config hashed under the old name, i.e. only the name changed
+    if let Some(info) = state.containers.get(old_name) {
+        if let Some(container) = missing.iter().find(|c| hash_container_config(c, old_name) == info.config_hash) {
+            return Some((container, true));
         }
     }
 
-    // Execute actions
-    for (container, action) in &actions {
-        execute_container_action(container, action, home_path, &mut state, args)?;
+    // Fallback: same image, config may have changed as well
+    let output = spawn_output_via(runner, &["podman", "inspect", "--format", "{{.ImageName}}", old_name]).ok()?;
+    let old_image = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if old_image.is_empty() {
+        return None;
     }
+    let mut same_image = missing.into_iter()
+        .filter(|c| old_image == c.image || image_matches_reference(&old_image, &c.image));
+    match (same_image.next(), same_image.next()) {
+        (Some(container), None) => Some((container, false)),
+        // Ambiguous, don't guess
+        _ => None,
+    }
+}
 
-    // Save updated state
-    save_container_state(&state)?;
+fn rename_managed_container(runner: &dyn CommandRunner, old_name: &str, container: &Container, exact: bool, state: &mut ContainerState, verbose: bool) -> Result<()> {
+    let home_dir = user_home_dir()?;
 
-    // Setup autostart for containers that need it
-    let autostart_containers: Vec<_> = containers.iter()
-        .filter(|c| c.autostart.unwrap_or(false))
-        .collect();
+    // The Quadlet unit is regenerated under the new name by the autostart setup
+    let service_name = format!("{}.service", old_name);
+    let _ = run_command_via(runner, &["systemctl", "--user", "stop", &service_name], &format!("Stopping systemd service for {}", old_name));
+    let quadlet_path = home_dir.join(".config").join("containers").join("systemd").join(format!("{}.container", old_name));
+    if quadlet_path.exists() {
+        delete_file(&quadlet_path).context(format!("Failed to remove Quadlet file for {}", old_name))?;
+        mark_daemon_reload(&ServiceScope::User);
+    }
 
-    if !autostart_containers.is_empty() {
-        setup_container_autostart(&autostart_containers, args.verbose)?;
+    run_command_via(runner, &["podman", "rename", old_name, &container.name], &format!("Renaming container {} to {}", old_name, container.name))?;
+
+    if let Some(mut info) = state.containers.remove(old_name) {
+        if exact {
+            // Nothing but the name changed, so the container is already up to date
+            info.config_hash = generate_container_config_hash(container);
+            info.config_fields = container_config_fields(container);
+            info.last_updated = get_current_timestamp();
+        } else if verbose {
+            println!("{} Config of {} differs from {}, it will still be updated", "[DEBUG]".cyan(), container.name, old_name);
+        }
+        state.containers.insert(container.name.clone(), info);
     }
 
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-enum ContainerAction {
-    Skip,
-    Create,
-    Update,
-    Recreate,
-}
-
-fn get_existing_containers() -> Result<HashMap<String, String>> {
-    let output = Command::new("podman")
-        .args(&["ps", "-a", "--format", "{{.Names}}"])
-        .output()
+fn get_existing_containers(runner: &dyn CommandRunner) -> Result<HashMap<String, String>> {
+    let output = spawn_output_via(runner, &["podman", "ps", "-a", "--format", "{{.Names}}"])
         .context("Failed to list existing containers")?;
 
     let mut containers = HashMap::new();
@@ -2630,6 +8142,7 @@ fn get_existing_containers() -> Result<HashMap<String, String>> {
 }
 
 fn determine_container_action(
+    runner: &dyn CommandRunner,
     container: &Container,
     state: &ContainerState,
     existing_containers: &HashMap<String, String>,
@@ -2647,22 +8160,134 @@ fn determine_container_action(
         return Ok(ContainerAction::Skip);
     }
 
-    // Check if container exists
-    if !exists {
-        return Ok(ContainerAction::Create);
-    }
+    // Check if container exists
+    if !exists {
+        return Ok(ContainerAction::Create);
+    }
+
+    // Check if we have previous state
+    if let Some(container_info) = state.containers.get(&container.name) {
+        if container_info.config_hash != current_hash {
+            return Ok(ContainerAction::Update);
+        } else if (args.update_images || container.recreate_on_image_change.unwrap_or(false))
+            && image_changed(runner, container, container_info)
+        {
+            return Ok(ContainerAction::Recreate);
+        } else {
+            return Ok(ContainerAction::Skip);
+        }
+    }
+
+    // Container exists but no state - likely first run with existing container
+    Ok(ContainerAction::Update)
+}
+
+/// Pulls every distinct image used by the containers (--update-images).
+fn pull_container_images(runner: &dyn CommandRunner, containers: &[Container]) -> Result<()> {
+    let mut images: Vec<&str> = containers.iter().map(|c| c.image.as_str()).collect();
+    images.sort();
+    images.dedup();
+
+    for image in images {
+        pull_image(runner, image)?;
+    }
+    Ok(())
+}
+
+/// Local image ID for an image reference, None if the image is not present.
+fn get_image_id(runner: &dyn CommandRunner, image: &str) -> Option<String> {
+    let output = spawn_output_via(runner, &["podman", "image", "inspect", "--format", "{{.Id}}", image]).ok()?;
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !id.is_empty()).then_some(id)
+}
+
+/// Whether the local image now differs from the one the container was created from.
+/// Falls back to the container's own image ID when the state predates image tracking.
+fn image_changed(runner: &dyn CommandRunner, container: &Container, info: &ContainerInfo) -> bool {
+    let Some(current) = get_image_id(runner, &container.image) else {
+        return false;
+    };
+    let stored = info.image_hash.clone().or_else(|| {
+        let output = spawn_output_via(runner, &["podman", "inspect", "--format", "{{.Image}}", &container.name]).ok()?;
+        let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !id.is_empty()).then_some(id)
+    });
+    stored.is_some_and(|stored| stored != current)
+}
+
+fn explain_container_action(
+    container: &Container,
+    action: &ContainerAction,
+    state: &ContainerState,
+    existing_containers: &HashMap<String, String>,
+    args: &Args,
+) {
+    let short = |hash: &str| hash.chars().take(12).collect::<String>();
+    let current_hash = generate_container_config_hash(container);
+    let stored = state.containers.get(&container.name);
+
+    println!("{} {} -> {:?}", "[EXPLAIN]".cyan(), container.name, action);
+    println!("  - Exists: {}", if existing_containers.contains_key(&container.name) { "yes" } else { "no" });
+
+    match stored {
+        Some(info) => {
+            let differs = info.config_hash != current_hash;
+            println!("  - Config hash: stored {} / current {}{}", short(&info.config_hash), short(&current_hash),
+                if differs { " (differs)" } else { " (matches)" });
+            if differs {
+                if info.config_fields.is_empty() {
+                    println!("  - Changed fields: unknown (state predates field tracking)");
+                } else {
+                    let current_fields = container_config_fields(container);
+                    let mut changed: Vec<&String> = current_fields.keys()
+                        .chain(info.config_fields.keys())
+                        .filter(|field| info.config_fields.get(*field) != current_fields.get(*field))
+                        .collect();
+                    changed.sort();
+                    changed.dedup();
+                    for field in changed {
+                        println!("  - Changed field '{}': {:?} -> {:?}", field,
+                            info.config_fields.get(field).map(String::as_str).unwrap_or("<unset>"),
+                            current_fields.get(field).map(String::as_str).unwrap_or("<unset>"));
+                    }
+                }
+            }
+            println!("  - Image hash: {}", info.image_hash.as_deref().map(short).unwrap_or_else(|| "not tracked".to_string()));
+        }
+        None => println!("  - No stored state (never managed, or state was reset)"),
+    }
+
+    let forced_by = if args.force_recreate {
+        "--force-recreate"
+    } else if args.no_recreate {
+        "--no-recreate"
+    } else if args.update_images && matches!(action, ContainerAction::Recreate) {
+        "--update-images (image changed)"
+    } else if container.recreate_on_image_change.unwrap_or(false) && matches!(action, ContainerAction::Recreate) {
+        "recreate_on_image_change (image changed)"
+    } else {
+        "none"
+    };
+    println!("  - Forced by flag: {}", forced_by);
+}
 
-    // Check if we have previous state
-    if let Some(container_info) = state.containers.get(&container.name) {
-        if container_info.config_hash != current_hash {
-            return Ok(ContainerAction::Update);
-        } else {
-            return Ok(ContainerAction::Skip);
-        }
+fn explain_container_actions(args: &Args) -> Result<()> {
+    let (_, config) = read_effective_config(args)?;
+    let containers = config.podman.as_ref().and_then(|p| p.containers.as_ref());
+    let Some(containers) = containers.filter(|c| !c.is_empty()) else {
+        println!("{} No containers configured", "[INFO]".blue());
+        return Ok(());
+    };
+
+    let state = load_container_state()?;
+    let existing_containers = get_existing_containers(command_runner())?;
+
+    for container in containers {
+        let action = determine_container_action(command_runner(), container, &state, &existing_containers, args)?;
+        explain_container_action(container, &action, &state, &existing_containers, args);
     }
 
-    // Container exists but no state - likely first run with existing container
-    Ok(ContainerAction::Update)
+    Ok(())
 }
 
 fn show_container_action_summary(actions: &[(&Container, ContainerAction)]) {
@@ -2673,13 +8298,14 @@ fn show_container_action_summary(actions: &[(&Container, ContainerAction)]) {
             ContainerAction::Skip => continue,
             ContainerAction::Create => println!("  {} {}: Create new container", "✨".green(), container.name),
             ContainerAction::Update => println!("  {} {}: Update (config changed)", "🔄".yellow(), container.name),
-            ContainerAction::Recreate => println!("  {} {}: Force recreate", "🔨".red(), container.name),
+            ContainerAction::Recreate => println!("  {} {}: Recreate (forced or image updated)", "🔨".red(), container.name),
         }
     }
     println!();
 }
 
 fn execute_container_action(
+    runner: &dyn CommandRunner,
     container: &Container,
     action: &ContainerAction,
     home_path: &str,
@@ -2688,9 +8314,7 @@ fn execute_container_action(
 ) -> Result<()> {
     match action {
         ContainerAction::Skip => {
-            if args.verbose {
-                println!("{} Skipping {} (no changes)", "[DEBUG]".cyan(), container.name);
-            }
+            debug!(args.verbose, "Skipping {} (no changes)", container.name);
             return Ok(());
         }
         ContainerAction::Create => {
@@ -2698,64 +8322,90 @@ fn execute_container_action(
         }
         ContainerAction::Update => {
             println!("{} Updating container {} (config changed)", "[INFO]".blue(), container.name);
+            if container.backup_volumes.unwrap_or(false) {
+                backup_container_volumes(runner, container, args.verbose)?;
+            }
             // Remove existing container
-            run_command(&["podman", "rm", "-f", &container.name], &format!("Removing existing container {}", container.name))?;
+            run_command_via(runner, &["podman", "rm", "-f", &container.name], &format!("Removing existing container {}", container.name))?;
         }
         ContainerAction::Recreate => {
             println!("{} Recreating container {}", "[INFO]".blue(), container.name);
+            if container.backup_volumes.unwrap_or(false) {
+                backup_container_volumes(runner, container, args.verbose)?;
+            }
             // Remove existing container
-            run_command(&["podman", "rm", "-f", &container.name], &format!("Removing existing container {}", container.name))?;
+            run_command_via(runner, &["podman", "rm", "-f", &container.name], &format!("Removing existing container {}", container.name))?;
         }
     }
 
-    // Check for conflicting startup configurations
-    if container.start_after_creation && container.autostart.unwrap_or(false) {
-        println!("{} Container '{}' has both start_after_creation=true and autostart=true", "[WARNING]".yellow(), container.name);
-        println!("  This creates a conflict between immediate startup and systemd management.");
-        println!("  Consider renaming 'start_after_creation' to 'immediate_start' for clarity.");
-        println!("  Using systemd management (autostart=true) and skipping immediate startup.");
-    }
+    prepare_bind_mounts(container, home_path, args.verbose)?;
 
-    // Only start immediately if not managed by systemd autostart
-    if container.start_after_creation && !container.autostart.unwrap_or(false) {
-        create_and_start_container(container, home_path)?;
-    } else if !container.start_after_creation {
+    // immediate_start and autostart are mutually exclusive (checked in validate_containers)
+    if container.immediate_start() {
+        create_and_start_container(runner, container, home_path)?;
+    } else {
         // Just create the container without starting
-        create_container_only(container, home_path)?;
+        create_container_only(runner, container, home_path)?;
     }
 
+    // Hooks run on a fresh container; one still pending from before an update carries over
+    let has_hooks = container.post_create_exec.as_ref().is_some_and(|cmds| !cmds.is_empty());
+    let was_pending = state.containers.get(&container.name).is_some_and(|info| info.post_create_pending);
+    let post_create_pending = has_hooks
+        && (matches!(action, ContainerAction::Create | ContainerAction::Recreate) || was_pending);
+
     // Update state
     let container_info = ContainerInfo {
+        config_fields: container_config_fields(container),
         config_hash: generate_container_config_hash(container),
-        image_hash: None, // TODO: Get actual image hash
+        image_hash: get_image_id(runner, &container.image),
         last_updated: get_current_timestamp(),
         managed: true,
+        post_create_pending,
     };
 
     state.containers.insert(container.name.clone(), container_info);
+    if matches!(action, ContainerAction::Create) {
+        record_action(ActionCategory::Container, ActionKind::Create, &container.name, None);
+    }
 
     println!("{} Container {} processed successfully", "[SUCCESS]".green(), container.name);
     Ok(())
 }
 
-fn create_and_start_container(container: &Container, home_path: &str) -> Result<()> {
-    let mut command = format!("podman run -d --name={} --label managed-by=fedoraforge", container.name);
+/// argv for `podman run -d`/`podman create`. raw_flags is split like a shell would split it, with
+/// only $HOME expanded, and nothing goes through a shell.
+fn podman_create_argv(container: &Container, home_path: &str, subcommand: &[&str]) -> Result<Vec<String>> {
+    let mut argv: Vec<String> = ["podman"].iter().chain(subcommand).map(|s| s.to_string()).collect();
+    argv.push(format!("--name={}", container.name));
+    argv.extend(["--label".to_string(), "managed-by=fedoraforge".to_string()]);
+    argv.extend(container_metadata_flags(container));
 
     if let Some(flags) = &container.raw_flags {
         let replaced_flags = flags.replace("$HOME", home_path);
-        command.push(' ');
-        command.push_str(&replaced_flags);
+        let words = split_shell_words(&replaced_flags)
+            .ok_or_else(|| FedoraForgeError::validation(format!("Container {}: unterminated quote in raw_flags", container.name)))?;
+        argv.extend(words);
     }
 
-    command.push(' ');
-    command.push_str(&container.image);
+    argv.push(container.image.clone());
+    Ok(argv)
+}
+
+fn create_and_start_container(runner: &dyn CommandRunner, container: &Container, home_path: &str) -> Result<()> {
+    let argv = podman_create_argv(container, home_path, &["run", "-d"])?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
 
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()
-        .with_context(|| format!("Failed to start container: {}", container.name))?;
+    if dry_run_skip(&format!("run: {}", format_argv(&argv))) {
+        return Ok(());
+    }
+
+    // An implicit pull inside podman run has no retries
+    if get_image_id(runner, &container.image).is_none() {
+        pull_image(runner, &container.image)?;
+    }
 
+    let output = spawn_output_via(runner, &argv)?;
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         println!("{} Failed to start container {}: {}", "[ERROR]".red(), container.name, stderr);
@@ -2765,24 +8415,20 @@ fn create_and_start_container(container: &Container, home_path: &str) -> Result<
     Ok(())
 }
 
-fn create_container_only(container: &Container, home_path: &str) -> Result<()> {
-    let mut command = format!("podman create --name={} --label managed-by=fedoraforge", container.name);
+fn create_container_only(runner: &dyn CommandRunner, container: &Container, home_path: &str) -> Result<()> {
+    let argv = podman_create_argv(container, home_path, &["create"])?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
 
-    if let Some(flags) = &container.raw_flags {
-        let replaced_flags = flags.replace("$HOME", home_path);
-        command.push(' ');
-        command.push_str(&replaced_flags);
+    if dry_run_skip(&format!("run: {}", format_argv(&argv))) {
+        return Ok(());
     }
 
-    command.push(' ');
-    command.push_str(&container.image);
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()
-        .with_context(|| format!("Failed to create container: {}", container.name))?;
+    // An implicit pull inside podman create has no retries
+    if get_image_id(runner, &container.image).is_none() {
+        pull_image(runner, &container.image)?;
+    }
 
+    let output = spawn_output_via(runner, &argv)?;
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         println!("{} Failed to create container {}: {}", "[ERROR]".red(), container.name, stderr);
@@ -2792,35 +8438,34 @@ fn create_container_only(container: &Container, home_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn setup_container_autostart(containers: &[&Container], verbose: bool) -> Result<()> {
+fn setup_container_autostart(runner: &dyn CommandRunner, containers: &[&Container], verbose: bool) -> Result<()> {
     let autostart_containers: Vec<_> = containers.iter()
         .filter(|cont| cont.autostart.unwrap_or(false))
         .collect();
 
     if autostart_containers.is_empty() {
-        if verbose {
-            println!("{} No containers configured for autostart", "[DEBUG]".cyan());
-        }
+        debug!(verbose, "No containers configured for autostart");
         return Ok(());
     }
 
     println!("{} Setting up autostart for {} containers using Quadlet", "[INFO]".blue(), autostart_containers.len());
 
     // Create systemd user directory for Quadlet
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
+    let home_dir = user_home_dir()?;
     let quadlet_dir = home_dir.join(".config/containers/systemd");
-    std::fs::create_dir_all(&quadlet_dir)
+    create_dir(&quadlet_dir)
         .context("Failed to create Quadlet directory")?;
 
     for container in &autostart_containers {
         create_quadlet_file(container, &quadlet_dir, verbose)?;
     }
+    chown_for_user(&home_dir.join(".config/containers"))?;
 
     // Enable lingering for the user so services start without login
-    run_command(&["sudo", "loginctl", "enable-linger", &std::env::var("USER")?], "Enabling user lingering for autostart")?;
+    run_command_via(runner, &["sudo", "loginctl", "enable-linger", &user_name()?], "Enabling user lingering for autostart")?;
 
     // Reload systemd user daemon to pick up new Quadlet files
-    run_command(&["systemctl", "--user", "daemon-reload"], "Reloading systemd user daemon")?;
+    mark_daemon_reload(&ServiceScope::User);
 
     // For Quadlet-generated services, we don't need to manually enable them
     // The .container files with WantedBy=default.target will auto-enable
@@ -2837,9 +8482,7 @@ fn setup_container_autostart(containers: &[&Container], verbose: bool) -> Result
 fn create_quadlet_file(container: &Container, quadlet_dir: &std::path::Path, verbose: bool) -> Result<()> {
     let quadlet_file = quadlet_dir.join(format!("{}.container", container.name));
 
-    if verbose {
-        println!("{} Creating Quadlet file: {}", "[DEBUG]".cyan(), quadlet_file.display());
-    }
+    debug!(verbose, "Creating Quadlet file: {}", quadlet_file.display());
 
     // Parse raw_flags to extract individual options
     let mut quadlet_content = String::new();
@@ -2855,6 +8498,15 @@ fn create_quadlet_file(container: &Container, quadlet_dir: &std::path::Path, ver
 
     // Add labels
     quadlet_content.push_str("Label=managed-by=fedoraforge\n");
+    for (key, value) in sorted_entries(&container.labels) {
+        if key == "managed-by" {
+            continue;
+        }
+        quadlet_content.push_str(&format!("Label=\"{}={}\"\n", key, value.replace('"', "\\\"")));
+    }
+    for (key, value) in sorted_entries(&container.annotations) {
+        quadlet_content.push_str(&format!("Annotation=\"{}={}\"\n", key, value.replace('"', "\\\"")));
+    }
 
     // Parse raw_flags and convert to Quadlet format
     if let Some(flags) = &container.raw_flags {
@@ -2869,16 +8521,45 @@ fn create_quadlet_file(container: &Container, quadlet_dir: &std::path::Path, ver
     quadlet_content.push_str("WantedBy=default.target\n");
 
     // Write the Quadlet file
-    std::fs::write(&quadlet_file, quadlet_content)
+    write_file(&quadlet_file, quadlet_content)
         .context(format!("Failed to write Quadlet file for {}", container.name))?;
 
     println!("{} Created Quadlet file for {}", "[SUCCESS]".green(), container.name);
     Ok(())
 }
 
+/// Quadlet translates the .container file into a service unit at daemon-reload; print both sides of
+/// that translation so an autostart that misbehaves can be traced to the flags that produced it.
+fn show_quadlet(container_name: &str) -> Result<()> {
+    let quadlet_file = user_home_dir()?.join(".config/containers/systemd").join(format!("{}.container", container_name));
+    let content = fs::read_to_string(&quadlet_file).map_err(|_| FedoraForgeError::validation(format!(
+        "No Quadlet file for container '{}' at {} (only containers with autostart = true get one)",
+        container_name, quadlet_file.display())))?;
+    let service_name = format!("{}.service", container_name);
+
+    println!("{} Quadlet file {}:", "[INFO]".blue(), quadlet_file.display());
+    println!("{}", content.trim_end());
+
+    println!("\n{} Generated unit (systemctl --user cat {}):", "[INFO]".blue(), service_name);
+    let unit = spawn_output(&["systemctl", "--user", "cat", &service_name])?;
+    if unit.status.success() {
+        println!("{}", String::from_utf8_lossy(&unit.stdout).trim_end());
+    } else {
+        println!("{} systemd has no unit for {}: {}", "[WARN]".yellow(), service_name, String::from_utf8_lossy(&unit.stderr).trim());
+        println!("  Run `systemctl --user daemon-reload`; if the unit is still missing, check the translation with");
+        println!("  `/usr/libexec/podman/quadlet -dryrun -user`");
+    }
+
+    // `status` exits non-zero for inactive units, which is still a useful answer here
+    println!("\n{} Status:", "[INFO]".blue());
+    let status = spawn_output(&["systemctl", "--user", "status", "--no-pager", &service_name])?;
+    println!("{}", String::from_utf8_lossy(&status.stdout).trim_end());
+    Ok(())
+}
+
 fn parse_raw_flags_to_quadlet(raw_flags: &str, content: &mut String) -> Result<()> {
     // Get home directory for volume path expansion
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
+    let home_dir = user_home_dir()?;
     let home_path = home_dir.to_str().context("Invalid home directory path")?;
 
     // Split raw_flags and convert to Quadlet format
@@ -2967,22 +8648,53 @@ fn parse_raw_flags_to_quadlet(raw_flags: &str, content: &mut String) -> Result<(
     Ok(())
 }
 
-// ========================= SERVICES MANAGEMENT =========================
+// ========================= SYSTEMD DAEMON RELOAD =========================
 
-fn sync_services(yes: bool, no: bool, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Starting services synchronization", "[DEBUG]".cyan());
+// Unit files changed since the last daemon-reload; reloads are deferred until a unit is acted on or the run ends
+static SYSTEM_DAEMON_RELOAD_PENDING: AtomicBool = AtomicBool::new(false);
+static USER_DAEMON_RELOAD_PENDING: AtomicBool = AtomicBool::new(false);
+
+fn daemon_reload_flag(scope: &ServiceScope) -> &'static AtomicBool {
+    match scope {
+        ServiceScope::System => &SYSTEM_DAEMON_RELOAD_PENDING,
+        ServiceScope::User => &USER_DAEMON_RELOAD_PENDING,
     }
+}
+
+fn mark_daemon_reload(scope: &ServiceScope) {
+    daemon_reload_flag(scope).store(true, Ordering::SeqCst);
+}
 
-    sync_system_services(yes, no, verbose)?;
-    sync_user_services(yes, no, verbose)?;
+fn flush_daemon_reload(scope: &ServiceScope) -> Result<()> {
+    if !daemon_reload_flag(scope).swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    match scope {
+        ServiceScope::System => run_command(&["sudo", "systemctl", "daemon-reload"], "Reloading system daemon"),
+        ServiceScope::User => run_command(&["systemctl", "--user", "daemon-reload"], "Reloading user daemon"),
+    }
+}
 
+fn flush_daemon_reloads() -> Result<()> {
+    flush_daemon_reload(&ServiceScope::System)?;
+    flush_daemon_reload(&ServiceScope::User)?;
     Ok(())
 }
 
-fn sync_system_services(yes: bool, no: bool, verbose: bool) -> Result<()> {
+// ========================= SERVICES MANAGEMENT =========================
+
+fn sync_services(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
+    debug!(verbose, "Starting services synchronization");
+
+    let mut result = sync_system_services(yes, no, no_removals, verbose)?;
+    result += sync_user_services(yes, no, no_removals, verbose)?;
+
+    Ok(result)
+}
+
+fn sync_system_services(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
     let config_path = "config/system-services.toml";
-    let config = load_system_services_config().unwrap_or_else(|_| SystemServicesConfig {
+    let config = load_system_services_config().unwrap_or(SystemServicesConfig {
         services: None,
         custom_services: None,
     });
@@ -2990,19 +8702,19 @@ fn sync_system_services(yes: bool, no: bool, verbose: bool) -> Result<()> {
     let declared = config.services.unwrap_or_default();
     let current = get_current_system_services(verbose)?;
 
-    sync_services_bidirectional(&declared, &current, ServiceScope::System, config_path, yes, no, verbose)?;
+    let result = sync_services_bidirectional(&declared, &current, ServiceScope::System, config_path, yes, no, no_removals, verbose)?;
 
     // Handle custom services
     if let Some(custom_services) = config.custom_services {
-        sync_custom_services(&custom_services, ServiceScope::System, yes, no, verbose)?;
+        sync_custom_services(&custom_services, ServiceScope::System, yes, no, no_removals, verbose)?;
     }
 
-    Ok(())
+    Ok(result)
 }
 
-fn sync_user_services(yes: bool, no: bool, verbose: bool) -> Result<()> {
+fn sync_user_services(yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
     let config_path = "config/user-services.toml";
-    let config = load_user_services_config().unwrap_or_else(|_| UserServicesConfig {
+    let config = load_user_services_config().unwrap_or(UserServicesConfig {
         services: None,
         custom_services: None,
         applications: None,
@@ -3011,21 +8723,22 @@ fn sync_user_services(yes: bool, no: bool, verbose: bool) -> Result<()> {
     let declared = config.services.unwrap_or_default();
     let current = get_current_user_services(verbose)?;
 
-    sync_services_bidirectional(&declared, &current, ServiceScope::User, config_path, yes, no, verbose)?;
+    let result = sync_services_bidirectional(&declared, &current, ServiceScope::User, config_path, yes, no, no_removals, verbose)?;
 
     // Handle custom services
     if let Some(custom_services) = config.custom_services {
-        sync_custom_services(&custom_services, ServiceScope::User, yes, no, verbose)?;
+        sync_custom_services(&custom_services, ServiceScope::User, yes, no, no_removals, verbose)?;
     }
 
     // Handle application autostart
     if let Some(applications) = config.applications {
-        sync_application_autostart(&applications, yes, no, verbose)?;
+        sync_application_autostart(&applications, yes, no, no_removals, verbose)?;
     }
 
-    Ok(())
+    Ok(result)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn sync_services_bidirectional(
     declared: &HashMap<String, ServiceState>,
     current: &HashMap<String, CurrentServiceInfo>,
@@ -3033,16 +8746,16 @@ fn sync_services_bidirectional(
     config_path: &str,
     yes: bool,
     no: bool,
+    no_removals: bool,
     verbose: bool,
-) -> Result<()> {
+) -> Result<SubsystemResult> {
+    let mut result = SubsystemResult::default();
     let scope_str = match scope {
         ServiceScope::System => "system",
         ServiceScope::User => "user",
     };
 
-    if verbose {
-        println!("{} Syncing {} services bidirectionally", "[DEBUG]".cyan(), scope_str);
-    }
+    debug!(verbose, "Syncing {} services bidirectionally", scope_str);
 
     // Find services in system but not in config (add to config)
     let undeclared: Vec<_> = current.iter()
@@ -3072,11 +8785,13 @@ fn sync_services_bidirectional(
     }
 
     // Find services in config but with different states (apply changes)
+    for (name, _) in declared.iter().filter(|(name, _)| current.get(*name).is_some_and(|info| !info.exists)) {
+        warn(format!("Service '{}' declared in config but not found on system", name))?;
+    }
     let to_change: Vec<_> = declared.iter()
         .filter_map(|(name, desired)| {
             current.get(name).and_then(|current_info| {
                 if !current_info.exists {
-                    println!("{} Service '{}' declared in config but not found on system", "[WARN]".yellow(), name);
                     None
                 } else if current_info.enabled != desired.enabled || current_info.active != desired.started {
                     Some((name, desired, current_info))
@@ -3094,77 +8809,46 @@ fn sync_services_bidirectional(
                 name, current.enabled, current.active, desired.enabled, desired.started);
         }
 
-        if ask_user_confirmation(&format!("Apply these {} service changes?", scope_str), yes, no, verbose)? {
-            apply_service_changes(&to_change, scope)?;
+        let subsystem = if matches!(scope, ServiceScope::User) { "user-services" } else { "system-services" };
+        let names: Vec<&str> = to_change.iter().map(|(name, _, _)| name.as_str()).collect();
+        if ask_user_confirmation(&format!("Apply these {} service changes?", scope_str), yes || planned(subsystem, &names), no, verbose)? {
+            apply_service_changes(&to_change, scope, no_removals)?;
+            result.modified += to_change.len();
+        } else {
+            result.skipped += to_change.len();
         }
     }
 
-    Ok(())
+    Ok(result)
 }
 
 fn get_current_system_services(verbose: bool) -> Result<HashMap<String, CurrentServiceInfo>> {
-    if verbose {
-        println!("{} Discovering system services", "[DEBUG]".cyan());
-    }
+    debug!(verbose, "Discovering system services");
 
     let mut services = HashMap::new();
 
     // Get enabled/disabled state
     let output = run_command_output(&["systemctl", "list-unit-files", "--type=service", "--no-pager", "--plain"])?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    for line in stdout.lines() {
-        if line.trim().is_empty() || line.starts_with("UNIT FILE") {
+    for (name, enabled) in parse_service_unit_files(&String::from_utf8_lossy(&output.stdout)) {
+        // Skip system services that are auto-managed (keep consistent with user services filtering)
+        if name == "uresourced" {
+            debug!(verbose, "Skipping auto-managed system service: {}", name);
             continue;
         }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let name = parts[0].trim_end_matches(".service");
-            let state = parts[1];
-
-            // Skip non-manageable service states
-            if matches!(state, "static" | "transient" | "generated" | "indirect" | "alias" | "masked" | "enabled-runtime") {
-                continue;
-            }
-
-            // Skip runtime dbus services and generated autostart services
-            if name.starts_with("dbus-:") || (name.starts_with("app-") && name.contains("@autostart")) {
-                continue;
-            }
-
-            // Skip system services that are auto-managed (keep consistent with user services filtering)
-            if name == "uresourced" {
-                if verbose {
-                    println!("{} Skipping auto-managed system service: {}", "[DEBUG]".cyan(), name);
-                }
-                continue;
-            }
 
-            let enabled = state == "enabled";
-            services.insert(name.to_string(), CurrentServiceInfo {
-                enabled,
-                active: false, // Will be updated below
-                exists: true,
-                is_custom: false, // Will be updated if we find it's custom
-            });
-        }
+        services.insert(name, CurrentServiceInfo {
+            enabled,
+            active: false, // Will be updated below
+            exists: true,
+            is_custom: false, // Will be updated if we find it's custom
+        });
     }
 
     // Get active/inactive state
     let output = run_command_output(&["systemctl", "list-units", "--type=service", "--no-pager", "--plain"])?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    for line in stdout.lines() {
-        if line.trim().is_empty() || line.starts_with("UNIT") {
-            continue;
-        }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 4 {
-            let name = parts[0].trim_end_matches(".service");
-            let active = parts[2] == "active";
-            if let Some(service) = services.get_mut(name) {
-                service.active = active;
-            }
+    for (name, active) in parse_active_services(&String::from_utf8_lossy(&output.stdout)) {
+        if let Some(service) = services.get_mut(&name) {
+            service.active = active;
         }
     }
 
@@ -3176,17 +8860,13 @@ fn get_current_system_services(verbose: bool) -> Result<HashMap<String, CurrentS
         }
     }
 
-    if verbose {
-        println!("{} Found {} system services", "[DEBUG]".cyan(), services.len());
-    }
+    debug!(verbose, "Found {} system services", services.len());
 
     Ok(services)
 }
 
 fn get_current_user_services(verbose: bool) -> Result<HashMap<String, CurrentServiceInfo>> {
-    if verbose {
-        println!("{} Discovering user services", "[DEBUG]".cyan());
-    }
+    debug!(verbose, "Discovering user services");
 
     let mut services = HashMap::new();
 
@@ -3196,74 +8876,38 @@ fn get_current_user_services(verbose: bool) -> Result<HashMap<String, CurrentSer
 
     // Get enabled/disabled state
     let output = run_command_output(&["systemctl", "--user", "list-unit-files", "--type=service", "--no-pager", "--plain"])?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    for line in stdout.lines() {
-        if line.trim().is_empty() || line.starts_with("UNIT FILE") {
+    for (name, enabled) in parse_service_unit_files(&String::from_utf8_lossy(&output.stdout)) {
+        // Skip container services managed by Quadlet
+        if managed_containers.contains(&name) {
+            debug!(verbose, "Skipping Quadlet-managed container service: {}", name);
             continue;
         }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let name = parts[0].trim_end_matches(".service");
-            let state = parts[1];
-
-            // Skip non-manageable service states
-            if matches!(state, "static" | "transient" | "generated" | "indirect" | "alias" | "masked" | "enabled-runtime") {
-                continue;
-            }
-
-            // Skip runtime dbus services and generated autostart services
-            if name.starts_with("dbus-:") || (name.starts_with("app-") && name.contains("@autostart")) {
-                continue;
-            }
-
-            // Skip container services managed by Quadlet
-            if managed_containers.contains(&name.to_string()) {
-                if verbose {
-                    println!("{} Skipping Quadlet-managed container service: {}", "[DEBUG]".cyan(), name);
-                }
-                continue;
-            }
-
-            // Skip desktop session services that shouldn't be managed
-            if matches!(name,
-                "pipewire" | "pipewire-pulse" |
-                "dconf" | "uresourced" | "podman-user-wait-network-online" |
-                "at-spi-dbus-bus"
-            ) || name.starts_with("gvfs-")
-              || name.starts_with("evolution-")
-              || (name.starts_with("xdg-") && name != "xdg-user-dirs") {
-                if verbose {
-                    println!("{} Skipping desktop session service: {}", "[DEBUG]".cyan(), name);
-                }
-                continue;
-            }
 
-            let enabled = state == "enabled";
-            services.insert(name.to_string(), CurrentServiceInfo {
-                enabled,
-                active: false, // Will be updated below
-                exists: true,
-                is_custom: false, // Will be updated if we find it's custom
-            });
+        // Skip desktop session services that shouldn't be managed
+        if matches!(name.as_str(),
+            "pipewire" | "pipewire-pulse" |
+            "dconf" | "uresourced" | "podman-user-wait-network-online" |
+            "at-spi-dbus-bus"
+        ) || name.starts_with("gvfs-")
+          || name.starts_with("evolution-")
+          || (name.starts_with("xdg-") && name != "xdg-user-dirs") {
+            debug!(verbose, "Skipping desktop session service: {}", name);
+            continue;
         }
+
+        services.insert(name, CurrentServiceInfo {
+            enabled,
+            active: false, // Will be updated below
+            exists: true,
+            is_custom: false, // Will be updated if we find it's custom
+        });
     }
 
     // Get active/inactive state
     let output = run_command_output(&["systemctl", "--user", "list-units", "--type=service", "--no-pager", "--plain"])?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    for line in stdout.lines() {
-        if line.trim().is_empty() || line.starts_with("UNIT") {
-            continue;
-        }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 4 {
-            let name = parts[0].trim_end_matches(".service");
-            let active = parts[2] == "active";
-            if let Some(service) = services.get_mut(name) {
-                service.active = active;
-            }
+    for (name, active) in parse_active_services(&String::from_utf8_lossy(&output.stdout)) {
+        if let Some(service) = services.get_mut(&name) {
+            service.active = active;
         }
     }
 
@@ -3275,9 +8919,7 @@ fn get_current_user_services(verbose: bool) -> Result<HashMap<String, CurrentSer
         }
     }
 
-    if verbose {
-        println!("{} Found {} user services", "[DEBUG]".cyan(), services.len());
-    }
+    debug!(verbose, "Found {} user services", services.len());
 
     Ok(services)
 }
@@ -3285,12 +8927,15 @@ fn get_current_user_services(verbose: bool) -> Result<HashMap<String, CurrentSer
 fn apply_service_changes(
     changes: &[(&String, &ServiceState, &CurrentServiceInfo)],
     scope: ServiceScope,
+    no_removals: bool,
 ) -> Result<()> {
     for (name, desired, current) in changes {
         // Handle enabled state
         if current.enabled != desired.enabled {
             if desired.enabled {
                 enable_service(name, &scope)?;
+            } else if no_removals {
+                println!("{} --no-removals: leaving {} enabled", "[INFO]".blue(), name);
             } else {
                 disable_service(name, &scope)?;
             }
@@ -3300,6 +8945,8 @@ fn apply_service_changes(
         if current.active != desired.started {
             if desired.started {
                 start_service(name, &scope)?;
+            } else if no_removals {
+                println!("{} --no-removals: leaving {} running", "[INFO]".blue(), name);
             } else {
                 stop_service(name, &scope)?;
             }
@@ -3308,7 +8955,15 @@ fn apply_service_changes(
     Ok(())
 }
 
+fn scope_label(scope: &ServiceScope) -> &'static str {
+    match scope {
+        ServiceScope::System => "system",
+        ServiceScope::User => "user",
+    }
+}
+
 fn enable_service(name: &str, scope: &ServiceScope) -> Result<()> {
+    flush_daemon_reload(scope)?;
     match scope {
         ServiceScope::System => {
             run_command(&["sudo", "systemctl", "enable", name], &format!("Enabling system service {}", name))?;
@@ -3317,10 +8972,12 @@ fn enable_service(name: &str, scope: &ServiceScope) -> Result<()> {
             run_command(&["systemctl", "--user", "enable", name], &format!("Enabling user service {}", name))?;
         }
     }
+    record_action(ActionCategory::Service, ActionKind::Enable, name, Some(scope_label(scope).to_string()));
     Ok(())
 }
 
 fn disable_service(name: &str, scope: &ServiceScope) -> Result<()> {
+    flush_daemon_reload(scope)?;
     match scope {
         ServiceScope::System => {
             run_command(&["sudo", "systemctl", "disable", name], &format!("Disabling system service {}", name))?;
@@ -3329,10 +8986,12 @@ fn disable_service(name: &str, scope: &ServiceScope) -> Result<()> {
             run_command(&["systemctl", "--user", "disable", name], &format!("Disabling user service {}", name))?;
         }
     }
+    record_action(ActionCategory::Service, ActionKind::Disable, name, Some(scope_label(scope).to_string()));
     Ok(())
 }
 
 fn start_service(name: &str, scope: &ServiceScope) -> Result<()> {
+    flush_daemon_reload(scope)?;
     match scope {
         ServiceScope::System => {
             run_command(&["sudo", "systemctl", "start", name], &format!("Starting system service {}", name))?;
@@ -3345,6 +9004,7 @@ fn start_service(name: &str, scope: &ServiceScope) -> Result<()> {
 }
 
 fn stop_service(name: &str, scope: &ServiceScope) -> Result<()> {
+    flush_daemon_reload(scope)?;
     match scope {
         ServiceScope::System => {
             run_command(&["sudo", "systemctl", "stop", name], &format!("Stopping system service {}", name))?;
@@ -3363,6 +9023,7 @@ fn sync_custom_services(
     scope: ServiceScope,
     yes: bool,
     no: bool,
+    no_removals: bool,
     verbose: bool,
 ) -> Result<()> {
     let mut state = load_custom_services_state()?;
@@ -3371,12 +9032,10 @@ fn sync_custom_services(
         ServiceScope::User => "user",
     };
 
-    if verbose {
-        println!("{} Syncing {} custom services", "[DEBUG]".cyan(), scope_str);
-    }
+    debug!(verbose, "Syncing {} custom services", scope_str);
 
     for service in custom_services {
-        let service_hash = generate_service_hash(&service.service_definition, &service.timer_definition)?;
+        let service_hash = generate_service_hash(&service.service_definition, service.timer_definition.as_deref());
         let state_map = match scope {
             ServiceScope::System => &mut state.system_services,
             ServiceScope::User => &mut state.user_services,
@@ -3387,35 +9046,109 @@ fn sync_custom_services(
             None => true,
         };
 
-        if needs_install {
-            if ask_user_confirmation(&format!("Install/update custom {} service '{}'?", scope_str, service.name), yes, no, verbose)? {
-                install_custom_service(service, &service_hash, &scope, state_map)?;
-            }
+        if needs_install
+            && ask_user_confirmation(&format!("Install/update custom {} service '{}'?", scope_str, service.name), yes, no, verbose)? {
+            install_custom_service(service, &service_hash, &scope, state_map)?;
         }
+    }
 
-        // Sync enabled/started state
-        sync_custom_service_state(service, &scope, verbose)?;
+    // Sync enabled/started state once all unit files are written (a single daemon-reload covers them all)
+    for service in custom_services {
+        sync_custom_service_state(service, &scope, no_removals, verbose)?;
     }
 
     // Remove orphaned custom services
-    cleanup_orphaned_custom_services(custom_services, &mut state, &scope, yes, no, verbose)?;
+    if no_removals {
+        debug!(verbose, "--no-removals: skipping orphaned custom {} service cleanup", scope_str);
+    } else {
+        cleanup_orphaned_custom_services(custom_services, &mut state, &scope, yes, no, verbose)?;
+    }
 
     save_custom_services_state(&state)?;
     Ok(())
 }
 
+/// Runs `systemd-analyze verify` on a custom service (and its timer) from a scratch directory,
+/// so broken units are refused before anything is installed or reloaded.
+fn verify_custom_service(service: &CustomService, scope: &ServiceScope) -> Result<()> {
+    let verify_dir = stage_private_dir(&format!("verify-{}", service.name))?;
+    let output = verify_units_in(&verify_dir, service, scope);
+    let _ = fs::remove_dir_all(&verify_dir);
+    let output = output?;
+
+    if !output.status.success() {
+        let errors = String::from_utf8_lossy(&output.stderr);
+        return Err(FedoraForgeError::validation(format!(
+            "Custom service {} failed systemd-analyze verify:\n{}",
+            service.name,
+            errors.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the service (and timer) into `dir` and runs `systemd-analyze verify` on them.
+fn verify_units_in(dir: &Path, service: &CustomService, scope: &ServiceScope) -> Result<Output> {
+    let service_file = dir.join(format!("{}.service", service.name));
+    fs::write(&service_file, &service.service_definition)?;
+    let mut units = vec![service_file.to_string_lossy().to_string()];
+    if let Some(timer_def) = &service.timer_definition {
+        let timer_file = dir.join(format!("{}.timer", service.name));
+        fs::write(&timer_file, timer_def)?;
+        units.push(timer_file.to_string_lossy().to_string());
+    }
+
+    let mut cmd: Vec<&str> = vec!["systemd-analyze", "verify"];
+    if matches!(scope, ServiceScope::User) {
+        cmd.push("--user");
+    }
+    cmd.extend(units.iter().map(String::as_str));
+    spawn_output(&cmd)
+}
+
+/// --check-only: verifies every custom service and timer in the services configs.
+fn check_custom_units() -> Result<()> {
+    let system = read_declarative_file::<SystemServicesConfig>("config/system-services.toml")?
+        .and_then(|c| c.custom_services)
+        .unwrap_or_default();
+    let user = read_declarative_file::<UserServicesConfig>("config/user-services.toml")?
+        .and_then(|c| c.custom_services)
+        .unwrap_or_default();
+
+    let mut failed = 0;
+    let units = system.iter().map(|s| (s, ServiceScope::System))
+        .chain(user.iter().map(|s| (s, ServiceScope::User)));
+    for (service, scope) in units {
+        match verify_custom_service(service, &scope) {
+            Ok(()) => println!("{} {} ({:?})", "[SUCCESS]".green(), service.name, scope),
+            Err(e) => {
+                println!("{} {:#}", "[ERROR]".red(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(FedoraForgeError::validation(format!("{} custom units failed verification", failed)));
+    }
+    println!("{} All {} custom units verified", "[SUCCESS]".green(), system.len() + user.len());
+    Ok(())
+}
+
 fn install_custom_service(
     service: &CustomService,
     content_hash: &str,
     scope: &ServiceScope,
     state_map: &mut HashMap<String, CustomServiceInfo>,
 ) -> Result<()> {
+    verify_custom_service(service, scope)?;
+
     let service_dir = match scope {
         ServiceScope::System => "/etc/systemd/system".to_string(),
         ServiceScope::User => {
-            let home = dirs::home_dir().context("Could not find home directory")?;
+            let home = user_home_dir()?;
             let user_dir = home.join(".config/systemd/user");
-            fs::create_dir_all(&user_dir)?;
+            create_dir(&user_dir)?;
             user_dir.to_str().unwrap().to_string()
         }
     };
@@ -3425,13 +9158,11 @@ fn install_custom_service(
 
     match scope {
         ServiceScope::System => {
-            // For system services, write to temp file first then sudo move it
-            let temp_file = format!("/tmp/{}.service", service.name);
-            fs::write(&temp_file, &service.service_definition)?;
-            run_command(&["sudo", "mv", &temp_file, &service_file], &format!("Installing system service {}", service.name))?;
+            replace_root_file_with_mode(&service_file, &service.service_definition, 0o644, &format!("Installing system service {}", service.name))?;
         }
         ServiceScope::User => {
-            fs::write(&service_file, &service.service_definition)?;
+            write_file(&service_file, &service.service_definition)?;
+            chown_for_user(&user_home_dir()?.join(".config/systemd"))?;
         }
     }
 
@@ -3441,21 +9172,17 @@ fn install_custom_service(
 
         match scope {
             ServiceScope::System => {
-                let temp_file = format!("/tmp/{}.timer", service.name);
-                fs::write(&temp_file, timer_def)?;
-                run_command(&["sudo", "mv", &temp_file, &timer_file], &format!("Installing system timer {}", service.name))?;
+                replace_root_file_with_mode(&timer_file, timer_def, 0o644, &format!("Installing system timer {}", service.name))?;
             }
             ServiceScope::User => {
-                fs::write(&timer_file, timer_def)?;
+                write_file(&timer_file, timer_def)?;
+                chown_for_user(Path::new(&timer_file))?;
             }
         }
     }
 
     // Reload systemd
-    match scope {
-        ServiceScope::System => run_command(&["sudo", "systemctl", "daemon-reload"], "Reloading system daemon")?,
-        ServiceScope::User => run_command(&["systemctl", "--user", "daemon-reload"], "Reloading user daemon")?,
-    }
+    mark_daemon_reload(scope);
 
     // Update state
     state_map.insert(service.name.clone(), CustomServiceInfo {
@@ -3467,7 +9194,10 @@ fn install_custom_service(
     Ok(())
 }
 
-fn sync_custom_service_state(service: &CustomService, scope: &ServiceScope, _verbose: bool) -> Result<()> {
+fn sync_custom_service_state(service: &CustomService, scope: &ServiceScope, no_removals: bool, _verbose: bool) -> Result<()> {
+    // Newly written unit files must be loaded before their state can be queried
+    flush_daemon_reload(scope)?;
+
     // Check current service state
     let is_enabled = check_service_enabled(&service.name, scope)?;
     let is_active = check_service_active(&service.name, scope)?;
@@ -3476,7 +9206,7 @@ fn sync_custom_service_state(service: &CustomService, scope: &ServiceScope, _ver
     if is_enabled != service.enabled {
         if service.enabled {
             enable_service(&service.name, scope)?;
-        } else {
+        } else if !no_removals {
             disable_service(&service.name, scope)?;
         }
     }
@@ -3485,7 +9215,7 @@ fn sync_custom_service_state(service: &CustomService, scope: &ServiceScope, _ver
     if is_active != service.started {
         if service.started {
             start_service(&service.name, scope)?;
-        } else {
+        } else if !no_removals {
             stop_service(&service.name, scope)?;
         }
     }
@@ -3566,7 +9296,7 @@ fn remove_custom_service(name: &str, scope: &ServiceScope) -> Result<()> {
     let service_dir = match scope {
         ServiceScope::System => "/etc/systemd/system".to_string(),
         ServiceScope::User => {
-            let home = dirs::home_dir().context("Could not find home directory")?;
+            let home = user_home_dir()?;
             home.join(".config/systemd/user").to_string_lossy().to_string()
         }
     };
@@ -3593,28 +9323,17 @@ fn remove_custom_service(name: &str, scope: &ServiceScope) -> Result<()> {
             let _ = run_command(&["sudo", "rm", "-f", &timer_file], &format!("Removing timer file {}", timer_file));
         }
         ServiceScope::User => {
-            let _ = fs::remove_file(&service_file);
-            let _ = fs::remove_file(&timer_file);
+            let _ = delete_file(&service_file);
+            let _ = delete_file(&timer_file);
         }
     }
 
     // Reload systemd
-    match scope {
-        ServiceScope::System => run_command(&["sudo", "systemctl", "daemon-reload"], "Reloading system daemon")?,
-        ServiceScope::User => run_command(&["systemctl", "--user", "daemon-reload"], "Reloading user daemon")?,
-    }
+    mark_daemon_reload(scope);
 
     Ok(())
 }
 
-fn generate_service_hash(service_def: &str, timer_def: &Option<String>) -> Result<String> {
-    let mut hasher = Sha256::new();
-    hasher.update(service_def.trim().as_bytes());
-    if let Some(timer) = timer_def {
-        hasher.update(timer.trim().as_bytes());
-    }
-    Ok(format!("{:x}", hasher.finalize()))
-}
 
 // ========================= APPLICATION AUTOSTART =========================
 
@@ -3622,11 +9341,10 @@ fn sync_application_autostart(
     applications: &HashMap<String, ApplicationAutostart>,
     yes: bool,
     no: bool,
+    no_removals: bool,
     verbose: bool,
 ) -> Result<()> {
-    if verbose {
-        println!("{} Syncing application autostart", "[DEBUG]".cyan());
-    }
+    debug!(verbose, "Syncing application autostart");
 
     // Convert applications to custom services
     let mut app_services = Vec::new();
@@ -3637,7 +9355,7 @@ fn sync_application_autostart(
     }
 
     if !app_services.is_empty() {
-        sync_custom_services(&app_services, ServiceScope::User, yes, no, verbose)?;
+        sync_custom_services(&app_services, ServiceScope::User, yes, no, no_removals, verbose)?;
     }
 
     Ok(())
@@ -3830,10 +9548,32 @@ fn load_user_services_config() -> Result<UserServicesConfig> {
 }
 
 fn load_custom_services_state() -> Result<CustomServicesState> {
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
-    let state_dir = home_dir.join(".config").join("repro-setup");
-    fs::create_dir_all(&state_dir)?;
-    let state_file = state_dir.join("custom_services.json");
+    if state_disabled() {
+        return Ok(CustomServicesState::default());
+    }
+
+    let mut state_file = state_dir()?.join("custom_services.json");
+
+    // One-time move from ~/.config/repro-setup, where this state used to live
+    let legacy_file = dirs::home_dir()
+        .context("Could not find home directory")?
+        .join(".config")
+        .join("repro-setup")
+        .join("custom_services.json");
+    if !state_file.exists() && legacy_file.exists() {
+        if dry_run() {
+            state_file = legacy_file;
+        } else {
+            fs::copy(&legacy_file, &state_file)
+                .with_context(|| format!("Failed to migrate {:?} to {:?}", legacy_file, state_file))?;
+            fs::remove_file(&legacy_file)
+                .with_context(|| format!("Failed to remove {:?}", legacy_file))?;
+            if let Some(legacy_dir) = legacy_file.parent() {
+                let _ = fs::remove_dir(legacy_dir); // Only succeeds when nothing else is left in it
+            }
+            println!("{} Moved custom service state from {:?} to {:?}", "[INFO]".blue(), legacy_file, state_file);
+        }
+    }
 
     if state_file.exists() {
         let content = fs::read_to_string(&state_file)?;
@@ -3845,10 +9585,11 @@ fn load_custom_services_state() -> Result<CustomServicesState> {
 }
 
 fn save_custom_services_state(state: &CustomServicesState) -> Result<()> {
-    let home_dir = dirs::home_dir().context("Could not find home directory")?;
-    let state_dir = home_dir.join(".config").join("repro-setup");
-    fs::create_dir_all(&state_dir)?;
-    let state_file = state_dir.join("custom_services.json");
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
+
+    let state_file = state_dir()?.join("custom_services.json");
 
     let content = serde_json::to_string_pretty(state)?;
     fs::write(&state_file, content)?;
@@ -3896,7 +9637,7 @@ fn update_services_config_with_discovered(
                     .join("\n")
             ),
         };
-        fs::write(config_path, config_content)?;
+        write_file(config_path, config_content)?;
     } else {
         // Append to existing config
         let mut content = existing_content;
@@ -3912,7 +9653,7 @@ fn update_services_config_with_discovered(
             content.push('\n');
         }
 
-        fs::write(config_path, content)?;
+        write_file(config_path, content)?;
     }
 
     Ok(())
@@ -3964,11 +9705,11 @@ fn validate_username(username: &str) -> Result<()> {
     let re = Regex::new(r"^[a-z_][a-z0-9_-]*[$]?$").context("Failed to compile username regex")?;
 
     if !re.is_match(username) {
-        anyhow::bail!("Invalid username '{}': must start with a lowercase letter or underscore, and contain only lowercase letters, numbers, underscores, hyphens, and optionally end with $", username);
+        return Err(FedoraForgeError::validation(format!("Invalid username '{}': must start with a lowercase letter or underscore, and contain only lowercase letters, numbers, underscores, hyphens, and optionally end with $", username)));
     }
 
     if username.len() > 32 {
-        anyhow::bail!("Username '{}' is too long (max 32 characters)", username);
+        return Err(FedoraForgeError::validation(format!("Username '{}' is too long (max 32 characters)", username)));
     }
 
     Ok(())
@@ -3980,28 +9721,48 @@ fn validate_groupname(groupname: &str) -> Result<()> {
 }
 
 fn validate_uid(uid: u32) -> Result<()> {
-    if uid < MIN_USER_UID {
-        anyhow::bail!("UID {} is below minimum {} (system UID range)", uid, MIN_USER_UID);
+    let range = user_uid_range();
+    if uid < *range.start() {
+        return Err(FedoraForgeError::validation(format!("UID {} is below minimum {} (system UID range)", uid, range.start())));
     }
-    if uid > MAX_USER_UID {
-        anyhow::bail!("UID {} exceeds maximum {}", uid, MAX_USER_UID);
+    if uid > *range.end() {
+        return Err(FedoraForgeError::validation(format!("UID {} exceeds maximum {}", uid, range.end())));
     }
     Ok(())
 }
 
 fn validate_gid(gid: u32) -> Result<()> {
-    if gid < MIN_GROUP_GID {
-        anyhow::bail!("GID {} is below minimum {} (system GID range)", gid, MIN_GROUP_GID);
+    let range = group_gid_range();
+    if gid < *range.start() {
+        return Err(FedoraForgeError::validation(format!("GID {} is below minimum {} (system GID range)", gid, range.start())));
     }
-    if gid > MAX_GROUP_GID {
-        anyhow::bail!("GID {} exceeds maximum {}", gid, MAX_GROUP_GID);
+    if gid > *range.end() {
+        return Err(FedoraForgeError::validation(format!("GID {} exceeds maximum {}", gid, range.end())));
     }
     Ok(())
 }
 
-fn validate_shell(shell: &str) -> Result<()> {
+// Shells for locked service accounts; often missing from /etc/shells on purpose
+const NOLOGIN_SHELLS: [&str; 4] = ["/sbin/nologin", "/usr/sbin/nologin", "/bin/false", "/usr/bin/false"];
+
+/// The value is never echoed back: a value that isn't a hash may well be a real password.
+fn validate_password_hash(username: &str, hash: &str) -> Result<()> {
+    if is_crypt_hash(hash) {
+        return Ok(());
+    }
+    Err(FedoraForgeError::validation(format!(
+        "User '{}': password_hash is not a crypt(3) hash and plaintext passwords are refused; generate one with: openssl passwd -6",
+        username
+    )))
+}
+
+fn validate_shell(runner: &dyn CommandRunner, shell: &str) -> Result<()> {
+    if NOLOGIN_SHELLS.contains(&shell) {
+        return Ok(());
+    }
+
     // Check if shell exists in /etc/shells
-    let shells_content = fs::read_to_string("/etc/shells")
+    let shells_content = runner.read_file("/etc/shells")
         .context("Failed to read /etc/shells")?;
 
     let valid_shells: Vec<&str> = shells_content
@@ -4010,18 +9771,21 @@ fn validate_shell(shell: &str) -> Result<()> {
         .map(|line| line.trim())
         .collect();
 
-    if !valid_shells.contains(&shell) {
-        anyhow::bail!("Shell '{}' is not listed in /etc/shells. Valid shells: {:?}", shell, valid_shells);
+    if valid_shells.contains(&shell) {
+        return Ok(());
     }
 
-    Ok(())
+    // Installed but unlisted shells work for login; only chsh-style tools refuse them
+    if Path::new(shell).is_file() {
+        return warn(format!("Shell '{}' exists but is not listed in /etc/shells", shell));
+    }
+
+    Err(FedoraForgeError::validation(format!("Shell '{}' is not listed in /etc/shells and does not exist. Valid shells: {:?}", shell, valid_shells)))
 }
 
 // Backup function
-fn backup_user_files(verbose: bool) -> Result<()> {
-    if verbose {
-        println!("{} Backing up user/group files", "[DEBUG]".cyan());
-    }
+fn backup_user_files(runner: &dyn CommandRunner, verbose: bool) -> Result<()> {
+    debug!(verbose, "Backing up user/group files");
 
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
@@ -4034,78 +9798,200 @@ fn backup_user_files(verbose: bool) -> Result<()> {
         ("shadow", format!("{}/shadow.fedoraforge.{}.backup", backup_dir, timestamp)),
     ];
 
-    for (file, backup) in files_to_backup {
-        let source = format!("{}/{}", backup_dir, file);
-        if Path::new(&source).exists() {
-            run_command(&["sudo", "cp", "-p", &source, &backup],
-                &format!("Backing up {}", file))?;
-            if verbose {
-                println!("{} Backed up {} to {}", "[DEBUG]".cyan(), source, backup);
-            }
-        }
+    for (file, backup) in files_to_backup {
+        let source = format!("{}/{}", backup_dir, file);
+        if Path::new(&source).exists() {
+            run_command_via(runner, &["sudo", "cp", "-p", &source, &backup],
+                &format!("Backing up {}", file))?;
+            debug!(verbose, "Backed up {} to {}", source, backup);
+        }
+    }
+
+    println!("{} User/group files backed up successfully", "[SUCCESS]".green());
+    Ok(())
+}
+
+// Discovery functions
+/// Users in the managed UID range, plus the `include`d users wherever their UID is.
+fn get_current_users(runner: &dyn CommandRunner, include: &[String], verbose: bool) -> Result<HashMap<String, CurrentUserInfo>> {
+    if verbose {
+        let range = user_uid_range();
+        println!("{} Discovering users (UID {}-{})", "[DEBUG]".cyan(), range.start(), range.end());
+    }
+
+    let passwd_content = runner.read_file("/etc/passwd")
+        .context("Failed to read /etc/passwd")?;
+
+    let mut users = HashMap::new();
+
+    for line in passwd_content.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
+        let Some(entry) = parse_passwd_line(line) else {
+            debug!(verbose, "Skipping malformed /etc/passwd line: {}", line);
+            continue;
+        };
+
+        // Filter system users and special accounts outside the managed range, unless declared explicitly
+        let declared = include.contains(&entry.name);
+        if !declared && !user_uid_range().contains(&entry.uid) {
+            continue;
+        }
+
+        // Get supplementary groups using id command
+        let groups = get_user_supplementary_groups(runner, &entry.name)?;
+
+        users.insert(entry.name, CurrentUserInfo {
+            uid: entry.uid,
+            gid: entry.gid,
+            groups,
+            home: entry.home,
+            shell: entry.shell,
+            comment: entry.comment,
+            homed: false,
+            shadow_password: None,
+        });
+    }
+
+    // systemd-homed users are served by NSS, not /etc/passwd
+    if homed_available(runner) {
+        for (username, info) in get_homed_users(runner)? {
+            users.entry(username).or_insert(info);
+        }
+    }
+
+    debug!(verbose, "Discovered {} non-system users", users.len());
+
+    Ok(users)
+}
+
+fn homed_available(runner: &dyn CommandRunner) -> bool {
+    spawn_output_via(runner, &["systemctl", "is-active", "--quiet", "systemd-homed"]).is_ok_and(|output| output.status.success())
+}
+
+/// Users managed by systemd-homed, resolved through `getent passwd` like regular accounts.
+fn get_homed_users(runner: &dyn CommandRunner) -> Result<HashMap<String, CurrentUserInfo>> {
+    let output = spawn_output_via(runner, &["homectl", "list", "--no-legend", "--no-pager"])?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::command_failed(&["homectl", "list"], &output).into());
+    }
+
+    let mut users = HashMap::new();
+    for name in String::from_utf8_lossy(&output.stdout).lines().filter_map(|l| l.split_whitespace().next()) {
+        let entry = spawn_output_via(runner, &["getent", "passwd", name])?;
+        let Some(passwd) = parse_passwd_line(String::from_utf8_lossy(&entry.stdout).trim()) else {
+            continue;
+        };
+
+        users.insert(name.to_string(), CurrentUserInfo {
+            uid: passwd.uid,
+            gid: passwd.gid,
+            groups: get_user_supplementary_groups(runner, name)?,
+            home: passwd.home,
+            shell: passwd.shell,
+            comment: passwd.comment,
+            homed: true,
+            shadow_password: None,
+        });
     }
+    Ok(users)
+}
 
-    println!("{} User/group files backed up successfully", "[SUCCESS]".green());
+fn require_homed(runner: &dyn CommandRunner, username: &str) -> Result<()> {
+    if !homed_available(runner) {
+        return Err(FedoraForgeError::validation(format!(
+            "User '{}' sets homed = true but systemd-homed is not active (enable it with: sudo systemctl enable --now systemd-homed)",
+            username
+        )));
+    }
     Ok(())
 }
 
-// Discovery functions
-fn get_current_users(verbose: bool) -> Result<HashMap<String, CurrentUserInfo>> {
-    if verbose {
-        println!("{} Discovering users (UID >= {})", "[DEBUG]".cyan(), MIN_USER_UID);
-    }
+fn create_homed_user(runner: &dyn CommandRunner, username: &str, config: &UserConfig) -> Result<()> {
+    require_homed(runner, username)?;
+    warn_homed_password(username, config)?;
 
-    let passwd_content = fs::read_to_string("/etc/passwd")
-        .context("Failed to read /etc/passwd")?;
+    let mut cmd_args = vec!["sudo".to_string(), "homectl".to_string(), "create".to_string(), username.to_string()];
+    if let Some(uid) = config.uid {
+        validate_uid(uid)?;
+        cmd_args.push(format!("--uid={}", uid));
+    }
+    if let Some(home) = &config.home {
+        cmd_args.push(format!("--home-dir={}", home));
+    }
+    if let Some(shell) = &config.shell {
+        validate_shell(runner, shell)?;
+        cmd_args.push(format!("--shell={}", shell));
+    }
+    if let Some(comment) = &config.comment {
+        cmd_args.push(format!("--real-name={}", comment));
+    }
+    if let Some(storage) = &config.storage {
+        cmd_args.push(format!("--storage={}", storage));
+    }
+    if let Some(groups) = config.groups.as_ref().filter(|g| !g.is_empty()) {
+        cmd_args.push(format!("--member-of={}", groups.join(",")));
+    }
 
-    let mut users = HashMap::new();
+    // homectl asks for the new user's password, so it needs the terminal
+    println!("{} Creating systemd-homed user {}", "[INFO]".blue(), username);
+    if dry_run_skip(&format!("run: {}", format_argv(&cmd_args.iter().map(String::as_str).collect::<Vec<_>>()))) {
+        return Ok(());
+    }
+    let output = spawn_streamed_via(runner, &cmd_args.iter().map(String::as_str).collect::<Vec<_>>())?;
+    if !output.status.success() {
+        return Err(FedoraForgeError::CommandFailed {
+            cmd: cmd_args.join(" "),
+            code: output.status.code(),
+            stderr: String::new(),
+        }.into());
+    }
+    record_action(ActionCategory::User, ActionKind::Create, username, None);
+    Ok(())
+}
 
-    for line in passwd_content.lines() {
-        let parts: Vec<&str> = line.split(':').collect();
-        if parts.len() < 7 {
-            continue;
-        }
+fn warn_homed_password(username: &str, config: &UserConfig) -> Result<()> {
+    if config.password_hash.is_some() || config.locked.is_some() {
+        warn(format!("homectl owns the password of systemd-homed user {}; password_hash and locked are ignored", username))?;
+    }
+    Ok(())
+}
 
-        let username = parts[0];
-        let uid: u32 = parts[2].parse().unwrap_or(0);
-        let gid: u32 = parts[3].parse().unwrap_or(0);
-        let comment = parts[4];
-        let home = parts[5];
-        let shell = parts[6];
+fn modify_homed_user(runner: &dyn CommandRunner, username: &str, current: &CurrentUserInfo, desired: &UserConfig) -> Result<()> {
+    require_homed(runner, username)?;
+    warn_homed_password(username, desired)?;
 
-        // Filter system users (UID < 1000) and special accounts
-        if uid < MIN_USER_UID {
-            continue;
-        }
+    if desired.uid.is_some_and(|uid| uid != current.uid) || desired.gid.is_some_and(|gid| gid != current.gid) {
+        warn(format!("UID/GID of systemd-homed user {} cannot be changed in place, skipping", username))?;
+    }
 
-        if uid > MAX_USER_UID {
-            continue;
+    let mut updates = Vec::new();
+    if let Some(shell) = desired.shell.as_ref().filter(|s| **s != current.shell) {
+        validate_shell(runner, shell)?;
+        updates.push(format!("--shell={}", shell));
+    }
+    if let Some(comment) = desired.comment.as_ref().filter(|c| **c != current.comment) {
+        updates.push(format!("--real-name={}", comment));
+    }
+    if let Some(home) = desired.home.as_ref().filter(|h| **h != current.home) {
+        updates.push(format!("--home-dir={}", home));
+    }
+    if let Some(groups) = &desired.groups {
+        let current_set: std::collections::HashSet<_> = current.groups.iter().collect();
+        if groups.iter().collect::<std::collections::HashSet<_>>() != current_set {
+            updates.push(format!("--member-of={}", groups.join(",")));
         }
-
-        // Get supplementary groups using id command
-        let groups = get_user_supplementary_groups(username)?;
-
-        users.insert(username.to_string(), CurrentUserInfo {
-            uid,
-            gid,
-            groups,
-            home: home.to_string(),
-            shell: shell.to_string(),
-            comment: comment.to_string(),
-        });
     }
 
-    if verbose {
-        println!("{} Discovered {} non-system users", "[DEBUG]".cyan(), users.len());
+    if updates.is_empty() {
+        return Ok(());
     }
 
-    Ok(users)
+    let mut cmd_args = vec!["sudo", "homectl", "update", username];
+    cmd_args.extend(updates.iter().map(String::as_str));
+    run_command_via(runner, &cmd_args, &format!("Updating systemd-homed user {}", username))
 }
 
-fn get_user_supplementary_groups(username: &str) -> Result<Vec<String>> {
-    let output = Command::new("id")
-        .args(&["-nG", username])
-        .output()
+fn get_user_supplementary_groups(runner: &dyn CommandRunner, username: &str) -> Result<Vec<String>> {
+    let output = spawn_output_via(runner, &["id", "-nG", username])
         .context(format!("Failed to get groups for user {}", username))?;
 
     if !output.status.success() {
@@ -4122,9 +10008,38 @@ fn get_user_supplementary_groups(username: &str) -> Result<Vec<String>> {
     Ok(groups)
 }
 
-fn get_current_groups(verbose: bool) -> Result<HashMap<String, CurrentGroupInfo>> {
+/// Fills in `shadow_password` for declared users that set password_hash or locked. /etc/shadow is
+/// only readable as root, so other users are left alone rather than asking for sudo on every run.
+fn read_shadow_passwords(runner: &dyn CommandRunner, config: &UsersGroupsConfig, users: &mut HashMap<String, CurrentUserInfo>) -> Result<()> {
+    let names: Vec<&str> = config.users.iter().flatten()
+        .filter(|(name, user)| {
+            (user.password_hash.is_some() || user.locked.is_some()) && users.get(*name).is_some_and(|u| !u.homed)
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let mut cmd = vec!["sudo", "getent", "shadow"];
+    cmd.extend(&names);
+    let output = spawn_output_via(runner, &cmd)?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut parts = line.split(':');
+        if let (Some(name), Some(password)) = (parts.next(), parts.next()) {
+            if let Some(user) = users.get_mut(name) {
+                user.shadow_password = Some(password.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Groups in the managed GID range, plus the `include`d groups wherever their GID is.
+fn get_current_groups(include: &[String], verbose: bool) -> Result<HashMap<String, CurrentGroupInfo>> {
     if verbose {
-        println!("{} Discovering groups (GID >= {})", "[DEBUG]".cyan(), MIN_GROUP_GID);
+        let range = group_gid_range();
+        println!("{} Discovering groups (GID {}-{})", "[DEBUG]".cyan(), range.start(), range.end());
     }
 
     let group_content = fs::read_to_string("/etc/group")
@@ -4132,40 +10047,20 @@ fn get_current_groups(verbose: bool) -> Result<HashMap<String, CurrentGroupInfo>
 
     let mut groups = HashMap::new();
 
-    for line in group_content.lines() {
-        let parts: Vec<&str> = line.split(':').collect();
-        if parts.len() < 4 {
-            continue;
-        }
-
-        let groupname = parts[0];
-        let gid: u32 = parts[2].parse().unwrap_or(0);
-        let members_str = parts[3];
-
-        // Filter system groups (GID < 1000)
-        if gid < MIN_GROUP_GID {
-            continue;
-        }
-
-        if gid > MAX_GROUP_GID {
+    for entry in parse_group(&group_content) {
+        // Filter system groups outside the managed range, unless declared explicitly
+        let declared = include.contains(&entry.name);
+        if !declared && !group_gid_range().contains(&entry.gid) {
             continue;
         }
 
-        let members: Vec<String> = if members_str.is_empty() {
-            Vec::new()
-        } else {
-            members_str.split(',').map(|s| s.to_string()).collect()
-        };
-
-        groups.insert(groupname.to_string(), CurrentGroupInfo {
-            gid,
-            members,
+        groups.insert(entry.name, CurrentGroupInfo {
+            gid: entry.gid,
+            members: entry.members,
         });
     }
 
-    if verbose {
-        println!("{} Discovered {} non-system groups", "[DEBUG]".cyan(), groups.len());
-    }
+    debug!(verbose, "Discovered {} non-system groups", groups.len());
 
     Ok(groups)
 }
@@ -4177,8 +10072,8 @@ fn load_users_groups_config() -> Result<UsersGroupsConfig> {
     if !Path::new(config_path).exists() {
         println!("{} config/users-groups.toml not found, creating from current system state...", "[INFO]".blue());
 
-        let current_users = get_current_users(false)?;
-        let current_groups = get_current_groups(false)?;
+        let current_users = get_current_users(command_runner(), &[], false)?;
+        let current_groups = get_current_groups(&[], false)?;
 
         if !current_users.is_empty() || !current_groups.is_empty() {
             update_users_groups_config_with_discovered(&current_users, &current_groups, config_path)?;
@@ -4196,15 +10091,18 @@ fn load_users_groups_config() -> Result<UsersGroupsConfig> {
 
     let config: UsersGroupsConfig = toml::from_str(&content)
         .context("Failed to parse users-groups TOML file")?;
+    config.apply_id_ranges()?;
 
     Ok(config)
 }
 
 // State management functions
 fn load_users_groups_state() -> Result<UsersGroupsState> {
-    let state_dir = dirs::home_dir()
-        .context("Could not find home directory")?
-        .join(".config/fedoraforge");
+    if state_disabled() {
+        return Ok(UsersGroupsState::default());
+    }
+
+    let state_dir = state_dir()?;
 
     let state_file = state_dir.join("users_groups_state.json");
 
@@ -4222,12 +10120,11 @@ fn load_users_groups_state() -> Result<UsersGroupsState> {
 }
 
 fn save_users_groups_state(state: &UsersGroupsState) -> Result<()> {
-    let state_dir = dirs::home_dir()
-        .context("Could not find home directory")?
-        .join(".config/fedoraforge");
+    if state_disabled() || dry_run() {
+        return Ok(());
+    }
 
-    fs::create_dir_all(&state_dir)
-        .context("Failed to create state directory")?;
+    let state_dir = state_dir()?;
 
     let state_file = state_dir.join("users_groups_state.json");
 
@@ -4273,6 +10170,9 @@ fn update_users_groups_config_with_discovered(
             }
 
             toml_content.push_str("create_home = true\n");
+            if info.homed {
+                toml_content.push_str("homed = true\n");
+            }
             toml_content.push_str("system = false\n\n");
         }
     }
@@ -4297,14 +10197,14 @@ fn update_users_groups_config_with_discovered(
         }
     }
 
-    fs::write(config_path, toml_content)
+    write_file(config_path, toml_content)
         .context(format!("Failed to write {}", config_path))?;
 
     Ok(())
 }
 
 // Group management functions
-fn create_group(groupname: &str, config: &GroupConfig, verbose: bool) -> Result<()> {
+fn create_group(runner: &dyn CommandRunner, groupname: &str, config: &GroupConfig) -> Result<()> {
     validate_groupname(groupname)?;
 
     if let Some(gid) = config.gid {
@@ -4326,18 +10226,18 @@ fn create_group(groupname: &str, config: &GroupConfig, verbose: bool) -> Result<
 
     cmd_args.push(groupname);
 
-    run_command(&cmd_args, &format!("Creating group {}", groupname))?;
+    run_command_via(runner, &cmd_args, &format!("Creating group {}", groupname))?;
+    record_action(ActionCategory::Group, ActionKind::Create, groupname, None);
 
     // Add members if specified
     if let Some(members) = &config.members {
         for member in members {
-            let result = run_command(
+            if let Err(e) = run_command_via(
+                runner,
                 &["sudo", "gpasswd", "-a", member, groupname],
                 &format!("Adding {} to group {}", member, groupname)
-            );
-            if result.is_err() && verbose {
-                println!("{} Failed to add user {} to group {}: user may not exist yet",
-                    "[WARN]".yellow(), member, groupname);
+            ) {
+                warn(format!("Failed to add user {} to group {} (user may not exist yet): {}", member, groupname, e))?;
             }
         }
     }
@@ -4345,12 +10245,13 @@ fn create_group(groupname: &str, config: &GroupConfig, verbose: bool) -> Result<
     Ok(())
 }
 
-fn modify_group(groupname: &str, current: &CurrentGroupInfo, desired: &GroupConfig, verbose: bool) -> Result<()> {
+fn modify_group(runner: &dyn CommandRunner, groupname: &str, current: &CurrentGroupInfo, desired: &GroupConfig) -> Result<()> {
     // Check if GID needs to change
     if let Some(desired_gid) = desired.gid {
         if desired_gid != current.gid {
             validate_gid(desired_gid)?;
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "groupmod", "-g", &desired_gid.to_string(), groupname],
                 &format!("Changing GID for group {}", groupname)
             )?;
@@ -4364,19 +10265,19 @@ fn modify_group(groupname: &str, current: &CurrentGroupInfo, desired: &GroupConf
 
         // Add missing members
         for member in desired_members_set.difference(&current_members) {
-            let result = run_command(
+            if let Err(e) = run_command_via(
+                runner,
                 &["sudo", "gpasswd", "-a", member, groupname],
                 &format!("Adding {} to group {}", member, groupname)
-            );
-            if result.is_err() && verbose {
-                println!("{} Failed to add user {} to group {}: user may not exist",
-                    "[WARN]".yellow(), member, groupname);
+            ) {
+                warn(format!("Failed to add user {} to group {} (user may not exist): {}", member, groupname, e))?;
             }
         }
 
         // Remove extra members
         for member in current_members.difference(&desired_members_set) {
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "gpasswd", "-d", member, groupname],
                 &format!("Removing {} from group {}", member, groupname)
             )?;
@@ -4386,19 +10287,25 @@ fn modify_group(groupname: &str, current: &CurrentGroupInfo, desired: &GroupConf
     Ok(())
 }
 
-fn delete_group(groupname: &str) -> Result<()> {
+fn delete_group(runner: &dyn CommandRunner, groupname: &str) -> Result<()> {
     validate_groupname(groupname)?;
-    run_command(
+    run_command_via(
+        runner,
         &["sudo", "groupdel", groupname],
         &format!("Deleting group {}", groupname)
     )?;
+    record_action(ActionCategory::Group, ActionKind::Delete, groupname, None);
     Ok(())
 }
 
 // User management functions
-fn create_user(username: &str, config: &UserConfig, verbose: bool) -> Result<()> {
+fn create_user(runner: &dyn CommandRunner, username: &str, config: &UserConfig) -> Result<()> {
     validate_username(username)?;
 
+    if config.homed.unwrap_or(false) {
+        return create_homed_user(runner, username, config);
+    }
+
     if let Some(uid) = config.uid {
         validate_uid(uid)?;
     }
@@ -4426,7 +10333,7 @@ fn create_user(username: &str, config: &UserConfig, verbose: bool) -> Result<()>
     }
 
     if let Some(shell) = &config.shell {
-        validate_shell(shell)?;
+        validate_shell(runner, shell)?;
         cmd_args.push("-s");
         cmd_args.push(shell);
     }
@@ -4449,32 +10356,43 @@ fn create_user(username: &str, config: &UserConfig, verbose: bool) -> Result<()>
 
     cmd_args.push(username);
 
-    run_command(&cmd_args, &format!("Creating user {}", username))?;
+    run_command_via(runner, &cmd_args, &format!("Creating user {}", username))?;
+    record_action(ActionCategory::User, ActionKind::Create, username, None);
 
     // Add to supplementary groups
     if let Some(groups) = &config.groups {
         if !groups.is_empty() {
             let groups_str = groups.join(",");
-            let result = run_command(
+            if let Err(e) = run_command_via(
+                runner,
                 &["sudo", "usermod", "-aG", &groups_str, username],
                 &format!("Adding {} to groups: {}", username, groups_str)
-            );
-            if result.is_err() && verbose {
-                println!("{} Failed to add user to some groups: groups may not exist yet",
-                    "[WARN]".yellow());
+            ) {
+                warn(format!("Failed to add user {} to groups {} (groups may not exist yet): {}", username, groups_str, e))?;
             }
         }
     }
 
-    Ok(())
+    apply_password(runner, username, config, None)
 }
 
-fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig, verbose: bool) -> Result<()> {
+fn modify_user(runner: &dyn CommandRunner, username: &str, current: &CurrentUserInfo, desired: &UserConfig) -> Result<()> {
+    if desired.homed.unwrap_or(false) != current.homed {
+        warn(format!("User {} is {} but config sets homed = {}; converting existing accounts is not supported",
+            username,
+            if current.homed { "managed by systemd-homed" } else { "a classic /etc/passwd account" },
+            desired.homed.unwrap_or(false)))?;
+    }
+    if current.homed {
+        return modify_homed_user(runner, username, current, desired);
+    }
+
     // Check UID change
     if let Some(desired_uid) = desired.uid {
         if desired_uid != current.uid {
             validate_uid(desired_uid)?;
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "usermod", "-u", &desired_uid.to_string(), username],
                 &format!("Changing UID for user {}", username)
             )?;
@@ -4485,7 +10403,8 @@ fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig,
     if let Some(desired_gid) = desired.gid {
         if desired_gid != current.gid {
             validate_gid(desired_gid)?;
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "usermod", "-g", &desired_gid.to_string(), username],
                 &format!("Changing primary GID for user {}", username)
             )?;
@@ -4495,7 +10414,8 @@ fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig,
     // Check home directory change
     if let Some(desired_home) = &desired.home {
         if desired_home != &current.home {
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "usermod", "-d", desired_home, username],
                 &format!("Changing home directory for user {}", username)
             )?;
@@ -4505,8 +10425,9 @@ fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig,
     // Check shell change
     if let Some(desired_shell) = &desired.shell {
         if desired_shell != &current.shell {
-            validate_shell(desired_shell)?;
-            run_command(
+            validate_shell(runner, desired_shell)?;
+            run_command_via(
+                runner,
                 &["sudo", "usermod", "-s", desired_shell, username],
                 &format!("Changing shell for user {}", username)
             )?;
@@ -4516,7 +10437,8 @@ fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig,
     // Check comment change
     if let Some(desired_comment) = &desired.comment {
         if desired_comment != &current.comment {
-            run_command(
+            run_command_via(
+                runner,
                 &["sudo", "usermod", "-c", desired_comment, username],
                 &format!("Changing comment for user {}", username)
             )?;
@@ -4531,23 +10453,64 @@ fn modify_user(username: &str, current: &CurrentUserInfo, desired: &UserConfig,
         if current_groups_set != desired_groups_set {
             // Set groups using -G flag (replaces all supplementary groups)
             let groups_str = desired_groups.join(",");
-            let result = run_command(
+            if let Err(e) = run_command_via(
+                runner,
                 &["sudo", "usermod", "-G", &groups_str, username],
                 &format!("Updating groups for user {}", username)
-            );
-            if result.is_err() && verbose {
-                println!("{} Failed to update groups: some groups may not exist",
-                    "[WARN]".yellow());
+            ) {
+                warn(format!("Failed to update groups of user {} (some groups may not exist): {}", username, e))?;
             }
         }
     }
 
-    Ok(())
+    // Unread when sudo getent shadow failed; changing the password blind would reset it every run
+    match current.shadow_password.as_deref() {
+        Some(password) => apply_password(runner, username, desired, Some(password)),
+        None => Ok(()),
+    }
+}
+
+/// Sets password_hash and applies locked. `current` is the /etc/shadow password field, None for a new user.
+fn apply_password(runner: &dyn CommandRunner, username: &str, desired: &UserConfig, current: Option<&str>) -> Result<()> {
+    let was_locked = current.is_some_and(|p| p.starts_with('!'));
+    let mut locked = was_locked;
+    if let Some(hash) = &desired.password_hash {
+        validate_password_hash(username, hash)?;
+        if current.map(|p| p.trim_start_matches('!')) != Some(hash.as_str()) {
+            set_password_hash(runner, username, hash)?;
+            // chpasswd replaces the whole field, including a lock's leading '!'
+            locked = false;
+        }
+    }
+
+    match desired.locked.unwrap_or(was_locked) {
+        true if !locked => run_command_via(runner, &["sudo", "usermod", "-L", username], &format!("Locking password of user {}", username)),
+        false if locked => run_command_via(runner, &["sudo", "usermod", "-U", username], &format!("Unlocking password of user {}", username)),
+        _ => Ok(()),
+    }
+}
+
+/// chpasswd reads `user:hash` on stdin, which keeps the hash out of the process list.
+fn set_password_hash(runner: &dyn CommandRunner, username: &str, hash: &str) -> Result<()> {
+    // The preview (and --print-effective-commands) shows a placeholder, never the hash
+    let cmd = ["sudo", "chpasswd", "-e"];
+    let preview = format!("printf '%s\\n' {} | {}", shell_quote(&format!("{}:<password_hash>", username)), format_argv(&cmd));
+    run_command_with_input_via(runner, &cmd, format!("{}:{}\n", username, hash).as_bytes(), &preview, &format!("Setting password of user {}", username))
 }
 
-fn delete_user(username: &str, remove_home: bool, verbose: bool) -> Result<()> {
+fn delete_user(runner: &dyn CommandRunner, username: &str, remove_home: bool, verbose: bool) -> Result<()> {
     validate_username(username)?;
 
+    // A homed user's home is its identity, so removing it always removes the home area
+    if homed_available(runner) && get_homed_users(runner)?.contains_key(username) {
+        if !remove_home {
+            println!("{} {} is a systemd-homed user; removing it also removes its home area", "[WARN]".yellow(), username);
+        }
+        run_command_via(runner, &["sudo", "homectl", "remove", username], &format!("Deleting systemd-homed user {}", username))?;
+        record_action(ActionCategory::User, ActionKind::Delete, username, None);
+        return Ok(());
+    }
+
     let mut cmd_args = vec!["sudo", "userdel"];
 
     if remove_home {
@@ -4559,22 +10522,142 @@ fn delete_user(username: &str, remove_home: bool, verbose: bool) -> Result<()> {
 
     cmd_args.push(username);
 
-    run_command(&cmd_args, &format!("Deleting user {}", username))?;
+    run_command_via(runner, &cmd_args, &format!("Deleting user {}", username))?;
+    record_action(ActionCategory::User, ActionKind::Delete, username, None);
+    Ok(())
+}
+
+// ========================= USERS/GROUPS PLAN =========================
+
+enum PlanAction {
+    Create,
+    Modify(Vec<FieldDiff>),
+    NoChange,
+}
+
+/// What a users/groups sync would do, computed without touching the system.
+struct UsersGroupsPlan {
+    groups: Vec<(String, PlanAction)>,
+    users: Vec<(String, PlanAction)>,
+    orphaned_groups: Vec<String>,   // Managed earlier, no longer declared
+    orphaned_users: Vec<String>,
+    undeclared_groups: Vec<String>, // In the managed range, never declared or managed
+    undeclared_users: Vec<String>,
+}
+
+fn plan_action<D, C>(desired: &D, current: Option<&C>, diffs: fn(&D, &C) -> Vec<FieldDiff>) -> PlanAction {
+    match current.map(|current| diffs(desired, current)) {
+        None => PlanAction::Create,
+        Some(changes) if changes.is_empty() => PlanAction::NoChange,
+        Some(changes) => PlanAction::Modify(changes),
+    }
+}
+
+fn plan_users_groups(
+    config: &UsersGroupsConfig,
+    current_users: &HashMap<String, CurrentUserInfo>,
+    current_groups: &HashMap<String, CurrentGroupInfo>,
+    state: &UsersGroupsState,
+) -> UsersGroupsPlan {
+    let sorted = |mut names: Vec<String>| { names.sort(); names };
+    let declared_groups = config.groups.clone().unwrap_or_default();
+    let declared_users = config.users.clone().unwrap_or_default();
+
+    let mut groups: Vec<_> = declared_groups.iter()
+        .map(|(name, desired)| (name.clone(), plan_action(desired, current_groups.get(name), group_field_diffs)))
+        .collect();
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut users: Vec<_> = declared_users.iter()
+        .map(|(name, desired)| (name.clone(), plan_action(desired, current_users.get(name), user_field_diffs)))
+        .collect();
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+
+    UsersGroupsPlan {
+        groups,
+        users,
+        orphaned_groups: sorted(state.managed_groups.keys()
+            .filter(|name| !declared_groups.contains_key(*name) && current_groups.contains_key(*name))
+            .cloned().collect()),
+        orphaned_users: sorted(state.managed_users.keys()
+            .filter(|name| !declared_users.contains_key(*name) && current_users.contains_key(*name))
+            .cloned().collect()),
+        undeclared_groups: sorted(current_groups.keys()
+            .filter(|name| !declared_groups.contains_key(*name) && !state.managed_groups.contains_key(*name))
+            .cloned().collect()),
+        undeclared_users: sorted(current_users.keys()
+            .filter(|name| !declared_users.contains_key(*name) && !state.managed_users.contains_key(*name))
+            .cloned().collect()),
+    }
+}
+
+fn print_field_diffs(changes: &[FieldDiff]) {
+    for change in changes {
+        println!("      {}: {} -> {}", change.field, change.current, change.desired);
+    }
+}
+
+fn print_plan_entries(kind: &str, entries: &[(String, PlanAction)]) {
+    println!("{} {}:", "[INFO]".blue(), kind);
+    if entries.is_empty() {
+        println!("  (none declared)");
+    }
+    for (name, action) in entries {
+        match action {
+            PlanAction::Create => println!("  {} {}", "create   ".green(), name),
+            PlanAction::Modify(changes) => {
+                println!("  {} {}", "modify   ".yellow(), name);
+                print_field_diffs(changes);
+            }
+            PlanAction::NoChange => println!("  no change {}", name),
+        }
+    }
+}
+
+/// Read-only preview of the users/groups sync, grouped per declared entry with field-level diffs.
+fn explain_users_groups_plan(verbose: bool) -> Result<()> {
+    let Some(config) = read_declarative_file::<UsersGroupsConfig>("config/users-groups.toml")? else {
+        println!("{} config/users-groups.toml not found, nothing to plan", "[INFO]".blue());
+        return Ok(());
+    };
+    config.apply_id_ranges()?;
+    let state = load_users_groups_state()?;
+    let mut current_users = get_current_users(command_runner(), &config.out_of_range_users(), verbose)?;
+    read_shadow_passwords(command_runner(), &config, &mut current_users)?;
+    let current_groups = get_current_groups(&config.out_of_range_groups(), verbose)?;
+    let plan = plan_users_groups(&config, &current_users, &current_groups, &state);
+
+    print_plan_entries("Groups", &plan.groups);
+    print_plan_entries("Users", &plan.users);
+    for (label, names) in [
+        ("Groups removed from config (offered for deletion)", &plan.orphaned_groups),
+        ("Users removed from config (offered for deletion)", &plan.orphaned_users),
+        ("Groups on the system not in config", &plan.undeclared_groups),
+        ("Users on the system not in config", &plan.undeclared_users),
+    ] {
+        if !names.is_empty() {
+            println!("{} {}:", "[INFO]".blue(), label);
+            for name in names {
+                println!("  - {}", name);
+            }
+        }
+    }
     Ok(())
 }
 
 // Bidirectional sync functions
+#[allow(clippy::too_many_arguments)]
 fn sync_groups_bidirectional(
+    runner: &dyn CommandRunner,
     declared: &HashMap<String, GroupConfig>,
     current: &HashMap<String, CurrentGroupInfo>,
     state: &mut UsersGroupsState,
     yes: bool,
     no: bool,
+    no_removals: bool,
     verbose: bool,
-) -> Result<()> {
-    if verbose {
-        println!("{} Syncing groups bidirectionally", "[DEBUG]".cyan());
-    }
+) -> Result<SubsystemResult> {
+    debug!(verbose, "Syncing groups bidirectionally");
+    let mut result = SubsystemResult::default();
 
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
@@ -4593,30 +10676,39 @@ fn sync_groups_bidirectional(
                 if info.members.is_empty() { "none".to_string() } else { info.members.join(", ") });
         }
 
-        println!("What would you like to do with these groups?");
-        println!("  1. Add to config (manage them)");
-        println!("  2. Delete from system");
-        println!("  3. Ignore (leave as-is)");
+        // Auto-yes adds them all to config, an applied --preflight plan only the ones it listed
+        let (approved, undeclared_groups): (HashMap<String, CurrentGroupInfo>, HashMap<String, CurrentGroupInfo>) = undeclared_groups.into_iter()
+            .partition(|(name, _)| yes || planned("groups", &[name.as_str()]));
+        if !approved.is_empty() {
+            update_users_groups_config_with_discovered(&HashMap::new(), &approved, "config/users-groups.toml")?;
+            println!("{} Added {} groups to config/users-groups.toml", "[SUCCESS]".green(), approved.len());
+        }
 
-        if yes {
-            // Auto-yes means add to config
-            update_users_groups_config_with_discovered(&HashMap::new(), &undeclared_groups, "config/users-groups.toml")?;
-            println!("{} Added {} groups to config/users-groups.toml", "[SUCCESS]".green(), undeclared_groups.len());
-        } else if !no {
-            print!("Enter choice [1-3]: ");
-            io::stdout().flush()?;
-            let mut choice = String::new();
-            io::stdin().read_line(&mut choice)?;
+        if !undeclared_groups.is_empty() && !no {
+            if approved.is_empty() {
+                println!("What would you like to do with these groups?");
+            } else {
+                let mut names: Vec<&str> = undeclared_groups.keys().map(String::as_str).collect();
+                names.sort();
+                println!("What would you like to do with the other groups ({})?", names.join(", "));
+            }
+            println!("  1. Add to config (manage them)");
+            println!("  2. Delete from system");
+            println!("  3. Ignore (leave as-is)");
 
-            match choice.trim() {
+            match ask_undeclared_choice("groups")?.as_str() {
                 "1" => {
                     update_users_groups_config_with_discovered(&HashMap::new(), &undeclared_groups, "config/users-groups.toml")?;
                     println!("{} Added {} groups to config/users-groups.toml", "[SUCCESS]".green(), undeclared_groups.len());
                 }
+                "2" if no_removals => {
+                    println!("{} --no-removals: not deleting groups", "[INFO]".blue());
+                }
                 "2" => {
                     if ask_user_confirmation("Are you sure you want to delete these groups?", false, false, verbose)? {
-                        for (name, _) in &undeclared_groups {
-                            delete_group(name)?;
+                        for name in undeclared_groups.keys() {
+                            delete_group(runner, name)?;
+                            result.removed += 1;
                             println!("{} Deleted group {}", "[SUCCESS]".green(), name);
                         }
                     }
@@ -4642,17 +10734,19 @@ fn sync_groups_bidirectional(
             println!("  - {}", name);
         }
 
-        if ask_user_confirmation("Create these groups?", yes, no, verbose)? {
+        let names: Vec<&str> = groups_to_create.iter().map(|(name, _)| name.as_str()).collect();
+        if ask_user_confirmation("Create these groups?", yes || planned("groups", &names), no, verbose)? {
             for (name, config) in groups_to_create {
-                create_group(name, config, verbose)?;
+                create_group(runner, name, config)?;
                 // Get the created group's GID
-                let created_info = get_current_groups(false)?;
+                let created_info = get_current_groups(std::slice::from_ref(name), false)?;
                 if let Some(info) = created_info.get(name) {
                     state.managed_groups.insert(name.clone(), ManagedGroupInfo {
                         gid: info.gid,
                         managed_at: timestamp,
                     });
                 }
+                result.created += 1;
                 println!("{} Created group {}", "[SUCCESS]".green(), name);
             }
         }
@@ -4662,51 +10756,36 @@ fn sync_groups_bidirectional(
     let groups_to_modify: Vec<_> = declared.iter()
         .filter_map(|(name, desired)| {
             current.get(name).and_then(|current_info| {
-                // Check if GID differs
-                let gid_differs = desired.gid.map_or(false, |gid| gid != current_info.gid);
-
-                // Check if members differ
-                let desired_members = desired.members.as_ref().map(|m| m.iter().collect::<std::collections::HashSet<_>>());
-                let current_members = current_info.members.iter().collect::<std::collections::HashSet<_>>();
-                let members_differ = desired_members.map_or(false, |dm| dm != current_members);
-
-                if gid_differs || members_differ {
-                    Some((name, desired, current_info))
-                } else {
-                    None
-                }
+                let changes = group_field_diffs(desired, current_info);
+                (!changes.is_empty()).then_some((name, desired, current_info, changes))
             })
         })
         .collect();
 
     if !groups_to_modify.is_empty() {
         println!("{} Found {} groups with different states:", "[INFO]".blue(), groups_to_modify.len());
-        for (name, desired, current) in &groups_to_modify {
-            println!("  - {}: current(GID={}, members=[{}]) -> desired(GID={}, members=[{}])",
-                name,
-                current.gid,
-                current.members.join(", "),
-                desired.gid.map_or_else(|| current.gid.to_string(), |g| g.to_string()),
-                desired.members.as_ref().map_or_else(|| "".to_string(), |m| m.join(", "))
-            );
+        for (name, _, _, changes) in &groups_to_modify {
+            println!("  - {}:", name);
+            print_field_diffs(changes);
         }
 
         if ask_user_confirmation("Apply these group changes?", yes, no, verbose)? {
-            for (name, desired, current) in groups_to_modify {
-                modify_group(name, current, desired, verbose)?;
+            for (name, desired, current, _) in groups_to_modify {
+                modify_group(runner, name, current, desired)?;
                 // Update state with new GID if changed
                 let new_gid = desired.gid.unwrap_or(current.gid);
                 state.managed_groups.insert(name.clone(), ManagedGroupInfo {
                     gid: new_gid,
                     managed_at: timestamp,
                 });
+                result.modified += 1;
                 println!("{} Modified group {}", "[SUCCESS]".green(), name);
             }
         }
     }
 
     // Update state for all declared groups
-    for (name, _config) in declared {
+    for name in declared.keys() {
         if let Some(info) = current.get(name) {
             state.managed_groups.entry(name.clone()).or_insert(ManagedGroupInfo {
                 gid: info.gid,
@@ -4715,20 +10794,22 @@ fn sync_groups_bidirectional(
         }
     }
 
-    Ok(())
+    Ok(result)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn sync_users_bidirectional(
+    runner: &dyn CommandRunner,
     declared: &HashMap<String, UserConfig>,
     current: &HashMap<String, CurrentUserInfo>,
     state: &mut UsersGroupsState,
     yes: bool,
     no: bool,
+    no_removals: bool,
     verbose: bool,
-) -> Result<()> {
-    if verbose {
-        println!("{} Syncing users bidirectionally", "[DEBUG]".cyan());
-    }
+) -> Result<SubsystemResult> {
+    debug!(verbose, "Syncing users bidirectionally");
+    let mut result = SubsystemResult::default();
 
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)?
@@ -4746,34 +10827,43 @@ fn sync_users_bidirectional(
             println!("  - {} (UID: {}, shell: {}, home: {})", name, info.uid, info.shell, info.home);
         }
 
-        println!("What would you like to do with these users?");
-        println!("  1. Add to config (manage them)");
-        println!("  2. Delete from system");
-        println!("  3. Ignore (leave as-is)");
+        // Auto-yes adds them all to config, an applied --preflight plan only the ones it listed
+        let (approved, undeclared_users): (HashMap<String, CurrentUserInfo>, HashMap<String, CurrentUserInfo>) = undeclared_users.into_iter()
+            .partition(|(name, _)| yes || planned("users", &[name.as_str()]));
+        if !approved.is_empty() {
+            update_users_groups_config_with_discovered(&approved, &HashMap::new(), "config/users-groups.toml")?;
+            println!("{} Added {} users to config/users-groups.toml", "[SUCCESS]".green(), approved.len());
+        }
 
-        if yes {
-            // Auto-yes means add to config
-            update_users_groups_config_with_discovered(&undeclared_users, &HashMap::new(), "config/users-groups.toml")?;
-            println!("{} Added {} users to config/users-groups.toml", "[SUCCESS]".green(), undeclared_users.len());
-        } else if !no {
-            print!("Enter choice [1-3]: ");
-            io::stdout().flush()?;
-            let mut choice = String::new();
-            io::stdin().read_line(&mut choice)?;
+        if !undeclared_users.is_empty() && !no {
+            if approved.is_empty() {
+                println!("What would you like to do with these users?");
+            } else {
+                let mut names: Vec<&str> = undeclared_users.keys().map(String::as_str).collect();
+                names.sort();
+                println!("What would you like to do with the other users ({})?", names.join(", "));
+            }
+            println!("  1. Add to config (manage them)");
+            println!("  2. Delete from system");
+            println!("  3. Ignore (leave as-is)");
 
-            match choice.trim() {
+            match ask_undeclared_choice("users")?.as_str() {
                 "1" => {
                     update_users_groups_config_with_discovered(&undeclared_users, &HashMap::new(), "config/users-groups.toml")?;
                     println!("{} Added {} users to config/users-groups.toml", "[SUCCESS]".green(), undeclared_users.len());
                 }
+                "2" if no_removals => {
+                    println!("{} --no-removals: not deleting users", "[INFO]".blue());
+                }
                 "2" => {
                     if ask_user_confirmation("Are you sure you want to delete these users?", false, false, verbose)? {
-                        for (name, _) in &undeclared_users {
+                        for name in undeclared_users.keys() {
                             let remove_home = ask_user_confirmation(
                                 &format!("Remove home directory for user '{}'?", name),
                                 false, false, verbose
                             )?;
-                            delete_user(name, remove_home, verbose)?;
+                            delete_user(runner, name, remove_home, verbose)?;
+                            result.removed += 1;
                             println!("{} Deleted user {}", "[SUCCESS]".green(), name);
                         }
                     }
@@ -4799,17 +10889,19 @@ fn sync_users_bidirectional(
             println!("  - {}", name);
         }
 
-        if ask_user_confirmation("Create these users?", yes, no, verbose)? {
+        let names: Vec<&str> = users_to_create.iter().map(|(name, _)| name.as_str()).collect();
+        if ask_user_confirmation("Create these users?", yes || planned("users", &names), no, verbose)? {
             for (name, config) in users_to_create {
-                create_user(name, config, verbose)?;
+                create_user(runner, name, config)?;
                 // Get the created user's UID
-                let created_info = get_current_users(false)?;
+                let created_info = get_current_users(runner, std::slice::from_ref(name), false)?;
                 if let Some(info) = created_info.get(name) {
                     state.managed_users.insert(name.clone(), ManagedUserInfo {
                         uid: info.uid,
                         managed_at: timestamp,
                     });
                 }
+                result.created += 1;
                 println!("{} Created user {}", "[SUCCESS]".green(), name);
             }
         }
@@ -4819,56 +10911,36 @@ fn sync_users_bidirectional(
     let users_to_modify: Vec<_> = declared.iter()
         .filter_map(|(name, desired)| {
             current.get(name).and_then(|current_info| {
-                // Check for differences
-                let uid_differs = desired.uid.map_or(false, |uid| uid != current_info.uid);
-                let gid_differs = desired.gid.map_or(false, |gid| gid != current_info.gid);
-                let home_differs = desired.home.as_ref().map_or(false, |h| h != &current_info.home);
-                let shell_differs = desired.shell.as_ref().map_or(false, |s| s != &current_info.shell);
-                let comment_differs = desired.comment.as_ref().map_or(false, |c| c != &current_info.comment);
-
-                let desired_groups_set = desired.groups.as_ref().map(|g| g.iter().collect::<std::collections::HashSet<_>>());
-                let current_groups_set = current_info.groups.iter().collect::<std::collections::HashSet<_>>();
-                let groups_differ = desired_groups_set.map_or(false, |dg| dg != current_groups_set);
-
-                if uid_differs || gid_differs || home_differs || shell_differs || comment_differs || groups_differ {
-                    Some((name, desired, current_info))
-                } else {
-                    None
-                }
+                let changes = user_field_diffs(desired, current_info);
+                (!changes.is_empty()).then_some((name, desired, current_info, changes))
             })
         })
         .collect();
 
     if !users_to_modify.is_empty() {
         println!("{} Found {} users with different states:", "[INFO]".blue(), users_to_modify.len());
-        for (name, desired, current) in &users_to_modify {
-            println!("  - {}: UID {} -> {}, shell {} -> {}, groups [{}] -> [{}]",
-                name,
-                current.uid,
-                desired.uid.map_or_else(|| current.uid.to_string(), |u| u.to_string()),
-                current.shell,
-                desired.shell.as_ref().unwrap_or(&current.shell),
-                current.groups.join(", "),
-                desired.groups.as_ref().map_or_else(|| "".to_string(), |g| g.join(", "))
-            );
+        for (name, _, _, changes) in &users_to_modify {
+            println!("  - {}:", name);
+            print_field_diffs(changes);
         }
 
         if ask_user_confirmation("Apply these user changes?", yes, no, verbose)? {
-            for (name, desired, current) in users_to_modify {
-                modify_user(name, current, desired, verbose)?;
+            for (name, desired, current, _) in users_to_modify {
+                modify_user(runner, name, current, desired)?;
                 // Update state with new UID if changed
                 let new_uid = desired.uid.unwrap_or(current.uid);
                 state.managed_users.insert(name.clone(), ManagedUserInfo {
                     uid: new_uid,
                     managed_at: timestamp,
                 });
+                result.modified += 1;
                 println!("{} Modified user {}", "[SUCCESS]".green(), name);
             }
         }
     }
 
     // Update state for all declared users
-    for (name, _config) in declared {
+    for name in declared.keys() {
         if let Some(info) = current.get(name) {
             state.managed_users.entry(name.clone()).or_insert(ManagedUserInfo {
                 uid: info.uid,
@@ -4877,23 +10949,28 @@ fn sync_users_bidirectional(
         }
     }
 
-    Ok(())
+    Ok(result)
 }
 
 // Main sync function
-fn sync_users_and_groups(yes: bool, no: bool, verbose: bool) -> Result<()> {
+fn sync_users_and_groups(runner: &dyn CommandRunner, memberships: &[(String, String, String)], yes: bool, no: bool, no_removals: bool, verbose: bool) -> Result<SubsystemResult> {
     println!("{} Synchronizing users and groups with system state...", "[INFO]".blue());
+    let mut result = SubsystemResult::default();
 
     // Backup files before making changes
-    backup_user_files(verbose)?;
+    backup_user_files(runner, verbose)?;
 
     // Load config and state
     let config = load_users_groups_config()?;
     let mut state = load_users_groups_state()?;
 
     // Get current system state
-    let current_users = get_current_users(verbose)?;
-    let current_groups = get_current_groups(verbose)?;
+    let mut current_users = get_current_users(runner, &config.out_of_range_users(), verbose)?;
+    read_shadow_passwords(runner, &config, &mut current_users)?;
+    let current_groups = get_current_groups(&config.out_of_range_groups(), verbose)?;
+
+    // Settle supplementary groups nobody declares before anything changes, or usermod -aG drops them
+    result.created += create_referenced_groups(runner, &config, yes, no, verbose)?;
 
     // Check for orphaned groups (previously managed but removed from config)
     let declared_group_names: std::collections::HashSet<_> = config.groups
@@ -4906,16 +10983,20 @@ fn sync_users_and_groups(yes: bool, no: bool, verbose: bool) -> Result<()> {
         .cloned()
         .collect();
 
-    if !orphaned_groups.is_empty() {
+    if !orphaned_groups.is_empty() && no_removals {
+        println!("{} --no-removals: keeping {} groups removed from config", "[INFO]".blue(), orphaned_groups.len());
+    } else if !orphaned_groups.is_empty() {
         println!("{} Found {} groups removed from config but still exist in system:", "[INFO]".blue(), orphaned_groups.len());
         for group in &orphaned_groups {
             println!("  - {}", group);
         }
 
-        if ask_user_confirmation("Delete these groups from the system?", yes, no, verbose)? {
+        let names: Vec<&str> = orphaned_groups.iter().map(String::as_str).collect();
+        if ask_user_confirmation("Delete these groups from the system?", yes || planned("groups", &names), no, verbose)? {
             for group in &orphaned_groups {
-                delete_group(group)?;
+                delete_group(runner, group)?;
                 state.managed_groups.remove(group);
+                result.removed += 1;
                 println!("{} Deleted group {}", "[SUCCESS]".green(), group);
             }
         }
@@ -4932,20 +11013,24 @@ fn sync_users_and_groups(yes: bool, no: bool, verbose: bool) -> Result<()> {
         .cloned()
         .collect();
 
-    if !orphaned_users.is_empty() {
+    if !orphaned_users.is_empty() && no_removals {
+        println!("{} --no-removals: keeping {} users removed from config", "[INFO]".blue(), orphaned_users.len());
+    } else if !orphaned_users.is_empty() {
         println!("{} Found {} users removed from config but still exist in system:", "[INFO]".blue(), orphaned_users.len());
         for user in &orphaned_users {
             println!("  - {}", user);
         }
 
-        if ask_user_confirmation("Delete these users from the system?", yes, no, verbose)? {
+        let names: Vec<&str> = orphaned_users.iter().map(String::as_str).collect();
+        if ask_user_confirmation("Delete these users from the system?", yes || planned("users", &names), no, verbose)? {
             for user in &orphaned_users {
                 if ask_user_confirmation(&format!("Remove home directory for user '{}'?", user), false, false, verbose)? {
-                    delete_user(user, true, verbose)?;
+                    delete_user(runner, user, true, verbose)?;
                 } else {
-                    delete_user(user, false, verbose)?;
+                    delete_user(runner, user, false, verbose)?;
                 }
                 state.managed_users.remove(user);
+                result.removed += 1;
                 println!("{} Deleted user {}", "[SUCCESS]".green(), user);
             }
         }
@@ -4953,22 +11038,135 @@ fn sync_users_and_groups(yes: bool, no: bool, verbose: bool) -> Result<()> {
 
     // Sync groups first (users may depend on groups)
     if let Some(declared_groups) = &config.groups {
-        sync_groups_bidirectional(declared_groups, &current_groups, &mut state, yes, no, verbose)?;
+        result += sync_groups_bidirectional(runner, declared_groups, &current_groups, &mut state, yes, no, no_removals, verbose)?;
     } else if verbose {
         println!("{} No groups declared in config", "[DEBUG]".cyan());
     }
 
     // Then sync users
     if let Some(declared_users) = &config.users {
-        sync_users_bidirectional(declared_users, &current_users, &mut state, yes, no, verbose)?;
+        result += sync_users_bidirectional(runner, declared_users, &current_users, &mut state, yes, no, no_removals, verbose)?;
     } else if verbose {
         println!("{} No users declared in config", "[DEBUG]".cyan());
     }
 
+    // Finally memberships other subsystems need
+    sync_group_memberships(runner, memberships, &mut state, yes, no, no_removals, verbose)?;
+
     // Save updated state
     save_users_groups_state(&state)?;
 
     println!("{} Users and groups synchronization complete", "[SUCCESS]".green());
+    Ok(result)
+}
+
+/// Supplementary groups named in a user's `groups` that are neither declared nor on the system.
+/// They are created as regular groups after confirmation; declining stops the sync with the list.
+fn create_referenced_groups(runner: &dyn CommandRunner, config: &UsersGroupsConfig, yes: bool, no: bool, verbose: bool) -> Result<usize> {
+    let declared = config.groups.as_ref();
+    let mut missing: Vec<&String> = config.users.iter().flatten()
+        .flat_map(|(_, user)| user.groups.iter().flatten())
+        .filter(|group| !declared.is_some_and(|groups| groups.contains_key(*group)))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    missing.retain(|group| !spawn_output_via(runner, &["getent", "group", group]).is_ok_and(|output| output.status.success()));
+
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    println!("{} Users reference {} groups that don't exist:", "[WARN]".yellow(), missing.len());
+    for group in &missing {
+        let users: Vec<&str> = config.users.iter().flatten()
+            .filter(|(_, user)| user.groups.iter().flatten().any(|g| g == *group))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        println!("  - {} (needed by {})", group, users.join(", "));
+    }
+
+    if !ask_user_confirmation("Create these as regular groups?", yes, no, verbose)? {
+        return Err(FedoraForgeError::validation(format!(
+            "Missing groups {}: declare them under [groups] or create them before syncing users",
+            missing.iter().map(|g| g.as_str()).collect::<Vec<_>>().join(", ")
+        )));
+    }
+
+    let config = GroupConfig { gid: None, members: None, system: Some(false) };
+    for group in &missing {
+        create_group(runner, group, &config)?;
+        println!("{} Created group {}", "[SUCCESS]".green(), group);
+    }
+    Ok(missing.len())
+}
+
+/// (user, group, reason) memberships required by other parts of the config.
+fn implied_group_memberships(config: &Config) -> Result<Vec<(String, String, String)>> {
+    let mut memberships = Vec::new();
+    if config.system.enable_amd_gpu {
+        memberships.push((user_name()?, "render".to_string(), "amd_gpu".to_string()));
+    }
+    Ok(memberships)
+}
+
+/// Adds implied memberships that are missing and tracks the ones we added, so they can be
+/// removed again once nothing requires them. Memberships that already existed are left alone.
+fn sync_group_memberships(
+    runner: &dyn CommandRunner,
+    memberships: &[(String, String, String)],
+    state: &mut UsersGroupsState,
+    yes: bool,
+    no: bool,
+    no_removals: bool,
+    verbose: bool,
+) -> Result<()> {
+    let timestamp = get_current_timestamp();
+
+    for (user, group, reason) in memberships {
+        if user_in_group(runner, user, group)? {
+            debug!(verbose, "{} is already in the {} group", user, group);
+            continue;
+        }
+
+        run_command_via(runner, &["sudo", "usermod", "-aG", group, user], &format!("Adding {} to {} group ({})", user, group, reason))?;
+        println!("{}", format!("Log out and back in for the {} group to take effect.", group).yellow());
+        if !state.managed_memberships.iter().any(|m| &m.user == user && &m.group == group) {
+            state.managed_memberships.push(ManagedMembership {
+                user: user.clone(),
+                group: group.clone(),
+                reason: reason.clone(),
+                managed_at: timestamp,
+            });
+        }
+    }
+
+    let stale: Vec<ManagedMembership> = state.managed_memberships.iter()
+        .filter(|m| !memberships.iter().any(|(user, group, _)| user == &m.user && group == &m.group))
+        .cloned()
+        .collect();
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    if no_removals {
+        println!("{} --no-removals: keeping {} group memberships no longer required", "[INFO]".blue(), stale.len());
+        return Ok(());
+    }
+
+    println!("{} Found {} group memberships no longer required by config:", "[INFO]".blue(), stale.len());
+    for m in &stale {
+        println!("  - {} in {} (added for {})", m.user, m.group, m.reason);
+    }
+
+    if ask_user_confirmation("Remove these group memberships?", yes, no, verbose)? {
+        for m in &stale {
+            if user_in_group(runner, &m.user, &m.group)? {
+                run_command_via(runner, &["sudo", "gpasswd", "-d", &m.user, &m.group], &format!("Removing {} from {} group", m.user, m.group))?;
+            }
+            state.managed_memberships.retain(|kept| !(kept.user == m.user && kept.group == m.group));
+        }
+    }
+
     Ok(())
 }
 
@@ -4976,8 +11174,8 @@ fn sync_users_and_groups(yes: bool, no: bool, verbose: bool) -> Result<()> {
 fn generate_initial_users_groups_config() -> Result<()> {
     println!("{} Generating users and groups configuration from current system state...", "[INFO]".blue());
 
-    let current_users = get_current_users(false)?;
-    let current_groups = get_current_groups(false)?;
+    let current_users = get_current_users(command_runner(), &[], false)?;
+    let current_groups = get_current_groups(&[], false)?;
 
     if current_users.is_empty() && current_groups.is_empty() {
         println!("{} No non-system users or groups found to add to config", "[WARN]".yellow());
@@ -4992,3 +11190,101 @@ fn generate_initial_users_groups_config() -> Result<()> {
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fedoraforge::MockRunner;
+
+    fn user_config(toml: &str) -> UserConfig {
+        toml::from_str(toml).expect("valid user config")
+    }
+
+    fn argv(cmd: &[&str]) -> Vec<String> {
+        cmd.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn sync_users_creates_declared_users() {
+        let runner = MockRunner::default();
+        runner.file("/etc/passwd", "root:x:0:0:root:/root:/bin/bash\nalice:x:1500:1500::/home/alice:/sbin/nologin\n");
+        let declared = HashMap::from([(
+            "alice".to_string(),
+            user_config("shell = \"/sbin/nologin\"\ngroups = [\"wheel\"]"),
+        )]);
+        let mut state = UsersGroupsState::default();
+
+        let result = sync_users_bidirectional(&runner, &declared, &HashMap::new(), &mut state, true, false, false, false).unwrap();
+
+        assert_eq!(result.created, 1);
+        let calls = runner.calls();
+        assert!(calls.contains(&argv(&["sudo", "useradd", "-s", "/sbin/nologin", "-m", "alice"])));
+        assert!(calls.contains(&argv(&["sudo", "usermod", "-aG", "wheel", "alice"])));
+        assert_eq!(state.managed_users["alice"].uid, 1500);
+    }
+
+    #[test]
+    fn sync_users_modifies_only_changed_fields() {
+        let runner = MockRunner::default();
+        let declared = HashMap::from([(
+            "alice".to_string(),
+            user_config("uid = 1500\nshell = \"/sbin/nologin\""),
+        )]);
+        let current = HashMap::from([(
+            "alice".to_string(),
+            CurrentUserInfo {
+                uid: 1500,
+                gid: 1500,
+                groups: Vec::new(),
+                home: "/home/alice".to_string(),
+                shell: "/bin/bash".to_string(),
+                comment: String::new(),
+                homed: false,
+                shadow_password: None,
+            },
+        )]);
+        let mut state = UsersGroupsState::default();
+
+        let result = sync_users_bidirectional(&runner, &declared, &current, &mut state, true, false, false, false).unwrap();
+
+        assert_eq!(result.modified, 1);
+        assert_eq!(runner.calls(), [argv(&["sudo", "usermod", "-s", "/sbin/nologin", "alice"])]);
+        assert_eq!(state.managed_users["alice"].uid, 1500);
+    }
+
+    #[test]
+    fn sync_system_packages_installs_declared_and_keeps_undeclared_with_no_removals() {
+        let runner = MockRunner::default();
+        runner.respond(&["dnf", "repoquery", "--leaves", "--userinstalled", "--qf", "%{name}\\n"], 0, "git\nhtop\n");
+        let entries = [PackageEntry::Name("git".to_string()), PackageEntry::Name("vim".to_string())];
+
+        let result = sync_system_packages(&runner, &entries, &[], false, true, true, false).unwrap();
+
+        assert_eq!(result.created, 1);
+        assert_eq!(result.removed, 0);
+        let calls = runner.calls();
+        assert!(calls.contains(&argv(&["sudo", "dnf", "install", "-y", "--skip-unavailable", "vim"])));
+        assert!(!calls.iter().any(|call| call.contains(&"remove".to_string())));
+    }
+
+    #[test]
+    fn manage_containers_creates_missing_containers() {
+        let runner = MockRunner::default();
+        runner.respond(&["podman", "ps", "-a", "--format", "{{.Names}}"], 0, "db\n");
+        runner.respond(&["podman", "image", "inspect", "--format", "{{.Id}}", "docker.io/library/nginx"], 0, "sha256:abc\n");
+        let containers: Vec<Container> = vec![toml::from_str("name = \"web\"\nimage = \"docker.io/library/nginx\"").unwrap()];
+        let args = Args::parse_from(["fedoraforge", "--yes"]);
+
+        let mut state = ContainerState::default();
+
+        let result = manage_containers_smart(&runner, &containers, &mut state, "/home/alice", &args).unwrap();
+
+        assert_eq!(result.created, 1);
+        assert_eq!(state.containers["web"].image_hash.as_deref(), Some("sha256:abc"));
+        let calls = runner.calls();
+        assert!(calls.contains(&argv(&[
+            "podman", "create", "--name=web", "--label", "managed-by=fedoraforge", "docker.io/library/nginx",
+        ])));
+        assert!(!calls.iter().any(|call| call.starts_with(&argv(&["podman", "pull"]))));
+    }
+}